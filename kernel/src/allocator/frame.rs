@@ -4,10 +4,11 @@ use crate::{
     config::PAGE_SIZE_4K,
     dtb::MACHINE_META,
     mem::{PhysAddr, align_down, align_up},
+    sync::SpinNoIrq,
 };
-use spin::Mutex;
 
-pub static PHYS_FRAME_ALLOCATOR: Mutex<PhysFrameAllocator> = Mutex::new(PhysFrameAllocator::new());
+pub static PHYS_FRAME_ALLOCATOR: SpinNoIrq<PhysFrameAllocator> =
+    SpinNoIrq::new(PhysFrameAllocator::new());
 
 pub fn init_frame_allocator() {
     unsafe extern "C" {
@@ -17,7 +18,27 @@ pub fn init_frame_allocator() {
     let meta = MACHINE_META.get().expect("dtb parsed");
     let phys_mem_end = meta.phys_mem_start + meta.phys_mem_size;
     let size = phys_mem_end - start;
-    PHYS_FRAME_ALLOCATOR.lock().init(start.into(), size);
+    let mut allocator = PHYS_FRAME_ALLOCATOR.lock();
+    allocator.init(start.into(), size);
+    // the initrd stays until it is unpacked, see `release_initrd`
+    if let Some(initrd) = meta.initrd.clone().filter(|initrd| initrd.start >= start) {
+        let first = align_down(initrd.start, PAGE_SIZE_4K);
+        let frames = (align_up(initrd.end, PAGE_SIZE_4K) - first) / PAGE_SIZE_4K;
+        allocator.alloc_range(first.into(), frames);
+    }
+}
+
+/// Returns the memory of the initrd to the allocator once it is unpacked.
+pub fn release_initrd() {
+    let Some(initrd) = MACHINE_META.get().and_then(|meta| meta.initrd.clone()) else {
+        return;
+    };
+    let mut allocator = PHYS_FRAME_ALLOCATOR.lock();
+    let first = align_down(initrd.start, PAGE_SIZE_4K);
+    if first >= allocator.base {
+        let frames = (align_up(initrd.end, PAGE_SIZE_4K) - first) / PAGE_SIZE_4K;
+        allocator.dealloc_frames(first.into(), frames);
+    }
 }
 
 pub struct PhysFrameAllocator {
@@ -65,15 +86,21 @@ impl PhysFrameAllocator {
     pub fn dealloc_frames(&mut self, pos: PhysAddr, num_frames: usize) -> bool {
         // TODO: not decrease `used_frames` if deallocation failed
         self.used_frames -= num_frames;
-        self.inner
-            .dealloc((pos.as_usize() - self.base) / PAGE_SIZE_4K)
+        let start = (pos.as_usize() - self.base) / PAGE_SIZE_4K;
+        (start..start + num_frames).all(|idx| self.inner.dealloc(idx))
     }
 
     pub fn alloc_range(&mut self, start: PhysAddr, num_frames: usize) {
         assert_eq!(start.as_usize() % PAGE_SIZE_4K, 0);
         assert!(start.as_usize() >= self.base);
         let start = (start.as_usize() - self.base) / PAGE_SIZE_4K;
-        self.inner.alloc_contiguous(Some(start), num_frames, 1);
+        if self
+            .inner
+            .alloc_contiguous(Some(start), num_frames, 1)
+            .is_some()
+        {
+            self.used_frames += num_frames;
+        }
     }
 
     pub fn total_frames(&self) -> usize {
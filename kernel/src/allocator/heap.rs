@@ -1,10 +1,10 @@
 use core::alloc::{GlobalAlloc, Layout};
 
 use alloc::vec::Vec;
-use buddy_system_allocator::LockedHeap;
+use buddy_system_allocator::Heap;
 use log::debug;
 
-use crate::{allocator::frame::PHYS_FRAME_ALLOCATOR, config::PAGE_SIZE_4K};
+use crate::{allocator::frame::PHYS_FRAME_ALLOCATOR, config::PAGE_SIZE_4K, sync::SpinNoIrq};
 
 #[global_allocator]
 static HEAP_ALLOCATOR: BuddyHeapAllocator = BuddyHeapAllocator::new();
@@ -25,13 +25,13 @@ pub fn init_heap_allocator() {
 }
 
 pub struct BuddyHeapAllocator {
-    inner: LockedHeap<32>,
+    inner: SpinNoIrq<Heap<32>>,
 }
 
 impl BuddyHeapAllocator {
     pub const fn new() -> Self {
         Self {
-            inner: LockedHeap::<32>::new(),
+            inner: SpinNoIrq::new(Heap::new()),
         }
     }
 
@@ -42,6 +42,17 @@ impl BuddyHeapAllocator {
     pub fn add_memory(&self, start: usize, size: usize) {
         unsafe { self.inner.lock().add_to_heap(start, start + size) };
     }
+
+    /// Bytes handed out by the heap and bytes of memory it manages.
+    pub fn stats(&self) -> (usize, usize) {
+        let inner = self.inner.lock();
+        (inner.stats_alloc_actual(), inner.stats_total_bytes())
+    }
+}
+
+/// Bytes of the kernel heap in use and its size.
+pub fn heap_stats() -> (usize, usize) {
+    HEAP_ALLOCATOR.stats()
 }
 
 unsafe impl GlobalAlloc for BuddyHeapAllocator {
@@ -0,0 +1,348 @@
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use log::warn;
+
+use crate::{
+    KError, KResult,
+    runtime::{spawn, timeout},
+    sync::{Notify, RwLock, RwLockReadGuard, RwLockWriteGuard, SpinNoIrq},
+};
+
+use super::BlockDevice;
+
+/// Bytes of block data the cache keeps before evicting clean buffers.
+pub const CACHE_CAPACITY: usize = 8 << 20;
+/// Blocks read ahead once a reader is found to be sequential.
+const READ_AHEAD_BLOCKS: usize = 16;
+/// Longest time dirty data stays in memory.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The cache shared by all block devices.
+pub static BUFFER_CACHE: BufferCache = BufferCache::new();
+
+/// Identifies a device in the cache while the cache holds a reference to it.
+fn device_id(device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(device) as *const u8 as usize
+}
+
+/// `(device, block size, block)`.
+type BufferKey = (usize, usize, usize);
+
+/// A cached block. The block size is chosen by the user of the device, e.g.
+/// the block size of a filesystem, and should be the same for all users.
+pub struct Buffer {
+    device: Arc<dyn BlockDevice>,
+    block: usize,
+    size: usize,
+    data: RwLock<Box<[u8]>>,
+    uptodate: AtomicBool,
+    dirty: AtomicBool,
+}
+
+impl Buffer {
+    pub fn block(&self) -> usize {
+        self.block
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Acquire)
+    }
+
+    /// First block of the device the buffer covers.
+    fn device_block(&self) -> usize {
+        self.block * (self.size / self.device.block_size())
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, Box<[u8]>> {
+        self.data.read().await
+    }
+
+    /// Locks the data for modification; the buffer is written back later by
+    /// the flusher or [`sync`](Self::sync).
+    pub async fn write(&self) -> RwLockWriteGuard<'_, Box<[u8]>> {
+        let data = self.data.write().await;
+        self.dirty.store(true, Ordering::Release);
+        data
+    }
+
+    /// Writes the buffer back if it is dirty.
+    pub async fn sync(&self) -> KResult<()> {
+        let data = self.data.read().await;
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let result = self.device.write_blocks(self.device_block(), &data).await;
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Release);
+        }
+        result
+    }
+
+    /// Loads the block from the device unless it already is.
+    async fn load(&self) -> KResult<()> {
+        if self.uptodate.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let mut data = self.data.write().await;
+        if !self.uptodate.load(Ordering::Acquire) {
+            self.device
+                .read_blocks(self.device_block(), &mut data)
+                .await?;
+            self.uptodate.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+}
+
+struct CacheEntry {
+    buffer: Arc<Buffer>,
+    stamp: u64,
+}
+
+struct CacheState {
+    entries: BTreeMap<BufferKey, CacheEntry>,
+    /// Keys by time of last use, oldest first.
+    lru: BTreeMap<u64, BufferKey>,
+    clock: u64,
+    bytes: usize,
+    /// Block each device is expected to be read at next if it is read
+    /// sequentially, by device and block size.
+    next_read: BTreeMap<(usize, usize), usize>,
+}
+
+/// An LRU cache of blocks with write-back of dirty buffers.
+pub struct BufferCache {
+    state: SpinNoIrq<CacheState>,
+    /// Wakes the flusher early.
+    flush_needed: Notify,
+}
+
+impl BufferCache {
+    const fn new() -> Self {
+        Self {
+            state: SpinNoIrq::new(CacheState {
+                entries: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                bytes: 0,
+                next_read: BTreeMap::new(),
+            }),
+            flush_needed: Notify::new(),
+        }
+    }
+
+    /// Bytes of block data cached.
+    pub fn bytes(&self) -> usize {
+        self.state.lock().bytes
+    }
+
+    /// Finds or creates the buffer of `block`, marking it most recently
+    /// used. The second value tells whether it was created.
+    fn lookup(
+        &self,
+        device: &Arc<dyn BlockDevice>,
+        block: usize,
+        size: usize,
+    ) -> (Arc<Buffer>, bool) {
+        let key = (device_id(device), size, block);
+        let mut state = self.state.lock();
+        state.clock += 1;
+        let stamp = state.clock;
+        if let Some(entry) = state.entries.get_mut(&key) {
+            let old = core::mem::replace(&mut entry.stamp, stamp);
+            let buffer = entry.buffer.clone();
+            state.lru.remove(&old);
+            state.lru.insert(stamp, key);
+            return (buffer, false);
+        }
+        let buffer = Arc::new(Buffer {
+            device: device.clone(),
+            block,
+            size,
+            data: RwLock::new(vec![0; size].into_boxed_slice()),
+            uptodate: AtomicBool::new(false),
+            dirty: AtomicBool::new(false),
+        });
+        state.entries.insert(key, CacheEntry {
+            buffer: buffer.clone(),
+            stamp,
+        });
+        state.lru.insert(stamp, key);
+        state.bytes += size;
+        if state.bytes > CACHE_CAPACITY && !Self::evict(&mut state) {
+            self.flush_needed.notify_one();
+        }
+        (buffer, true)
+    }
+
+    /// Drops least recently used buffers which are clean and unused until
+    /// the cache fits its capacity. Returns false if dirty buffers are in
+    /// the way.
+    fn evict(state: &mut CacheState) -> bool {
+        let mut victims = Vec::new();
+        let mut freed = 0;
+        let mut blocked_by_dirty = false;
+        for (&stamp, key) in state.lru.iter() {
+            if state.bytes - freed <= CACHE_CAPACITY {
+                break;
+            }
+            let buffer = &state.entries[key].buffer;
+            if buffer.is_dirty() {
+                blocked_by_dirty = true;
+            } else if Arc::strong_count(buffer) == 1 {
+                victims.push(stamp);
+                freed += key.1;
+            }
+        }
+        for stamp in victims {
+            let key = state.lru.remove(&stamp).unwrap();
+            state.entries.remove(&key);
+        }
+        state.bytes -= freed;
+        !blocked_by_dirty || state.bytes <= CACHE_CAPACITY
+    }
+
+    /// Returns the buffer of block `block` of `size` bytes, reading it if it
+    /// is not cached. Sequential reads start read-ahead of the next blocks.
+    pub async fn bread(
+        &self,
+        device: &Arc<dyn BlockDevice>,
+        block: usize,
+        size: usize,
+    ) -> KResult<Arc<Buffer>> {
+        if size == 0 || size % device.block_size() != 0 {
+            return Err(KError::InvalidArgument);
+        }
+        let (buffer, _) = self.lookup(device, block, size);
+        let read_ahead = {
+            let mut state = self.state.lock();
+            let id = device_id(device);
+            let sequential = state.next_read.insert((id, size), block + 1) == Some(block);
+            sequential && !state.entries.contains_key(&(id, size, block + 1))
+        };
+        if read_ahead {
+            spawn(Self::read_ahead(device.clone(), block + 1, size)).detach();
+        }
+        buffer.load().await?;
+        Ok(buffer)
+    }
+
+    /// Returns the buffer of block `block` without reading it, for callers
+    /// about to overwrite all of it.
+    pub fn getblk(&self, device: &Arc<dyn BlockDevice>, block: usize, size: usize) -> Arc<Buffer> {
+        let (buffer, _) = self.lookup(device, block, size);
+        buffer.uptodate.store(true, Ordering::Release);
+        buffer
+    }
+
+    /// Loads up to [`READ_AHEAD_BLOCKS`] blocks from `start` on in one
+    /// request, stopping at the first one already cached.
+    async fn read_ahead(device: Arc<dyn BlockDevice>, start: usize, size: usize) {
+        let cache = &BUFFER_CACHE;
+        let end = (start + READ_AHEAD_BLOCKS).min(device.num_blocks() * device.block_size() / size);
+        let mut buffers = Vec::new();
+        for block in start..end {
+            let (buffer, created) = cache.lookup(&device, block, size);
+            if !created {
+                break;
+            }
+            buffers.push(buffer);
+        }
+        let mut guards = Vec::with_capacity(buffers.len());
+        for buffer in &buffers {
+            guards.push(buffer.data.write().await);
+        }
+        if guards.is_empty() {
+            return;
+        }
+        let mut data = vec![0; guards.len() * size];
+        let block = buffers[0].device_block();
+        if device.read_blocks(block, &mut data).await.is_err() {
+            // left for `bread` to retry
+            return;
+        }
+        for ((buffer, guard), chunk) in buffers.iter().zip(&mut guards).zip(data.chunks(size)) {
+            if !buffer.uptodate.load(Ordering::Acquire) {
+                guard.copy_from_slice(chunk);
+                buffer.uptodate.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    fn dirty_buffers(&self, device: Option<usize>) -> Vec<Arc<Buffer>> {
+        self.state
+            .lock()
+            .entries
+            .iter()
+            .filter(|(key, entry)| device.is_none_or(|id| key.0 == id) && entry.buffer.is_dirty())
+            .map(|(_, entry)| entry.buffer.clone())
+            .collect()
+    }
+
+    /// Writes back all dirty buffers of `device` and flushes it.
+    pub async fn sync_device(&self, device: &Arc<dyn BlockDevice>) -> KResult<()> {
+        for buffer in self.dirty_buffers(Some(device_id(device))) {
+            buffer.sync().await?;
+        }
+        device.flush().await
+    }
+
+    /// Writes back all dirty buffers and flushes their devices. Keeps going
+    /// after errors and returns the first one.
+    pub async fn sync_all(&self) -> KResult<()> {
+        let mut result = Ok(());
+        let mut devices: BTreeMap<usize, Arc<dyn BlockDevice>> = BTreeMap::new();
+        for buffer in self.dirty_buffers(None) {
+            if let Err(err) = buffer.sync().await {
+                result = result.and(Err(err));
+            }
+            devices.insert(device_id(&buffer.device), buffer.device.clone());
+        }
+        for device in devices.values() {
+            if let Err(err) = device.flush().await {
+                result = result.and(Err(err));
+            }
+        }
+        result
+    }
+
+    /// Drops the clean, unused buffers of `device`, e.g. when it is
+    /// unmounted.
+    pub fn invalidate_device(&self, device: &Arc<dyn BlockDevice>) {
+        let id = device_id(device);
+        let mut state = self.state.lock();
+        let keys: Vec<_> = state
+            .entries
+            .iter()
+            .filter(|(key, entry)| {
+                key.0 == id && !entry.buffer.is_dirty() && Arc::strong_count(&entry.buffer) == 1
+            })
+            .map(|(key, entry)| (*key, entry.stamp))
+            .collect();
+        for (key, stamp) in keys {
+            state.entries.remove(&key);
+            state.lru.remove(&stamp);
+            state.bytes -= key.1;
+        }
+        state.next_read.retain(|(device, _), _| *device != id);
+    }
+}
+
+/// Kernel task writing dirty buffers back every [`FLUSH_INTERVAL`], or
+/// earlier when dirty buffers keep the cache from shrinking.
+pub async fn flusher() {
+    loop {
+        let _ = timeout(FLUSH_INTERVAL, BUFFER_CACHE.flush_needed.notified()).await;
+        if let Err(err) = BUFFER_CACHE.sync_all().await {
+            warn!("buffer cache write-back failed: {:?}", err);
+        }
+    }
+}
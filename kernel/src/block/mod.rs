@@ -0,0 +1,124 @@
+mod cache;
+mod partition;
+
+pub use cache::*;
+pub use partition::*;
+
+use core::pin::Pin;
+
+use alloc::{boxed::Box, collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use log::{info, warn};
+
+use crate::{
+    KResult,
+    fs::{major, minor},
+    runtime::spawn,
+    sync::{Event, SpinNoIrq},
+};
+
+/// Future returned by [`BlockDevice`] operations. They are boxed so that
+/// devices can be used as trait objects.
+pub type BlockFuture<'a, T> = Pin<Box<dyn Future<Output = KResult<T>> + Send + 'a>>;
+
+/// A device storing fixed-size blocks, e.g. a disk or a partition of one.
+pub trait BlockDevice: Send + Sync {
+    /// Size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks.
+    fn num_blocks(&self) -> usize;
+
+    fn is_readonly(&self) -> bool {
+        false
+    }
+
+    /// Reads blocks from `block` on into `buf`, whose length must be a
+    /// multiple of the block size.
+    fn read_blocks<'a>(&'a self, block: usize, buf: &'a mut [u8]) -> BlockFuture<'a, ()>;
+
+    /// Writes `buf`, whose length must be a multiple of the block size, to
+    /// the blocks from `block` on.
+    fn write_blocks<'a>(&'a self, block: usize, buf: &'a [u8]) -> BlockFuture<'a, ()>;
+
+    /// Waits until completed writes are persistent.
+    fn flush(&self) -> BlockFuture<'_, ()>;
+}
+
+struct BlockDeviceEntry {
+    rdev: u64,
+    device: Arc<dyn BlockDevice>,
+}
+
+/// Block devices by name, e.g. `vda` for a disk and `vda1` for its first
+/// partition.
+static BLOCK_DEVICES: SpinNoIrq<BTreeMap<String, BlockDeviceEntry>> =
+    SpinNoIrq::new(BTreeMap::new());
+
+/// Registers `device` as `name` with device number `rdev`, which makes it
+/// show up in devfs.
+pub fn register_block_device(name: String, rdev: u64, device: Arc<dyn BlockDevice>) {
+    info!(
+        "block device {} ({}:{}): {} blocks of {} bytes",
+        name,
+        major(rdev),
+        minor(rdev),
+        device.num_blocks(),
+        device.block_size()
+    );
+    BLOCK_DEVICES
+        .lock()
+        .insert(name, BlockDeviceEntry { rdev, device });
+}
+
+pub fn block_device(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    BLOCK_DEVICES
+        .lock()
+        .get(name)
+        .map(|entry| entry.device.clone())
+}
+
+/// The block device with device number `rdev`.
+pub fn block_device_by_number(rdev: u64) -> Option<Arc<dyn BlockDevice>> {
+    BLOCK_DEVICES
+        .lock()
+        .values()
+        .find(|entry| entry.rdev == rdev)
+        .map(|entry| entry.device.clone())
+}
+
+/// All block devices with their names and device numbers, sorted by name.
+pub fn block_devices() -> Vec<(String, u64, Arc<dyn BlockDevice>)> {
+    BLOCK_DEVICES
+        .lock()
+        .iter()
+        .map(|(name, entry)| (name.clone(), entry.rdev, entry.device.clone()))
+        .collect()
+}
+
+/// First free name of the form `<prefix>a`, `<prefix>b`, ... for a disk,
+/// with its index, 0 for `a`.
+pub fn alloc_disk_name(prefix: &str) -> (String, u32) {
+    let devices = BLOCK_DEVICES.lock();
+    (b'a'..=b'z')
+        .map(|c| (alloc::format!("{}{}", prefix, c as char), (c - b'a') as u32))
+        .find(|(name, _)| !devices.contains_key(name))
+        .expect("too many disks")
+}
+
+/// Set once the partitions of the disks found at boot are registered.
+pub static PARTITIONS_SCANNED: Event = Event::new();
+
+/// Scans the disks registered by the drivers for partitions and starts the
+/// write-back flusher. Needs the executor, as both run as kernel tasks.
+pub fn init() {
+    spawn(async {
+        for (name, rdev, disk) in block_devices() {
+            if let Err(err) = scan_partitions(&name, rdev, disk).await {
+                warn!("{}: partition table unreadable: {:?}", name, err);
+            }
+        }
+        PARTITIONS_SCANNED.set();
+    })
+    .detach();
+    spawn(flusher()).detach();
+}
@@ -0,0 +1,209 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use alloc::{boxed::Box, format, sync::Arc, vec, vec::Vec};
+use log::info;
+
+use crate::{
+    KError, KResult,
+    fs::{major, makedev, minor},
+};
+
+use super::{BlockDevice, BlockFuture, register_block_device};
+
+/// Minors a disk spans: its own and those of its first 15 partitions.
+pub const DISK_MINORS: u32 = 16;
+/// Major of partitions beyond those a disk has minors for, as in Linux.
+pub const BLOCK_EXT_MAJOR: u32 = 259;
+
+static NEXT_EXT_MINOR: AtomicU32 = AtomicU32::new(0);
+
+/// Sector size partition tables are addressed in.
+const LBA_SIZE: usize = 512;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES: usize = 446;
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+const MBR_TYPE_GPT: u8 = 0xee;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// A contiguous range of the blocks of a disk, seen as a device of its own.
+pub struct Partition {
+    disk: Arc<dyn BlockDevice>,
+    /// First block, in blocks of the disk.
+    start: usize,
+    num_blocks: usize,
+}
+
+impl Partition {
+    fn check_range(&self, block: usize, len: usize) -> KResult<usize> {
+        let count = len.div_ceil(self.block_size());
+        if block + count > self.num_blocks {
+            return Err(KError::InvalidArgument);
+        }
+        Ok(self.start + block)
+    }
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    fn is_readonly(&self) -> bool {
+        self.disk.is_readonly()
+    }
+
+    fn read_blocks<'a>(&'a self, block: usize, buf: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            let block = self.check_range(block, buf.len())?;
+            self.disk.read_blocks(block, buf).await
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, block: usize, buf: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            let block = self.check_range(block, buf.len())?;
+            self.disk.write_blocks(block, buf).await
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        self.disk.flush()
+    }
+}
+
+/// A partition table entry, in `LBA_SIZE` sectors.
+struct PartitionEntry {
+    /// Partition number, from 1 in the order of the table slots.
+    number: usize,
+    start: u64,
+    sectors: u64,
+}
+
+/// Reads `LBA_SIZE` sector `lba` of `disk`.
+async fn read_lba(disk: &dyn BlockDevice, lba: u64, buf: &mut [u8]) -> KResult<()> {
+    let block_size = disk.block_size();
+    let offset = lba as usize * LBA_SIZE;
+    let mut block = vec![0; block_size.max(LBA_SIZE)];
+    let len = block.len();
+    disk.read_blocks(offset / block_size, &mut block[..len - len % block_size])
+        .await?;
+    let start = offset % block_size;
+    buf.copy_from_slice(&block[start..start + LBA_SIZE]);
+    Ok(())
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Primary partitions of an MBR. Logical partitions in extended ones are
+/// not supported.
+fn parse_mbr(sector: &[u8]) -> Vec<PartitionEntry> {
+    (0..4)
+        .map(|i| (i, &sector[MBR_ENTRIES + 16 * i..MBR_ENTRIES + 16 * (i + 1)]))
+        .filter(|(_, entry)| entry[4] != MBR_TYPE_EMPTY && !MBR_TYPE_EXTENDED.contains(&entry[4]))
+        .map(|(i, entry)| PartitionEntry {
+            number: i + 1,
+            start: u32_at(entry, 8) as u64,
+            sectors: u32_at(entry, 12) as u64,
+        })
+        .collect()
+}
+
+/// Partitions of the GPT whose header is in sector 1.
+async fn parse_gpt(disk: &dyn BlockDevice) -> KResult<Vec<PartitionEntry>> {
+    let mut header = [0; LBA_SIZE];
+    read_lba(disk, 1, &mut header).await?;
+    if &header[..8] != GPT_SIGNATURE {
+        return Err(KError::InvalidArgument);
+    }
+    let entries_lba = u64_at(&header, 72);
+    let num_entries = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    if entry_size < 48 || LBA_SIZE % entry_size != 0 {
+        return Err(KError::InvalidArgument);
+    }
+
+    let mut partitions = Vec::new();
+    let mut sector = [0; LBA_SIZE];
+    let per_sector = LBA_SIZE / entry_size;
+    for i in 0..num_entries {
+        if i % per_sector == 0 {
+            read_lba(disk, entries_lba + (i / per_sector) as u64, &mut sector).await?;
+        }
+        let entry = &sector[(i % per_sector) * entry_size..][..entry_size];
+        // an all-zero type GUID marks an unused entry
+        if entry[..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let first = u64_at(entry, 32);
+        let last = u64_at(entry, 40);
+        if last >= first {
+            partitions.push(PartitionEntry {
+                number: i + 1,
+                start: first,
+                sectors: last - first + 1,
+            });
+        }
+    }
+    Ok(partitions)
+}
+
+/// Registers the partitions of disk `name` as `<name>1`, `<name>2`, ...,
+/// reading a GPT if the MBR is a protective one. They are numbered after
+/// the disk's device number `rdev`.
+pub async fn scan_partitions(name: &str, rdev: u64, disk: Arc<dyn BlockDevice>) -> KResult<()> {
+    let mut mbr = [0; LBA_SIZE];
+    read_lba(&*disk, 0, &mut mbr).await?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(());
+    }
+    let entries = match mbr[MBR_ENTRIES + 4] {
+        MBR_TYPE_GPT => parse_gpt(&*disk).await?,
+        _ => parse_mbr(&mbr),
+    };
+    let block_size = disk.block_size();
+    for entry in entries {
+        let number = entry.number;
+        let start = entry.start as usize * LBA_SIZE;
+        let size = entry.sectors as usize * LBA_SIZE;
+        if start % block_size != 0 || size % block_size != 0 {
+            info!("{}: partition {} not aligned to blocks", name, number);
+            continue;
+        }
+        let (start, num_blocks) = (start / block_size, size / block_size);
+        if start + num_blocks > disk.num_blocks() {
+            info!("{}: partition {} beyond the end of the disk", name, number);
+            continue;
+        }
+        let partition = Partition {
+            disk: disk.clone(),
+            start,
+            num_blocks,
+        };
+        let number_rdev = match number < DISK_MINORS as usize {
+            true => makedev(major(rdev), minor(rdev) + number as u32),
+            false => makedev(
+                BLOCK_EXT_MAJOR,
+                NEXT_EXT_MINOR.fetch_add(1, Ordering::Relaxed),
+            ),
+        };
+        register_block_device(
+            format!("{}{}", name, number),
+            number_rdev,
+            Arc::new(partition),
+        );
+    }
+    Ok(())
+}
@@ -3,5 +3,11 @@ pub const MAX_HARTS: usize = 8;
 
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE_4K + 1;
 
+/// The user stack ends at the top of the lower half of Sv39.
+pub const USER_STACK_TOP: usize = 0x40_0000_0000;
+pub const USER_STACK_SIZE: usize = 256 * 1024; // 256KB
+/// Where position-independent programs are loaded.
+pub const USER_PIE_BASE: usize = 0x20_0000_0000;
+
 pub const BOOT_STACK_SIZE: usize = 64 * 1024; // 64KB
 pub const KERNEL_STACK_SIZE: usize = 2 * 1024 * 1024; // 1MB
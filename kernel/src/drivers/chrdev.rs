@@ -0,0 +1,54 @@
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use log::info;
+
+use crate::{
+    Errno,
+    fs::{File, OpenFlags, Path, major, minor},
+    sync::SpinNoIrq,
+};
+
+/// A character device, e.g. a terminal or `/dev/null`, opened through a
+/// node carrying its device number.
+pub trait CharDevice: Send + Sync {
+    /// Opens the device through the node at `path`, which the file reports
+    /// in `fstat` and as its path.
+    fn open(&self, path: Path, flags: OpenFlags) -> Result<Arc<dyn File>, Errno>;
+}
+
+struct CharDeviceEntry {
+    name: String,
+    /// Permission bits of the node in devfs.
+    mode: u32,
+    device: Arc<dyn CharDevice>,
+}
+
+/// Character devices by device number.
+static CHAR_DEVICES: SpinNoIrq<BTreeMap<u64, CharDeviceEntry>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Registers `device` as `name` with device number `rdev`, which makes it
+/// show up in devfs with permission bits `mode`.
+pub fn register_char_device(name: &str, rdev: u64, mode: u32, device: Arc<dyn CharDevice>) {
+    info!("char device {} ({}:{})", name, major(rdev), minor(rdev));
+    CHAR_DEVICES.lock().insert(rdev, CharDeviceEntry {
+        name: String::from(name),
+        mode,
+        device,
+    });
+}
+
+pub fn char_device(rdev: u64) -> Option<Arc<dyn CharDevice>> {
+    CHAR_DEVICES
+        .lock()
+        .get(&rdev)
+        .map(|entry| entry.device.clone())
+}
+
+/// All character devices with their names, device numbers and permission
+/// bits, sorted by device number.
+pub fn char_devices() -> Vec<(String, u64, u32)> {
+    CHAR_DEVICES
+        .lock()
+        .iter()
+        .map(|(&rdev, entry)| (entry.name.clone(), rdev, entry.mode))
+        .collect()
+}
@@ -0,0 +1,111 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use log::warn;
+
+use crate::{KError, KResult, config::MAX_HARTS, dtb::MACHINE_META, hart, sync::SpinNoIrq};
+
+use super::{DEFAULT_IRQ_PRIORITY, PLIC, plic_context};
+
+/// Handles an external interrupt. It runs with interrupts disabled and
+/// should only move data and wake tasks.
+pub type IrqHandler = Arc<dyn Fn() + Send + Sync>;
+
+/// A handler installed for a source, with the interrupts it handled.
+struct IrqAction {
+    name: &'static str,
+    handler: IrqHandler,
+    /// Interrupts handled, per CPU.
+    counts: [AtomicUsize; MAX_HARTS],
+}
+
+static IRQ_HANDLERS: SpinNoIrq<BTreeMap<usize, IrqAction>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Installs `handler` for PLIC source `irq` and enables the source on every
+/// hart. `name` tells the device in `/proc/interrupts`. Fails with
+/// [`KError::Busy`] if the source already has a handler.
+pub fn register_irq(
+    irq: usize,
+    name: &'static str,
+    handler: impl Fn() + Send + Sync + 'static,
+) -> KResult<()> {
+    let plic = PLIC.get().ok_or(KError::InvalidArgument)?;
+    if irq == 0 || irq > plic.sources() {
+        return Err(KError::InvalidArgument);
+    }
+    let mut handlers = IRQ_HANDLERS.lock();
+    if handlers.contains_key(&irq) {
+        return Err(KError::Busy);
+    }
+    handlers.insert(irq, IrqAction {
+        name,
+        handler: Arc::new(handler),
+        counts: [const { AtomicUsize::new(0) }; MAX_HARTS],
+    });
+    drop(handlers);
+    plic.set_priority(irq, DEFAULT_IRQ_PRIORITY);
+    for context in all_contexts() {
+        plic.set_enabled(context, irq, true);
+    }
+    Ok(())
+}
+
+/// Disables source `irq` and removes its handler.
+pub fn unregister_irq(irq: usize) {
+    let Some(plic) = PLIC.get() else {
+        return;
+    };
+    for context in all_contexts() {
+        plic.set_enabled(context, irq, false);
+    }
+    plic.set_priority(irq, 0);
+    IRQ_HANDLERS.lock().remove(&irq);
+}
+
+fn all_contexts() -> impl Iterator<Item = usize> {
+    MACHINE_META
+        .get()
+        .into_iter()
+        .flat_map(|meta| meta.harts.iter().filter_map(|h| h.plic_context))
+}
+
+/// Handles a supervisor external interrupt: claims pending sources from the
+/// PLIC until none is left and runs their handlers.
+pub fn handle_external_irq() {
+    let cpu = hart::hart_id();
+    let (Some(plic), Some(context)) = (PLIC.get(), plic_context(cpu)) else {
+        return;
+    };
+    while let Some(irq) = plic.claim(context) {
+        let handler = IRQ_HANDLERS.lock().get(&irq).map(|action| {
+            action.counts[cpu].fetch_add(1, Ordering::Relaxed);
+            action.handler.clone()
+        });
+        match handler {
+            Some(handler) => handler(),
+            None => warn!("spurious external interrupt {}", irq),
+        }
+        plic.complete(context, irq);
+    }
+}
+
+/// Counters of an interrupt source, as listed in `/proc/interrupts`.
+pub struct IrqStats {
+    pub irq: usize,
+    pub name: &'static str,
+    /// Interrupts handled, per CPU.
+    pub counts: [usize; MAX_HARTS],
+}
+
+/// The counters of all sources with a handler, by source.
+pub fn irq_stats() -> Vec<IrqStats> {
+    IRQ_HANDLERS
+        .lock()
+        .iter()
+        .map(|(&irq, action)| IrqStats {
+            irq,
+            name: action.name,
+            counts: core::array::from_fn(|cpu| action.counts[cpu].load(Ordering::Relaxed)),
+        })
+        .collect()
+}
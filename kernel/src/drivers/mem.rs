@@ -0,0 +1,165 @@
+//! The memory devices: `/dev/null`, `/dev/zero`, `/dev/random` and
+//! `/dev/urandom`.
+
+use alloc::{boxed::Box, sync::Arc};
+
+use crate::{
+    Errno,
+    fs::{File, FileFlags, FsFuture, Metadata, OpenFlags, Path, SeekFrom, makedev},
+    runtime::now,
+    sync::SpinNoIrq,
+};
+
+use super::{CharDevice, register_char_device};
+
+pub const MEM_MAJOR: u32 = 1;
+const NULL_MINOR: u32 = 3;
+const ZERO_MINOR: u32 = 5;
+const RANDOM_MINOR: u32 = 8;
+const URANDOM_MINOR: u32 = 9;
+
+#[derive(Debug, Clone, Copy)]
+enum MemKind {
+    /// Reads nothing and discards writes.
+    Null,
+    /// Reads zeros and discards writes.
+    Zero,
+    /// Reads random bytes; writes are mixed into the generator.
+    Random,
+}
+
+/// Generator behind `/dev/random` and `/dev/urandom`: xoshiro256** seeded
+/// from the time counter, which is also mixed in on every read. There is
+/// no entropy source, so the bytes are unpredictable only to programs that
+/// cannot time the boot; it is no source for keys.
+struct Rng {
+    state: [u64; 4],
+}
+
+impl Rng {
+    const fn new() -> Self {
+        Self { state: [0; 4] }
+    }
+
+    /// Mixes `value` into the state, through splitmix64 so that similar
+    /// values give unrelated states.
+    fn mix(&mut self, value: u64) {
+        let mut x = value;
+        for word in &mut self.state {
+            x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *word ^= z ^ (z >> 31);
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        self.mix(now());
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+static RNG: SpinNoIrq<Rng> = SpinNoIrq::new(Rng::new());
+
+struct MemDevice {
+    kind: MemKind,
+}
+
+impl CharDevice for MemDevice {
+    fn open(&self, path: Path, flags: OpenFlags) -> Result<Arc<dyn File>, Errno> {
+        Ok(Arc::new(MemFile {
+            kind: self.kind,
+            path,
+            flags: FileFlags::new(flags),
+        }))
+    }
+}
+
+struct MemFile {
+    kind: MemKind,
+    /// The node the device was opened through.
+    path: Path,
+    flags: FileFlags,
+}
+
+impl File for MemFile {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        let n = match self.kind {
+            MemKind::Null => 0,
+            MemKind::Zero => {
+                buf.fill(0);
+                buf.len()
+            }
+            MemKind::Random => {
+                RNG.lock().fill(buf);
+                buf.len()
+            }
+        };
+        Box::pin(async move { Ok(n) })
+    }
+
+    fn write<'a>(&'a self, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        if let MemKind::Random = self.kind {
+            let mut rng = RNG.lock();
+            for chunk in buf.chunks(8) {
+                let mut bytes = [0; 8];
+                bytes[..chunk.len()].copy_from_slice(chunk);
+                rng.mix(u64::from_le_bytes(bytes));
+            }
+        }
+        Box::pin(async move { Ok(buf.len()) })
+    }
+
+    /// Positions mean nothing here; seeking always succeeds, as in Linux.
+    fn seek(&self, _pos: SeekFrom) -> FsFuture<'_, u64> {
+        Box::pin(async { Ok(0) })
+    }
+
+    fn stat(&self) -> FsFuture<'_, Metadata> {
+        self.path.dentry.inode().metadata()
+    }
+
+    fn flags(&self) -> OpenFlags {
+        self.flags.get()
+    }
+
+    fn set_flags(&self, flags: OpenFlags) {
+        self.flags.set(flags);
+    }
+
+    fn path(&self) -> Option<Path> {
+        Some(self.path.clone())
+    }
+}
+
+/// Seeds the random generator and registers the memory devices.
+pub fn init_mem_devices() {
+    RNG.lock().mix(now());
+    let devices = [
+        ("null", NULL_MINOR, MemKind::Null),
+        ("zero", ZERO_MINOR, MemKind::Zero),
+        ("random", RANDOM_MINOR, MemKind::Random),
+        ("urandom", URANDOM_MINOR, MemKind::Random),
+    ];
+    for (name, minor, kind) in devices {
+        let rdev = makedev(MEM_MAJOR, minor);
+        register_char_device(name, rdev, 0o666, Arc::new(MemDevice { kind }));
+    }
+}
@@ -0,0 +1,22 @@
+mod chrdev;
+mod irq;
+mod mem;
+mod plic;
+mod tty;
+mod uart;
+mod virtio;
+
+pub use chrdev::*;
+pub use irq::*;
+pub use mem::*;
+pub use plic::*;
+pub use tty::*;
+pub use uart::*;
+pub use virtio::*;
+
+pub fn init() {
+    init_plic();
+    init_mem_devices();
+    init_uart();
+    probe_virtio();
+}
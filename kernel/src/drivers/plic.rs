@@ -0,0 +1,120 @@
+use core::ptr::{read_volatile, write_volatile};
+
+use log::info;
+use riscv::register::sie;
+use spin::Once;
+
+use crate::{dtb::MACHINE_META, hart, sync::SpinNoIrq};
+
+const PRIORITY_BASE: usize = 0;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0;
+const CONTEXT_CLAIM: usize = 4;
+
+/// Priority given to registered sources. Source priority 0 never
+/// interrupts, so anything above the threshold of 0 does.
+pub const DEFAULT_IRQ_PRIORITY: u32 = 1;
+
+pub static PLIC: Once<Plic> = Once::new();
+
+/// RISC-V platform-level interrupt controller.
+pub struct Plic {
+    base: usize,
+    sources: usize,
+    /// Serializes read-modify-write of the enable bits.
+    enable_lock: SpinNoIrq<()>,
+}
+
+impl Plic {
+    /// Number of interrupt sources, valid ids are `1..=sources`.
+    pub fn sources(&self) -> usize {
+        self.sources
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    pub fn set_priority(&self, irq: usize, priority: u32) {
+        unsafe { write_volatile(self.reg(PRIORITY_BASE + 4 * irq), priority) };
+    }
+
+    pub fn set_enabled(&self, context: usize, irq: usize, enabled: bool) {
+        let reg = self.reg(ENABLE_BASE + ENABLE_STRIDE * context + 4 * (irq / 32));
+        let bit = 1 << (irq % 32);
+        let _guard = self.enable_lock.lock();
+        unsafe {
+            let bits = read_volatile(reg);
+            write_volatile(reg, if enabled { bits | bit } else { bits & !bit });
+        }
+    }
+
+    /// Sources with a priority not above `threshold` are masked for
+    /// `context`.
+    pub fn set_threshold(&self, context: usize, threshold: u32) {
+        let reg = self.reg(CONTEXT_BASE + CONTEXT_STRIDE * context + CONTEXT_THRESHOLD);
+        unsafe { write_volatile(reg, threshold) };
+    }
+
+    /// Takes the highest-priority pending interrupt of `context`.
+    pub fn claim(&self, context: usize) -> Option<usize> {
+        let reg = self.reg(CONTEXT_BASE + CONTEXT_STRIDE * context + CONTEXT_CLAIM);
+        match unsafe { read_volatile(reg) } {
+            0 => None,
+            irq => Some(irq as usize),
+        }
+    }
+
+    /// Signals that the interrupt `irq` claimed by `context` was handled.
+    pub fn complete(&self, context: usize, irq: usize) {
+        let reg = self.reg(CONTEXT_BASE + CONTEXT_STRIDE * context + CONTEXT_CLAIM);
+        unsafe { write_volatile(reg, irq as u32) };
+    }
+}
+
+/// Sets up the PLIC found in the dtb, with every source disabled.
+pub fn init_plic() {
+    let meta = MACHINE_META.get().expect("dtb parsed");
+    let Some(device) = &meta.plic else {
+        info!("no PLIC found, external interrupts unavailable");
+        return;
+    };
+    let plic = PLIC.call_once(|| Plic {
+        base: device.base_address,
+        sources: meta.plic_sources,
+        enable_lock: SpinNoIrq::new(()),
+    });
+    for irq in 1..=plic.sources {
+        plic.set_priority(irq, 0);
+        for context in meta.harts.iter().filter_map(|h| h.plic_context) {
+            plic.set_enabled(context, irq, false);
+        }
+    }
+    info!(
+        "PLIC at {:#x} with {} sources",
+        device.base_address, plic.sources
+    );
+}
+
+/// PLIC context of logical CPU `cpu`.
+pub fn plic_context(cpu: usize) -> Option<usize> {
+    let hartid = hart::hartid_of(cpu);
+    MACHINE_META
+        .get()?
+        .harts
+        .iter()
+        .find(|h| h.hartid == hartid)?
+        .plic_context
+}
+
+/// Lets external interrupts through to this hart.
+pub fn init_plic_hart() {
+    let (Some(plic), Some(context)) = (PLIC.get(), plic_context(hart::hart_id())) else {
+        return;
+    };
+    plic.set_threshold(context, 0);
+    unsafe { sie::set_sext() };
+}
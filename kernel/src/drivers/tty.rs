@@ -0,0 +1,605 @@
+use core::{
+    future::poll_fn,
+    task::{Poll, Waker},
+};
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+use log::info;
+use spin::Once;
+
+use crate::{
+    Errno, SysResult,
+    fs::{File, FileFlags, FsFuture, Metadata, OpenFlags, Path, S_IFCHR, makedev},
+    hart::{current_space, with_current_task},
+    logging::console_write,
+    mem::VirtAddr,
+    runtime::spawn,
+    sync::SpinNoIrq,
+    task::{
+        SIGINT, SIGQUIT, SIGTSTP, SIGTTIN, SIGWINCH, TaskShared, send_group_signal, tasks_in_group,
+    },
+};
+
+use super::{CharDevice, UART, register_char_device};
+
+pub const TCGETS: usize = 0x5401;
+pub const TCSETS: usize = 0x5402;
+pub const TCSETSW: usize = 0x5403;
+pub const TCSETSF: usize = 0x5404;
+pub const TIOCGPGRP: usize = 0x540f;
+pub const TIOCSPGRP: usize = 0x5410;
+pub const TIOCGWINSZ: usize = 0x5413;
+pub const TIOCSWINSZ: usize = 0x5414;
+pub const FIONREAD: usize = 0x541b;
+
+// c_iflag
+pub const INLCR: u32 = 0o100;
+pub const IGNCR: u32 = 0o200;
+pub const ICRNL: u32 = 0o400;
+pub const IXON: u32 = 0o2000;
+// c_oflag
+pub const OPOST: u32 = 0o1;
+pub const ONLCR: u32 = 0o4;
+// c_cflag
+pub const B38400: u32 = 0o17;
+pub const CS8: u32 = 0o60;
+pub const CREAD: u32 = 0o200;
+pub const HUPCL: u32 = 0o2000;
+// c_lflag
+pub const ISIG: u32 = 0o1;
+pub const ICANON: u32 = 0o2;
+pub const ECHO: u32 = 0o10;
+pub const ECHOE: u32 = 0o20;
+pub const ECHOK: u32 = 0o40;
+pub const ECHONL: u32 = 0o100;
+pub const NOFLSH: u32 = 0o200;
+pub const ECHOCTL: u32 = 0o1000;
+pub const ECHOKE: u32 = 0o4000;
+pub const IEXTEN: u32 = 0o100000;
+
+// indices into c_cc
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const VSUSP: usize = 10;
+pub const VEOL: usize = 11;
+pub const VWERASE: usize = 14;
+pub const NCCS: usize = 19;
+
+/// Longest line canonical mode buffers, as in Linux.
+const MAX_LINE: usize = 4095;
+
+/// `struct termios` of the `TCGETS`/`TCSETS` ioctls.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Termios {
+    pub c_iflag: u32,
+    pub c_oflag: u32,
+    pub c_cflag: u32,
+    pub c_lflag: u32,
+    pub c_line: u8,
+    pub c_cc: [u8; NCCS],
+}
+
+impl Default for Termios {
+    /// The settings of a freshly opened Linux terminal: cooked mode with
+    /// echo and signals.
+    fn default() -> Self {
+        let mut c_cc = [0; NCCS];
+        c_cc[VINTR] = 0x03; // ^C
+        c_cc[VQUIT] = 0x1c; // ^\
+        c_cc[VERASE] = 0x7f; // DEL
+        c_cc[VKILL] = 0x15; // ^U
+        c_cc[VEOF] = 0x04; // ^D
+        c_cc[VMIN] = 1;
+        c_cc[VSUSP] = 0x1a; // ^Z
+        c_cc[VWERASE] = 0x17; // ^W
+        Self {
+            c_iflag: ICRNL | IXON,
+            c_oflag: OPOST | ONLCR,
+            c_cflag: B38400 | CS8 | CREAD | HUPCL,
+            c_lflag: ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHOCTL | ECHOKE | IEXTEN,
+            c_line: 0,
+            c_cc,
+        }
+    }
+}
+
+impl Termios {
+    fn is_canonical(&self) -> bool {
+        self.c_lflag & ICANON != 0
+    }
+
+    /// Whether `byte` is the enabled control character `index`.
+    fn is_cc(&self, index: usize, byte: u8) -> bool {
+        self.c_cc[index] != 0 && self.c_cc[index] == byte
+    }
+}
+
+/// `struct winsize` of `TIOCGWINSZ`/`TIOCSWINSZ`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct WinSize {
+    pub ws_row: u16,
+    pub ws_col: u16,
+    pub ws_xpixel: u16,
+    pub ws_ypixel: u16,
+}
+
+impl Default for WinSize {
+    fn default() -> Self {
+        Self {
+            ws_row: 24,
+            ws_col: 80,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+}
+
+struct TtyState {
+    termios: Termios,
+    winsize: WinSize,
+    /// Line being edited in canonical mode.
+    line: Vec<u8>,
+    /// Input ready for readers. In canonical mode it holds whole lines, each
+    /// ending after a delimiter or an end-of-file.
+    input: VecDeque<u8>,
+    /// Length of each complete line in `input`, in canonical mode. A zero
+    /// length is an end-of-file.
+    lines: VecDeque<usize>,
+    /// Process group reading from the terminal; other groups get `SIGTTIN`.
+    foreground: Option<usize>,
+    readers: Vec<Waker>,
+}
+
+impl TtyState {
+    /// Queues `bytes` as input, as a line in canonical mode.
+    fn push_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+        if self.termios.is_canonical() {
+            self.lines.push_back(bytes.len());
+        }
+    }
+
+    fn flush_input(&mut self) {
+        self.line.clear();
+        self.input.clear();
+        self.lines.clear();
+    }
+
+    /// Bytes a read would return now.
+    fn readable(&self) -> usize {
+        match self.termios.is_canonical() {
+            true => self.lines.front().copied().unwrap_or(0),
+            false => self.input.len(),
+        }
+    }
+
+    /// Whether a read of up to `wanted` bytes can complete, possibly with an
+    /// end-of-file.
+    fn can_read(&self, wanted: usize) -> bool {
+        match self.termios.is_canonical() {
+            true => !self.lines.is_empty(),
+            false => self.input.len() >= wanted.min(self.termios.c_cc[VMIN] as usize),
+        }
+    }
+
+    /// Takes the wakers of waiting readers if there is input for them.
+    fn take_readers(&mut self) -> Vec<Waker> {
+        match self.can_read(1) {
+            true => core::mem::take(&mut self.readers),
+            false => Vec::new(),
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.readable());
+        for (dst, src) in buf.iter_mut().zip(self.input.drain(..n)) {
+            *dst = src;
+        }
+        if self.termios.is_canonical() {
+            self.lines[0] -= n;
+            if self.lines[0] == 0 {
+                self.lines.pop_front();
+            }
+        }
+        n
+    }
+
+    /// Switches between canonical and raw input, keeping what was typed.
+    fn set_termios(&mut self, termios: Termios) {
+        let was_canonical = self.termios.is_canonical();
+        self.termios = termios;
+        match (was_canonical, termios.is_canonical()) {
+            (true, false) => {
+                self.lines.clear();
+                let line = core::mem::take(&mut self.line);
+                self.input.extend(line);
+            }
+            (false, true) if !self.input.is_empty() => {
+                self.lines.push_back(self.input.len());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A terminal over the console: a line discipline turning received bytes
+/// into input for readers, with editing, echo and job control signals.
+pub struct Tty {
+    state: SpinNoIrq<TtyState>,
+}
+
+impl Tty {
+    fn new() -> Self {
+        Self {
+            state: SpinNoIrq::new(TtyState {
+                termios: Termios::default(),
+                winsize: WinSize::default(),
+                line: Vec::new(),
+                input: VecDeque::new(),
+                lines: VecDeque::new(),
+                foreground: None,
+                readers: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn termios(&self) -> Termios {
+        self.state.lock().termios
+    }
+
+    /// Changes the settings. With `flush`, pending input is discarded first,
+    /// as for `TCSETSF`.
+    pub fn set_termios(&self, termios: Termios, flush: bool) {
+        let mut state = self.state.lock();
+        if flush {
+            state.flush_input();
+        }
+        state.set_termios(termios);
+        let readers = state.take_readers();
+        drop(state);
+        readers.into_iter().for_each(|reader| reader.wake());
+    }
+
+    pub fn winsize(&self) -> WinSize {
+        self.state.lock().winsize
+    }
+
+    /// Resizes the terminal and notifies the foreground group.
+    pub fn set_winsize(&self, winsize: WinSize) {
+        let foreground = {
+            let mut state = self.state.lock();
+            state.winsize = winsize;
+            state.foreground
+        };
+        if let Some(pgid) = foreground {
+            let _ = send_group_signal(pgid, SIGWINCH);
+        }
+    }
+
+    pub fn foreground(&self) -> Option<usize> {
+        self.state.lock().foreground
+    }
+
+    pub fn set_foreground(&self, pgid: usize) {
+        self.state.lock().foreground = Some(pgid);
+    }
+
+    /// Bytes available to read without waiting, for `FIONREAD`.
+    pub fn readable(&self) -> usize {
+        self.state.lock().readable()
+    }
+
+    /// Writes `bytes` with output processing.
+    pub fn write(&self, bytes: &[u8]) {
+        let termios = self.termios();
+        Self::output(&termios, bytes);
+    }
+
+    fn output(termios: &Termios, bytes: &[u8]) {
+        if termios.c_oflag & (OPOST | ONLCR) != OPOST | ONLCR {
+            console_write(bytes);
+            return;
+        }
+        for chunk in bytes.split_inclusive(|&b| b == b'\n') {
+            match chunk.strip_suffix(b"\n") {
+                Some(line) => {
+                    console_write(line);
+                    console_write(b"\r\n");
+                }
+                None => console_write(chunk),
+            }
+        }
+    }
+
+    /// Echoes an input byte, showing control characters as `^X` with
+    /// `ECHOCTL`.
+    fn echo(termios: &Termios, byte: u8) {
+        let is_ctl = byte < 0x20 && !matches!(byte, b'\n' | b'\t') || byte == 0x7f;
+        if is_ctl && termios.c_lflag & ECHOCTL != 0 {
+            console_write(&[b'^', byte ^ 0x40]);
+        } else {
+            Self::output(termios, &[byte]);
+        }
+    }
+
+    /// Erases the last character of the line being edited, or the last word.
+    fn erase(state: &mut TtyState, word: bool) {
+        let echo = state.termios.c_lflag & (ECHO | ECHOE) == ECHO | ECHOE;
+        let mut seen_word = false;
+        while let Some(&last) = state.line.last() {
+            if word {
+                let is_space = last == b' ' || last == b'\t';
+                if is_space && seen_word {
+                    break;
+                }
+                seen_word |= !is_space;
+            }
+            state.line.pop();
+            if echo {
+                console_write(b"\x08 \x08");
+            }
+            if !word {
+                break;
+            }
+        }
+    }
+
+    /// Runs the line discipline on received bytes.
+    pub fn receive(&self, bytes: &[u8]) {
+        let mut signals = Vec::new();
+        let mut state = self.state.lock();
+        for &byte in bytes {
+            let termios = state.termios;
+            let lflag = termios.c_lflag;
+            let byte = match byte {
+                b'\r' if termios.c_iflag & IGNCR != 0 => continue,
+                b'\r' if termios.c_iflag & ICRNL != 0 => b'\n',
+                b'\n' if termios.c_iflag & INLCR != 0 => b'\r',
+                byte => byte,
+            };
+
+            if lflag & ISIG != 0 {
+                let sig = match byte {
+                    b if termios.is_cc(VINTR, b) => Some(SIGINT),
+                    b if termios.is_cc(VQUIT, b) => Some(SIGQUIT),
+                    b if termios.is_cc(VSUSP, b) => Some(SIGTSTP),
+                    _ => None,
+                };
+                if let Some(sig) = sig {
+                    if lflag & NOFLSH == 0 {
+                        state.flush_input();
+                    }
+                    if lflag & ECHO != 0 {
+                        Self::echo(&termios, byte);
+                    }
+                    signals.extend(state.foreground.map(|pgid| (pgid, sig)));
+                    continue;
+                }
+            }
+
+            if !termios.is_canonical() {
+                state.input.push_back(byte);
+                if lflag & ECHO != 0 {
+                    Self::echo(&termios, byte);
+                }
+                continue;
+            }
+
+            if termios.is_cc(VERASE, byte) || byte == 0x08 {
+                Self::erase(&mut state, false);
+            } else if lflag & IEXTEN != 0 && termios.is_cc(VWERASE, byte) {
+                Self::erase(&mut state, true);
+            } else if termios.is_cc(VKILL, byte) {
+                if lflag & ECHOKE != 0 {
+                    while !state.line.is_empty() {
+                        Self::erase(&mut state, false);
+                    }
+                } else {
+                    state.line.clear();
+                    if lflag & ECHOK != 0 {
+                        Self::output(&termios, b"\n");
+                    }
+                }
+            } else if termios.is_cc(VEOF, byte) {
+                let line = core::mem::take(&mut state.line);
+                state.push_input(&line);
+            } else if byte == b'\n' || termios.is_cc(VEOL, byte) {
+                let mut line = core::mem::take(&mut state.line);
+                line.push(byte);
+                state.push_input(&line);
+                if lflag & (ECHO | ECHONL) != 0 {
+                    Self::echo(&termios, byte);
+                }
+            } else if state.line.len() < MAX_LINE {
+                state.line.push(byte);
+                if lflag & ECHO != 0 {
+                    Self::echo(&termios, byte);
+                }
+            }
+        }
+        let readers = state.take_readers();
+        drop(state);
+        readers.into_iter().for_each(|reader| reader.wake());
+        for (pgid, sig) in signals {
+            let _ = send_group_signal(pgid, sig);
+        }
+    }
+
+    /// Reads input for `reader`: a line in canonical mode, else at least
+    /// `VMIN` bytes (`VTIME` is not supported). Readers outside the
+    /// foreground group get `SIGTTIN` instead, and a pending signal which
+    /// [interrupts](crate::task::PendingSignals::interrupts) ends the wait
+    /// with `EINTR`.
+    pub async fn read(&self, buf: &mut [u8], reader: &TaskShared) -> Result<usize, Errno> {
+        let foreground = self.foreground();
+        if foreground.is_some_and(|pgid| pgid != reader.pgid()) {
+            let _ = send_group_signal(reader.pgid(), SIGTTIN);
+            return Err(Errno::EINTR);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        poll_fn(|cx| {
+            let mut state = self.state.lock();
+            if state.can_read(buf.len()) {
+                return Poll::Ready(Ok(state.read(buf)));
+            }
+            if reader.signals.interrupts() {
+                return Poll::Ready(Err(Errno::EINTR));
+            }
+            state.readers.push(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+static CONSOLE_TTY: Once<Tty> = Once::new();
+
+/// The terminal on the console.
+pub fn console_tty() -> &'static Tty {
+    CONSOLE_TTY.call_once(Tty::new)
+}
+
+/// Device number of the console terminal.
+pub const CONSOLE_MAJOR: u32 = 5;
+pub const CONSOLE_MINOR: u32 = 1;
+/// Device number of `/dev/tty`, the controlling terminal.
+const TTY_MINOR: u32 = 0;
+/// Device numbers of the serial ports, `ttyS0` being the first.
+pub const SERIAL_MAJOR: u32 = 4;
+const SERIAL_MINOR_BASE: u32 = 64;
+
+/// The console terminal opened as a file.
+pub struct TtyFile {
+    flags: FileFlags,
+    /// The node it was opened through, if any.
+    path: Option<Path>,
+}
+
+impl TtyFile {
+    /// Opens the console without a node, e.g. for the first task.
+    pub fn new(flags: OpenFlags) -> Self {
+        Self {
+            flags: FileFlags::new(flags),
+            path: None,
+        }
+    }
+}
+
+impl File for TtyFile {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let reader = with_current_task(|task| task.shared().clone());
+            console_tty().read(buf, &reader).await
+        })
+    }
+
+    fn write<'a>(&'a self, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        console_tty().write(buf);
+        Box::pin(async move { Ok(buf.len()) })
+    }
+
+    fn stat(&self) -> FsFuture<'_, Metadata> {
+        if let Some(path) = &self.path {
+            return path.dentry.inode().metadata();
+        }
+        Box::pin(async {
+            Ok(Metadata {
+                mode: S_IFCHR | 0o620,
+                nlink: 1,
+                rdev: makedev(CONSOLE_MAJOR, CONSOLE_MINOR),
+                blksize: 1024,
+                ..Default::default()
+            })
+        })
+    }
+
+    fn ioctl(&self, request: usize, arg: VirtAddr) -> SysResult {
+        let tty = console_tty();
+        let space = current_space();
+        match request {
+            TCGETS => space.write_user(arg, &tty.termios())?,
+            TCSETS | TCSETSW | TCSETSF => {
+                // output is handed to the driver synchronously, so there is
+                // nothing to drain for TCSETSW
+                let termios: Termios = space.read_user(arg)?;
+                tty.set_termios(termios, request == TCSETSF);
+            }
+            TIOCGWINSZ => space.write_user(arg, &tty.winsize())?,
+            TIOCSWINSZ => tty.set_winsize(space.read_user::<WinSize>(arg)?),
+            TIOCGPGRP => {
+                let pgid = tty.foreground().unwrap_or(0) as i32;
+                space.write_user(arg, &pgid)?;
+            }
+            TIOCSPGRP => {
+                let pgid: i32 = space.read_user(arg)?;
+                let pgid = usize::try_from(pgid).map_err(|_| Errno::EINVAL)?;
+                if tasks_in_group(pgid).is_empty() {
+                    return Err(Errno::EPERM);
+                }
+                tty.set_foreground(pgid);
+            }
+            FIONREAD => space.write_user(arg, &(tty.readable() as i32))?,
+            _ => return Err(Errno::ENOTTY),
+        }
+        Ok(0)
+    }
+
+    fn flags(&self) -> OpenFlags {
+        self.flags.get()
+    }
+
+    fn set_flags(&self, flags: OpenFlags) {
+        self.flags.set(flags);
+    }
+
+    fn path(&self) -> Option<Path> {
+        self.path.clone()
+    }
+}
+
+/// A node of the console terminal. Tasks have no controlling terminal of
+/// their own, so `/dev/tty` and the serial port are the console as well.
+struct TtyDevice;
+
+impl CharDevice for TtyDevice {
+    fn open(&self, path: Path, flags: OpenFlags) -> Result<Arc<dyn File>, Errno> {
+        Ok(Arc::new(TtyFile {
+            flags: FileFlags::new(flags),
+            path: Some(path),
+        }))
+    }
+}
+
+fn register_tty(name: &str, major: u32, minor: u32, mode: u32) {
+    register_char_device(name, makedev(major, minor), mode, Arc::new(TtyDevice));
+}
+
+/// Registers the terminal devices and starts feeding console input to the
+/// terminal. Needs the executor, as the input is read by a kernel task.
+pub fn init_tty() {
+    let tty = console_tty();
+    register_tty("tty", CONSOLE_MAJOR, TTY_MINOR, 0o666);
+    register_tty("console", CONSOLE_MAJOR, CONSOLE_MINOR, 0o600);
+    let Some(uart) = UART.get() else {
+        info!("no console input, tty is output only");
+        return;
+    };
+    register_tty("ttyS0", SERIAL_MAJOR, SERIAL_MINOR_BASE, 0o660);
+    spawn(async move {
+        let mut buf = [0; 64];
+        loop {
+            let n = uart.read(&mut buf).await;
+            tty.receive(&buf[..n]);
+        }
+    })
+    .detach();
+}
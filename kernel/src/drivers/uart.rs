@@ -0,0 +1,183 @@
+use core::{
+    future::poll_fn,
+    ptr::{read_volatile, write_volatile},
+    task::{Poll, Waker},
+};
+
+use alloc::collections::VecDeque;
+use log::{info, warn};
+use spin::Once;
+
+use crate::{dtb::MACHINE_META, sync::SpinNoIrq};
+
+use super::register_irq;
+
+// register offsets, in units of `1 << reg_shift`
+const RBR: usize = 0; // receive buffer (read)
+const THR: usize = 0; // transmit holding (write)
+const IER: usize = 1;
+const FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+const FCR_ENABLE_CLEAR: u8 = 0b111;
+const LCR_8N1: u8 = 0b11;
+/// DTR, RTS and OUT2, which gates the interrupt line.
+const MCR_DTR_RTS_OUT2: u8 = 0b1011;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// Size of the transmit FIFO of a 16550A.
+const TX_FIFO_SIZE: usize = 16;
+const RX_BUFFER_SIZE: usize = 4096;
+const TX_BUFFER_SIZE: usize = 4096;
+
+/// The console UART, available once [`init_uart`] found one in the dtb.
+pub static UART: Once<Uart> = Once::new();
+
+struct UartState {
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+    reader: Option<Waker>,
+}
+
+/// Driver of an ns16550a-compatible UART. Received bytes are buffered by the
+/// interrupt handler until read, written bytes go to a buffer which is moved
+/// to the transmit FIFO whenever it has room.
+pub struct Uart {
+    base: usize,
+    reg_shift: usize,
+    state: SpinNoIrq<UartState>,
+}
+
+impl Uart {
+    fn read_reg(&self, reg: usize) -> u8 {
+        unsafe { read_volatile((self.base + (reg << self.reg_shift)) as *const u8) }
+    }
+
+    fn write_reg(&self, reg: usize, value: u8) {
+        unsafe { write_volatile((self.base + (reg << self.reg_shift)) as *mut u8, value) }
+    }
+
+    fn init(&self) {
+        self.write_reg(IER, 0);
+        self.write_reg(LCR, LCR_8N1);
+        self.write_reg(FCR, FCR_ENABLE_CLEAR);
+        self.write_reg(MCR, MCR_DTR_RTS_OUT2);
+        self.write_reg(IER, IER_RX_AVAILABLE);
+    }
+
+    /// Moves buffered output to the transmit FIFO while it has room, and
+    /// asks for an interrupt when it drains if output is left.
+    fn push_tx(&self, state: &mut UartState) {
+        if self.read_reg(LSR) & LSR_THR_EMPTY != 0 {
+            for _ in 0..TX_FIFO_SIZE {
+                let Some(byte) = state.tx.pop_front() else {
+                    break;
+                };
+                self.write_reg(THR, byte);
+            }
+        }
+        let ier = if state.tx.is_empty() {
+            IER_RX_AVAILABLE
+        } else {
+            IER_RX_AVAILABLE | IER_TX_EMPTY
+        };
+        self.write_reg(IER, ier);
+    }
+
+    /// Queues `bytes` for output. If the buffer is full, waits for the
+    /// hardware to take some.
+    pub fn write(&self, bytes: &[u8]) {
+        let mut state = self.state.lock();
+        for &byte in bytes {
+            while state.tx.len() >= TX_BUFFER_SIZE {
+                self.push_tx(&mut state);
+                core::hint::spin_loop();
+            }
+            state.tx.push_back(byte);
+        }
+        self.push_tx(&mut state);
+    }
+
+    /// Waits until all buffered output is handed to the hardware, e.g.
+    /// before the machine is reset.
+    pub fn flush(&self) {
+        let mut state = self.state.lock();
+        while !state.tx.is_empty() {
+            self.push_tx(&mut state);
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Reads received bytes into `buf`, waiting until at least one arrives.
+    pub async fn read(&self, buf: &mut [u8]) -> usize {
+        poll_fn(|cx| {
+            let mut state = self.state.lock();
+            if state.rx.is_empty() {
+                state.reader = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let n = buf.len().min(state.rx.len());
+            for (dst, src) in buf.iter_mut().zip(state.rx.drain(..n)) {
+                *dst = src;
+            }
+            Poll::Ready(n)
+        })
+        .await
+    }
+
+    /// Interrupt handler: buffers received bytes and refills the transmit
+    /// FIFO.
+    fn handle_irq(&self) {
+        let mut state = self.state.lock();
+        let mut received = false;
+        while self.read_reg(LSR) & LSR_DATA_READY != 0 {
+            let byte = self.read_reg(RBR);
+            if state.rx.len() >= RX_BUFFER_SIZE {
+                // keep the newest input
+                state.rx.pop_front();
+            }
+            state.rx.push_back(byte);
+            received = true;
+        }
+        self.push_tx(&mut state);
+        let reader = if received { state.reader.take() } else { None };
+        drop(state);
+        if let Some(reader) = reader {
+            reader.wake();
+        }
+    }
+}
+
+/// Takes over the console UART described by the dtb. Output goes through
+/// the SBI until then.
+pub fn init_uart() {
+    let meta = MACHINE_META.get().expect("dtb parsed");
+    let Some(serial) = &meta.serial else {
+        info!("no ns16550a UART found, console stays on SBI");
+        return;
+    };
+    let uart = UART.call_once(|| Uart {
+        base: serial.base_address,
+        reg_shift: serial.reg_shift,
+        state: SpinNoIrq::new(UartState {
+            rx: VecDeque::with_capacity(RX_BUFFER_SIZE),
+            tx: VecDeque::with_capacity(TX_BUFFER_SIZE),
+            reader: None,
+        }),
+    });
+    uart.init();
+    match serial.irq {
+        Some(irq) => {
+            if let Err(err) = register_irq(irq, "ttyS0", || UART.get().unwrap().handle_irq()) {
+                warn!("UART irq {} unavailable: {:?}", irq, err);
+            }
+        }
+        None => warn!("UART has no interrupt, console input unavailable"),
+    }
+    info!("console on ns16550a at {:#x}", serial.base_address);
+}
@@ -0,0 +1,319 @@
+use core::{
+    future::poll_fn,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use log::info;
+
+use crate::{
+    KError, KResult,
+    block::{BlockDevice, BlockFuture, DISK_MINORS, alloc_disk_name, register_block_device},
+    drivers::register_irq,
+    fs::makedev,
+    sync::SpinNoIrq,
+};
+
+use super::{MmioTransport, VirtQueue};
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Major of virtio disks; Linux allocates one dynamically, counting down
+/// from 254.
+pub const VIRTIO_BLK_MAJOR: u32 = 254;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// Offset of `capacity` in the configuration, in sectors.
+const CONFIG_CAPACITY: usize = 0;
+
+const QUEUE_SIZE: u16 = 64;
+
+#[repr(C)]
+struct BlkReqHeader {
+    req_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// A request handed to the device. It is boxed so the device can write to
+/// it while the submitting future moves, and data goes through its own
+/// buffer so that dropping that future cannot free memory under the device.
+struct BlkRequest {
+    header: BlkReqHeader,
+    data: Vec<u8>,
+    status: u8,
+}
+
+struct InFlight {
+    request: Box<BlkRequest>,
+    done: bool,
+    /// The submitting future was dropped, the request is freed on
+    /// completion.
+    abandoned: bool,
+    waker: Option<Waker>,
+}
+
+struct BlkInner {
+    queue: VirtQueue,
+    in_flight: BTreeMap<u16, InFlight>,
+    /// Submitters waiting for free descriptors.
+    submitters: Vec<Waker>,
+}
+
+/// A virtio-blk device. Any number of tasks may have requests in flight;
+/// each parks until the interrupt handler finds its request completed.
+pub struct VirtioBlk {
+    transport: MmioTransport,
+    capacity: usize,
+    readonly: bool,
+    can_flush: bool,
+    inner: SpinNoIrq<BlkInner>,
+}
+
+impl VirtioBlk {
+    /// Number of sectors.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    /// Reads sectors from `sector` on into `buf`, whose length must be a
+    /// multiple of [`SECTOR_SIZE`].
+    pub async fn read_blocks(&self, sector: usize, buf: &mut [u8]) -> KResult<()> {
+        self.check_range(sector, buf.len())?;
+        let request = self
+            .submit(VIRTIO_BLK_T_IN, sector, vec![0; buf.len()])
+            .await?;
+        buf.copy_from_slice(&request.data);
+        Ok(())
+    }
+
+    /// Writes `buf`, whose length must be a multiple of [`SECTOR_SIZE`], to
+    /// the sectors from `sector` on.
+    pub async fn write_blocks(&self, sector: usize, buf: &[u8]) -> KResult<()> {
+        if self.readonly {
+            return Err(KError::InvalidArgument);
+        }
+        self.check_range(sector, buf.len())?;
+        self.submit(VIRTIO_BLK_T_OUT, sector, buf.to_vec()).await?;
+        Ok(())
+    }
+
+    /// Waits until completed writes are persistent. Devices without a
+    /// volatile write cache have nothing to flush.
+    pub async fn flush(&self) -> KResult<()> {
+        if !self.can_flush {
+            return Ok(());
+        }
+        self.submit(VIRTIO_BLK_T_FLUSH, 0, Vec::new()).await?;
+        Ok(())
+    }
+
+    fn check_range(&self, sector: usize, len: usize) -> KResult<()> {
+        let count = len / SECTOR_SIZE;
+        if len == 0 || len % SECTOR_SIZE != 0 || sector + count > self.capacity {
+            return Err(KError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    async fn submit(
+        &self,
+        req_type: u32,
+        sector: usize,
+        data: Vec<u8>,
+    ) -> KResult<Box<BlkRequest>> {
+        let mut request = Some(Box::new(BlkRequest {
+            header: BlkReqHeader {
+                req_type,
+                reserved: 0,
+                sector: sector as u64,
+            },
+            data,
+            status: 0xff,
+        }));
+        let token = poll_fn(|cx| {
+            let mut inner = self.inner.lock();
+            let req = request.as_mut().unwrap();
+            let header = unsafe {
+                core::slice::from_raw_parts(
+                    &req.header as *const BlkReqHeader as *const u8,
+                    size_of::<BlkReqHeader>(),
+                )
+            };
+            let status = core::slice::from_mut(&mut req.status);
+            let data = req.data.as_mut_slice();
+            // the request is owned by `in_flight` until the device is done
+            let added = match req_type {
+                VIRTIO_BLK_T_IN => unsafe { inner.queue.add(&[header], &[data, status]) },
+                VIRTIO_BLK_T_OUT => unsafe { inner.queue.add(&[header, data], &[status]) },
+                _ => unsafe { inner.queue.add(&[header], &[status]) },
+            };
+            match added {
+                Ok(token) => {
+                    inner.in_flight.insert(token, InFlight {
+                        request: request.take().unwrap(),
+                        done: false,
+                        abandoned: false,
+                        waker: None,
+                    });
+                    if inner.queue.should_notify() {
+                        self.transport.notify(inner.queue.idx());
+                    }
+                    Poll::Ready(Ok(token))
+                }
+                Err(KError::Busy) => {
+                    inner.submitters.push(cx.waker().clone());
+                    Poll::Pending
+                }
+                Err(err) => Poll::Ready(Err(err)),
+            }
+        })
+        .await?;
+
+        let request = Completion {
+            blk: self,
+            token,
+            finished: false,
+        }
+        .await;
+        match request.status {
+            VIRTIO_BLK_S_OK => Ok(request),
+            VIRTIO_BLK_S_UNSUPP => Err(KError::Unsupported),
+            _ => Err(KError::Io),
+        }
+    }
+
+    /// Interrupt handler: completes the requests the device is done with.
+    fn handle_irq(&self) {
+        self.transport.ack_interrupt();
+        let mut wakers = Vec::new();
+        let mut inner = self.inner.lock();
+        while let Some((token, _)) = inner.queue.pop_used() {
+            let Some(request) = inner.in_flight.get_mut(&token) else {
+                continue;
+            };
+            if request.abandoned {
+                inner.in_flight.remove(&token);
+                continue;
+            }
+            request.done = true;
+            wakers.extend(request.waker.take());
+        }
+        wakers.append(&mut inner.submitters);
+        drop(inner);
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.capacity
+    }
+
+    fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    fn read_blocks<'a>(&'a self, block: usize, buf: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(VirtioBlk::read_blocks(self, block, buf))
+    }
+
+    fn write_blocks<'a>(&'a self, block: usize, buf: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(VirtioBlk::write_blocks(self, block, buf))
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        Box::pin(VirtioBlk::flush(self))
+    }
+}
+
+/// Waits for the request with `token` to complete.
+struct Completion<'a> {
+    blk: &'a VirtioBlk,
+    token: u16,
+    /// The request was taken back, `token` may belong to another one now.
+    finished: bool,
+}
+
+impl Future for Completion<'_> {
+    type Output = Box<BlkRequest>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.blk.inner.lock();
+        let request = inner.in_flight.get_mut(&self.token).unwrap();
+        if !request.done {
+            request.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let request = inner.in_flight.remove(&self.token).unwrap();
+        drop(inner);
+        self.finished = true;
+        Poll::Ready(request.request)
+    }
+}
+
+impl Drop for Completion<'_> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let mut inner = self.blk.inner.lock();
+        if let Some(request) = inner.in_flight.get_mut(&self.token) {
+            if request.done {
+                inner.in_flight.remove(&self.token);
+            } else {
+                request.abandoned = true;
+            }
+        }
+    }
+}
+
+/// Sets up a virtio-blk device with one request queue.
+pub fn probe_blk(transport: MmioTransport, irq: Option<usize>) -> KResult<()> {
+    let irq = irq.ok_or(KError::Unsupported)?;
+    let features = transport.init(VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH)?;
+    let queue = VirtQueue::new(&transport, 0, QUEUE_SIZE).inspect_err(|_| transport.fail())?;
+    let capacity = transport.read_config::<u64>(CONFIG_CAPACITY) as usize;
+    let blk = Arc::new(VirtioBlk {
+        transport,
+        capacity,
+        readonly: features & VIRTIO_BLK_F_RO != 0,
+        can_flush: features & VIRTIO_BLK_F_FLUSH != 0,
+        inner: SpinNoIrq::new(BlkInner {
+            queue,
+            in_flight: BTreeMap::new(),
+            submitters: Vec::new(),
+        }),
+    });
+    let handler = blk.clone();
+    register_irq(irq, "virtio-blk", move || handler.handle_irq())
+        .inspect_err(|_| blk.transport.fail())?;
+    blk.transport.driver_ok();
+    info!(
+        "virtio-blk: {} sectors ({} MiB){}",
+        capacity,
+        (capacity * SECTOR_SIZE) >> 20,
+        if blk.readonly { ", read-only" } else { "" }
+    );
+    let (name, index) = alloc_disk_name("vd");
+    register_block_device(name, makedev(VIRTIO_BLK_MAJOR, index * DISK_MINORS), blk);
+    Ok(())
+}
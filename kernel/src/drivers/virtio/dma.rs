@@ -0,0 +1,39 @@
+use crate::{
+    KError, KResult, allocator::PHYS_FRAME_ALLOCATOR, config::PAGE_SIZE_4K, mem::PhysAddr,
+};
+
+/// Zeroed, physically contiguous frames shared with a device. Physical
+/// memory is identity-mapped in kernel space, so the frames are accessed
+/// through their physical address.
+pub struct Dma {
+    paddr: PhysAddr,
+    pages: usize,
+}
+
+impl Dma {
+    pub fn new(pages: usize) -> KResult<Self> {
+        let paddr = PHYS_FRAME_ALLOCATOR
+            .lock()
+            .alloc_frames(pages, PAGE_SIZE_4K)
+            .ok_or(KError::NoMemory)?;
+        unsafe { core::ptr::write_bytes(paddr.as_usize() as *mut u8, 0, pages * PAGE_SIZE_4K) };
+        Ok(Self { paddr, pages })
+    }
+
+    pub fn paddr(&self) -> usize {
+        self.paddr.as_usize()
+    }
+
+    pub fn as_ptr<T>(&self, offset: usize) -> *mut T {
+        debug_assert!(offset + size_of::<T>() <= self.pages * PAGE_SIZE_4K);
+        (self.paddr.as_usize() + offset) as *mut T
+    }
+}
+
+impl Drop for Dma {
+    fn drop(&mut self) {
+        PHYS_FRAME_ALLOCATOR
+            .lock()
+            .dealloc_frames(self.paddr, self.pages);
+    }
+}
@@ -0,0 +1,241 @@
+use core::ptr::{read_volatile, write_volatile};
+
+use log::warn;
+
+use crate::{KError, KResult, config::PAGE_SIZE_4K};
+
+const MAGIC: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const GUEST_PAGE_SIZE: usize = 0x028; // legacy
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_ALIGN: usize = 0x03c; // legacy
+const QUEUE_PFN: usize = 0x040; // legacy
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG_GENERATION: usize = 0x0fc;
+const CONFIG: usize = 0x100;
+
+/// "virt" in little endian.
+const MAGIC_VALUE: u32 = 0x7472_6976;
+
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_FAILED: u32 = 128;
+
+/// Feature bits independent of the device type.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Alignment of the used ring of legacy devices, which take the queue as
+/// one page frame number.
+pub const LEGACY_QUEUE_ALIGN: usize = PAGE_SIZE_4K;
+
+/// Device types as in the VirtIO specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Network,
+    Block,
+    Console,
+    EntropySource,
+    Other(u32),
+}
+
+impl From<u32> for DeviceType {
+    fn from(id: u32) -> Self {
+        match id {
+            1 => Self::Network,
+            2 => Self::Block,
+            3 => Self::Console,
+            4 => Self::EntropySource,
+            id => Self::Other(id),
+        }
+    }
+}
+
+/// Physical addresses of the three parts of a split virtqueue.
+pub struct QueueLayout {
+    pub size: u16,
+    pub desc: usize,
+    pub avail: usize,
+    pub used: usize,
+}
+
+/// The VirtIO-MMIO register interface of one device, either a legacy
+/// (version 1) or a modern (version 2) one.
+pub struct MmioTransport {
+    base: usize,
+    version: u32,
+    device_type: DeviceType,
+}
+
+impl MmioTransport {
+    /// Checks the header at `base`. Empty slots, with device ID 0, give
+    /// `None`.
+    pub fn probe(base: usize) -> KResult<Option<Self>> {
+        let mut transport = Self {
+            base,
+            version: 0,
+            device_type: DeviceType::Other(0),
+        };
+        if transport.read(MAGIC) != MAGIC_VALUE {
+            warn!("virtio-mmio at {:#x}: bad magic", base);
+            return Err(KError::Unsupported);
+        }
+        transport.version = transport.read(VERSION);
+        if !matches!(transport.version, 1 | 2) {
+            warn!(
+                "virtio-mmio at {:#x}: unsupported version {}",
+                base, transport.version
+            );
+            return Err(KError::Unsupported);
+        }
+        match transport.read(DEVICE_ID) {
+            0 => Ok(None),
+            id => {
+                transport.device_type = DeviceType::from(id);
+                Ok(Some(transport))
+            }
+        }
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { write_volatile((self.base + reg) as *mut u32, value) }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    pub fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    /// Resets the device and negotiates features: the result is the subset
+    /// of `supported` the device offers. Modern devices must offer
+    /// `VIRTIO_F_VERSION_1`, which is then always accepted.
+    pub fn init(&self, supported: u64) -> KResult<u64> {
+        self.write(STATUS, 0);
+        self.write(STATUS, STATUS_ACKNOWLEDGE);
+        self.write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let mut offered = 0;
+        for sel in 0..2 {
+            self.write(DEVICE_FEATURES_SEL, sel);
+            offered |= (self.read(DEVICE_FEATURES) as u64) << (32 * sel);
+        }
+        let mut features = offered & supported;
+        if !self.is_legacy() {
+            if offered & VIRTIO_F_VERSION_1 == 0 {
+                self.fail();
+                return Err(KError::Unsupported);
+            }
+            features |= VIRTIO_F_VERSION_1;
+        }
+        for sel in 0..2 {
+            self.write(DRIVER_FEATURES_SEL, sel);
+            self.write(DRIVER_FEATURES, (features >> (32 * sel)) as u32);
+        }
+
+        if self.is_legacy() {
+            self.write(GUEST_PAGE_SIZE, PAGE_SIZE_4K as u32);
+        } else {
+            self.write(
+                STATUS,
+                STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK,
+            );
+            if self.read(STATUS) & STATUS_FEATURES_OK == 0 {
+                self.fail();
+                return Err(KError::Unsupported);
+            }
+        }
+        Ok(features)
+    }
+
+    /// Marks the driver as ready, after the queues are set up.
+    pub fn driver_ok(&self) {
+        let status = self.read(STATUS);
+        self.write(STATUS, status | STATUS_DRIVER_OK);
+    }
+
+    /// Gives up on the device.
+    pub fn fail(&self) {
+        let status = self.read(STATUS);
+        self.write(STATUS, status | STATUS_FAILED);
+    }
+
+    /// Largest size of queue `idx`, 0 if the queue does not exist.
+    pub fn max_queue_size(&self, idx: u16) -> u16 {
+        self.write(QUEUE_SEL, idx as u32);
+        self.read(QUEUE_NUM_MAX) as u16
+    }
+
+    /// Hands queue `idx` to the device. Legacy devices expect the rings
+    /// contiguous, with the used ring aligned to [`LEGACY_QUEUE_ALIGN`].
+    pub fn setup_queue(&self, idx: u16, layout: &QueueLayout) {
+        self.write(QUEUE_SEL, idx as u32);
+        self.write(QUEUE_NUM, layout.size as u32);
+        if self.is_legacy() {
+            self.write(QUEUE_ALIGN, LEGACY_QUEUE_ALIGN as u32);
+            self.write(QUEUE_PFN, (layout.desc / PAGE_SIZE_4K) as u32);
+        } else {
+            let write_addr = |low, high, addr: usize| {
+                self.write(low, addr as u32);
+                self.write(high, (addr >> 32) as u32);
+            };
+            write_addr(QUEUE_DESC_LOW, QUEUE_DESC_HIGH, layout.desc);
+            write_addr(QUEUE_DRIVER_LOW, QUEUE_DRIVER_HIGH, layout.avail);
+            write_addr(QUEUE_DEVICE_LOW, QUEUE_DEVICE_HIGH, layout.used);
+            self.write(QUEUE_READY, 1);
+        }
+    }
+
+    pub fn notify(&self, idx: u16) {
+        self.write(QUEUE_NOTIFY, idx as u32);
+    }
+
+    /// Acknowledges a pending interrupt, returning its causes.
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(INTERRUPT_STATUS);
+        if status != 0 {
+            self.write(INTERRUPT_ACK, status);
+        }
+        status
+    }
+
+    /// Reads a field of the device-specific configuration. Modern devices
+    /// are re-read until the configuration did not change meanwhile.
+    pub fn read_config<T: Copy>(&self, offset: usize) -> T {
+        let ptr = (self.base + CONFIG + offset) as *const T;
+        if self.is_legacy() {
+            return unsafe { read_volatile(ptr) };
+        }
+        loop {
+            let generation = self.read(CONFIG_GENERATION);
+            let value = unsafe { read_volatile(ptr) };
+            if self.read(CONFIG_GENERATION) == generation {
+                return value;
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+mod blk;
+mod dma;
+mod mmio;
+mod queue;
+
+pub use blk::*;
+pub use dma::*;
+pub use mmio::*;
+pub use queue::*;
+
+use log::{info, warn};
+
+use crate::{KResult, dtb::MACHINE_META};
+
+/// Takes over a device found by [`probe_virtio`]: negotiates features, sets
+/// up its queues and registers its interrupt handler.
+pub type VirtioProbe = fn(MmioTransport, Option<usize>) -> KResult<()>;
+
+/// Drivers of VirtIO device types.
+const VIRTIO_DRIVERS: &[(DeviceType, VirtioProbe)] = &[(DeviceType::Block, probe_blk)];
+
+/// Probes the VirtIO-MMIO slots of the dtb and starts a driver for each
+/// device of a supported type.
+pub fn probe_virtio() {
+    let meta = MACHINE_META.get().expect("dtb parsed");
+    for slot in meta.virtio.iter() {
+        // empty slots and broken headers are skipped
+        let Ok(Some(transport)) = MmioTransport::probe(slot.base_address) else {
+            continue;
+        };
+        let device_type = transport.device_type();
+        let Some((_, probe)) = VIRTIO_DRIVERS.iter().find(|(ty, _)| *ty == device_type) else {
+            info!(
+                "virtio-mmio at {:#x}: no driver for {:?}",
+                slot.base_address, device_type
+            );
+            continue;
+        };
+        info!(
+            "virtio-mmio at {:#x}: {:?}, {}",
+            slot.base_address,
+            device_type,
+            if transport.is_legacy() {
+                "legacy"
+            } else {
+                "modern"
+            }
+        );
+        if let Err(err) = probe(transport, slot.irq) {
+            warn!(
+                "virtio-mmio at {:#x}: {:?} failed: {:?}",
+                slot.base_address, device_type, err
+            );
+        }
+    }
+}
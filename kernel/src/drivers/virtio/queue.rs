@@ -0,0 +1,208 @@
+use core::{
+    ptr::{addr_of, addr_of_mut, read_volatile, write_volatile},
+    sync::atomic::{Ordering, fence},
+};
+
+use alloc::vec::Vec;
+
+use crate::{KError, KResult, config::PAGE_SIZE_4K, mem::align_up};
+
+use super::{Dma, LEGACY_QUEUE_ALIGN, MmioTransport, QueueLayout};
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+/// Set by the device in the used ring when it does not need notifications.
+const USED_F_NO_NOTIFY: u16 = 1;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// A split virtqueue in DMA frames. Buffers are handed to the device as
+/// descriptor chains and identified by the index of their head descriptor,
+/// the token returned by [`add`](Self::add).
+pub struct VirtQueue {
+    idx: u16,
+    size: u16,
+    dma: Dma,
+    layout: QueueLayout,
+    free_head: u16,
+    num_free: u16,
+    /// Number of descriptors of each chain in flight, by head.
+    chain_len: Vec<u16>,
+    avail_idx: u16,
+    last_used_idx: u16,
+}
+
+impl VirtQueue {
+    /// Creates queue `idx` of `transport` with up to `size` entries.
+    pub fn new(transport: &MmioTransport, idx: u16, size: u16) -> KResult<Self> {
+        let max = transport.max_queue_size(idx);
+        if max == 0 {
+            return Err(KError::Unsupported);
+        }
+        let size = size.min(max);
+        if !size.is_power_of_two() {
+            return Err(KError::InvalidArgument);
+        }
+        let n = size as usize;
+        let used_offset = align_up(16 * n + 6 + 2 * n, LEGACY_QUEUE_ALIGN);
+        let total = used_offset + align_up(6 + 8 * n, LEGACY_QUEUE_ALIGN);
+        let dma = Dma::new(total / PAGE_SIZE_4K)?;
+        let layout = QueueLayout {
+            size,
+            desc: dma.paddr(),
+            avail: dma.paddr() + 16 * n,
+            used: dma.paddr() + used_offset,
+        };
+        let queue = Self {
+            idx,
+            size,
+            dma,
+            layout,
+            free_head: 0,
+            num_free: size,
+            chain_len: alloc::vec![0; n],
+            avail_idx: 0,
+            last_used_idx: 0,
+        };
+        for i in 0..size {
+            unsafe { (*queue.desc(i)).next = (i + 1) % size };
+        }
+        transport.setup_queue(idx, &queue.layout);
+        Ok(queue)
+    }
+
+    pub fn idx(&self) -> u16 {
+        self.idx
+    }
+
+    fn desc(&self, i: u16) -> *mut Descriptor {
+        self.dma.as_ptr(16 * i as usize)
+    }
+
+    fn avail_idx_ptr(&self) -> *mut u16 {
+        (self.layout.avail + 2) as *mut u16
+    }
+
+    fn avail_ring(&self, i: u16) -> *mut u16 {
+        (self.layout.avail + 4 + 2 * (i % self.size) as usize) as *mut u16
+    }
+
+    fn used_flags(&self) -> u16 {
+        unsafe { read_volatile(self.layout.used as *const u16) }
+    }
+
+    fn used_idx(&self) -> u16 {
+        unsafe { read_volatile((self.layout.used + 2) as *const u16) }
+    }
+
+    fn used_elem(&self, i: u16) -> *const UsedElem {
+        (self.layout.used + 4 + 8 * (i % self.size) as usize) as *const UsedElem
+    }
+
+    /// Makes a chain of the device-readable `inputs` followed by the
+    /// device-writable `outputs` available to the device, returning its
+    /// token. Returns [`KError::Busy`] if the queue is full.
+    ///
+    /// # Safety
+    ///
+    /// The buffers are accessed by the device until the token comes back
+    /// from [`pop_used`](Self::pop_used), so they must stay alive and not be
+    /// accessed meanwhile. They must be in the linearly mapped kernel memory.
+    pub unsafe fn add(&mut self, inputs: &[&[u8]], outputs: &[&mut [u8]]) -> KResult<u16> {
+        let count = inputs.len() + outputs.len();
+        if count == 0 {
+            return Err(KError::InvalidArgument);
+        }
+        if count > self.num_free as usize {
+            return Err(KError::Busy);
+        }
+        let buffers = inputs
+            .iter()
+            .map(|buf| (buf.as_ptr() as usize, buf.len(), 0))
+            .chain(
+                outputs
+                    .iter()
+                    .map(|buf| (buf.as_ptr() as usize, buf.len(), DESC_F_WRITE)),
+            );
+        // descriptors are linked through `next` in the free list already,
+        // so the chain follows it
+        let head = self.free_head;
+        for (i, (addr, len, flags)) in buffers.enumerate() {
+            let desc = self.desc(self.free_head);
+            unsafe {
+                write_volatile(addr_of_mut!((*desc).addr), addr as u64);
+                write_volatile(addr_of_mut!((*desc).len), len as u32);
+                let next = match i + 1 < count {
+                    true => DESC_F_NEXT,
+                    false => 0,
+                };
+                write_volatile(addr_of_mut!((*desc).flags), flags | next);
+                self.free_head = read_volatile(addr_of!((*desc).next));
+            }
+        }
+        self.num_free -= count as u16;
+        self.chain_len[head as usize] = count as u16;
+
+        unsafe { write_volatile(self.avail_ring(self.avail_idx), head) };
+        // the chain must be visible before the index which publishes it
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        unsafe { write_volatile(self.avail_idx_ptr(), self.avail_idx) };
+        fence(Ordering::SeqCst);
+        Ok(head)
+    }
+
+    /// Whether the device wants to be notified of new buffers.
+    pub fn should_notify(&self) -> bool {
+        fence(Ordering::SeqCst);
+        self.used_flags() & USED_F_NO_NOTIFY == 0
+    }
+
+    /// Whether the device returned buffers not popped yet.
+    pub fn can_pop(&self) -> bool {
+        fence(Ordering::SeqCst);
+        self.used_idx() != self.last_used_idx
+    }
+
+    /// Takes the next chain the device is done with, returning its token and
+    /// the number of bytes written to it.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.can_pop() {
+            return None;
+        }
+        let elem = self.used_elem(self.last_used_idx);
+        let (head, len) = unsafe {
+            (
+                read_volatile(addr_of!((*elem).id)) as u16,
+                read_volatile(addr_of!((*elem).len)),
+            )
+        };
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        self.recycle(head);
+        Some((head, len))
+    }
+
+    /// Returns the descriptors of chain `head` to the free list.
+    fn recycle(&mut self, head: u16) {
+        let count = core::mem::take(&mut self.chain_len[head as usize]);
+        let mut tail = head;
+        for _ in 1..count {
+            tail = unsafe { read_volatile(addr_of!((*self.desc(tail)).next)) };
+        }
+        unsafe { write_volatile(addr_of_mut!((*self.desc(tail)).next), self.free_head) };
+        self.free_head = head;
+        self.num_free += count;
+    }
+}
@@ -1,6 +1,8 @@
-use arrayvec::ArrayVec;
-use fdt::Fdt;
-use log::{debug, info};
+use core::ops::Range;
+
+use arrayvec::{ArrayString, ArrayVec};
+use fdt::{Fdt, node::FdtNode, standard_nodes::Cpu};
+use log::{debug, info, warn};
 use spin::Once;
 
 pub static MACHINE_META: Once<MachineMeta> = Once::new();
@@ -9,20 +11,65 @@ pub static MACHINE_META: Once<MachineMeta> = Once::new();
 pub struct Device {
     pub base_address: usize,
     pub size: usize,
+    /// Interrupt source at the PLIC, if the device raises interrupts.
+    pub irq: Option<usize>,
+}
+
+/// An ns16550a-compatible UART.
+#[derive(Clone, Debug)]
+pub struct Serial {
+    pub base_address: usize,
+    pub size: usize,
+    pub irq: Option<usize>,
+    /// Registers are `1 << reg_shift` bytes apart.
+    pub reg_shift: usize,
 }
 
 #[derive(Clone, Debug)]
 pub struct Hart {
     pub hartid: usize,
-    pub plic_context: usize,
+    /// PLIC context receiving the S-mode external interrupt of this hart.
+    pub plic_context: Option<usize>,
+    /// Extensions of the hart, from `riscv,isa`, e.g. `rv64imafdc`.
+    pub isa: ArrayString<64>,
+    /// Translation mode, from `mmu-type`, e.g. `riscv,sv39`.
+    pub mmu: ArrayString<16>,
 }
 
+/// Interrupt id of the supervisor external interrupt in the local
+/// interrupt controller of a hart.
+const IRQ_S_EXT: u32 = 9;
+
 #[derive(Debug, Clone, Default)]
 pub struct MachineMeta {
     pub phys_mem_start: usize,
     pub phys_mem_size: usize,
+    pub timebase_frequency: usize,
     pub harts: ArrayVec<Hart, 16>,
+    pub plic: Option<Device>,
+    /// Number of interrupt sources of the PLIC, from `riscv,ndev`.
+    pub plic_sources: usize,
     pub virtio: ArrayVec<Device, 16>,
+    /// The console UART.
+    pub serial: Option<Serial>,
+    /// Physical memory holding the initrd loaded by the bootloader.
+    pub initrd: Option<Range<usize>>,
+    /// The kernel command line, from `bootargs` of `/chosen`.
+    pub bootargs: ArrayString<512>,
+}
+
+impl MachineMeta {
+    /// The value of `key=value` in the command line, or an empty string
+    /// for a bare `key`.
+    pub fn bootarg(&self, key: &str) -> Option<&str> {
+        self.bootargs
+            .split_whitespace()
+            .find_map(|arg| match arg.strip_prefix(key) {
+                Some("") => Some(""),
+                Some(value) => value.strip_prefix('='),
+                None => None,
+            })
+    }
 }
 
 pub fn parse(dtb: usize) {
@@ -34,12 +81,18 @@ pub fn parse(dtb: usize) {
         meta.phys_mem_size = region.size.unwrap();
     }
     for cpu in fdt.cpus() {
+        meta.timebase_frequency = cpu.timebase_frequency();
         meta.harts.push(Hart {
             hartid: cpu.ids().first(),
-            // TODO: get plic context
-            plic_context: 0,
+            plic_context: None,
+            isa: cpu_string(&cpu, "riscv,isa"),
+            mmu: cpu_string(&cpu, "mmu-type"),
         });
     }
+    parse_plic(&fdt, &mut meta);
+    parse_serial(&fdt, &mut meta);
+    parse_initrd(&fdt, &mut meta);
+    parse_bootargs(&fdt, &mut meta);
     for node in fdt.find_all_nodes("/soc/virtio_mmio") {
         if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
             let paddr = reg.starting_address as usize;
@@ -47,8 +100,135 @@ pub fn parse(dtb: usize) {
             meta.virtio.push(Device {
                 base_address: paddr,
                 size,
+                irq: node.interrupts().and_then(|mut irqs| irqs.next()),
             })
         }
     }
     MACHINE_META.call_once(|| meta);
 }
+
+/// String property `name` of a cpu node, empty if missing or too long.
+fn cpu_string<const N: usize>(cpu: &Cpu, name: &str) -> ArrayString<N> {
+    cpu.property(name)
+        .and_then(|prop| prop.as_str())
+        .and_then(|value| ArrayString::from(value).ok())
+        .unwrap_or_default()
+}
+
+/// Finds the initrd from the `linux,initrd-start` and `linux,initrd-end`
+/// properties of `/chosen`.
+fn parse_initrd(fdt: &Fdt, meta: &mut MachineMeta) {
+    let Some(chosen) = fdt.find_node("/chosen") else {
+        return;
+    };
+    let property = |name| chosen.property(name).and_then(|prop| prop.as_usize());
+    meta.initrd = property("linux,initrd-start")
+        .zip(property("linux,initrd-end"))
+        .map(|(start, end)| start..end)
+        .filter(|range| !range.is_empty());
+    debug!("initrd: {:?}", meta.initrd);
+}
+
+/// Reads the command line from `bootargs` of `/chosen`, which QEMU sets
+/// from `-append`.
+fn parse_bootargs(fdt: &Fdt, meta: &mut MachineMeta) {
+    let Some(bootargs) = fdt
+        .find_node("/chosen")
+        .and_then(|chosen| chosen.property("bootargs"))
+        .and_then(|prop| prop.as_str())
+    else {
+        return;
+    };
+    match ArrayString::from(bootargs) {
+        Ok(bootargs) => meta.bootargs = bootargs,
+        Err(_) => warn!("bootargs: command line too long, ignored"),
+    }
+    debug!("bootargs: {:?}", meta.bootargs);
+}
+
+/// Finds the console UART: the `stdout-path` of `/chosen` if it is an
+/// ns16550a, else the first one under `/soc/serial`.
+fn parse_serial(fdt: &Fdt, meta: &mut MachineMeta) {
+    let is_ns16550a = |node: &FdtNode| {
+        node.compatible()
+            .is_some_and(|compatible| compatible.all().any(|c| c == "ns16550a"))
+    };
+    let stdout = fdt
+        .find_node("/chosen")
+        .and_then(|_| fdt.chosen().stdout())
+        .filter(is_ns16550a);
+    let Some(node) = stdout.or_else(|| fdt.find_all_nodes("/soc/serial").find(is_ns16550a)) else {
+        return;
+    };
+    let Some(reg) = node.reg().and_then(|mut reg| reg.next()) else {
+        return;
+    };
+    meta.serial = Some(Serial {
+        base_address: reg.starting_address as usize,
+        size: reg.size.unwrap_or(0x100),
+        irq: node.interrupts().and_then(|mut irqs| irqs.next()),
+        reg_shift: node
+            .property("reg-shift")
+            .and_then(|prop| prop.as_usize())
+            .unwrap_or(0),
+    });
+    debug!("serial: {:?}", meta.serial);
+}
+
+/// Finds the PLIC and, from its `interrupts-extended` property, which of its
+/// contexts is wired to the S-mode external interrupt of each hart.
+fn parse_plic(fdt: &Fdt, meta: &mut MachineMeta) {
+    let Some(plic) = fdt.find_compatible(&["riscv,plic0", "sifive,plic-1.0.0"]) else {
+        return;
+    };
+    let Some(reg) = plic.reg().and_then(|mut reg| reg.next()) else {
+        return;
+    };
+    meta.plic = Some(Device {
+        base_address: reg.starting_address as usize,
+        size: reg.size.unwrap_or(0),
+        irq: None,
+    });
+    meta.plic_sources = plic
+        .property("riscv,ndev")
+        .and_then(|ndev| ndev.as_usize())
+        .unwrap_or(0);
+    let Some(contexts) = plic.property("interrupts-extended") else {
+        return;
+    };
+    // (phandle of a hart's interrupt controller, interrupt id) per context
+    let cells = contexts
+        .value
+        .chunks_exact(4)
+        .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()));
+    let pairs: ArrayVec<(u32, u32), 64> = cells
+        .clone()
+        .step_by(2)
+        .zip(cells.skip(1).step_by(2))
+        .take(64)
+        .collect();
+    for cpu in fdt.find_all_nodes("/cpus/cpu") {
+        let Some(hartid) = cpu
+            .reg()
+            .and_then(|mut reg| reg.next())
+            .map(|reg| reg.starting_address as usize)
+        else {
+            continue;
+        };
+        let Some(phandle) = cpu
+            .children()
+            .find(|child| child.name == "interrupt-controller")
+            .and_then(|intc| intc.property("phandle"))
+            .and_then(|phandle| phandle.as_usize())
+        else {
+            continue;
+        };
+        let context = pairs
+            .iter()
+            .position(|&(p, irq)| p as usize == phandle && irq == IRQ_S_EXT);
+        if let Some(hart) = meta.harts.iter_mut().find(|h| h.hartid == hartid) {
+            hart.plic_context = context;
+        }
+    }
+    debug!("plic: {:?}, harts: {:?}", meta.plic, meta.harts);
+}
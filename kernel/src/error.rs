@@ -1,6 +1,74 @@
 #[derive(Debug)]
 pub enum KError {
     MemNotMapped,
+    TaskAborted,
+    InvalidArgument,
+    Busy,
+    TimedOut,
+    NoMemory,
+    /// The device or a feature it needs is not supported.
+    Unsupported,
+    /// The device or the firmware reported an error.
+    Io,
 }
 
 pub type KResult<T> = Result<T, KError>;
+
+/// Error numbers returned to user space, as in Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(isize)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    ENXIO = 6,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    EXDEV = 18,
+    ENODEV = 19,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENOTTY = 25,
+    EFBIG = 27,
+    ENOSPC = 28,
+    ESPIPE = 29,
+    EROFS = 30,
+    EMLINK = 31,
+    EPIPE = 32,
+    ERANGE = 34,
+    EDEADLK = 35,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ENOTEMPTY = 39,
+    ELOOP = 40,
+    EOPNOTSUPP = 95,
+    ETIMEDOUT = 110,
+}
+
+pub type SysResult = Result<usize, Errno>;
+
+impl From<KError> for Errno {
+    fn from(err: KError) -> Self {
+        match err {
+            KError::MemNotMapped => Errno::EFAULT,
+            KError::TaskAborted => Errno::EINTR,
+            KError::InvalidArgument => Errno::EINVAL,
+            KError::Busy => Errno::EBUSY,
+            KError::TimedOut => Errno::ETIMEDOUT,
+            KError::NoMemory => Errno::ENOMEM,
+            KError::Unsupported => Errno::EOPNOTSUPP,
+            KError::Io => Errno::EIO,
+        }
+    }
+}
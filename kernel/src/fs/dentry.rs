@@ -0,0 +1,120 @@
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+};
+
+use crate::{Errno, sync::SpinMutex};
+
+use super::Inode;
+
+/// A name in the directory tree of a filesystem bound to its inode. Looked
+/// up names are cached in their parent, so walking a path only reaches the
+/// filesystem for names not seen before.
+pub struct Dentry {
+    link: SpinMutex<Link>,
+    inode: Arc<dyn Inode>,
+    children: SpinMutex<BTreeMap<String, Arc<Dentry>>>,
+}
+
+/// Where a dentry is in the tree, which changes when it is renamed.
+struct Link {
+    name: String,
+    parent: Option<Arc<Dentry>>,
+}
+
+impl Dentry {
+    /// The dentry of the root directory of a filesystem.
+    pub fn new_root(inode: Arc<dyn Inode>) -> Arc<Self> {
+        Arc::new(Self {
+            link: SpinMutex::new(Link {
+                name: String::from("/"),
+                parent: None,
+            }),
+            inode,
+            children: SpinMutex::new(BTreeMap::new()),
+        })
+    }
+
+    pub fn name(&self) -> String {
+        self.link.lock().name.clone()
+    }
+
+    /// The directory containing this one, `None` for the root of the
+    /// filesystem.
+    pub fn parent(&self) -> Option<Arc<Dentry>> {
+        self.link.lock().parent.clone()
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    /// Finds entry `name` of this directory, which is neither `.` nor `..`.
+    pub async fn lookup(self: &Arc<Self>, name: &str) -> Result<Arc<Dentry>, Errno> {
+        if let Some(child) = self.children.lock().get(name) {
+            return Ok(child.clone());
+        }
+        let inode = self.inode.lookup(name).await?;
+        if !self.inode.cache_lookups() {
+            return Ok(self.new_child(name, inode));
+        }
+        Ok(self.insert(name, inode))
+    }
+
+    fn new_child(self: &Arc<Self>, name: &str, inode: Arc<dyn Inode>) -> Arc<Dentry> {
+        Arc::new(Dentry {
+            link: SpinMutex::new(Link {
+                name: name.to_string(),
+                parent: Some(self.clone()),
+            }),
+            inode,
+            children: SpinMutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Caches entry `name` of this directory, e.g. after creating it. An
+    /// entry cached meanwhile by a concurrent lookup is kept.
+    pub fn insert(self: &Arc<Self>, name: &str, inode: Arc<dyn Inode>) -> Arc<Dentry> {
+        self.children
+            .lock()
+            .entry(name.to_string())
+            .or_insert_with(|| self.new_child(name, inode))
+            .clone()
+    }
+
+    /// Forgets entry `name` after it was removed.
+    pub fn invalidate(&self, name: &str) {
+        self.children.lock().remove(name);
+    }
+
+    /// Moves the cached entry `old_name` of this directory to `new_name` of
+    /// `new_dir` after a rename, so that paths holding it, e.g. working
+    /// directories, follow it. With `exchange` the entry at `new_name`
+    /// moves the other way, else it is forgotten.
+    pub fn rename(
+        self: &Arc<Self>,
+        old_name: &str,
+        new_dir: &Arc<Dentry>,
+        new_name: &str,
+        exchange: bool,
+    ) {
+        let old = self.children.lock().remove(old_name);
+        let new = new_dir.children.lock().remove(new_name);
+        if let Some(old) = old {
+            old.relink(new_name, new_dir);
+            new_dir.children.lock().insert(new_name.to_string(), old);
+        }
+        if let Some(new) = new.filter(|_| exchange) {
+            new.relink(old_name, self);
+            self.children.lock().insert(old_name.to_string(), new);
+        }
+    }
+
+    fn relink(&self, name: &str, parent: &Arc<Dentry>) {
+        *self.link.lock() = Link {
+            name: name.to_string(),
+            parent: Some(parent.clone()),
+        };
+    }
+}
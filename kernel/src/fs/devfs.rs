@@ -0,0 +1,221 @@
+//! The device filesystem mounted on `/dev`. Its directory lists the
+//! character and block devices registered by the drivers when it is read,
+//! so nodes appear as soon as a driver registers a device, e.g. the
+//! partitions once a disk is scanned. Nodes cannot be created in it.
+
+use core::any::Any;
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use crate::{
+    Errno,
+    block::{BlockDevice, block_devices},
+    drivers::char_devices,
+};
+
+use super::{
+    DirEntry, FileSystemType, FsFuture, Inode, InodeType, Metadata, SuperBlock, alloc_anon_dev,
+};
+
+pub const DEVTMPFS: FileSystemType = FileSystemType {
+    name: "devtmpfs",
+    needs_device: false,
+    mount: mount_dev,
+};
+
+/// Links to the descriptors of the task reading them, which udev creates
+/// on Linux.
+const LINKS: &[(&str, &str)] = &[
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+];
+
+/// Permission bits of block device nodes, which drivers do not choose.
+const BLOCK_MODE: u32 = 0o660;
+
+#[derive(Debug, Clone, Copy)]
+enum Node {
+    Root,
+    /// An entry of [`LINKS`].
+    Link(usize),
+    Device {
+        ty: InodeType,
+        rdev: u64,
+        mode: u32,
+    },
+}
+
+/// The entries of the directory: the links, the character devices, then
+/// the block devices.
+fn entries() -> Vec<(String, Node)> {
+    let links = LINKS
+        .iter()
+        .enumerate()
+        .map(|(index, (name, _))| (name.to_string(), Node::Link(index)));
+    let chars = char_devices().into_iter().map(|(name, rdev, mode)| {
+        let ty = InodeType::CharDevice;
+        (name, Node::Device { ty, rdev, mode })
+    });
+    let blocks = block_devices().into_iter().map(|(name, rdev, _)| {
+        let ty = InodeType::BlockDevice;
+        (name, Node::Device {
+            ty,
+            rdev,
+            mode: BLOCK_MODE,
+        })
+    });
+    links.chain(chars).chain(blocks).collect()
+}
+
+pub struct DevInode {
+    dev: u64,
+    node: Node,
+}
+
+impl DevInode {
+    /// Devices are numbered after their device number, so that their inode
+    /// numbers are stable.
+    fn ino(node: Node) -> u64 {
+        match node {
+            Node::Root => 1,
+            Node::Link(index) => 2 + index as u64,
+            Node::Device { ty, rdev, .. } => {
+                let block = (ty == InodeType::BlockDevice) as u64;
+                16 + (rdev << 1 | block)
+            }
+        }
+    }
+}
+
+fn node_type(node: Node) -> InodeType {
+    match node {
+        Node::Root => InodeType::Dir,
+        Node::Link(_) => InodeType::Symlink,
+        Node::Device { ty, .. } => ty,
+    }
+}
+
+impl Inode for DevInode {
+    fn metadata(&self) -> FsFuture<'_, Metadata> {
+        Box::pin(async move {
+            let (perm, nlink, size, rdev) = match self.node {
+                Node::Root => (0o755, 2, 0, 0),
+                Node::Link(index) => (0o777, 1, LINKS[index].1.len() as u64, 0),
+                Node::Device { rdev, mode, .. } => (mode, 1, 0, rdev),
+            };
+            Ok(Metadata {
+                dev: self.dev,
+                ino: Self::ino(self.node),
+                mode: self.inode_type().mode_bits() | perm,
+                nlink,
+                size,
+                rdev,
+                blksize: 4096,
+                ..Metadata::default()
+            })
+        })
+    }
+
+    fn inode_type(&self) -> InodeType {
+        node_type(self.node)
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            let Node::Root = self.node else {
+                return Err(Errno::ENOTDIR);
+            };
+            let (_, node) = entries()
+                .into_iter()
+                .find(|entry| entry.0 == name)
+                .ok_or(Errno::ENOENT)?;
+            let inode: Arc<dyn Inode> = Arc::new(DevInode {
+                dev: self.dev,
+                node,
+            });
+            Ok(inode)
+        })
+    }
+
+    fn create<'a>(
+        &'a self,
+        _name: &'a str,
+        _ty: InodeType,
+        _mode: u32,
+        _rdev: u64,
+    ) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            match self.node {
+                Node::Root => Err(Errno::EPERM),
+                _ => Err(Errno::ENOTDIR),
+            }
+        })
+    }
+
+    fn read_link(&self) -> FsFuture<'_, String> {
+        Box::pin(async move {
+            match self.node {
+                Node::Link(index) => Ok(LINKS[index].1.to_string()),
+                _ => Err(Errno::EINVAL),
+            }
+        })
+    }
+
+    fn read_dir(&self, index: usize) -> FsFuture<'_, Option<DirEntry>> {
+        Box::pin(async move {
+            let Node::Root = self.node else {
+                return Err(Errno::ENOTDIR);
+            };
+            Ok(entries()
+                .into_iter()
+                .nth(index)
+                .map(|(name, node)| DirEntry {
+                    ino: Self::ino(node),
+                    name,
+                    ty: node_type(node),
+                }))
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct DevFs {
+    root: Arc<DevInode>,
+}
+
+impl DevFs {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            root: Arc::new(DevInode {
+                dev: alloc_anon_dev(),
+                node: Node::Root,
+            }),
+        })
+    }
+}
+
+impl SuperBlock for DevFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+fn mount_dev(
+    _device: Option<Arc<dyn BlockDevice>>,
+    _data: &str,
+) -> FsFuture<'_, Arc<dyn SuperBlock>> {
+    Box::pin(async move {
+        let fs: Arc<dyn SuperBlock> = DevFs::new();
+        Ok(fs)
+    })
+}
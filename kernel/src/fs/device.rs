@@ -0,0 +1,151 @@
+use alloc::{boxed::Box, sync::Arc};
+
+use crate::{
+    Errno, SysResult,
+    block::{BUFFER_CACHE, BlockDevice, block_device_by_number},
+    drivers::char_device,
+    hart::current_space,
+    mem::VirtAddr,
+    sync::SpinMutex,
+};
+
+use super::{File, FileFlags, FsFuture, InodeType, Metadata, OpenFlags, Path, SeekFrom};
+
+pub const BLKGETSIZE: usize = 0x1260;
+pub const BLKSSZGET: usize = 0x1268;
+pub const BLKGETSIZE64: usize = 0x8008_1272;
+
+/// Opens the device a character or block device node at `path` stands
+/// for, found by its device number whichever filesystem holds the node.
+pub async fn open_device(path: Path, flags: OpenFlags) -> Result<Arc<dyn File>, Errno> {
+    let rdev = path.dentry.inode().metadata().await?.rdev;
+    match path.inode_type() {
+        InodeType::CharDevice => char_device(rdev).ok_or(Errno::ENXIO)?.open(path, flags),
+        InodeType::BlockDevice => {
+            let device = block_device_by_number(rdev).ok_or(Errno::ENXIO)?;
+            if flags.writable() && device.is_readonly() {
+                return Err(Errno::EROFS);
+            }
+            Ok(Arc::new(BlockDeviceFile {
+                path,
+                device,
+                flags: FileFlags::new(flags),
+                pos: SpinMutex::new(0),
+            }))
+        }
+        _ => Err(Errno::ENODEV),
+    }
+}
+
+/// A block device opened as a file. It is read and written through the
+/// buffer cache in blocks of the device, so filesystems mounted from it
+/// with a different block size do not see the changes until remounted.
+struct BlockDeviceFile {
+    path: Path,
+    device: Arc<dyn BlockDevice>,
+    flags: FileFlags,
+    pos: SpinMutex<u64>,
+}
+
+impl BlockDeviceFile {
+    fn size(&self) -> u64 {
+        (self.device.num_blocks() * self.device.block_size()) as u64
+    }
+}
+
+impl File for BlockDeviceFile {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if !self.flags().readable() {
+                return Err(Errno::EBADF);
+            }
+            let block_size = self.device.block_size();
+            let pos = *self.pos.lock();
+            let len = buf.len().min(self.size().saturating_sub(pos) as usize);
+            let mut done = 0;
+            while done < len {
+                let offset = pos as usize + done;
+                let (block, start) = (offset / block_size, offset % block_size);
+                let n = (block_size - start).min(len - done);
+                let buffer = BUFFER_CACHE.bread(&self.device, block, block_size).await?;
+                buf[done..done + n].copy_from_slice(&buffer.read().await[start..start + n]);
+                done += n;
+            }
+            *self.pos.lock() = pos + done as u64;
+            Ok(done)
+        })
+    }
+
+    fn write<'a>(&'a self, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if !self.flags().writable() {
+                return Err(Errno::EBADF);
+            }
+            let block_size = self.device.block_size();
+            let pos = *self.pos.lock();
+            let len = buf.len().min(self.size().saturating_sub(pos) as usize);
+            if len == 0 && !buf.is_empty() {
+                return Err(Errno::ENOSPC);
+            }
+            let mut done = 0;
+            while done < len {
+                let offset = pos as usize + done;
+                let (block, start) = (offset / block_size, offset % block_size);
+                let n = (block_size - start).min(len - done);
+                // whole blocks are overwritten without reading them first
+                let buffer = match n == block_size {
+                    true => BUFFER_CACHE.getblk(&self.device, block, block_size),
+                    false => BUFFER_CACHE.bread(&self.device, block, block_size).await?,
+                };
+                buffer.write().await[start..start + n].copy_from_slice(&buf[done..done + n]);
+                done += n;
+            }
+            *self.pos.lock() = pos + done as u64;
+            Ok(done)
+        })
+    }
+
+    fn seek(&self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        Box::pin(async move {
+            let mut cur = self.pos.lock();
+            let (base, offset) = match pos {
+                SeekFrom::Start(offset) => (0, offset as i64),
+                SeekFrom::Current(offset) => (*cur, offset),
+                SeekFrom::End(offset) => (self.size(), offset),
+            };
+            *cur = base.checked_add_signed(offset).ok_or(Errno::EINVAL)?;
+            Ok(*cur)
+        })
+    }
+
+    fn stat(&self) -> FsFuture<'_, Metadata> {
+        self.path.dentry.inode().metadata()
+    }
+
+    fn ioctl(&self, request: usize, arg: VirtAddr) -> SysResult {
+        let space = current_space();
+        match request {
+            BLKGETSIZE64 => space.write_user(arg, &self.size())?,
+            BLKGETSIZE => space.write_user(arg, &(self.size() / 512))?,
+            BLKSSZGET => space.write_user(arg, &(self.device.block_size() as i32))?,
+            _ => return Err(Errno::ENOTTY),
+        }
+        Ok(0)
+    }
+
+    fn sync(&self) -> FsFuture<'_, ()> {
+        Box::pin(async move { Ok(BUFFER_CACHE.sync_device(&self.device).await?) })
+    }
+
+    fn flags(&self) -> OpenFlags {
+        self.flags.get()
+    }
+
+    fn set_flags(&self, flags: OpenFlags) {
+        self.flags.set(flags);
+    }
+
+    fn path(&self) -> Option<Path> {
+        Some(self.path.clone())
+    }
+}
@@ -0,0 +1,957 @@
+use alloc::{string::String, vec, vec::Vec};
+
+use crate::{Errno, fs::InodeType};
+
+use super::{
+    COMPAT_DIR_INDEX, Ext4FsInfo, INCOMPAT_FILETYPE, INCOMPAT_LARGEDIR,
+    crc::crc32c,
+    inode::{INDEX_FL, InodeState, ListEntry},
+    set_u16, set_u32, u16_at, u32_at,
+};
+
+/// Longest name of an entry.
+pub const NAME_MAX: usize = 255;
+const ENTRY_HEADER: usize = 8;
+/// Size of the fake entry at the end of directory blocks of
+/// `metadata_csum` filesystems holding their checksum.
+const TAIL_SIZE: usize = 12;
+const TAIL_FILE_TYPE: u8 = 0xde;
+
+/// Offset of the hash information in the first block of an indexed
+/// directory, after the `.` and `..` entries.
+const DX_ROOT_INFO: usize = 24;
+/// Offset of the count and limit of the entries of an index node, after a
+/// fake empty entry covering the block.
+const DX_NODE_ENTRIES: usize = 8;
+const DX_ENTRY_SIZE: usize = 8;
+/// Size of the checksum after the entries of index nodes.
+const DX_TAIL_SIZE: usize = 8;
+/// Bits of a block number of an index entry; the top ones are reserved.
+const DX_BLOCK_MASK: u32 = 0x0fff_ffff;
+
+const DX_HASH_LEGACY: u8 = 0;
+const DX_HASH_HALF_MD4: u8 = 1;
+const DX_HASH_TEA: u8 = 2;
+
+/// The `file_type` of entries of filesystems with the `filetype` feature.
+pub fn file_type(ty: InodeType) -> u8 {
+    match ty {
+        InodeType::File => 1,
+        InodeType::Dir => 2,
+        InodeType::CharDevice => 3,
+        InodeType::BlockDevice => 4,
+        InodeType::Fifo => 5,
+        InodeType::Socket => 6,
+        InodeType::Symlink => 7,
+    }
+}
+
+fn type_of_file_type(file_type: u8) -> Option<InodeType> {
+    match file_type {
+        1 => Some(InodeType::File),
+        2 => Some(InodeType::Dir),
+        3 => Some(InodeType::CharDevice),
+        4 => Some(InodeType::BlockDevice),
+        5 => Some(InodeType::Fifo),
+        6 => Some(InodeType::Socket),
+        7 => Some(InodeType::Symlink),
+        _ => None,
+    }
+}
+
+/// Space an entry with a name of `len` bytes takes.
+fn entry_size(len: usize) -> usize {
+    (ENTRY_HEADER + len).next_multiple_of(4)
+}
+
+/// A directory entry in a block. Entries with inode 0 are unused space.
+pub struct RawEntry<'a> {
+    pub offset: usize,
+    pub ino: u32,
+    rec_len: usize,
+    pub name: &'a [u8],
+    pub file_type: u8,
+}
+
+/// Record lengths of 64 KiB blocks do not fit in 16 bits.
+fn decode_rec_len(raw: u16, block_size: usize) -> usize {
+    match raw {
+        0 | 0xffff if block_size >= 1 << 16 => 1 << 16,
+        len => len as usize,
+    }
+}
+
+fn encode_rec_len(len: usize) -> u16 {
+    match len {
+        0x10000 => 0xffff,
+        len => len as u16,
+    }
+}
+
+fn has_tail(block: &[u8]) -> bool {
+    let tail = &block[block.len() - TAIL_SIZE..];
+    u32_at(tail, 0) == 0 && u16_at(tail, 4) == TAIL_SIZE as u16 && tail[6..8] == [0, TAIL_FILE_TYPE]
+}
+
+/// End of the entries of a block, before the checksum if there is one.
+fn entries_end(block: &[u8]) -> usize {
+    match has_tail(block) {
+        true => block.len() - TAIL_SIZE,
+        false => block.len(),
+    }
+}
+
+/// The entries of a directory block, checking that they cover it.
+pub fn parse(block: &[u8]) -> Result<Vec<RawEntry<'_>>, Errno> {
+    let end = entries_end(block);
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < end {
+        if offset + ENTRY_HEADER > end {
+            return Err(Errno::EIO);
+        }
+        let rec_len = decode_rec_len(u16_at(block, offset + 4), block.len());
+        let name_len = block[offset + 6] as usize;
+        if rec_len < ENTRY_HEADER
+            || rec_len % 4 != 0
+            || offset + rec_len > end
+            || ENTRY_HEADER + name_len > rec_len
+        {
+            return Err(Errno::EIO);
+        }
+        entries.push(RawEntry {
+            offset,
+            ino: u32_at(block, offset),
+            rec_len,
+            name: &block[offset + ENTRY_HEADER..][..name_len],
+            file_type: block[offset + 7],
+        });
+        offset += rec_len;
+    }
+    Ok(entries)
+}
+
+fn write_entry(block: &mut [u8], offset: usize, rec_len: usize, ino: u32, name: &[u8], ty: u8) {
+    set_u32(block, offset, ino);
+    set_u16(block, offset + 4, encode_rec_len(rec_len));
+    block[offset + 6] = name.len() as u8;
+    block[offset + 7] = ty;
+    block[offset + ENTRY_HEADER..][..name.len()].copy_from_slice(name);
+}
+
+/// Adds an entry to `block`, returning whether there was room.
+fn insert(block: &mut [u8], name: &[u8], ino: u32, ty: u8) -> Result<bool, Errno> {
+    let needed = entry_size(name.len());
+    let found = parse(block)?.iter().find_map(|entry| {
+        let used = match entry.ino {
+            0 => 0,
+            _ => entry_size(entry.name.len()),
+        };
+        (entry.rec_len - used >= needed).then_some((entry.offset, entry.rec_len, used))
+    });
+    let Some((offset, rec_len, used)) = found else {
+        return Ok(false);
+    };
+    if used != 0 {
+        set_u16(block, offset + 4, encode_rec_len(used));
+    }
+    write_entry(block, offset + used, rec_len - used, ino, name, ty);
+    Ok(true)
+}
+
+/// Removes the entry at `offset`, merging its space into the entry before.
+fn remove(block: &mut [u8], offset: usize) -> Result<(), Errno> {
+    let entries = parse(block)?;
+    let index = entries
+        .iter()
+        .position(|entry| entry.offset == offset)
+        .ok_or(Errno::EIO)?;
+    match index {
+        0 => set_u32(block, offset, 0),
+        _ => {
+            let prev = entries[index - 1].offset;
+            let rec_len = entries[index - 1].rec_len + entries[index].rec_len;
+            set_u16(block, prev + 4, encode_rec_len(rec_len));
+        }
+    }
+    Ok(())
+}
+
+/// An empty directory block, with room for a checksum if `csum`.
+fn empty_block(block_size: usize, csum: bool) -> Vec<u8> {
+    let mut block = vec![0; block_size];
+    let end = match csum {
+        true => block_size - TAIL_SIZE,
+        false => block_size,
+    };
+    set_u16(&mut block, 4, encode_rec_len(end));
+    if csum {
+        set_u16(&mut block, end + 4, TAIL_SIZE as u16);
+        block[end + 7] = TAIL_FILE_TYPE;
+    }
+    block
+}
+
+/// The first block of a new directory, with `.` and `..` entries.
+pub fn new_dir_block(block_size: usize, csum: bool, ino: u32, parent: u32, ty: u8) -> Vec<u8> {
+    let mut block = empty_block(block_size, csum);
+    let end = entries_end(&block);
+    let dot = entry_size(1);
+    write_entry(&mut block, 0, dot, ino, b".", ty);
+    write_entry(&mut block, dot, end - dot, parent, b"..", ty);
+    block
+}
+
+/// Updates the checksum of a directory block, if it has room for one.
+fn update_tail(block: &mut [u8], seed: Option<u32>) {
+    let Some(seed) = seed else {
+        return;
+    };
+    if has_tail(block) {
+        let end = block.len() - TAIL_SIZE;
+        let csum = crc32c(seed, &block[..end]);
+        set_u32(block, block.len() - 4, csum);
+    }
+}
+
+/// Packs `(hash, value)` pairs of the `in` array of the hash functions
+/// from a name, as `str2hashbuf` of Linux does.
+fn str2hashbuf(msg: &[u8], num: usize, unsigned: bool) -> [u32; 8] {
+    let len = msg.len() as u32;
+    let pad = (len | (len << 8)) | (len | (len << 8)) << 16;
+    let mut buf = [0; 8];
+    let mut words = 0;
+    let mut val = pad;
+    for (i, &byte) in msg.iter().take(num * 4).enumerate() {
+        let c = match unsigned {
+            true => byte as u32,
+            false => byte as i8 as i32 as u32,
+        };
+        val = c.wrapping_add(val << 8);
+        if i % 4 == 3 {
+            buf[words] = val;
+            words += 1;
+            val = pad;
+        }
+    }
+    if words < num {
+        buf[words] = val;
+        words += 1;
+    }
+    buf[words..num].fill(pad);
+    buf
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const K2: u32 = 0o13240474631;
+    const K3: u32 = 0o15666365641;
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let [mut a, mut b, mut c, mut d] = *buf;
+    macro_rules! round {
+        ($f:expr, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+            $a = $a
+                .wrapping_add($f($b, $c, $d))
+                .wrapping_add($x)
+                .rotate_left($s);
+        };
+    }
+    round!(f, a, b, c, d, input[0], 3);
+    round!(f, d, a, b, c, input[1], 7);
+    round!(f, c, d, a, b, input[2], 11);
+    round!(f, b, c, d, a, input[3], 19);
+    round!(f, a, b, c, d, input[4], 3);
+    round!(f, d, a, b, c, input[5], 7);
+    round!(f, c, d, a, b, input[6], 11);
+    round!(f, b, c, d, a, input[7], 19);
+
+    round!(g, a, b, c, d, input[1].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[3].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[5].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+    round!(g, a, b, c, d, input[0].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[2].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[4].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+    round!(h, a, b, c, d, input[3].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[7].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+    round!(h, a, b, c, d, input[1].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[5].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const DELTA: u32 = 0x9e37_79b9;
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let [a, b, c, d] = [input[0], input[1], input[2], input[3]];
+    let mut sum = 0u32;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b),
+        );
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+fn legacy_hash(name: &[u8], unsigned: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12a3_fe2d_u32, 0x37ab_e8f9_u32);
+    for &byte in name {
+        let c = match unsigned {
+            true => byte as i32,
+            false => byte as i8 as i32,
+        };
+        let mut hash = hash1.wrapping_add(hash0 ^ c.wrapping_mul(7_152_373) as u32);
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7fff_ffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+/// Hash of `name` in directory indexes, with the lowest bit, which marks
+/// collisions in index entries, clear. `None` for unknown hash versions.
+fn dx_hash(name: &[u8], version: u8, unsigned: bool, seed: &[u32; 4]) -> Option<u32> {
+    let mut buf = match seed.iter().any(|&word| word != 0) {
+        true => *seed,
+        false => [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476],
+    };
+    let hash = match version {
+        DX_HASH_LEGACY => legacy_hash(name, unsigned),
+        DX_HASH_HALF_MD4 => {
+            for chunk in (0..name.len()).step_by(32) {
+                half_md4_transform(&mut buf, &str2hashbuf(&name[chunk..], 8, unsigned));
+            }
+            buf[1]
+        }
+        DX_HASH_TEA => {
+            for chunk in (0..name.len()).step_by(16) {
+                tea_transform(&mut buf, &str2hashbuf(&name[chunk..], 4, unsigned));
+            }
+            buf[0]
+        }
+        _ => return None,
+    };
+    Some(match hash & !1 {
+        0xffff_fffe => 0xffff_fffc,
+        hash => hash,
+    })
+}
+
+/// An index node on the path to a leaf: its block and the entry followed.
+struct DxFrame {
+    block: u64,
+    data: Vec<u8>,
+    /// Offset of the count and limit of the entries.
+    base: usize,
+    index: usize,
+}
+
+impl DxFrame {
+    fn limit(&self) -> usize {
+        u16_at(&self.data, self.base) as usize
+    }
+
+    fn count(&self) -> usize {
+        u16_at(&self.data, self.base + 2) as usize
+    }
+
+    fn set_count(&mut self, count: usize) {
+        set_u16(&mut self.data, self.base + 2, count as u16);
+    }
+
+    /// Hash of entry `i`; the first entry covers all hashes below the
+    /// second, and has the count and limit in place of the hash.
+    fn hash(&self, i: usize) -> u32 {
+        match i {
+            0 => 0,
+            _ => u32_at(&self.data, self.base + i * DX_ENTRY_SIZE),
+        }
+    }
+
+    fn child(&self, i: usize) -> u64 {
+        (u32_at(&self.data, self.base + i * DX_ENTRY_SIZE + 4) & DX_BLOCK_MASK) as u64
+    }
+
+    /// Inserts an entry after the one followed, which needs room for it.
+    fn insert(&mut self, hash: u32, child: u64) {
+        let count = self.count();
+        let at = self.base + (self.index + 1) * DX_ENTRY_SIZE;
+        let end = self.base + count * DX_ENTRY_SIZE;
+        self.data.copy_within(at..end, at + DX_ENTRY_SIZE);
+        set_u32(&mut self.data, at, hash);
+        set_u32(&mut self.data, at + 4, child as u32);
+        self.set_count(count + 1);
+    }
+}
+
+/// The index nodes from the root down to the leaf holding a hash.
+struct DxPath {
+    frames: Vec<DxFrame>,
+    hash: u32,
+    leaf: u64,
+}
+
+/// Where an entry was found.
+pub struct Found {
+    /// Block of the device holding the entry.
+    block: u64,
+    offset: usize,
+    pub ino: u32,
+}
+
+impl Ext4FsInfo {
+    /// Most entries an index node starting with entries at `base` holds.
+    fn dx_limit(&self, base: usize) -> usize {
+        let tail = match self.csum_seed {
+            Some(_) => DX_TAIL_SIZE,
+            None => 0,
+        };
+        (self.block_size - base - tail) / DX_ENTRY_SIZE
+    }
+}
+
+/// Updates the checksum after the entries of an index node.
+fn update_dx_tail(frame: &mut DxFrame, seed: Option<u32>) {
+    let Some(seed) = seed else {
+        return;
+    };
+    let tail = frame.base + frame.limit() * DX_ENTRY_SIZE;
+    if tail + DX_TAIL_SIZE > frame.data.len() {
+        return;
+    }
+    let crc = crc32c(
+        seed,
+        &frame.data[..frame.base + frame.count() * DX_ENTRY_SIZE],
+    );
+    let crc = crc32c(crc, &frame.data[tail..tail + 4]);
+    let crc = crc32c(crc, &[0; 4]);
+    set_u32(&mut frame.data, tail + 4, crc);
+}
+
+impl InodeState {
+    fn dir_blocks(&self, fs: &Ext4FsInfo) -> u64 {
+        self.disk.size() / fs.block_size as u64
+    }
+
+    fn is_indexed(&self, fs: &Ext4FsInfo) -> bool {
+        fs.compat & COMPAT_DIR_INDEX != 0 && self.disk.flags() & INDEX_FL != 0
+    }
+
+    /// Reads block `lblk` of the directory, `None` for a hole.
+    async fn read_dir_block(
+        &mut self,
+        fs: &Ext4FsInfo,
+        lblk: u64,
+    ) -> Result<Option<(u64, Vec<u8>)>, Errno> {
+        let Some(block) = self.map(fs, lblk).await? else {
+            return Ok(None);
+        };
+        let mut data = vec![0; fs.block_size];
+        fs.read_block(block, 0, &mut data).await?;
+        Ok(Some((block, data)))
+    }
+
+    pub async fn write_dir_block(
+        &self,
+        fs: &Ext4FsInfo,
+        block: u64,
+        data: &mut [u8],
+    ) -> Result<(), Errno> {
+        update_tail(data, self.seed(fs));
+        fs.write_block(block, 0, data).await
+    }
+
+    async fn write_dx_frame(&self, fs: &Ext4FsInfo, frame: &mut DxFrame) -> Result<(), Errno> {
+        update_dx_tail(frame, self.seed(fs));
+        fs.write_block(frame.block, 0, &frame.data).await
+    }
+
+    /// Adds a block to the directory, returning its numbers in the
+    /// directory and on the device.
+    async fn grow_dir(&mut self, fs: &Ext4FsInfo) -> Result<(u64, u64), Errno> {
+        let lblk = self.dir_blocks(fs);
+        let (block, _) = self.map_alloc(fs, lblk).await?;
+        self.disk.set_size((lblk + 1) * fs.block_size as u64);
+        self.save(fs).await?;
+        Ok((lblk, block))
+    }
+
+    fn csum_blocks(fs: &Ext4FsInfo) -> bool {
+        fs.csum_seed.is_some()
+    }
+
+    /// Walks the index of the directory down to the leaf holding `name`.
+    /// `None` if the directory has an index this driver cannot use.
+    async fn dx_probe(&mut self, fs: &Ext4FsInfo, name: &[u8]) -> Result<Option<DxPath>, Errno> {
+        let Some((block, data)) = self.read_dir_block(fs, 0).await? else {
+            return Ok(None);
+        };
+        let version = data[DX_ROOT_INFO + 4];
+        let info_len = data[DX_ROOT_INFO + 5] as usize;
+        let levels = data[DX_ROOT_INFO + 6] as usize;
+        let max_levels = match fs.has_incompat(INCOMPAT_LARGEDIR) {
+            true => 3,
+            false => 2,
+        };
+        if u32_at(&data, DX_ROOT_INFO) != 0 || info_len != 8 || levels >= max_levels {
+            return Ok(None);
+        }
+        let unsigned = fs.hash_unsigned && version <= DX_HASH_TEA;
+        let Some(hash) = dx_hash(name, version, unsigned, &fs.hash_seed) else {
+            return Ok(None);
+        };
+        let mut frame = DxFrame {
+            block,
+            data,
+            base: DX_ROOT_INFO + info_len,
+            index: 0,
+        };
+        let mut frames = Vec::new();
+        loop {
+            let (count, limit) = (frame.count(), frame.limit());
+            if count == 0 || count > limit || frame.base + limit * DX_ENTRY_SIZE > fs.block_size {
+                return Err(Errno::EIO);
+            }
+            // the last entry whose hash is not above the one looked for
+            let (mut low, mut high) = (1, count);
+            while low < high {
+                let mid = (low + high) / 2;
+                match frame.hash(mid) <= hash {
+                    true => low = mid + 1,
+                    false => high = mid,
+                }
+            }
+            frame.index = low - 1;
+            let child = frame.child(frame.index);
+            frames.push(frame);
+            if frames.len() > levels {
+                return Ok(Some(DxPath {
+                    frames,
+                    hash,
+                    leaf: child,
+                }));
+            }
+            let (block, data) = self.read_dir_block(fs, child).await?.ok_or(Errno::EIO)?;
+            frame = DxFrame {
+                block,
+                data,
+                base: DX_NODE_ENTRIES,
+                index: 0,
+            };
+        }
+    }
+
+    /// Moves `path` to the next leaf, returning the first hash it holds,
+    /// or `None` after the last leaf.
+    async fn dx_next(&mut self, fs: &Ext4FsInfo, path: &mut DxPath) -> Result<Option<u32>, Errno> {
+        let Some(level) = path
+            .frames
+            .iter()
+            .rposition(|frame| frame.index + 1 < frame.count())
+        else {
+            return Ok(None);
+        };
+        path.frames[level].index += 1;
+        let hash = path.frames[level].hash(path.frames[level].index);
+        for level in level + 1..path.frames.len() {
+            let parent = &path.frames[level - 1];
+            let child = parent.child(parent.index);
+            let (block, data) = self.read_dir_block(fs, child).await?.ok_or(Errno::EIO)?;
+            path.frames[level] = DxFrame {
+                block,
+                data,
+                base: DX_NODE_ENTRIES,
+                index: 0,
+            };
+        }
+        let last = path.frames.last().unwrap();
+        path.leaf = last.child(last.index);
+        Ok(Some(hash))
+    }
+
+    /// Looks for `name` in block `lblk`.
+    async fn find_in_block(
+        &mut self,
+        fs: &Ext4FsInfo,
+        lblk: u64,
+        name: &[u8],
+    ) -> Result<Option<Found>, Errno> {
+        let Some((block, data)) = self.read_dir_block(fs, lblk).await? else {
+            return Ok(None);
+        };
+        Ok(parse(&data)?
+            .iter()
+            .find(|entry| entry.ino != 0 && entry.name == name)
+            .map(|entry| Found {
+                block,
+                offset: entry.offset,
+                ino: entry.ino,
+            }))
+    }
+
+    /// Finds entry `name`, through the index if the directory has one.
+    pub async fn find_entry(
+        &mut self,
+        fs: &Ext4FsInfo,
+        name: &str,
+    ) -> Result<Option<Found>, Errno> {
+        let name = name.as_bytes();
+        if self.is_indexed(fs) {
+            if let Some(mut path) = self.dx_probe(fs, name).await? {
+                loop {
+                    if let Some(found) = self.find_in_block(fs, path.leaf, name).await? {
+                        return Ok(Some(found));
+                    }
+                    // names of one hash may continue in the next leaf
+                    match self.dx_next(fs, &mut path).await? {
+                        Some(hash) if hash & !1 == path.hash => {}
+                        _ => return Ok(None),
+                    }
+                }
+            }
+        }
+        for lblk in 0..self.dir_blocks(fs) {
+            if let Some(found) = self.find_in_block(fs, lblk, name).await? {
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Adds entry `name` for inode `ino` of type `ty`.
+    pub async fn add_entry(
+        &mut self,
+        fs: &Ext4FsInfo,
+        name: &str,
+        ino: u32,
+        ty: InodeType,
+    ) -> Result<(), Errno> {
+        let bytes = name.as_bytes();
+        let file_type = match fs.has_incompat(INCOMPAT_FILETYPE) {
+            true => file_type(ty),
+            false => 0,
+        };
+        if self.is_indexed(fs) {
+            if self.dx_add(fs, bytes, ino, file_type).await? {
+                self.listed(name, ino, ty);
+                return Ok(());
+            }
+            // entries added out of the index would break it
+            self.disk.set_flags(self.disk.flags() & !INDEX_FL);
+            self.save(fs).await?;
+        }
+        self.linear_add(fs, bytes, ino, file_type).await?;
+        self.listed(name, ino, ty);
+        Ok(())
+    }
+
+    /// Adds a new entry to the listing, if it was read.
+    fn listed(&mut self, name: &str, ino: u32, ty: InodeType) {
+        if let Some(listing) = &mut self.listing {
+            listing.push(ListEntry {
+                ino,
+                name: String::from(name),
+                ty: Some(ty),
+            });
+        }
+    }
+
+    /// Adds an entry to the first block with room, or a new one.
+    async fn linear_add(
+        &mut self,
+        fs: &Ext4FsInfo,
+        name: &[u8],
+        ino: u32,
+        file_type: u8,
+    ) -> Result<(), Errno> {
+        for lblk in 0..self.dir_blocks(fs) {
+            let Some((block, mut data)) = self.read_dir_block(fs, lblk).await? else {
+                continue;
+            };
+            if insert(&mut data, name, ino, file_type)? {
+                return self.write_dir_block(fs, block, &mut data).await;
+            }
+        }
+        let (_, block) = self.grow_dir(fs).await?;
+        let mut data = empty_block(fs.block_size, Self::csum_blocks(fs));
+        insert(&mut data, name, ino, file_type)?;
+        self.write_dir_block(fs, block, &mut data).await
+    }
+
+    /// Adds an entry through the index, splitting the leaf it belongs in if
+    /// it is full. Returns `false` if the index cannot be used.
+    async fn dx_add(
+        &mut self,
+        fs: &Ext4FsInfo,
+        name: &[u8],
+        ino: u32,
+        file_type: u8,
+    ) -> Result<bool, Errno> {
+        let Some(mut path) = self.dx_probe(fs, name).await? else {
+            return Ok(false);
+        };
+        let (block, mut data) = self
+            .read_dir_block(fs, path.leaf)
+            .await?
+            .ok_or(Errno::EIO)?;
+        if insert(&mut data, name, ino, file_type)? {
+            self.write_dir_block(fs, block, &mut data).await?;
+            return Ok(true);
+        }
+
+        // split the leaf in two halves by hash
+        let version = path.frames[0].data[DX_ROOT_INFO + 4];
+        let unsigned = fs.hash_unsigned && version <= DX_HASH_TEA;
+        let mut entries: Vec<_> = parse(&data)?
+            .into_iter()
+            .filter(|entry| entry.ino != 0)
+            .map(|entry| {
+                let hash = dx_hash(entry.name, version, unsigned, &fs.hash_seed).unwrap_or(0);
+                (hash, entry.name.to_vec(), entry.ino, entry.file_type)
+            })
+            .collect();
+        if entries.len() < 2 {
+            return Err(Errno::ENOSPC);
+        }
+        entries.sort_unstable_by_key(|entry| entry.0);
+        let split = entries.len() / 2;
+        // a hash continued in the next leaf is marked in the index entry
+        let split_hash = entries[split].0 | (entries[split - 1].0 == entries[split].0) as u32;
+        let (lblk, new_block) = self.grow_dir(fs).await?;
+        let csum = Self::csum_blocks(fs);
+        let mut low = empty_block(fs.block_size, csum);
+        let mut high = empty_block(fs.block_size, csum);
+        for (i, (_, name, ino, file_type)) in entries.iter().enumerate() {
+            let half = match i < split {
+                true => &mut low,
+                false => &mut high,
+            };
+            insert(half, name, *ino, *file_type)?;
+        }
+        let half = match path.hash >= split_hash {
+            true => &mut high,
+            false => &mut low,
+        };
+        if !insert(half, name, ino, file_type)? {
+            return Err(Errno::ENOSPC);
+        }
+        self.write_dir_block(fs, block, &mut low).await?;
+        self.write_dir_block(fs, new_block, &mut high).await?;
+        self.dx_insert(fs, &mut path, split_hash, lblk).await?;
+        Ok(true)
+    }
+
+    /// Adds an index entry for leaf `child` holding hashes from `hash` on
+    /// after the entry `path` follows, splitting or deepening the index if
+    /// the node is full.
+    async fn dx_insert(
+        &mut self,
+        fs: &Ext4FsInfo,
+        path: &mut DxPath,
+        hash: u32,
+        child: u64,
+    ) -> Result<(), Errno> {
+        let depth = path.frames.len() - 1;
+        let frame = &mut path.frames[depth];
+        if frame.count() < frame.limit() {
+            frame.insert(hash, child);
+            let mut frame = path.frames.pop().unwrap();
+            return self.write_dx_frame(fs, &mut frame).await;
+        }
+        let node_limit = fs.dx_limit(DX_NODE_ENTRIES);
+        match depth {
+            // move the entries of the root to a new node below it
+            0 => {
+                let (lblk, block) = self.grow_dir(fs).await?;
+                let root = &mut path.frames[0];
+                let count = root.count();
+                let mut node = DxFrame {
+                    block,
+                    data: vec![0; fs.block_size],
+                    base: DX_NODE_ENTRIES,
+                    index: root.index,
+                };
+                set_u16(&mut node.data, 4, encode_rec_len(fs.block_size));
+                let entries = root.base..root.base + count * DX_ENTRY_SIZE;
+                node.data[DX_NODE_ENTRIES..][..entries.len()].copy_from_slice(&root.data[entries]);
+                set_u16(&mut node.data, DX_NODE_ENTRIES, node_limit as u16);
+                node.insert(hash, child);
+
+                root.set_count(1);
+                set_u32(&mut root.data, root.base + 4, lblk as u32);
+                root.data[DX_ROOT_INFO + 6] += 1;
+                let mut root = path.frames.pop().unwrap();
+                self.write_dx_frame(fs, &mut node).await?;
+                self.write_dx_frame(fs, &mut root).await
+            }
+            // split the node, adding the second half to the root
+            1 if path.frames[0].count() < path.frames[0].limit() => {
+                let (lblk, block) = self.grow_dir(fs).await?;
+                let mut low = path.frames.pop().unwrap();
+                let mut root = path.frames.pop().unwrap();
+                let count = low.count();
+                let split = count / 2;
+                let mut high = DxFrame {
+                    block,
+                    data: vec![0; fs.block_size],
+                    base: DX_NODE_ENTRIES,
+                    index: 0,
+                };
+                set_u16(&mut high.data, 4, encode_rec_len(fs.block_size));
+                let moved = low.base + split * DX_ENTRY_SIZE..low.base + count * DX_ENTRY_SIZE;
+                high.data[DX_NODE_ENTRIES..][..moved.len()].copy_from_slice(&low.data[moved]);
+                set_u16(&mut high.data, DX_NODE_ENTRIES, node_limit as u16);
+                high.set_count(count - split);
+                low.set_count(split);
+                root.insert(low.hash(split), lblk);
+                match low.index < split {
+                    true => low.insert(hash, child),
+                    false => {
+                        high.index = low.index - split;
+                        high.insert(hash, child);
+                    }
+                }
+                self.write_dx_frame(fs, &mut low).await?;
+                self.write_dx_frame(fs, &mut high).await?;
+                self.write_dx_frame(fs, &mut root).await
+            }
+            _ => Err(Errno::ENOSPC),
+        }
+    }
+
+    /// Removes the entry `found` points to.
+    pub async fn remove_entry(&mut self, fs: &Ext4FsInfo, found: &Found) -> Result<(), Errno> {
+        let mut data = vec![0; fs.block_size];
+        fs.read_block(found.block, 0, &mut data).await?;
+        let name = parse(&data)?
+            .iter()
+            .find(|entry| entry.offset == found.offset)
+            .map(|entry| entry.name.to_vec())
+            .ok_or(Errno::EIO)?;
+        remove(&mut data, found.offset)?;
+        self.write_dir_block(fs, found.block, &mut data).await?;
+        if let Some(listing) = &mut self.listing {
+            listing.retain(|entry| entry.name.as_bytes() != name);
+        }
+        Ok(())
+    }
+
+    /// Points the entry `found` points to at inode `ino` of type `ty`.
+    pub async fn replace_entry(
+        &mut self,
+        fs: &Ext4FsInfo,
+        found: &Found,
+        ino: u32,
+        ty: InodeType,
+    ) -> Result<(), Errno> {
+        let mut data = vec![0; fs.block_size];
+        fs.read_block(found.block, 0, &mut data).await?;
+        set_u32(&mut data, found.offset, ino);
+        if fs.has_incompat(INCOMPAT_FILETYPE) {
+            data[found.offset + 7] = file_type(ty);
+        }
+        self.write_dir_block(fs, found.block, &mut data).await?;
+        let name = &data[found.offset + ENTRY_HEADER..][..data[found.offset + 6] as usize];
+        if let Some(entry) = self
+            .listing
+            .iter_mut()
+            .flatten()
+            .find(|entry| entry.name.as_bytes() == name)
+        {
+            entry.ino = ino;
+            entry.ty = Some(ty);
+        }
+        Ok(())
+    }
+
+    /// Points the `..` entry of the directory at `parent`.
+    pub async fn set_parent(&mut self, fs: &Ext4FsInfo, parent: u32) -> Result<(), Errno> {
+        let (block, mut data) = self.read_dir_block(fs, 0).await?.ok_or(Errno::EIO)?;
+        let entries = parse(&data)?;
+        let dotdot = entries
+            .iter()
+            .find(|entry| entry.name == b"..")
+            .map(|entry| entry.offset)
+            .ok_or(Errno::EIO)?;
+        set_u32(&mut data, dotdot, parent);
+        match self.is_indexed(fs) {
+            // the root of an index has no checksum of its own
+            true => {
+                let mut frame = DxFrame {
+                    block,
+                    base: DX_ROOT_INFO + data[DX_ROOT_INFO + 5] as usize,
+                    data,
+                    index: 0,
+                };
+                self.write_dx_frame(fs, &mut frame).await
+            }
+            false => self.write_dir_block(fs, block, &mut data).await,
+        }
+    }
+
+    /// Whether the directory has no entries but `.` and `..`.
+    pub async fn is_empty_dir(&mut self, fs: &Ext4FsInfo) -> Result<bool, Errno> {
+        for lblk in 0..self.dir_blocks(fs) {
+            let Some((_, data)) = self.read_dir_block(fs, lblk).await? else {
+                continue;
+            };
+            if parse(&data)?
+                .iter()
+                .any(|entry| entry.ino != 0 && entry.name != b"." && entry.name != b"..")
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The entries of the directory but `.` and `..`, read on first use and
+    /// then kept up to date.
+    pub async fn listing(&mut self, fs: &Ext4FsInfo) -> Result<&mut Vec<ListEntry>, Errno> {
+        if self.listing.is_none() {
+            let mut listing = Vec::new();
+            for lblk in 0..self.dir_blocks(fs) {
+                let Some((_, data)) = self.read_dir_block(fs, lblk).await? else {
+                    continue;
+                };
+                for entry in parse(&data)? {
+                    if entry.ino == 0 || entry.name == b"." || entry.name == b".." {
+                        continue;
+                    }
+                    listing.push(ListEntry {
+                        ino: entry.ino,
+                        name: String::from_utf8_lossy(entry.name).into_owned(),
+                        ty: entry_type(fs, entry.file_type),
+                    });
+                }
+            }
+            self.listing = Some(listing);
+        }
+        Ok(self.listing.as_mut().unwrap())
+    }
+}
+
+/// Type of an entry from its `file_type`, if the filesystem keeps them.
+pub fn entry_type(fs: &Ext4FsInfo, file_type: u8) -> Option<InodeType> {
+    match fs.has_incompat(INCOMPAT_FILETYPE) {
+        true => type_of_file_type(file_type),
+        false => None,
+    }
+}
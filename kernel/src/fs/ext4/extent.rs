@@ -0,0 +1,338 @@
+//! Extent trees of ext4 files, which map ranges of blocks of a file to
+//! runs of blocks of the device. The tree of a file is loaded whole and
+//! rebuilt when it is stored, which keeps changes simple at the cost of
+//! rewriting all nodes of large fragmented files.
+
+use alloc::{vec, vec::Vec};
+
+use crate::Errno;
+
+use super::{Ext4FsInfo, crc::crc32c, set_u16, set_u32, u16_at, u32_at};
+
+const EXTENT_MAGIC: u16 = 0xf30a;
+const HEADER_SIZE: usize = 12;
+const ENTRY_SIZE: usize = 12;
+/// Entries of the root of the tree, in the 60 bytes of `i_block`.
+const ROOT_ENTRIES: usize = 4;
+const MAX_DEPTH: u16 = 5;
+/// Longest extents; unwritten ones store their length plus this.
+const MAX_LEN: u32 = 32768;
+const MAX_UNWRITTEN_LEN: u32 = 32767;
+
+/// A run of blocks of the file at `block` stored from block `start` of the
+/// device on. Unwritten extents are allocated but read as zeros.
+#[derive(Clone, Copy)]
+struct Extent {
+    block: u32,
+    len: u32,
+    start: u64,
+    unwritten: bool,
+}
+
+impl Extent {
+    fn end(&self) -> u64 {
+        self.block as u64 + self.len as u64
+    }
+
+    fn to_bytes(self) -> [u8; ENTRY_SIZE] {
+        let mut raw = [0; ENTRY_SIZE];
+        let len = match self.unwritten {
+            true => self.len + MAX_LEN,
+            false => self.len,
+        };
+        set_u32(&mut raw, 0, self.block);
+        set_u16(&mut raw, 4, len as u16);
+        set_u16(&mut raw, 6, (self.start >> 32) as u16);
+        set_u32(&mut raw, 8, self.start as u32);
+        raw
+    }
+
+    /// Whether `next` continues the extent on the device as in the file.
+    fn can_merge(&self, next: &Extent) -> bool {
+        let max = match self.unwritten {
+            true => MAX_UNWRITTEN_LEN,
+            false => MAX_LEN,
+        };
+        self.unwritten == next.unwritten
+            && self.end() == next.block as u64
+            && self.start + self.len as u64 == next.start
+            && self.len + next.len <= max
+    }
+}
+
+fn write_header(node: &mut [u8], entries: usize, max: usize, depth: u16) {
+    set_u16(node, 0, EXTENT_MAGIC);
+    set_u16(node, 2, entries as u16);
+    set_u16(node, 4, max as u16);
+    set_u16(node, 6, depth);
+    set_u32(node, 8, 0);
+}
+
+/// Checks the header of a node of at most `capacity` entries, returning
+/// its number of entries and depth.
+fn check_header(node: &[u8], capacity: usize) -> Result<(usize, u16), Errno> {
+    let entries = u16_at(node, 2) as usize;
+    let max = u16_at(node, 4) as usize;
+    let depth = u16_at(node, 6);
+    if u16_at(node, 0) != EXTENT_MAGIC || max > capacity || entries > max || depth > MAX_DEPTH {
+        return Err(Errno::EIO);
+    }
+    Ok((entries, depth))
+}
+
+/// The extents of a file, with the blocks of the nodes below the root.
+pub struct ExtentTree {
+    extents: Vec<Extent>,
+    nodes: Vec<u64>,
+    /// Whether the extents changed since the tree was stored.
+    dirty: bool,
+}
+
+impl ExtentTree {
+    /// Writes an empty tree to the root `root`, for a new file.
+    pub fn init_root(root: &mut [u8]) {
+        root.fill(0);
+        write_header(root, 0, ROOT_ENTRIES, 0);
+    }
+
+    pub fn new() -> Self {
+        Self {
+            extents: Vec::new(),
+            nodes: Vec::new(),
+            dirty: false,
+        }
+    }
+
+    /// Entries of a node below the root.
+    fn node_capacity(fs: &Ext4FsInfo) -> usize {
+        (fs.block_size - HEADER_SIZE) / ENTRY_SIZE
+    }
+
+    /// Reads the tree whose root is `root`.
+    pub async fn load(fs: &Ext4FsInfo, root: &[u8]) -> Result<Self, Errno> {
+        let mut tree = Self::new();
+        let (_, depth) = check_header(root, ROOT_ENTRIES)?;
+        let mut pending = Vec::new();
+        tree.parse_node(root, depth, &mut pending)?;
+        let capacity = Self::node_capacity(fs);
+        let mut node = vec![0; fs.block_size];
+        // children are pushed in reverse, so that extents come in order
+        while let Some((block, depth)) = pending.pop() {
+            if tree.nodes.len() as u64 >= fs.blocks_count {
+                return Err(Errno::EIO);
+            }
+            fs.read_block(block, 0, &mut node).await?;
+            if check_header(&node, capacity)?.1 != depth {
+                return Err(Errno::EIO);
+            }
+            tree.nodes.push(block);
+            tree.parse_node(&node, depth, &mut pending)?;
+        }
+        Ok(tree)
+    }
+
+    /// Adds the extents of a leaf, or the children of an index node to
+    /// `pending`.
+    fn parse_node(
+        &mut self,
+        node: &[u8],
+        depth: u16,
+        pending: &mut Vec<(u64, u16)>,
+    ) -> Result<(), Errno> {
+        let entries = u16_at(node, 2) as usize;
+        let entry = |i: usize| &node[HEADER_SIZE + i * ENTRY_SIZE..][..ENTRY_SIZE];
+        if depth > 0 {
+            for i in (0..entries).rev() {
+                let raw = entry(i);
+                let child = u32_at(raw, 4) as u64 | (u16_at(raw, 8) as u64) << 32;
+                pending.push((child, depth - 1));
+            }
+            return Ok(());
+        }
+        for i in 0..entries {
+            let raw = entry(i);
+            let len = u16_at(raw, 4) as u32;
+            let extent = Extent {
+                block: u32_at(raw, 0),
+                len: match len > MAX_LEN {
+                    true => len - MAX_LEN,
+                    false => len,
+                },
+                start: u32_at(raw, 8) as u64 | (u16_at(raw, 6) as u64) << 32,
+                unwritten: len > MAX_LEN,
+            };
+            if self
+                .extents
+                .last()
+                .is_some_and(|last| last.end() > extent.block as u64)
+            {
+                return Err(Errno::EIO);
+            }
+            if extent.len > 0 {
+                self.extents.push(extent);
+            }
+        }
+        Ok(())
+    }
+
+    /// The block of the device holding block `block` of the file, and
+    /// whether it is unwritten.
+    pub fn map(&self, block: u64) -> Option<(u64, bool)> {
+        let i = self.extents.partition_point(|extent| extent.end() <= block);
+        let extent = self.extents.get(i)?;
+        (extent.block as u64 <= block)
+            .then(|| (extent.start + block - extent.block as u64, extent.unwritten))
+    }
+
+    /// Where to allocate block `block`: after the blocks of the extent
+    /// before it, so that the file stays contiguous.
+    pub fn goal(&self, block: u64) -> Option<u64> {
+        let i = self
+            .extents
+            .partition_point(|extent| (extent.block as u64) < block);
+        let extent = self.extents.get(i.checked_sub(1)?)?;
+        Some(extent.start + (block - extent.block as u64))
+    }
+
+    /// Maps block `block` of the file to `mapping`, a block of the device
+    /// and whether it is unwritten, or unmaps it.
+    pub fn set(&mut self, block: u64, mapping: Option<(u64, bool)>) {
+        self.dirty = true;
+        let mut i = self.extents.partition_point(|extent| extent.end() <= block);
+        if let Some(&extent) = self.extents.get(i) {
+            if extent.block as u64 <= block {
+                self.extents.remove(i);
+                let after = block + 1;
+                if after < extent.end() {
+                    let skipped = after - extent.block as u64;
+                    self.extents.insert(i, Extent {
+                        block: after as u32,
+                        len: (extent.end() - after) as u32,
+                        start: extent.start + skipped,
+                        unwritten: extent.unwritten,
+                    });
+                }
+                if (extent.block as u64) < block {
+                    let len = (block - extent.block as u64) as u32;
+                    self.extents.insert(i, Extent { len, ..extent });
+                    i += 1;
+                }
+            }
+        }
+        let Some((start, unwritten)) = mapping else {
+            return;
+        };
+        self.extents.insert(i, Extent {
+            block: block as u32,
+            len: 1,
+            start,
+            unwritten,
+        });
+        self.merge(i);
+        if i > 0 {
+            self.merge(i - 1);
+        }
+    }
+
+    /// Merges extent `i` with the next one if they are contiguous.
+    fn merge(&mut self, i: usize) {
+        if i + 1 < self.extents.len() && self.extents[i].can_merge(&self.extents[i + 1]) {
+            self.extents[i].len += self.extents[i + 1].len;
+            self.extents.remove(i + 1);
+        }
+    }
+
+    /// Unmaps the blocks of the file from `from` on, returning the runs of
+    /// blocks of the device they were stored in.
+    pub fn truncate(&mut self, from: u64) -> Vec<(u64, u64)> {
+        let mut freed = Vec::new();
+        while let Some(extent) = self.extents.last_mut() {
+            if extent.end() <= from {
+                break;
+            }
+            self.dirty = true;
+            if extent.block as u64 >= from {
+                freed.push((extent.start, extent.len as u64));
+                self.extents.pop();
+            } else {
+                let keep = from - extent.block as u64;
+                freed.push((extent.start + keep, extent.len as u64 - keep));
+                extent.len = keep as u32;
+                break;
+            }
+        }
+        freed
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Writes the tree to `root` and nodes below it, reusing the blocks of
+    /// the old nodes and allocating more near `goal`. Nodes are checksummed
+    /// with `seed`. Returns how many node blocks were allocated, less those
+    /// freed.
+    pub async fn store(
+        &mut self,
+        fs: &Ext4FsInfo,
+        seed: Option<u32>,
+        root: &mut [u8],
+        goal: u64,
+    ) -> Result<i64, Errno> {
+        let capacity = Self::node_capacity(fs);
+        let mut entries: Vec<(u32, [u8; ENTRY_SIZE])> = self
+            .extents
+            .iter()
+            .map(|extent| (extent.block, extent.to_bytes()))
+            .collect();
+        let mut old = core::mem::take(&mut self.nodes).into_iter();
+        let mut delta = 0;
+        let mut depth = 0;
+        while entries.len() > ROOT_ENTRIES {
+            if depth == MAX_DEPTH {
+                return Err(Errno::EFBIG);
+            }
+            let mut parents = Vec::new();
+            for chunk in entries.chunks(capacity) {
+                let block = match old.next() {
+                    Some(block) => block,
+                    None => {
+                        delta += 1;
+                        fs.alloc_block(goal).await?
+                    }
+                };
+                let mut node = vec![0; fs.block_size];
+                write_header(&mut node, chunk.len(), capacity, depth);
+                for (i, (_, raw)) in chunk.iter().enumerate() {
+                    node[HEADER_SIZE + i * ENTRY_SIZE..][..ENTRY_SIZE].copy_from_slice(raw);
+                }
+                // the checksum follows the room for entries
+                if let Some(seed) = seed {
+                    let tail = HEADER_SIZE + capacity * ENTRY_SIZE;
+                    let csum = crc32c(seed, &node[..tail]);
+                    set_u32(&mut node, tail, csum);
+                }
+                fs.write_block(block, 0, &node).await?;
+                self.nodes.push(block);
+
+                let mut index = [0; ENTRY_SIZE];
+                set_u32(&mut index, 0, chunk[0].0);
+                set_u32(&mut index, 4, block as u32);
+                set_u16(&mut index, 8, (block >> 32) as u16);
+                parents.push((chunk[0].0, index));
+            }
+            entries = parents;
+            depth += 1;
+        }
+        root.fill(0);
+        write_header(root, entries.len(), ROOT_ENTRIES, depth);
+        for (i, (_, raw)) in entries.iter().enumerate() {
+            root[HEADER_SIZE + i * ENTRY_SIZE..][..ENTRY_SIZE].copy_from_slice(raw);
+        }
+        let unused: Vec<_> = old.map(|block| (block, 1)).collect();
+        delta -= unused.len() as i64;
+        fs.free_blocks(&unused).await?;
+        self.dirty = false;
+        Ok(delta)
+    }
+}
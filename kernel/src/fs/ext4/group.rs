@@ -0,0 +1,472 @@
+use alloc::{sync::Arc, vec, vec::Vec};
+use log::warn;
+
+use crate::{
+    Errno,
+    block::{BUFFER_CACHE, Buffer},
+};
+
+use super::{
+    AllocState, Ext4FsInfo, RO_COMPAT_GDT_CSUM, RO_COMPAT_SPARSE_SUPER, SB_FREE_BLOCKS,
+    SB_FREE_BLOCKS_HI, SB_FREE_INODES,
+    crc::{crc16, crc32c},
+    set_u16, set_u32, u16_at, u32_at,
+};
+
+/// The inode bitmap was never written: all inodes are free.
+const BG_INODE_UNINIT: u16 = 0x1;
+/// The block bitmap was never written: only metadata is allocated.
+const BG_BLOCK_UNINIT: u16 = 0x2;
+/// Offset of the checksum of a group descriptor.
+const BG_CHECKSUM: usize = 0x1e;
+/// Descriptors of 64-bit filesystems hold the high halves of fields.
+const DESC_SIZE_64BIT: usize = 64;
+
+/// A group descriptor as stored, so that fields the driver does not know
+/// are written back unchanged.
+pub(super) struct GroupDesc(Vec<u8>);
+
+impl GroupDesc {
+    pub fn parse(raw: &[u8]) -> Self {
+        Self(raw.to_vec())
+    }
+
+    fn is_64bit(&self) -> bool {
+        self.0.len() >= DESC_SIZE_64BIT
+    }
+
+    /// A field of 32 bits with its high half at `hi` in 64-bit descriptors.
+    fn get32(&self, lo: usize, hi: usize) -> u64 {
+        let lo = u32_at(&self.0, lo) as u64;
+        match self.is_64bit() {
+            true => lo | (u32_at(&self.0, hi) as u64) << 32,
+            false => lo,
+        }
+    }
+
+    /// A field of 16 bits with its high half at `hi` in 64-bit descriptors.
+    fn get16(&self, lo: usize, hi: usize) -> u32 {
+        let lo = u16_at(&self.0, lo) as u32;
+        match self.is_64bit() {
+            true => lo | (u16_at(&self.0, hi) as u32) << 16,
+            false => lo,
+        }
+    }
+
+    fn set16(&mut self, lo: usize, hi: usize, value: u32) {
+        set_u16(&mut self.0, lo, value as u16);
+        if self.is_64bit() {
+            set_u16(&mut self.0, hi, (value >> 16) as u16);
+        }
+    }
+
+    pub fn block_bitmap(&self) -> u64 {
+        self.get32(0x00, 0x20)
+    }
+
+    pub fn inode_bitmap(&self) -> u64 {
+        self.get32(0x04, 0x24)
+    }
+
+    pub fn inode_table(&self) -> u64 {
+        self.get32(0x08, 0x28)
+    }
+
+    pub fn free_blocks(&self) -> u32 {
+        self.get16(0x0c, 0x2c)
+    }
+
+    fn set_free_blocks(&mut self, count: u32) {
+        self.set16(0x0c, 0x2c, count);
+    }
+
+    pub fn free_inodes(&self) -> u32 {
+        self.get16(0x0e, 0x2e)
+    }
+
+    fn set_free_inodes(&mut self, count: u32) {
+        self.set16(0x0e, 0x2e, count);
+    }
+
+    fn used_dirs(&self) -> u32 {
+        self.get16(0x10, 0x30)
+    }
+
+    fn set_used_dirs(&mut self, count: u32) {
+        self.set16(0x10, 0x30, count);
+    }
+
+    fn flags(&self) -> u16 {
+        u16_at(&self.0, 0x12)
+    }
+
+    fn set_flags(&mut self, flags: u16) {
+        set_u16(&mut self.0, 0x12, flags);
+    }
+
+    fn set_block_bitmap_csum(&mut self, csum: u32) {
+        self.set16(0x18, 0x38, csum);
+    }
+
+    fn set_inode_bitmap_csum(&mut self, csum: u32) {
+        self.set16(0x1a, 0x3a, csum);
+    }
+
+    /// Inodes at the end of the inode table never used.
+    fn itable_unused(&self) -> u32 {
+        self.get16(0x1c, 0x32)
+    }
+
+    fn set_itable_unused(&mut self, count: u32) {
+        self.set16(0x1c, 0x32, count);
+    }
+}
+
+/// Finds a clear bit of `bitmap` in `from..to`.
+fn find_zero(bitmap: &[u8], from: usize, to: usize) -> Option<usize> {
+    let mut bit = from;
+    while bit < to {
+        if bit % 8 == 0 && bitmap[bit / 8] == 0xff {
+            bit += 8;
+            continue;
+        }
+        if bitmap[bit / 8] & (1 << (bit % 8)) == 0 {
+            return Some(bit);
+        }
+        bit += 1;
+    }
+    None
+}
+
+fn set_bits(bitmap: &mut [u8], bits: core::ops::Range<usize>) {
+    for bit in bits {
+        bitmap[bit / 8] |= 1 << (bit % 8);
+    }
+}
+
+fn add_sb_free_blocks(sb: &mut [u8], delta: i64) {
+    let free = u32_at(sb, SB_FREE_BLOCKS) as u64 | (u32_at(sb, SB_FREE_BLOCKS_HI) as u64) << 32;
+    let free = free.saturating_add_signed(delta);
+    set_u32(sb, SB_FREE_BLOCKS, free as u32);
+    set_u32(sb, SB_FREE_BLOCKS_HI, (free >> 32) as u32);
+}
+
+fn add_sb_free_inodes(sb: &mut [u8], delta: i32) {
+    let free = u32_at(sb, SB_FREE_INODES).saturating_add_signed(delta);
+    set_u32(sb, SB_FREE_INODES, free);
+}
+
+impl Ext4FsInfo {
+    /// Checks that the metadata of `group` lies within the filesystem.
+    pub(super) fn check_group(&self, group: u32, desc: &GroupDesc) -> Result<(), Errno> {
+        let table_end = desc.inode_table() + self.inode_table_blocks();
+        let valid = |block: u64| (self.first_data_block..self.blocks_count).contains(&block);
+        if !valid(desc.block_bitmap())
+            || !valid(desc.inode_bitmap())
+            || !valid(desc.inode_table())
+            || table_end > self.blocks_count
+        {
+            warn!("ext4: group {} has invalid metadata locations", group);
+            return Err(Errno::EINVAL);
+        }
+        Ok(())
+    }
+
+    /// Whether group descriptors have checksums, which makes their
+    /// `*_UNINIT` flags valid.
+    fn has_group_csum(&self) -> bool {
+        self.csum_seed.is_some() || self.has_ro_compat(RO_COMPAT_GDT_CSUM)
+    }
+
+    pub(super) fn group_first_block(&self, group: u32) -> u64 {
+        self.first_data_block + group as u64 * self.blocks_per_group
+    }
+
+    fn blocks_in_group(&self, group: u32) -> u64 {
+        (self.blocks_count - self.group_first_block(group)).min(self.blocks_per_group)
+    }
+
+    pub(super) fn group_of_block(&self, block: u64) -> u32 {
+        ((block.max(self.first_data_block) - self.first_data_block) / self.blocks_per_group) as u32
+    }
+
+    pub(super) fn inode_table_blocks(&self) -> u64 {
+        (self.inodes_per_group as u64 * self.inode_size as u64).div_ceil(self.block_size as u64)
+    }
+
+    /// Whether `group` holds a backup of the superblock and descriptors:
+    /// with `sparse_super`, only groups 0, 1 and powers of 3, 5 and 7 do.
+    fn has_super(&self, group: u32) -> bool {
+        let is_power = |mut n: u32, base: u32| {
+            while n % base == 0 {
+                n /= base;
+            }
+            n == 1
+        };
+        group <= 1
+            || !self.has_ro_compat(RO_COMPAT_SPARSE_SUPER)
+            || is_power(group, 3)
+            || is_power(group, 5)
+            || is_power(group, 7)
+    }
+
+    fn desc_checksum(&self, group: u32, raw: &[u8]) -> Option<u16> {
+        let group = group.to_le_bytes();
+        let rest = &raw[BG_CHECKSUM + 2..];
+        if let Some(seed) = self.csum_seed {
+            let crc = crc32c(seed, &group);
+            let crc = crc32c(crc, &raw[..BG_CHECKSUM]);
+            let crc = crc32c(crc, &[0, 0]);
+            return Some(crc32c(crc, rest) as u16);
+        }
+        if !self.has_ro_compat(RO_COMPAT_GDT_CSUM) {
+            return None;
+        }
+        let crc = crc16(!0, &self.uuid);
+        let crc = crc16(crc, &group);
+        let crc = crc16(crc, &raw[..BG_CHECKSUM]);
+        Some(crc16(crc, rest))
+    }
+
+    /// Writes the descriptor of `group` with an updated checksum.
+    async fn write_desc(&self, group: u32, desc: &mut GroupDesc) -> Result<(), Errno> {
+        if let Some(csum) = self.desc_checksum(group, &desc.0) {
+            set_u16(&mut desc.0, BG_CHECKSUM, csum);
+        }
+        let gdt = self.first_data_block + 1;
+        let pos = group as usize * self.desc_size;
+        self.write_block(
+            gdt + (pos / self.block_size) as u64,
+            pos % self.block_size,
+            &desc.0,
+        )
+        .await
+    }
+
+    /// Checksum of the first `len` bytes of a bitmap.
+    fn bitmap_csum(&self, bitmap: &[u8], len: usize) -> Option<u32> {
+        Some(crc32c(self.csum_seed?, &bitmap[..len]))
+    }
+
+    /// The block bitmap of `group`, built from the locations of metadata
+    /// if it was never written.
+    async fn block_bitmap(&self, alloc: &mut AllocState, group: u32) -> Result<Arc<Buffer>, Errno> {
+        let desc = &alloc.groups[group as usize];
+        if !self.has_group_csum() || desc.flags() & BG_BLOCK_UNINIT == 0 {
+            return self.bread(desc.block_bitmap()).await;
+        }
+        let first = self.group_first_block(group);
+        let count = self.blocks_in_group(group);
+        let mut bitmap = vec![0; self.block_size];
+        let mut mark = |start: u64, len: u64| {
+            let start = start.max(first);
+            let end = (start + len).min(first + count);
+            if start < end {
+                set_bits(
+                    &mut bitmap,
+                    (start - first) as usize..(end - first) as usize,
+                );
+            }
+        };
+        if self.has_super(group) {
+            let gdt_blocks =
+                (self.group_count as u64 * self.desc_size as u64).div_ceil(self.block_size as u64);
+            mark(first, 1 + gdt_blocks + self.reserved_gdt_blocks);
+        }
+        // with `flex_bg`, a group may hold the metadata of others
+        for desc in &alloc.groups {
+            mark(desc.block_bitmap(), 1);
+            mark(desc.inode_bitmap(), 1);
+            mark(desc.inode_table(), self.inode_table_blocks());
+        }
+        set_bits(&mut bitmap, count as usize..self.block_size * 8);
+
+        let desc = &mut alloc.groups[group as usize];
+        let buffer =
+            BUFFER_CACHE.getblk(&self.device, desc.block_bitmap() as usize, self.block_size);
+        buffer.write().await.copy_from_slice(&bitmap);
+        desc.set_flags(desc.flags() & !BG_BLOCK_UNINIT);
+        Ok(buffer)
+    }
+
+    /// The inode bitmap of `group`, cleared if it was never written.
+    async fn inode_bitmap(&self, alloc: &mut AllocState, group: u32) -> Result<Arc<Buffer>, Errno> {
+        let desc = &mut alloc.groups[group as usize];
+        if !self.has_group_csum() || desc.flags() & BG_INODE_UNINIT == 0 {
+            return self.bread(desc.inode_bitmap()).await;
+        }
+        let mut bitmap = vec![0; self.block_size];
+        set_bits(
+            &mut bitmap,
+            self.inodes_per_group as usize..self.block_size * 8,
+        );
+        let buffer =
+            BUFFER_CACHE.getblk(&self.device, desc.inode_bitmap() as usize, self.block_size);
+        buffer.write().await.copy_from_slice(&bitmap);
+        desc.set_flags(desc.flags() & !BG_INODE_UNINIT);
+        Ok(buffer)
+    }
+
+    /// Allocates a block, as close after `goal` as there is a free one.
+    pub(super) async fn alloc_block(&self, goal: u64) -> Result<u64, Errno> {
+        if self.readonly {
+            return Err(Errno::EROFS);
+        }
+        let mut alloc = self.alloc.lock().await;
+        let alloc = &mut *alloc;
+        let goal = match (self.first_data_block..self.blocks_count).contains(&goal) {
+            true => goal,
+            false => self.first_data_block,
+        };
+        let first_group = self.group_of_block(goal);
+        // the group of the goal is searched again from its start at the end
+        for i in 0..=self.group_count {
+            let group = (first_group + i) % self.group_count;
+            if alloc.groups[group as usize].free_blocks() == 0 {
+                continue;
+            }
+            let first = self.group_first_block(group);
+            let start = match i {
+                0 => (goal - first) as usize,
+                _ => 0,
+            };
+            let buffer = self.block_bitmap(alloc, group).await?;
+            let mut bitmap = buffer.write().await;
+            let Some(bit) = find_zero(&bitmap, start, self.blocks_in_group(group) as usize) else {
+                continue;
+            };
+            bitmap[bit / 8] |= 1 << (bit % 8);
+            let csum = self.bitmap_csum(&bitmap, self.blocks_per_group as usize / 8);
+            drop(bitmap);
+
+            let desc = &mut alloc.groups[group as usize];
+            desc.set_free_blocks(desc.free_blocks() - 1);
+            if let Some(csum) = csum {
+                desc.set_block_bitmap_csum(csum);
+            }
+            self.write_desc(group, desc).await?;
+            add_sb_free_blocks(&mut alloc.sb, -1);
+            return Ok(first + bit as u64);
+        }
+        Err(Errno::ENOSPC)
+    }
+
+    /// Frees runs of blocks given by their first block and length.
+    pub(super) async fn free_blocks(&self, runs: &[(u64, u64)]) -> Result<(), Errno> {
+        if runs.is_empty() {
+            return Ok(());
+        }
+        let mut alloc = self.alloc.lock().await;
+        let alloc = &mut *alloc;
+        for &(start, len) in runs {
+            let end = start + len;
+            if start < self.first_data_block || end > self.blocks_count {
+                warn!("ext4: freeing invalid blocks {}..{}", start, end);
+                continue;
+            }
+            // a run may cross groups
+            let mut block = start;
+            while block < end {
+                let group = self.group_of_block(block);
+                let first = self.group_first_block(group);
+                let group_end = (first + self.blocks_in_group(group)).min(end);
+                let buffer = self.block_bitmap(alloc, group).await?;
+                let mut bitmap = buffer.write().await;
+                let mut freed = 0;
+                for bit in (block - first) as usize..(group_end - first) as usize {
+                    if bitmap[bit / 8] & (1 << (bit % 8)) != 0 {
+                        bitmap[bit / 8] &= !(1 << (bit % 8));
+                        freed += 1;
+                    }
+                }
+                let csum = self.bitmap_csum(&bitmap, self.blocks_per_group as usize / 8);
+                drop(bitmap);
+
+                let desc = &mut alloc.groups[group as usize];
+                desc.set_free_blocks(desc.free_blocks() + freed);
+                if let Some(csum) = csum {
+                    desc.set_block_bitmap_csum(csum);
+                }
+                self.write_desc(group, desc).await?;
+                add_sb_free_blocks(&mut alloc.sb, freed as i64);
+                block = group_end;
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocates an inode, preferably in the group of `parent`.
+    pub(super) async fn alloc_inode(&self, parent: u32, dir: bool) -> Result<u32, Errno> {
+        if self.readonly {
+            return Err(Errno::EROFS);
+        }
+        let mut alloc = self.alloc.lock().await;
+        let alloc = &mut *alloc;
+        let first_group = (parent - 1) / self.inodes_per_group;
+        for i in 0..self.group_count {
+            let group = (first_group + i) % self.group_count;
+            if alloc.groups[group as usize].free_inodes() == 0 {
+                continue;
+            }
+            let first_ino = group * self.inodes_per_group + 1;
+            let count = (self.inodes_count + 1 - first_ino).min(self.inodes_per_group);
+            let start = self.first_ino.saturating_sub(first_ino).min(count);
+            let buffer = self.inode_bitmap(alloc, group).await?;
+            let mut bitmap = buffer.write().await;
+            let Some(bit) = find_zero(&bitmap, start as usize, count as usize) else {
+                continue;
+            };
+            bitmap[bit / 8] |= 1 << (bit % 8);
+            let csum = self.bitmap_csum(&bitmap, self.inodes_per_group as usize / 8);
+            drop(bitmap);
+
+            let desc = &mut alloc.groups[group as usize];
+            desc.set_free_inodes(desc.free_inodes() - 1);
+            if dir {
+                desc.set_used_dirs(desc.used_dirs() + 1);
+            }
+            if self.has_group_csum() {
+                let unused = desc.itable_unused();
+                let bit = bit as u32;
+                if bit >= self.inodes_per_group.saturating_sub(unused) {
+                    desc.set_itable_unused(self.inodes_per_group - bit - 1);
+                }
+            }
+            if let Some(csum) = csum {
+                desc.set_inode_bitmap_csum(csum);
+            }
+            self.write_desc(group, desc).await?;
+            add_sb_free_inodes(&mut alloc.sb, -1);
+            return Ok(first_ino + bit as u32);
+        }
+        Err(Errno::ENOSPC)
+    }
+
+    pub(super) async fn free_inode(&self, ino: u32, dir: bool) -> Result<(), Errno> {
+        let mut alloc = self.alloc.lock().await;
+        let alloc = &mut *alloc;
+        let group = (ino - 1) / self.inodes_per_group;
+        let bit = ((ino - 1) % self.inodes_per_group) as usize;
+        let buffer = self.inode_bitmap(alloc, group).await?;
+        let mut bitmap = buffer.write().await;
+        if bitmap[bit / 8] & (1 << (bit % 8)) == 0 {
+            warn!("ext4: freeing free inode {}", ino);
+            return Ok(());
+        }
+        bitmap[bit / 8] &= !(1 << (bit % 8));
+        let csum = self.bitmap_csum(&bitmap, self.inodes_per_group as usize / 8);
+        drop(bitmap);
+
+        let desc = &mut alloc.groups[group as usize];
+        desc.set_free_inodes(desc.free_inodes() + 1);
+        if dir {
+            desc.set_used_dirs(desc.used_dirs().saturating_sub(1));
+        }
+        if let Some(csum) = csum {
+            desc.set_inode_bitmap_csum(csum);
+        }
+        self.write_desc(group, desc).await?;
+        add_sb_free_inodes(&mut alloc.sb, 1);
+        Ok(())
+    }
+}
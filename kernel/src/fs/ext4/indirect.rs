@@ -0,0 +1,199 @@
+//! Block maps of ext2 and ext3 files: twelve pointers to blocks of the
+//! file, then pointers to an indirect, a double indirect and a triple
+//! indirect block.
+
+use core::pin::Pin;
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use arrayvec::ArrayVec;
+
+use crate::Errno;
+
+use super::{Ext4FsInfo, set_u32, u32_at};
+
+const DIRECT_BLOCKS: u64 = 12;
+/// Slot in `i_block` of the indirect block.
+const INDIRECT: usize = 12;
+
+fn per_block(fs: &Ext4FsInfo) -> u64 {
+    fs.block_size as u64 / 4
+}
+
+/// Blocks a file can have.
+pub fn max_blocks(fs: &Ext4FsInfo) -> u64 {
+    let per_block = per_block(fs);
+    let max = DIRECT_BLOCKS + per_block + per_block.pow(2) + per_block.pow(3);
+    max.min(u32::MAX as u64)
+}
+
+/// The pointers leading to block `lblk`: its slot in `i_block`, then its
+/// index in each indirect block.
+fn path(fs: &Ext4FsInfo, lblk: u64) -> Option<ArrayVec<usize, 4>> {
+    let per_block = per_block(fs);
+    let mut path = ArrayVec::new();
+    if lblk < DIRECT_BLOCKS {
+        path.push(lblk as usize);
+        return Some(path);
+    }
+    let mut rest = lblk - DIRECT_BLOCKS;
+    let mut span = per_block;
+    for level in 1..=3 {
+        if rest < span {
+            path.push(INDIRECT + level - 1);
+            for i in (0..level).rev() {
+                path.push((rest / per_block.pow(i as u32) % per_block) as usize);
+            }
+            return Some(path);
+        }
+        rest -= span;
+        span *= per_block;
+    }
+    None
+}
+
+/// The block of the device holding block `lblk` of the file whose map is
+/// `area`, `None` for a hole.
+pub async fn map(fs: &Ext4FsInfo, area: &[u8], lblk: u64) -> Result<Option<u64>, Errno> {
+    let Some(path) = path(fs, lblk) else {
+        return Ok(None);
+    };
+    let mut block = u32_at(area, path[0] * 4);
+    for &index in &path[1..] {
+        if block == 0 {
+            return Ok(None);
+        }
+        let mut raw = [0; 4];
+        fs.read_block(block as u64, index * 4, &mut raw).await?;
+        block = u32::from_le_bytes(raw);
+    }
+    Ok((block != 0).then_some(block as u64))
+}
+
+/// Maps block `lblk` of the file, allocating it and the indirect blocks
+/// leading to it near `goal`. Returns the block, whether it is new, and
+/// how many blocks were allocated.
+pub async fn map_alloc(
+    fs: &Ext4FsInfo,
+    area: &mut [u8],
+    lblk: u64,
+    goal: u64,
+) -> Result<(u64, bool, u64), Errno> {
+    let path = path(fs, lblk).ok_or(Errno::EFBIG)?;
+    let zeros = vec![0; fs.block_size];
+    let mut allocated = 0;
+    let mut new = false;
+    let mut block = u32_at(area, path[0] * 4) as u64;
+    if block == 0 {
+        block = fs.alloc_block(goal).await?;
+        allocated += 1;
+        new = true;
+        set_u32(area, path[0] * 4, block as u32);
+        if path.len() > 1 {
+            fs.write_block(block, 0, &zeros).await?;
+        }
+    }
+    for (level, &index) in path.iter().enumerate().skip(1) {
+        let parent = block;
+        let mut raw = [0; 4];
+        fs.read_block(parent, index * 4, &mut raw).await?;
+        block = u32::from_le_bytes(raw) as u64;
+        new = block == 0;
+        if new {
+            block = fs.alloc_block(parent + 1).await?;
+            allocated += 1;
+            fs.write_block(parent, index * 4, &(block as u32).to_le_bytes())
+                .await?;
+            if level + 1 < path.len() {
+                fs.write_block(block, 0, &zeros).await?;
+            }
+        }
+    }
+    Ok((block, new, allocated))
+}
+
+/// Unmaps the blocks of the file from `from` on, freeing indirect blocks
+/// left empty. Returns the runs of blocks freed.
+pub async fn truncate(
+    fs: &Ext4FsInfo,
+    area: &mut [u8],
+    from: u64,
+) -> Result<Vec<(u64, u64)>, Errno> {
+    let mut freed = Vec::new();
+    for slot in from.min(DIRECT_BLOCKS) as usize..DIRECT_BLOCKS as usize {
+        let block = u32_at(area, slot * 4);
+        if block != 0 {
+            freed.push(block as u64);
+            set_u32(area, slot * 4, 0);
+        }
+    }
+    let per_block = per_block(fs);
+    let mut first = DIRECT_BLOCKS;
+    let mut span = per_block;
+    for level in 1..=3 {
+        let slot = INDIRECT + level as usize - 1;
+        let block = u32_at(area, slot * 4) as u64;
+        if block != 0
+            && first + span > from
+            && truncate_node(fs, block, level, first, from, &mut freed).await?
+        {
+            freed.push(block);
+            set_u32(area, slot * 4, 0);
+        }
+        first += span;
+        span *= per_block;
+    }
+    Ok(runs(freed))
+}
+
+/// Unmaps the blocks from `from` on below indirect block `block` of
+/// `level`, which maps the blocks from `first` on. Returns whether the
+/// block is left empty.
+fn truncate_node<'a>(
+    fs: &'a Ext4FsInfo,
+    block: u64,
+    level: u32,
+    first: u64,
+    from: u64,
+    freed: &'a mut Vec<u64>,
+) -> Pin<Box<dyn Future<Output = Result<bool, Errno>> + Send + 'a>> {
+    Box::pin(async move {
+        let mut data = vec![0; fs.block_size];
+        fs.read_block(block, 0, &mut data).await?;
+        let span = per_block(fs).pow(level - 1);
+        let mut changed = false;
+        for i in 0..data.len() / 4 {
+            let child = u32_at(&data, i * 4) as u64;
+            let child_first = first + i as u64 * span;
+            if child == 0 || child_first + span <= from {
+                continue;
+            }
+            let empty = match level {
+                1 => true,
+                _ => truncate_node(fs, child, level - 1, child_first, from, freed).await?,
+            };
+            if empty {
+                freed.push(child);
+                set_u32(&mut data, i * 4, 0);
+                changed = true;
+            }
+        }
+        let empty = data.iter().all(|&byte| byte == 0);
+        if changed && !empty {
+            fs.write_block(block, 0, &data).await?;
+        }
+        Ok(empty)
+    })
+}
+
+/// Coalesces `blocks` into runs of contiguous blocks.
+fn runs(mut blocks: Vec<u64>) -> Vec<(u64, u64)> {
+    blocks.sort_unstable();
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    for block in blocks {
+        match runs.last_mut() {
+            Some((start, len)) if *start + *len == block => *len += 1,
+            _ => runs.push((block, 1)),
+        }
+    }
+    runs
+}
@@ -0,0 +1,1169 @@
+use core::{any::Any, ptr};
+
+use alloc::{
+    boxed::Box,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use log::{info, warn};
+
+use crate::{
+    Errno,
+    block::BUFFER_CACHE,
+    fs::{DirEntry, FsFuture, Inode, InodeType, Metadata, RenameFlags, makedev},
+    runtime::{TimeSpec, spawn},
+    sync::Mutex,
+};
+
+use super::{
+    Ext4FsInfo, GOOD_OLD_INODE_SIZE, INCOMPAT_EXTENTS, RO_COMPAT_DIR_NLINK, RO_COMPAT_HUGE_FILE,
+    dir::{NAME_MAX, new_dir_block},
+    extent::ExtentTree,
+    indirect, set_u16, set_u32, u16_at, u32_at,
+};
+
+/// The directory has a hash index.
+pub(super) const INDEX_FL: u32 = 0x1000;
+/// `i_blocks` counts blocks of the filesystem rather than sectors.
+const HUGE_FILE_FL: u32 = 0x40000;
+/// The blocks are mapped by an extent tree.
+const EXTENTS_FL: u32 = 0x80000;
+
+/// Most links of an inode. Directories with more subdirectories have a
+/// count of 1 with `dir_nlink`.
+const MAX_LINKS: u16 = 65000;
+/// Size of `i_block`, holding the block map or a short symlink target.
+const I_BLOCK_SIZE: usize = 60;
+
+// fields of the large part of inodes, whose presence depends on
+// `i_extra_isize`
+const EXTRA_ISIZE: usize = 0x80;
+const CHECKSUM_HI: usize = 0x82;
+const CTIME_EXTRA: usize = 0x84;
+const MTIME_EXTRA: usize = 0x88;
+const ATIME_EXTRA: usize = 0x8c;
+const CRTIME: usize = 0x90;
+const CRTIME_EXTRA: usize = 0x94;
+
+/// An inode as stored in the inode table.
+#[derive(Default)]
+pub(super) struct DiskInode(Vec<u8>);
+
+impl From<Vec<u8>> for DiskInode {
+    fn from(raw: Vec<u8>) -> Self {
+        Self(raw)
+    }
+}
+
+impl DiskInode {
+    fn new(size: usize, extra_isize: u16) -> Self {
+        let mut inode = Self(vec![0; size]);
+        if size > GOOD_OLD_INODE_SIZE {
+            set_u16(&mut inode.0, EXTRA_ISIZE, extra_isize);
+        }
+        inode
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Whether the field at `offset` of the large part is in use.
+    fn has_field(&self, offset: usize) -> bool {
+        self.0.len() > GOOD_OLD_INODE_SIZE
+            && GOOD_OLD_INODE_SIZE + u16_at(&self.0, EXTRA_ISIZE) as usize >= offset + 4
+            && offset + 4 <= self.0.len()
+    }
+
+    fn mode(&self) -> u32 {
+        u16_at(&self.0, 0x00) as u32
+    }
+
+    fn set_mode(&mut self, mode: u32) {
+        set_u16(&mut self.0, 0x00, mode as u16);
+    }
+
+    fn uid(&self) -> u32 {
+        u16_at(&self.0, 0x02) as u32 | (u16_at(&self.0, 0x78) as u32) << 16
+    }
+
+    fn gid(&self) -> u32 {
+        u16_at(&self.0, 0x18) as u32 | (u16_at(&self.0, 0x7a) as u32) << 16
+    }
+
+    pub fn size(&self) -> u64 {
+        u32_at(&self.0, 0x04) as u64 | (u32_at(&self.0, 0x6c) as u64) << 32
+    }
+
+    pub fn set_size(&mut self, size: u64) {
+        set_u32(&mut self.0, 0x04, size as u32);
+        set_u32(&mut self.0, 0x6c, (size >> 32) as u32);
+    }
+
+    fn links(&self) -> u16 {
+        u16_at(&self.0, 0x1a)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        set_u16(&mut self.0, 0x1a, links);
+    }
+
+    /// Allocated space in 512-byte units.
+    fn sectors(&self, fs: &Ext4FsInfo) -> u64 {
+        let mut blocks = u32_at(&self.0, 0x1c) as u64;
+        if fs.has_ro_compat(RO_COMPAT_HUGE_FILE) {
+            blocks |= (u16_at(&self.0, 0x74) as u64) << 32;
+            if self.flags() & HUGE_FILE_FL != 0 {
+                blocks *= fs.block_size as u64 / 512;
+            }
+        }
+        blocks
+    }
+
+    fn set_sectors(&mut self, sectors: u64) {
+        set_u32(&mut self.0, 0x1c, sectors as u32);
+        set_u16(&mut self.0, 0x74, (sectors >> 32) as u16);
+        self.set_flags(self.flags() & !HUGE_FILE_FL);
+    }
+
+    pub fn flags(&self) -> u32 {
+        u32_at(&self.0, 0x20)
+    }
+
+    pub fn set_flags(&mut self, flags: u32) {
+        set_u32(&mut self.0, 0x20, flags);
+    }
+
+    fn i_block(&self) -> &[u8] {
+        &self.0[0x28..0x28 + I_BLOCK_SIZE]
+    }
+
+    fn i_block_mut(&mut self) -> &mut [u8] {
+        &mut self.0[0x28..0x28 + I_BLOCK_SIZE]
+    }
+
+    pub fn generation(&self) -> u32 {
+        u32_at(&self.0, 0x64)
+    }
+
+    fn file_acl(&self) -> u64 {
+        u32_at(&self.0, 0x68) as u64 | (u16_at(&self.0, 0x76) as u64) << 32
+    }
+
+    /// A timestamp of seconds at `offset`, with nanoseconds and more bits
+    /// of seconds at `extra` if the inode has room for them.
+    fn time(&self, offset: usize, extra: usize) -> TimeSpec {
+        let mut time = TimeSpec {
+            tv_sec: u32_at(&self.0, offset) as i32 as i64,
+            tv_nsec: 0,
+        };
+        if self.has_field(extra) {
+            let extra = u32_at(&self.0, extra);
+            time.tv_sec += ((extra & 3) as i64) << 32;
+            time.tv_nsec = (extra >> 2) as i64;
+        }
+        time
+    }
+
+    fn set_time(&mut self, offset: usize, extra: usize, time: TimeSpec) {
+        set_u32(&mut self.0, offset, time.tv_sec as u32);
+        if self.has_field(extra) {
+            let epoch = ((time.tv_sec - time.tv_sec as i32 as i64) >> 32) as u32 & 3;
+            set_u32(&mut self.0, extra, (time.tv_nsec as u32) << 2 | epoch);
+        }
+    }
+
+    fn atime(&self) -> TimeSpec {
+        self.time(0x08, ATIME_EXTRA)
+    }
+
+    fn set_atime(&mut self, time: TimeSpec) {
+        self.set_time(0x08, ATIME_EXTRA, time);
+    }
+
+    fn ctime(&self) -> TimeSpec {
+        self.time(0x0c, CTIME_EXTRA)
+    }
+
+    fn set_ctime(&mut self, time: TimeSpec) {
+        self.set_time(0x0c, CTIME_EXTRA, time);
+    }
+
+    fn mtime(&self) -> TimeSpec {
+        self.time(0x10, MTIME_EXTRA)
+    }
+
+    fn set_mtime(&mut self, time: TimeSpec) {
+        self.set_time(0x10, MTIME_EXTRA, time);
+    }
+
+    /// Device number of device inodes, in the old encoding if it fits in
+    /// `i_block[0]`, else in the new one in `i_block[1]`.
+    fn rdev(&self) -> u64 {
+        let (old, new) = (u32_at(self.i_block(), 0), u32_at(self.i_block(), 4));
+        match old {
+            0 => makedev((new & 0xfff00) >> 8, (new & 0xff) | ((new >> 12) & 0xfff00)),
+            _ => makedev((old >> 8) & 0xff, old & 0xff),
+        }
+    }
+
+    fn set_rdev(&mut self, rdev: u64) {
+        let major = ((rdev >> 8) & 0xfff | (rdev >> 32) & !0xfff) as u32;
+        let minor = (rdev & 0xff | (rdev >> 12) & !0xff) as u32;
+        let area = self.i_block_mut();
+        area.fill(0);
+        match major < 256 && minor < 256 {
+            true => set_u32(area, 0, major << 8 | minor),
+            false => set_u32(area, 4, (minor & 0xff) | major << 8 | (minor & !0xff) << 12),
+        }
+    }
+
+    /// Sets the checksum of the inode, of all its bytes with the checksum
+    /// fields zeroed.
+    pub fn update_checksum(&mut self, seed: u32) {
+        // the high half follows `i_extra_isize` in its first 4 bytes
+        let has_hi = self.has_field(EXTRA_ISIZE);
+        set_u16(&mut self.0, 0x7c, 0);
+        if has_hi {
+            set_u16(&mut self.0, CHECKSUM_HI, 0);
+        }
+        let csum = super::crc::crc32c(seed, &self.0);
+        set_u16(&mut self.0, 0x7c, csum as u16);
+        if has_hi {
+            set_u16(&mut self.0, CHECKSUM_HI, (csum >> 16) as u16);
+        }
+    }
+}
+
+/// An entry of a directory listing, whose type is not known on filesystems
+/// without the `filetype` feature until the inode is read.
+pub(super) struct ListEntry {
+    pub ino: u32,
+    pub name: String,
+    pub ty: Option<InodeType>,
+}
+
+#[derive(Default)]
+pub(super) struct InodeState {
+    pub(super) ino: u32,
+    pub(super) disk: DiskInode,
+    /// Extents of the file, loaded on first use.
+    extents: Option<ExtentTree>,
+    /// Entries of a directory, read on first use and then kept up to date.
+    pub(super) listing: Option<Vec<ListEntry>>,
+}
+
+impl InodeState {
+    pub(super) fn new(ino: u32, disk: DiskInode) -> Self {
+        Self {
+            ino,
+            disk,
+            extents: None,
+            listing: None,
+        }
+    }
+
+    fn has_extents(&self) -> bool {
+        self.disk.flags() & EXTENTS_FL != 0
+    }
+
+    /// Seed of the checksums of the metadata of the inode.
+    pub(super) fn seed(&self, fs: &Ext4FsInfo) -> Option<u32> {
+        fs.inode_seed(self.ino, self.disk.generation())
+    }
+
+    async fn extents(&mut self, fs: &Ext4FsInfo) -> Result<&mut ExtentTree, Errno> {
+        if self.extents.is_none() {
+            self.extents = Some(ExtentTree::load(fs, self.disk.i_block()).await?);
+        }
+        Ok(self.extents.as_mut().unwrap())
+    }
+
+    /// Blocks the file can have.
+    fn max_blocks(&self, fs: &Ext4FsInfo) -> u64 {
+        match self.has_extents() {
+            true => u32::MAX as u64,
+            false => indirect::max_blocks(fs),
+        }
+    }
+
+    /// The block of the device holding block `lblk` of the file, `None`
+    /// for holes and unwritten blocks, which read as zeros.
+    pub(super) async fn map(&mut self, fs: &Ext4FsInfo, lblk: u64) -> Result<Option<u64>, Errno> {
+        if lblk >= self.max_blocks(fs) {
+            return Ok(None);
+        }
+        match self.has_extents() {
+            true => Ok(match self.extents(fs).await?.map(lblk) {
+                Some((block, false)) => Some(block),
+                _ => None,
+            }),
+            false => indirect::map(fs, self.disk.i_block(), lblk).await,
+        }
+    }
+
+    /// Maps block `lblk` of the file, allocating it if needed. Returns the
+    /// block and whether it is new, in which case it holds garbage.
+    pub(super) async fn map_alloc(
+        &mut self,
+        fs: &Ext4FsInfo,
+        lblk: u64,
+    ) -> Result<(u64, bool), Errno> {
+        if lblk >= self.max_blocks(fs) {
+            return Err(Errno::EFBIG);
+        }
+        let group = (self.ino - 1) / fs.inodes_per_group;
+        let default_goal = fs.group_first_block(group);
+        if !self.has_extents() {
+            let (block, new, allocated) =
+                indirect::map_alloc(fs, self.disk.i_block_mut(), lblk, default_goal).await?;
+            self.add_blocks(fs, allocated as i64);
+            return Ok((block, new));
+        }
+        let tree = self.extents(fs).await?;
+        match tree.map(lblk) {
+            Some((block, false)) => return Ok((block, false)),
+            // written blocks of unwritten extents are zeroed by the caller
+            Some((block, true)) => {
+                tree.set(lblk, Some((block, false)));
+                return Ok((block, true));
+            }
+            None => {}
+        }
+        let goal = tree.goal(lblk).unwrap_or(default_goal);
+        let block = fs.alloc_block(goal).await?;
+        self.extents(fs).await?.set(lblk, Some((block, false)));
+        self.add_blocks(fs, 1);
+        Ok((block, true))
+    }
+
+    fn add_blocks(&mut self, fs: &Ext4FsInfo, blocks: i64) {
+        let sectors = blocks * (fs.block_size / 512) as i64;
+        let total = self.disk.sectors(fs).saturating_add_signed(sectors);
+        self.disk.set_sectors(total);
+    }
+
+    /// Frees the blocks of the file from `lblk` on.
+    async fn free_from(&mut self, fs: &Ext4FsInfo, lblk: u64) -> Result<(), Errno> {
+        let freed = match self.has_extents() {
+            true => self.extents(fs).await?.truncate(lblk),
+            false => indirect::truncate(fs, self.disk.i_block_mut(), lblk).await?,
+        };
+        let count: u64 = freed.iter().map(|&(_, len)| len).sum();
+        self.add_blocks(fs, -(count as i64));
+        fs.free_blocks(&freed).await
+    }
+
+    /// Writes the inode back, with its extents if they changed.
+    pub(super) async fn save(&mut self, fs: &Ext4FsInfo) -> Result<(), Errno> {
+        let seed = self.seed(fs);
+        let goal = fs.group_first_block((self.ino - 1) / fs.inodes_per_group);
+        if let Some(tree) = self.extents.as_mut().filter(|tree| tree.is_dirty()) {
+            let nodes = tree.store(fs, seed, self.disk.i_block_mut(), goal).await?;
+            self.add_blocks(fs, nodes);
+        }
+        fs.note_large_file(self.disk.size()).await;
+        fs.write_inode(self.ino, &mut self.disk).await
+    }
+
+    /// Updates the modification and change times.
+    fn touch(&mut self) {
+        let now = TimeSpec::now();
+        self.disk.set_mtime(now);
+        self.disk.set_ctime(now);
+    }
+
+    async fn read(&mut self, fs: &Ext4FsInfo, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let size = self.disk.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let block_size = fs.block_size as u64;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let in_block = (pos % block_size) as usize;
+            let chunk = (fs.block_size - in_block).min(len - done);
+            let buf = &mut buf[done..done + chunk];
+            match self.map(fs, pos / block_size).await? {
+                Some(block) => fs.read_block(block, in_block, buf).await?,
+                None => buf.fill(0),
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    /// Writes `buf` at `offset`, allocating blocks. Returns how much was
+    /// written, which is less if the filesystem is full.
+    async fn write(&mut self, fs: &Ext4FsInfo, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        let block_size = fs.block_size as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_block = (pos % block_size) as usize;
+            let chunk = (fs.block_size - in_block).min(buf.len() - done);
+            let (block, new) = match self.map_alloc(fs, pos / block_size).await {
+                Ok(mapped) => mapped,
+                Err(Errno::ENOSPC | Errno::EFBIG) if done > 0 => break,
+                Err(err) => return Err(err),
+            };
+            match new && chunk < fs.block_size {
+                true => {
+                    let mut data = vec![0; fs.block_size];
+                    data[in_block..in_block + chunk].copy_from_slice(&buf[done..done + chunk]);
+                    fs.write_block(block, 0, &data).await?;
+                }
+                false => {
+                    fs.write_block(block, in_block, &buf[done..done + chunk])
+                        .await?
+                }
+            }
+            done += chunk;
+        }
+        let end = offset + done as u64;
+        if end > self.disk.size() {
+            self.disk.set_size(end);
+        }
+        Ok(done)
+    }
+
+    /// Sets the size of the file, freeing blocks past it.
+    async fn resize(&mut self, fs: &Ext4FsInfo, size: u64) -> Result<(), Errno> {
+        let block_size = fs.block_size as u64;
+        if size.div_ceil(block_size) > self.max_blocks(fs) {
+            return Err(Errno::EFBIG);
+        }
+        if size < self.disk.size() {
+            // what is past the end of the last block must read as zeros if
+            // the file grows again
+            let in_block = (size % block_size) as usize;
+            if in_block != 0 {
+                if let Some(block) = self.map(fs, size / block_size).await? {
+                    let zeros = vec![0; fs.block_size - in_block];
+                    fs.write_block(block, in_block, &zeros).await?;
+                }
+            }
+            self.free_from(fs, size.div_ceil(block_size)).await?;
+        }
+        self.disk.set_size(size);
+        Ok(())
+    }
+
+    fn is_fast_symlink(&self, fs: &Ext4FsInfo) -> bool {
+        let acl_sectors = match self.disk.file_acl() {
+            0 => 0,
+            _ => fs.block_size as u64 / 512,
+        };
+        self.disk.sectors(fs) == acl_sectors && self.disk.size() < I_BLOCK_SIZE as u64
+    }
+}
+
+/// A file, directory or other inode of an ext2/3/4 filesystem.
+pub struct Ext4Inode {
+    ino: u32,
+    ty: InodeType,
+    fs: Arc<Ext4FsInfo>,
+    state: Mutex<InodeState>,
+}
+
+impl Ext4Inode {
+    fn insert(fs: &Arc<Ext4FsInfo>, ty: InodeType, state: InodeState) -> Arc<Self> {
+        let ino = state.ino;
+        let inode = Arc::new(Self {
+            ino,
+            ty,
+            fs: fs.clone(),
+            state: Mutex::new(state),
+        });
+        fs.inodes.lock().insert(ino, Arc::downgrade(&inode));
+        inode
+    }
+
+    /// Inode `ino`, from memory or read from the inode table.
+    pub(super) async fn load(fs: &Arc<Ext4FsInfo>, ino: u32) -> Result<Arc<Self>, Errno> {
+        let cached = fs.inodes.lock().get(&ino).and_then(Weak::upgrade);
+        if let Some(inode) = cached {
+            return Ok(inode);
+        }
+        let disk = fs.read_inode(ino).await?;
+        let ty = InodeType::from_mode(disk.mode()).filter(|_| disk.links() != 0);
+        let Some(ty) = ty else {
+            warn!("ext4: entry refers to unused inode {}", ino);
+            return Err(Errno::EIO);
+        };
+        // another task may have read it meanwhile
+        let cached = fs.inodes.lock().get(&ino).and_then(Weak::upgrade);
+        if let Some(inode) = cached {
+            return Ok(inode);
+        }
+        Ok(Self::insert(fs, ty, InodeState::new(ino, disk)))
+    }
+
+    /// Allocates an inode of type `ty` near directory `parent`. Files,
+    /// directories and long symlinks get an extent tree if the filesystem
+    /// has extents.
+    async fn alloc(
+        fs: &Arc<Ext4FsInfo>,
+        parent: u32,
+        ty: InodeType,
+        mode: u32,
+        extents: bool,
+    ) -> Result<Arc<Self>, Errno> {
+        let ino = fs.alloc_inode(parent, ty == InodeType::Dir).await?;
+        let mut disk = DiskInode::new(fs.inode_size, fs.extra_isize);
+        disk.set_mode(ty.mode_bits() | mode & 0o7777);
+        disk.set_links(1);
+        let now = TimeSpec::now();
+        disk.set_atime(now);
+        disk.set_mtime(now);
+        disk.set_ctime(now);
+        if disk.has_field(CRTIME_EXTRA) {
+            disk.set_time(CRTIME, CRTIME_EXTRA, now);
+        }
+        set_u32(&mut disk.0, 0x64, (now.tv_nsec as u32).wrapping_add(ino));
+        let mut state = InodeState::new(ino, disk);
+        if extents && fs.has_incompat(INCOMPAT_EXTENTS) {
+            state.disk.set_flags(EXTENTS_FL);
+            ExtentTree::init_root(state.disk.i_block_mut());
+            state.extents = Some(ExtentTree::new());
+        }
+        if let Err(err) = state.save(fs).await {
+            fs.free_inode(ino, ty == InodeType::Dir).await?;
+            return Err(err);
+        }
+        Ok(Self::insert(fs, ty, state))
+    }
+
+    fn check_writable(&self) -> Result<(), Errno> {
+        match self.fs.readonly {
+            true => Err(Errno::EROFS),
+            false => Ok(()),
+        }
+    }
+
+    fn check_dir(&self) -> Result<(), Errno> {
+        match self.ty {
+            InodeType::Dir => Ok(()),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn check_file(&self) -> Result<(), Errno> {
+        match self.ty {
+            InodeType::Dir => Err(Errno::EISDIR),
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds the new inode `inode` to the directory as `name`, freeing the
+    /// inode if that fails.
+    async fn add_new(
+        &self,
+        state: &mut InodeState,
+        name: &str,
+        inode: &Ext4Inode,
+        child: &mut InodeState,
+    ) -> Result<(), Errno> {
+        let fs = &self.fs;
+        let mut result = match inode.ty {
+            InodeType::Dir => self.init_dir(child).await,
+            _ => Ok(()),
+        };
+        if result.is_ok() {
+            result = state.add_entry(fs, name, inode.ino, inode.ty).await;
+        }
+        if let Err(err) = result {
+            // the inode is freed when dropped
+            child.disk.set_links(0);
+            return Err(err);
+        }
+        if inode.ty == InodeType::Dir {
+            add_link(fs, state, 1);
+        }
+        state.touch();
+        state.save(fs).await
+    }
+
+    /// Writes the first block of new directory `child` of this one.
+    async fn init_dir(&self, child: &mut InodeState) -> Result<(), Errno> {
+        let fs = &self.fs;
+        let (block, _) = child.map_alloc(fs, 0).await?;
+        let mut data = new_dir_block(
+            fs.block_size,
+            fs.csum_seed.is_some(),
+            child.ino,
+            self.ino,
+            super::dir::file_type(InodeType::Dir),
+        );
+        if !fs.has_incompat(super::INCOMPAT_FILETYPE) {
+            data[7] = 0;
+            data[12 + 7] = 0;
+        }
+        child.disk.set_size(fs.block_size as u64);
+        child.disk.set_links(2);
+        child.save(fs).await?;
+        child.write_dir_block(fs, block, &mut data).await
+    }
+}
+
+/// Changes the link count of directory `state` as a subdirectory is added
+/// or removed. Past the most links, `dir_nlink` filesystems count 1.
+fn add_link(fs: &Ext4FsInfo, state: &mut InodeState, delta: i32) {
+    let links = state.disk.links();
+    let links = match (links, delta > 0) {
+        (1, _) if fs.has_ro_compat(RO_COMPAT_DIR_NLINK) => 1,
+        (MAX_LINKS, true) => 1,
+        _ => links.saturating_add_signed(delta as i16),
+    };
+    state.disk.set_links(links);
+}
+
+/// Frees the blocks and the inode of a file with no links left.
+async fn free_unlinked(fs: &Ext4FsInfo, mut state: InodeState, dir: bool) -> Result<(), Errno> {
+    // device inodes and short symlinks keep no block map in `i_block`
+    let ty = InodeType::from_mode(state.disk.mode());
+    let has_map = match ty {
+        Some(InodeType::File | InodeType::Dir) => true,
+        Some(InodeType::Symlink) => !state.is_fast_symlink(fs),
+        _ => false,
+    };
+    if has_map {
+        state.free_from(fs, 0).await?;
+    }
+    state.disk.set_size(0);
+    set_u32(&mut state.disk.0, 0x14, TimeSpec::now().tv_sec as u32);
+    state.save(fs).await?;
+    fs.free_inode(state.ino, dir).await
+}
+
+/// Releases the inodes on the orphan list starting at `ino`, which a crash
+/// left behind: inodes removed while open are freed, and files shrunk while
+/// open lose the blocks past their size.
+pub(super) async fn release_orphans(fs: &Ext4FsInfo, mut ino: u32) -> Result<(), Errno> {
+    // the list is linked through `i_dtime`, and may loop if damaged
+    let mut left = fs.inodes_count;
+    while ino != 0 && left > 0 {
+        left -= 1;
+        let disk = fs.read_inode(ino).await?;
+        let next = u32_at(&disk.0, 0x14);
+        let mut state = InodeState::new(ino, disk);
+        let ty = InodeType::from_mode(state.disk.mode());
+        if state.disk.links() == 0 {
+            info!("ext4: freeing orphan inode {}", ino);
+            free_unlinked(fs, state, ty == Some(InodeType::Dir)).await?;
+        } else {
+            info!("ext4: truncating orphan inode {}", ino);
+            if ty == Some(InodeType::File) {
+                let end = state.disk.size().div_ceil(fs.block_size as u64);
+                state.free_from(fs, end).await?;
+            }
+            set_u32(&mut state.disk.0, 0x14, 0);
+            state.save(fs).await?;
+        }
+        ino = next;
+    }
+    Ok(())
+}
+
+fn is_device(state: &InodeState) -> bool {
+    matches!(
+        InodeType::from_mode(state.disk.mode()),
+        Some(InodeType::CharDevice | InodeType::BlockDevice)
+    )
+}
+
+impl Drop for Ext4Inode {
+    fn drop(&mut self) {
+        {
+            let mut inodes = self.fs.inodes.lock();
+            if inodes
+                .get(&self.ino)
+                .is_some_and(|inode| inode.strong_count() == 0)
+            {
+                inodes.remove(&self.ino);
+            }
+        }
+        let state = self.state.get_mut();
+        if state.disk.links() != 0 || self.fs.readonly {
+            return;
+        }
+        // freeing takes the allocation lock, which cannot be waited for here
+        let fs = self.fs.clone();
+        let state = core::mem::take(state);
+        let dir = self.ty == InodeType::Dir;
+        spawn(async move {
+            if let Err(err) = free_unlinked(&fs, state, dir).await {
+                warn!("ext4: cannot free a removed inode: {:?}", err);
+            }
+        })
+        .detach();
+    }
+}
+
+impl Inode for Ext4Inode {
+    fn metadata(&self) -> FsFuture<'_, Metadata> {
+        Box::pin(async move {
+            let fs = &self.fs;
+            let state = self.state.lock().await;
+            let disk = &state.disk;
+            Ok(Metadata {
+                dev: fs.dev,
+                ino: self.ino as u64,
+                mode: disk.mode(),
+                nlink: disk.links() as u32,
+                uid: disk.uid(),
+                gid: disk.gid(),
+                rdev: match is_device(&state) {
+                    true => disk.rdev(),
+                    false => 0,
+                },
+                size: disk.size(),
+                blksize: fs.block_size as u32,
+                blocks: disk.sectors(fs),
+                atime: disk.atime(),
+                mtime: disk.mtime(),
+                ctime: disk.ctime(),
+            })
+        })
+    }
+
+    fn inode_type(&self) -> InodeType {
+        self.ty
+    }
+
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            self.check_file()?;
+            let mut state = self.state.lock().await;
+            state.read(&self.fs, offset, buf).await
+        })
+    }
+
+    fn write_at<'a>(&'a self, offset: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            self.check_file()?;
+            self.check_writable()?;
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            let fs = &self.fs;
+            let mut state = self.state.lock().await;
+            let result = state.write(fs, offset, buf).await;
+            // blocks written before a failure are kept
+            state.touch();
+            state.save(fs).await?;
+            result
+        })
+    }
+
+    fn truncate(&self, size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            self.check_file()?;
+            self.check_writable()?;
+            let fs = &self.fs;
+            let mut state = self.state.lock().await;
+            let result = state.resize(fs, size).await;
+            state.touch();
+            state.save(fs).await?;
+            result
+        })
+    }
+
+    fn set_mode(&self, mode: u32) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            self.check_writable()?;
+            let mut state = self.state.lock().await;
+            state.disk.set_mode(self.ty.mode_bits() | mode & 0o7777);
+            state.disk.set_ctime(TimeSpec::now());
+            state.save(&self.fs).await
+        })
+    }
+
+    fn set_times(&self, atime: Option<TimeSpec>, mtime: Option<TimeSpec>) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            self.check_writable()?;
+            let mut state = self.state.lock().await;
+            if let Some(atime) = atime {
+                state.disk.set_atime(atime);
+            }
+            if let Some(mtime) = mtime {
+                state.disk.set_mtime(mtime);
+            }
+            state.disk.set_ctime(TimeSpec::now());
+            state.save(&self.fs).await
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            self.check_dir()?;
+            let fs = &self.fs;
+            let mut state = self.state.lock().await;
+            let found = state.find_entry(fs, name).await?.ok_or(Errno::ENOENT)?;
+            let inode: Arc<dyn Inode> = Ext4Inode::load(fs, found.ino).await?;
+            Ok(inode)
+        })
+    }
+
+    fn create<'a>(
+        &'a self,
+        name: &'a str,
+        ty: InodeType,
+        mode: u32,
+        rdev: u64,
+    ) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            self.check_dir()?;
+            self.check_writable()?;
+            check_name(name)?;
+            let fs = &self.fs;
+            let mut state = self.state.lock().await;
+            if state.disk.links() == 0 {
+                return Err(Errno::ENOENT);
+            }
+            if state.find_entry(fs, name).await?.is_some() {
+                return Err(Errno::EEXIST);
+            }
+            if ty == InodeType::Dir
+                && state.disk.links() >= MAX_LINKS
+                && !fs.has_ro_compat(RO_COMPAT_DIR_NLINK)
+            {
+                return Err(Errno::EMLINK);
+            }
+            let extents = matches!(ty, InodeType::File | InodeType::Dir);
+            let inode = Ext4Inode::alloc(fs, self.ino, ty, mode, extents).await?;
+            let mut child = inode.state.lock().await;
+            if matches!(ty, InodeType::CharDevice | InodeType::BlockDevice) {
+                child.disk.set_rdev(rdev);
+                child.save(fs).await?;
+            }
+            self.add_new(&mut state, name, &inode, &mut child).await?;
+            drop(child);
+            let inode: Arc<dyn Inode> = inode;
+            Ok(inode)
+        })
+    }
+
+    /// Targets shorter than 60 bytes are stored in the inode.
+    fn symlink<'a>(&'a self, name: &'a str, target: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            self.check_dir()?;
+            self.check_writable()?;
+            check_name(name)?;
+            if target.is_empty() {
+                return Err(Errno::ENOENT);
+            }
+            let fs = &self.fs;
+            if target.len() >= fs.block_size {
+                return Err(Errno::ENAMETOOLONG);
+            }
+            let mut state = self.state.lock().await;
+            if state.disk.links() == 0 {
+                return Err(Errno::ENOENT);
+            }
+            if state.find_entry(fs, name).await?.is_some() {
+                return Err(Errno::EEXIST);
+            }
+            let fast = target.len() < I_BLOCK_SIZE;
+            let inode = Ext4Inode::alloc(fs, self.ino, InodeType::Symlink, 0o777, !fast).await?;
+            let mut child = inode.state.lock().await;
+            let stored = match fast {
+                true => {
+                    child.disk.i_block_mut()[..target.len()].copy_from_slice(target.as_bytes());
+                    child.disk.set_size(target.len() as u64);
+                    Ok(())
+                }
+                false => match child.write(fs, 0, target.as_bytes()).await {
+                    Ok(len) if len < target.len() => Err(Errno::ENOSPC),
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(err),
+                },
+            };
+            let saved = child.save(fs).await;
+            if let Err(err) = stored.and(saved) {
+                child.disk.set_links(0);
+                return Err(err);
+            }
+            self.add_new(&mut state, name, &inode, &mut child).await?;
+            drop(child);
+            let inode: Arc<dyn Inode> = inode;
+            Ok(inode)
+        })
+    }
+
+    fn link<'a>(&'a self, name: &'a str, target: &'a Arc<dyn Inode>) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            self.check_dir()?;
+            let target = target
+                .as_any()
+                .downcast_ref::<Ext4Inode>()
+                .filter(|inode| Arc::ptr_eq(&inode.fs, &self.fs))
+                .ok_or(Errno::EXDEV)?;
+            if target.ty == InodeType::Dir {
+                return Err(Errno::EPERM);
+            }
+            self.check_writable()?;
+            check_name(name)?;
+            let fs = &self.fs;
+            let mut state = self.state.lock().await;
+            if state.disk.links() == 0 {
+                return Err(Errno::ENOENT);
+            }
+            if state.find_entry(fs, name).await?.is_some() {
+                return Err(Errno::EEXIST);
+            }
+            let mut child = target.state.lock().await;
+            match child.disk.links() {
+                0 => return Err(Errno::ENOENT),
+                MAX_LINKS => return Err(Errno::EMLINK),
+                _ => {}
+            }
+            state.add_entry(fs, name, target.ino, target.ty).await?;
+            let links = child.disk.links() + 1;
+            child.disk.set_links(links);
+            child.disk.set_ctime(TimeSpec::now());
+            child.save(fs).await?;
+            state.touch();
+            state.save(fs).await
+        })
+    }
+
+    /// The blocks of a removed file are freed once it is no longer open.
+    fn unlink<'a>(&'a self, name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            self.check_dir()?;
+            self.check_writable()?;
+            let fs = &self.fs;
+            // checking that a directory is empty locks a second directory
+            let _rename = fs.rename_lock.lock().await;
+            let mut state = self.state.lock().await;
+            let found = state.find_entry(fs, name).await?.ok_or(Errno::ENOENT)?;
+            let inode = Ext4Inode::load(fs, found.ino).await?;
+            let mut child = inode.state.lock().await;
+            if inode.ty == InodeType::Dir && !child.is_empty_dir(fs).await? {
+                return Err(Errno::ENOTEMPTY);
+            }
+            state.remove_entry(fs, &found).await?;
+            unlinked(fs, &inode, &mut child, &mut state).await?;
+            drop(child);
+            state.touch();
+            state.save(fs).await
+        })
+    }
+
+    fn rename<'a>(
+        &'a self,
+        old: &'a str,
+        new_dir: &'a Arc<dyn Inode>,
+        new: &'a str,
+        flags: RenameFlags,
+    ) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            self.check_dir()?;
+            if flags.intersects(RenameFlags::EXCHANGE | RenameFlags::WHITEOUT) {
+                return Err(Errno::EINVAL);
+            }
+            let new_dir = new_dir
+                .as_any()
+                .downcast_ref::<Ext4Inode>()
+                .filter(|dir| Arc::ptr_eq(&dir.fs, &self.fs))
+                .ok_or(Errno::EXDEV)?;
+            new_dir.check_dir()?;
+            self.check_writable()?;
+            check_name(new)?;
+            let fs = &self.fs;
+            let _rename = fs.rename_lock.lock().await;
+            let mut src = self.state.lock().await;
+            let mut dst = match ptr::eq(self, new_dir) {
+                true => None,
+                false => Some(new_dir.state.lock().await),
+            };
+            rename_entry(
+                fs,
+                (self, &mut src),
+                (new_dir, dst.as_deref_mut()),
+                old,
+                new,
+                flags,
+            )
+            .await
+        })
+    }
+
+    fn read_link(&self) -> FsFuture<'_, String> {
+        Box::pin(async move {
+            if self.ty != InodeType::Symlink {
+                return Err(Errno::EINVAL);
+            }
+            let fs = &self.fs;
+            let mut state = self.state.lock().await;
+            let size = state.disk.size() as usize;
+            let target = match state.is_fast_symlink(fs) {
+                true => state.disk.i_block()[..size].to_vec(),
+                false => {
+                    let mut target = vec![0; size.min(fs.block_size)];
+                    let len = state.read(fs, 0, &mut target).await?;
+                    target.truncate(len);
+                    target
+                }
+            };
+            String::from_utf8(target).map_err(|_| Errno::EIO)
+        })
+    }
+
+    fn read_dir(&self, index: usize) -> FsFuture<'_, Option<DirEntry>> {
+        Box::pin(async move {
+            self.check_dir()?;
+            let fs = &self.fs;
+            let mut state = self.state.lock().await;
+            let listing = state.listing(fs).await?;
+            let Some(entry) = listing.get_mut(index) else {
+                return Ok(None);
+            };
+            let ty = match entry.ty {
+                Some(ty) => ty,
+                None => {
+                    let ty = Ext4Inode::load(fs, entry.ino).await?.ty;
+                    entry.ty = Some(ty);
+                    ty
+                }
+            };
+            Ok(Some(DirEntry {
+                ino: entry.ino as u64,
+                name: entry.name.clone(),
+                ty,
+            }))
+        })
+    }
+
+    fn sync(&self) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            BUFFER_CACHE.sync_device(&self.fs.device).await?;
+            Ok(())
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn check_name(name: &str) -> Result<(), Errno> {
+    match name.len() {
+        0 => Err(Errno::ENOENT),
+        len if len > NAME_MAX => Err(Errno::ENAMETOOLONG),
+        _ => Ok(()),
+    }
+}
+
+/// Drops the link of an entry to `inode` removed from directory `parent`.
+async fn unlinked(
+    fs: &Ext4FsInfo,
+    inode: &Ext4Inode,
+    child: &mut InodeState,
+    parent: &mut InodeState,
+) -> Result<(), Errno> {
+    match inode.ty {
+        InodeType::Dir => {
+            child.disk.set_links(0);
+            add_link(fs, parent, -1);
+        }
+        _ => child.disk.set_links(child.disk.links().saturating_sub(1)),
+    }
+    child.disk.set_ctime(TimeSpec::now());
+    child.save(fs).await
+}
+
+/// Moves entry `old` of directory `src` to `new` of `dst`, which is `src`
+/// itself if its state is `None`.
+async fn rename_entry(
+    fs: &Arc<Ext4FsInfo>,
+    (src_dir, src): (&Ext4Inode, &mut InodeState),
+    (dst_dir, mut dst): (&Ext4Inode, Option<&mut InodeState>),
+    old: &str,
+    new: &str,
+    flags: RenameFlags,
+) -> Result<(), Errno> {
+    if dst
+        .as_deref()
+        .map_or(src.disk.links(), |dst| dst.disk.links())
+        == 0
+    {
+        return Err(Errno::ENOENT);
+    }
+    let found = src.find_entry(fs, old).await?.ok_or(Errno::ENOENT)?;
+    let source = Ext4Inode::load(fs, found.ino).await?;
+    let target = match &mut dst {
+        Some(dst) => dst.find_entry(fs, new).await?,
+        None => src.find_entry(fs, new).await?,
+    };
+    let moved_dir = source.ty == InodeType::Dir && !ptr::eq(src_dir, dst_dir);
+    match target {
+        // links to the same inode are left alone
+        Some(target) if target.ino == found.ino => return Ok(()),
+        Some(_) if flags.contains(RenameFlags::NOREPLACE) => return Err(Errno::EEXIST),
+        // the target contains the source, and its state is locked
+        Some(target) if target.ino == src_dir.ino || target.ino == dst_dir.ino => {
+            return Err(Errno::ENOTEMPTY);
+        }
+        Some(target) => {
+            let inode = Ext4Inode::load(fs, target.ino).await?;
+            let mut state = inode.state.lock().await;
+            match (source.ty, inode.ty) {
+                (InodeType::Dir, InodeType::Dir) if !state.is_empty_dir(fs).await? => {
+                    return Err(Errno::ENOTEMPTY);
+                }
+                (InodeType::Dir, InodeType::Dir) => {}
+                (InodeType::Dir, _) => return Err(Errno::ENOTDIR),
+                (_, InodeType::Dir) => return Err(Errno::EISDIR),
+                _ => {}
+            }
+            let dst_state = match &mut dst {
+                Some(dst) => &mut **dst,
+                None => &mut *src,
+            };
+            dst_state
+                .replace_entry(fs, &target, source.ino, source.ty)
+                .await?;
+            unlinked(fs, &inode, &mut state, dst_state).await?;
+        }
+        None => {
+            if moved_dir {
+                let dst = dst.as_deref().ok_or(Errno::EIO)?;
+                if dst.disk.links() >= MAX_LINKS && !fs.has_ro_compat(RO_COMPAT_DIR_NLINK) {
+                    return Err(Errno::EMLINK);
+                }
+            }
+            match &mut dst {
+                Some(dst) => dst.add_entry(fs, new, source.ino, source.ty).await?,
+                None => src.add_entry(fs, new, source.ino, source.ty).await?,
+            }
+        }
+    }
+
+    // adding the entry may have moved the old one to another block
+    let found = src.find_entry(fs, old).await?.ok_or(Errno::EIO)?;
+    src.remove_entry(fs, &found).await?;
+    let mut state = source.state.lock().await;
+    if moved_dir {
+        state.set_parent(fs, dst_dir.ino).await?;
+        add_link(fs, src, -1);
+        if let Some(dst) = &mut dst {
+            add_link(fs, dst, 1);
+        }
+    }
+    state.disk.set_ctime(TimeSpec::now());
+    state.save(fs).await?;
+    drop(state);
+    src.touch();
+    src.save(fs).await?;
+    if let Some(dst) = &mut dst {
+        dst.touch();
+        dst.save(fs).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,389 @@
+//! Recovery of the jbd2 journal of ext3 and ext4. Transactions committed
+//! to the journal but not yet written in place are replayed at mount, as
+//! Linux does after a crash; the driver itself writes metadata in place.
+//! Fields of the journal are big-endian.
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use log::{info, warn};
+
+use crate::Errno;
+
+use super::{Ext4FsInfo, crc::crc32c, inode::InodeState, u32_at};
+
+const JOURNAL_MAGIC: u32 = 0xc03b_3998;
+
+const BLOCK_DESCRIPTOR: u32 = 1;
+const BLOCK_COMMIT: u32 = 2;
+const BLOCK_SUPERBLOCK_V1: u32 = 3;
+const BLOCK_SUPERBLOCK_V2: u32 = 4;
+const BLOCK_REVOKE: u32 = 5;
+
+// fields of the journal superblock
+const JSB_BLOCK_SIZE: usize = 0x0c;
+const JSB_MAX_LEN: usize = 0x10;
+const JSB_FIRST: usize = 0x14;
+const JSB_SEQUENCE: usize = 0x18;
+const JSB_START: usize = 0x1c;
+const JSB_INCOMPAT: usize = 0x28;
+const JSB_UUID: usize = 0x30;
+const JSB_CHECKSUM: usize = 0xfc;
+const JSB_SIZE: usize = 1024;
+
+const INCOMPAT_REVOKE: u32 = 0x1;
+const INCOMPAT_64BIT: u32 = 0x2;
+const INCOMPAT_ASYNC_COMMIT: u32 = 0x4;
+const INCOMPAT_CSUM_V2: u32 = 0x8;
+const INCOMPAT_CSUM_V3: u32 = 0x10;
+const INCOMPAT_SUPPORTED: u32 =
+    INCOMPAT_REVOKE | INCOMPAT_64BIT | INCOMPAT_ASYNC_COMMIT | INCOMPAT_CSUM_V2 | INCOMPAT_CSUM_V3;
+
+/// The block of the log was escaped, as its content began with the magic.
+const TAG_ESCAPE: u32 = 0x1;
+/// No UUID follows the tag.
+const TAG_SAME_UUID: u32 = 0x2;
+const TAG_LAST: u32 = 0x8;
+
+/// Offset of the checksum of commit blocks.
+const COMMIT_CHECKSUM: usize = 16;
+/// Inode of the journal, in the superblock of the filesystem.
+const SB_JOURNAL_INUM: usize = 0xe0;
+
+fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn set_be32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+/// A block written by a transaction: where it is in the log, where it
+/// goes, and whether its first bytes must be restored to the magic.
+struct LoggedBlock {
+    log: u64,
+    target: u64,
+    escaped: bool,
+    /// Checksum of the block from its tag, of which `csum_v2` journals
+    /// keep the low 16 bits.
+    checksum: u32,
+}
+
+struct Transaction {
+    sequence: u32,
+    blocks: Vec<LoggedBlock>,
+}
+
+/// The journal of a filesystem, read through its inode.
+struct Journal<'a> {
+    fs: &'a Ext4FsInfo,
+    inode: InodeState,
+    first: u64,
+    max_len: u64,
+    incompat: u32,
+    /// Seed of the checksums of `csum_v2` and `csum_v3` journals.
+    csum_seed: Option<u32>,
+}
+
+impl Journal<'_> {
+    fn has_incompat(&self, feature: u32) -> bool {
+        self.incompat & feature != 0
+    }
+
+    async fn read(&mut self, log: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        let block = self.inode.map(self.fs, log).await?.ok_or(Errno::EIO)?;
+        self.fs.read_block(block, 0, buf).await
+    }
+
+    async fn write(&mut self, log: u64, buf: &[u8]) -> Result<(), Errno> {
+        let block = self.inode.map(self.fs, log).await?.ok_or(Errno::EIO)?;
+        self.fs.write_block(block, 0, buf).await
+    }
+
+    /// The block of the log after `log`, which wraps around to the first.
+    fn next(&self, log: u64) -> u64 {
+        match log + 1 {
+            next if next >= self.max_len => self.first,
+            next => next,
+        }
+    }
+
+    fn tag_size(&self) -> usize {
+        let size = match (
+            self.has_incompat(INCOMPAT_CSUM_V3),
+            self.has_incompat(INCOMPAT_CSUM_V2),
+        ) {
+            (true, _) => return 16,
+            (_, true) => 14,
+            _ => 12,
+        };
+        match self.has_incompat(INCOMPAT_64BIT) {
+            true => size,
+            false => size - 4,
+        }
+    }
+
+    /// Bytes at the end of descriptor and revoke blocks for their checksum.
+    fn tail_size(&self) -> usize {
+        match self.csum_seed {
+            Some(_) => 4,
+            None => 0,
+        }
+    }
+
+    /// Whether the checksum in the tail of descriptor or revoke block
+    /// `block` is right, if the journal has checksums.
+    fn tail_valid(&self, block: &mut [u8]) -> bool {
+        let Some(seed) = self.csum_seed else {
+            return true;
+        };
+        let tail = block.len() - 4;
+        let stored = be32(block, tail);
+        set_be32(block, tail, 0);
+        crc32c(seed, block) == stored
+    }
+
+    /// Whether the blocks of transaction `sequence` in `blocks` match the
+    /// checksums of their tags, if the journal has checksums.
+    async fn data_valid(&mut self, blocks: &[LoggedBlock], sequence: u32) -> Result<bool, Errno> {
+        let Some(seed) = self.csum_seed else {
+            return Ok(true);
+        };
+        let seed = crc32c(seed, &sequence.to_be_bytes());
+        let mut data = vec![0; self.fs.block_size];
+        for logged in blocks {
+            // the checksum covers the block as logged, still escaped
+            self.read(logged.log, &mut data).await?;
+            let csum = crc32c(seed, &data);
+            let valid = match self.has_incompat(INCOMPAT_CSUM_V3) {
+                true => csum == logged.checksum,
+                false => csum as u16 == logged.checksum as u16,
+            };
+            if !valid {
+                warn!(
+                    "ext4: bad checksum of journal block {} in transaction {}",
+                    logged.log, sequence
+                );
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether the checksum of commit block `block` is right, if the
+    /// journal has checksums.
+    fn commit_valid(&self, block: &mut [u8]) -> bool {
+        let Some(seed) = self.csum_seed else {
+            return true;
+        };
+        let stored = be32(block, COMMIT_CHECKSUM);
+        set_be32(block, COMMIT_CHECKSUM, 0);
+        crc32c(seed, block) == stored
+    }
+
+    /// Reads the blocks a descriptor block at `log` lists, returning the
+    /// last block of the log they take.
+    fn parse_descriptor(&self, block: &[u8], mut log: u64, blocks: &mut Vec<LoggedBlock>) -> u64 {
+        let tag_size = self.tag_size();
+        let end = block.len() - self.tail_size();
+        let mut offset = 12;
+        while offset + tag_size <= end {
+            let tag = &block[offset..offset + tag_size];
+            let (flags, checksum) = match self.has_incompat(INCOMPAT_CSUM_V3) {
+                true => (be32(tag, 4), be32(tag, 12)),
+                false => (be16(tag, 6) as u32, be16(tag, 4) as u32),
+            };
+            let mut target = be32(tag, 0) as u64;
+            if self.has_incompat(INCOMPAT_64BIT) {
+                target |= (be32(tag, 8) as u64) << 32;
+            }
+            log = self.next(log);
+            blocks.push(LoggedBlock {
+                log,
+                target,
+                escaped: flags & TAG_ESCAPE != 0,
+                checksum,
+            });
+            offset += tag_size;
+            if flags & TAG_SAME_UUID == 0 {
+                offset += 16;
+            }
+            if flags & TAG_LAST != 0 {
+                break;
+            }
+        }
+        log
+    }
+
+    /// Adds the blocks a revoke block revokes in transaction `sequence`.
+    fn parse_revoke(&self, block: &[u8], sequence: u32, revoked: &mut BTreeMap<u64, u32>) {
+        let record_size = match self.has_incompat(INCOMPAT_64BIT) {
+            true => 8,
+            false => 4,
+        };
+        let end = (be32(block, 12) as usize).min(block.len() - self.tail_size());
+        let mut offset = 16;
+        while offset + record_size <= end {
+            let target = match record_size {
+                8 => (be32(block, offset) as u64) << 32 | be32(block, offset + 4) as u64,
+                _ => be32(block, offset) as u64,
+            };
+            let latest = revoked.entry(target).or_insert(sequence);
+            *latest = (*latest).max(sequence);
+            offset += record_size;
+        }
+    }
+
+    /// Reads the committed transactions of the log from block `start` on,
+    /// and the blocks they revoke with the last transaction revoking each.
+    /// The log ends at the first block whose checksum is wrong, as with
+    /// jbd2.
+    async fn scan(
+        &mut self,
+        start: u64,
+        mut sequence: u32,
+    ) -> Result<(Vec<Transaction>, BTreeMap<u64, u32>), Errno> {
+        let mut transactions = Vec::new();
+        let mut revoked = BTreeMap::new();
+        let mut pending_revoked = BTreeMap::new();
+        let mut blocks = Vec::new();
+        let mut block = vec![0; self.fs.block_size];
+        let mut log = start;
+        let mut seen = 0;
+        // a log wrapping around without an end is corrupted
+        while seen <= self.max_len {
+            self.read(log, &mut block).await?;
+            if be32(&block, 0) != JOURNAL_MAGIC || be32(&block, 8) != sequence {
+                break;
+            }
+            match be32(&block, 4) {
+                BLOCK_DESCRIPTOR if self.tail_valid(&mut block) => {
+                    let listed = blocks.len();
+                    let last = self.parse_descriptor(&block, log, &mut blocks);
+                    if !self.data_valid(&blocks[listed..], sequence).await? {
+                        break;
+                    }
+                    seen += (last + self.max_len - log) % self.max_len;
+                    log = last;
+                }
+                BLOCK_REVOKE if self.tail_valid(&mut block) => {
+                    self.parse_revoke(&block, sequence, &mut pending_revoked)
+                }
+                BLOCK_COMMIT if self.commit_valid(&mut block) => {
+                    transactions.push(Transaction {
+                        sequence,
+                        blocks: core::mem::take(&mut blocks),
+                    });
+                    for (target, sequence) in core::mem::take(&mut pending_revoked) {
+                        let latest = revoked.entry(target).or_insert(sequence);
+                        *latest = (*latest).max(sequence);
+                    }
+                    sequence = sequence.wrapping_add(1);
+                }
+                _ => break,
+            }
+            log = self.next(log);
+            seen += 1;
+        }
+        Ok((transactions, revoked))
+    }
+}
+
+/// Replays the journal of `fs`, then marks it empty.
+pub async fn replay(fs: &Ext4FsInfo) -> Result<(), Errno> {
+    let sb = fs.alloc.lock().await.sb.clone();
+    let ino = u32_at(&sb, SB_JOURNAL_INUM);
+    if ino == 0 {
+        warn!("ext4: journals on other devices are not supported");
+        return Err(Errno::EINVAL);
+    }
+    let mut inode = InodeState::new(ino, fs.read_inode(ino).await?);
+    let mut jsb = vec![0; fs.block_size];
+    let block = inode.map(fs, 0).await?.ok_or(Errno::EIO)?;
+    fs.read_block(block, 0, &mut jsb).await?;
+    let version = be32(&jsb, 4);
+    if be32(&jsb, 0) != JOURNAL_MAGIC
+        || !matches!(version, BLOCK_SUPERBLOCK_V1 | BLOCK_SUPERBLOCK_V2)
+        || be32(&jsb, JSB_BLOCK_SIZE) as usize != fs.block_size
+    {
+        return Err(Errno::EINVAL);
+    }
+    let incompat = match version {
+        BLOCK_SUPERBLOCK_V2 => be32(&jsb, JSB_INCOMPAT),
+        _ => 0,
+    };
+    if incompat & !INCOMPAT_SUPPORTED != 0 {
+        warn!(
+            "ext4: unsupported journal features {:#x}",
+            incompat & !INCOMPAT_SUPPORTED
+        );
+        return Err(Errno::EINVAL);
+    }
+    let csum_seed = (incompat & (INCOMPAT_CSUM_V2 | INCOMPAT_CSUM_V3) != 0)
+        .then(|| crc32c(!0, &jsb[JSB_UUID..JSB_UUID + 16]));
+    if csum_seed.is_some() {
+        let mut raw = jsb[..JSB_SIZE].to_vec();
+        set_be32(&mut raw, JSB_CHECKSUM, 0);
+        if crc32c(!0, &raw) != be32(&jsb, JSB_CHECKSUM) {
+            warn!("ext4: bad checksum of the journal superblock");
+            return Err(Errno::EINVAL);
+        }
+    }
+    let max_len = be32(&jsb, JSB_MAX_LEN) as u64;
+    let first = be32(&jsb, JSB_FIRST) as u64;
+    let start = be32(&jsb, JSB_START) as u64;
+    if first == 0 || first >= max_len || start >= max_len {
+        return Err(Errno::EINVAL);
+    }
+    let mut journal = Journal {
+        fs,
+        inode,
+        first,
+        max_len,
+        incompat,
+        csum_seed,
+    };
+    // a journal starting at 0 is empty
+    let mut sequence = be32(&jsb, JSB_SEQUENCE);
+    if start != 0 {
+        let (transactions, revoked) = journal.scan(start, sequence).await?;
+        let mut data = vec![0; fs.block_size];
+        let mut replayed = 0;
+        for transaction in &transactions {
+            for logged in &transaction.blocks {
+                // revoked by this transaction or a later one
+                if revoked
+                    .get(&logged.target)
+                    .is_some_and(|&revoke| revoke.wrapping_sub(transaction.sequence) as i32 >= 0)
+                {
+                    continue;
+                }
+                journal.read(logged.log, &mut data).await?;
+                if logged.escaped {
+                    set_be32(&mut data, 0, JOURNAL_MAGIC);
+                }
+                fs.write_block(logged.target, 0, &data).await?;
+                replayed += 1;
+            }
+        }
+        if let Some(last) = transactions.last() {
+            sequence = last.sequence.wrapping_add(1);
+        }
+        info!(
+            "ext4: replayed {} transactions, {} blocks",
+            transactions.len(),
+            replayed
+        );
+    }
+
+    set_be32(&mut jsb, JSB_SEQUENCE, sequence);
+    set_be32(&mut jsb, JSB_START, 0);
+    if csum_seed.is_some() {
+        set_be32(&mut jsb, JSB_CHECKSUM, 0);
+        let csum = crc32c(!0, &jsb[..JSB_SIZE]);
+        set_be32(&mut jsb, JSB_CHECKSUM, csum);
+    }
+    journal.write(0, &jsb).await
+}
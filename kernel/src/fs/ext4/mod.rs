@@ -0,0 +1,598 @@
+mod crc;
+mod dir;
+mod extent;
+mod group;
+mod indirect;
+mod inode;
+mod journal;
+
+pub use inode::*;
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use log::{info, warn};
+
+use crate::{
+    Errno,
+    block::{BUFFER_CACHE, BlockDevice, Buffer},
+    runtime::TimeSpec,
+    sync::{Mutex, SpinMutex},
+};
+
+use super::{FileSystemType, FsFuture, Inode, SuperBlock, alloc_anon_dev};
+
+use crc::crc32c;
+use group::GroupDesc;
+
+/// ext2 and ext3 are mounted by the same driver, as on Linux.
+pub const EXT2: FileSystemType = FileSystemType {
+    name: "ext2",
+    needs_device: true,
+    mount: mount_ext4,
+};
+
+pub const EXT3: FileSystemType = FileSystemType {
+    name: "ext3",
+    needs_device: true,
+    mount: mount_ext4,
+};
+
+pub const EXT4: FileSystemType = FileSystemType {
+    name: "ext4",
+    needs_device: true,
+    mount: mount_ext4,
+};
+
+/// The superblock is 1024 bytes at byte 1024, whatever the block size.
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT4_MAGIC: u16 = 0xef53;
+
+// fields of the superblock the driver changes
+const SB_FREE_BLOCKS: usize = 0x0c;
+const SB_FREE_INODES: usize = 0x10;
+const SB_MTIME: usize = 0x2c;
+const SB_WTIME: usize = 0x30;
+const SB_MNT_COUNT: usize = 0x34;
+const SB_STATE: usize = 0x3a;
+const SB_FEATURE_INCOMPAT: usize = 0x60;
+const SB_FEATURE_RO_COMPAT: usize = 0x64;
+const SB_LAST_ORPHAN: usize = 0xe8;
+const SB_FREE_BLOCKS_HI: usize = 0x158;
+const SB_CHECKSUM: usize = 0x3fc;
+
+/// `s_state` bits: unmounted cleanly, and errors were detected.
+const STATE_VALID: u16 = 0x1;
+const STATE_ERROR: u16 = 0x2;
+
+const COMPAT_HAS_JOURNAL: u32 = 0x4;
+/// Directories may have hash indexes.
+const COMPAT_DIR_INDEX: u32 = 0x20;
+
+const INCOMPAT_FILETYPE: u32 = 0x2;
+/// The journal has transactions to replay.
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+/// Features the driver understands. Filesystems with others are not
+/// mounted.
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_RECOVER
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR;
+
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const RO_COMPAT_BTREE_DIR: u32 = 0x4;
+const RO_COMPAT_HUGE_FILE: u32 = 0x8;
+const RO_COMPAT_GDT_CSUM: u32 = 0x10;
+const RO_COMPAT_DIR_NLINK: u32 = 0x20;
+const RO_COMPAT_EXTRA_ISIZE: u32 = 0x40;
+const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+/// Features the driver keeps consistent when writing. Filesystems with
+/// others are mounted read-only.
+const RO_COMPAT_SUPPORTED: u32 = RO_COMPAT_SPARSE_SUPER
+    | RO_COMPAT_LARGE_FILE
+    | RO_COMPAT_BTREE_DIR
+    | RO_COMPAT_HUGE_FILE
+    | RO_COMPAT_GDT_CSUM
+    | RO_COMPAT_DIR_NLINK
+    | RO_COMPAT_EXTRA_ISIZE
+    | RO_COMPAT_METADATA_CSUM;
+
+/// Directory indexes hash names as unsigned chars.
+const FLAGS_UNSIGNED_HASH: u32 = 0x2;
+
+/// Inode of the root directory.
+const ROOT_INO: u32 = 2;
+/// Size of inodes of revision 0 filesystems, and of the part of larger ones
+/// all filesystems have.
+const GOOD_OLD_INODE_SIZE: usize = 128;
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn set_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Options of `mount`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ext4Options {
+    pub readonly: bool,
+}
+
+impl Ext4Options {
+    /// Parses `ro` and `rw` options separated by commas.
+    pub fn parse(data: &str) -> Result<Self, Errno> {
+        let mut options = Self::default();
+        for option in data.split(',').filter(|option| !option.is_empty()) {
+            match option {
+                "ro" => options.readonly = true,
+                "rw" => options.readonly = false,
+                _ => return Err(Errno::EINVAL),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Allocation state, changed under one lock.
+struct AllocState {
+    /// The superblock, written back by `sync`.
+    sb: Vec<u8>,
+    groups: Vec<GroupDesc>,
+}
+
+/// State of a mounted ext2/3/4 filesystem shared by its inodes.
+pub struct Ext4FsInfo {
+    device: Arc<dyn BlockDevice>,
+    dev: u64,
+    readonly: bool,
+    /// `s_state` when mounted, written back on unmount. The clean bit is
+    /// cleared on the device while the filesystem is mounted read-write.
+    mount_state: u16,
+    block_size: usize,
+    blocks_count: u64,
+    first_data_block: u64,
+    blocks_per_group: u64,
+    inodes_per_group: u32,
+    inodes_count: u32,
+    inode_size: usize,
+    /// Bytes past the first 128 of an inode new inodes use.
+    extra_isize: u16,
+    desc_size: usize,
+    group_count: u32,
+    /// First inode not reserved by the filesystem.
+    first_ino: u32,
+    compat: u32,
+    incompat: u32,
+    ro_compat: u32,
+    reserved_gdt_blocks: u64,
+    uuid: [u8; 16],
+    /// Seed of the checksums of `metadata_csum` filesystems.
+    csum_seed: Option<u32>,
+    hash_seed: [u32; 4],
+    hash_unsigned: bool,
+    /// First block of the inode table of each group.
+    inode_tables: Vec<u64>,
+    /// Serializes changes of the bitmaps, group descriptors and superblock.
+    alloc: Mutex<AllocState>,
+    /// Inodes in memory by number, so that all lookups of a file share one
+    /// inode.
+    inodes: SpinMutex<BTreeMap<u32, Weak<Ext4Inode>>>,
+    /// Serializes the operations locking more than one directory, renames
+    /// and removals.
+    rename_lock: Mutex<()>,
+}
+
+impl Ext4FsInfo {
+    async fn new(device: Arc<dyn BlockDevice>, options: Ext4Options) -> Result<Arc<Self>, Errno> {
+        let sb = read_super(&device).await?;
+        let mut fs = Self::load(device.clone(), sb).await?;
+        if fs.has_incompat(INCOMPAT_RECOVER) {
+            if fs.readonly {
+                warn!("ext4: journal needs recovery on a read-only device");
+                return Err(Errno::EROFS);
+            }
+            journal::replay(&fs).await?;
+            // the journal may hold the superblock and group descriptors
+            let mut sb = vec![0; SUPERBLOCK_SIZE];
+            fs.read_bytes(SUPERBLOCK_OFFSET, &mut sb).await?;
+            let incompat = u32_at(&sb, SB_FEATURE_INCOMPAT) & !INCOMPAT_RECOVER;
+            set_u32(&mut sb, SB_FEATURE_INCOMPAT, incompat);
+            fs = Self::load(device, sb).await?;
+            let mut sb = fs.alloc.get_mut().sb.clone();
+            fs.write_super(&mut sb).await?;
+            BUFFER_CACHE.sync_device(&fs.device).await?;
+        }
+        let unsupported = fs.ro_compat & !RO_COMPAT_SUPPORTED;
+        if unsupported != 0 && !fs.readonly && !options.readonly {
+            warn!(
+                "ext4: mounting read-only, unsupported features {:#x}",
+                unsupported
+            );
+        }
+        fs.readonly |= options.readonly || unsupported != 0;
+        if !fs.readonly {
+            if fs.mount_state & STATE_VALID == 0 {
+                warn!("ext4: not cleanly unmounted, running e2fsck is recommended");
+            } else if fs.mount_state & STATE_ERROR != 0 {
+                warn!("ext4: errors were detected, running e2fsck is recommended");
+            }
+            let orphans = u32_at(&fs.alloc.get_mut().sb, SB_LAST_ORPHAN);
+            match inode::release_orphans(&fs, orphans).await {
+                Ok(()) => set_u32(&mut fs.alloc.get_mut().sb, SB_LAST_ORPHAN, 0),
+                Err(err) => warn!("ext4: cannot release orphan inodes: {:?}", err),
+            }
+            let sb = &mut fs.alloc.get_mut().sb;
+            set_u16(sb, SB_STATE, fs.mount_state & !STATE_VALID);
+            let mnt_count = u16_at(sb, SB_MNT_COUNT).wrapping_add(1);
+            set_u16(sb, SB_MNT_COUNT, mnt_count);
+            set_u32(sb, SB_MTIME, TimeSpec::now().tv_sec as u32);
+            let mut sb = sb.clone();
+            fs.write_super(&mut sb).await?;
+            BUFFER_CACHE.sync_device(&fs.device).await?;
+        }
+        Ok(Arc::new(fs))
+    }
+
+    /// Checks the superblock `sb` and reads the group descriptors. The
+    /// filesystem is writable unless the device is not, for replaying the
+    /// journal.
+    async fn load(device: Arc<dyn BlockDevice>, mut sb: Vec<u8>) -> Result<Self, Errno> {
+        if u16_at(&sb, 0x38) != EXT4_MAGIC {
+            return Err(Errno::EINVAL);
+        }
+        let log_block_size = u32_at(&sb, 0x18);
+        if log_block_size > 6 {
+            return Err(Errno::EINVAL);
+        }
+        let block_size = 1024 << log_block_size;
+        let revision = u32_at(&sb, 0x4c);
+        let (compat, incompat, mut ro_compat) = match revision {
+            0 => (0, 0, 0),
+            _ => (u32_at(&sb, 0x5c), u32_at(&sb, 0x60), u32_at(&sb, 0x64)),
+        };
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            warn!(
+                "ext4: unsupported features {:#x}",
+                incompat & !INCOMPAT_SUPPORTED
+            );
+            return Err(Errno::EINVAL);
+        }
+        if incompat & INCOMPAT_RECOVER != 0 && compat & COMPAT_HAS_JOURNAL == 0 {
+            return Err(Errno::EINVAL);
+        }
+        // checksums of metadata supersede those of group descriptors
+        if ro_compat & RO_COMPAT_METADATA_CSUM != 0 {
+            ro_compat &= !RO_COMPAT_GDT_CSUM;
+        }
+
+        let blocks_count = match incompat & INCOMPAT_64BIT {
+            0 => u32_at(&sb, 0x04) as u64,
+            _ => u32_at(&sb, 0x04) as u64 | (u32_at(&sb, 0x150) as u64) << 32,
+        };
+        let first_data_block = u32_at(&sb, 0x14) as u64;
+        let blocks_per_group = u32_at(&sb, 0x20) as u64;
+        let inodes_per_group = u32_at(&sb, 0x28);
+        let (inode_size, first_ino) = match revision {
+            0 => (GOOD_OLD_INODE_SIZE, 11),
+            _ => (u16_at(&sb, 0x58) as usize, u32_at(&sb, 0x54)),
+        };
+        let desc_size = match incompat & INCOMPAT_64BIT {
+            0 => 32,
+            _ => u16_at(&sb, 0xfe) as usize,
+        };
+        if block_size < device.block_size()
+            || blocks_count * block_size as u64 > (device.num_blocks() * device.block_size()) as u64
+            || first_data_block >= blocks_count
+            || blocks_per_group == 0
+            || blocks_per_group > 8 * block_size as u64
+            || inodes_per_group == 0
+            || inodes_per_group as usize > 8 * block_size
+            || !inode_size.is_power_of_two()
+            || !(GOOD_OLD_INODE_SIZE..=block_size).contains(&inode_size)
+            || !desc_size.is_power_of_two()
+            || !(32..=1024).contains(&desc_size)
+        {
+            return Err(Errno::EINVAL);
+        }
+        let group_count = (blocks_count - first_data_block).div_ceil(blocks_per_group) as u32;
+        let extra_isize = match inode_size {
+            GOOD_OLD_INODE_SIZE => 0,
+            _ => match u16_at(&sb, 0x15e) {
+                0 => 32,
+                size => size,
+            }
+            .min((inode_size - GOOD_OLD_INODE_SIZE) as u16),
+        };
+        let uuid: [u8; 16] = sb[0x68..0x78].try_into().unwrap();
+        let csum_seed = match (
+            ro_compat & RO_COMPAT_METADATA_CSUM,
+            incompat & INCOMPAT_CSUM_SEED,
+        ) {
+            (0, _) => None,
+            (_, 0) => Some(crc32c(!0, &uuid)),
+            _ => Some(u32_at(&sb, 0x270)),
+        };
+        let hash_seed = core::array::from_fn(|i| u32_at(&sb, 0xec + 4 * i));
+        let mut fs = Self {
+            readonly: device.is_readonly(),
+            mount_state: u16_at(&sb, SB_STATE),
+            device,
+            dev: alloc_anon_dev(),
+            block_size,
+            blocks_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inodes_count: u32_at(&sb, 0x00),
+            inode_size,
+            extra_isize,
+            desc_size,
+            group_count,
+            first_ino,
+            compat,
+            incompat,
+            ro_compat,
+            reserved_gdt_blocks: u16_at(&sb, 0xce) as u64,
+            uuid,
+            csum_seed,
+            hash_seed,
+            hash_unsigned: u32_at(&sb, 0x160) & FLAGS_UNSIGNED_HASH != 0,
+            inode_tables: Vec::new(),
+            alloc: Mutex::new(AllocState {
+                sb: Vec::new(),
+                groups: Vec::new(),
+            }),
+            inodes: SpinMutex::new(BTreeMap::new()),
+            rename_lock: Mutex::new(()),
+        };
+        if fs.inodes_count > inodes_per_group * group_count {
+            return Err(Errno::EINVAL);
+        }
+
+        // the group descriptors follow the block of the superblock
+        let gdt = (fs.first_data_block + 1) * block_size as u64;
+        let mut raw = vec![0; group_count as usize * desc_size];
+        fs.read_bytes(gdt, &mut raw).await?;
+        let groups: Vec<_> = raw.chunks(desc_size).map(GroupDesc::parse).collect();
+        for (group, desc) in groups.iter().enumerate() {
+            fs.check_group(group as u32, desc)?;
+        }
+        fs.inode_tables = groups.iter().map(GroupDesc::inode_table).collect();
+        // the counts of the superblock are only updated now and then
+        let free_blocks: u64 = groups.iter().map(|desc| desc.free_blocks() as u64).sum();
+        let free_inodes: u32 = groups.iter().map(GroupDesc::free_inodes).sum();
+        set_u32(&mut sb, SB_FREE_BLOCKS, free_blocks as u32);
+        set_u32(&mut sb, SB_FREE_BLOCKS_HI, (free_blocks >> 32) as u32);
+        set_u32(&mut sb, SB_FREE_INODES, free_inodes);
+        *fs.alloc.get_mut() = AllocState { sb, groups };
+        Ok(fs)
+    }
+
+    fn has_incompat(&self, feature: u32) -> bool {
+        self.incompat & feature != 0
+    }
+
+    fn has_ro_compat(&self, feature: u32) -> bool {
+        self.ro_compat & feature != 0
+    }
+
+    /// Seed of the checksums of the metadata of inode `ino`, which are
+    /// only kept by `metadata_csum` filesystems.
+    fn inode_seed(&self, ino: u32, generation: u32) -> Option<u32> {
+        let seed = crc32c(self.csum_seed?, &ino.to_le_bytes());
+        Some(crc32c(seed, &generation.to_le_bytes()))
+    }
+
+    async fn bread(&self, block: u64) -> Result<Arc<Buffer>, Errno> {
+        if block >= self.blocks_count {
+            return Err(Errno::EIO);
+        }
+        Ok(BUFFER_CACHE
+            .bread(&self.device, block as usize, self.block_size)
+            .await?)
+    }
+
+    /// Reads `buf` from byte `offset` of `block` on, through the cache.
+    async fn read_block(&self, block: u64, offset: usize, buf: &mut [u8]) -> Result<(), Errno> {
+        let buffer = self.bread(block).await?;
+        buf.copy_from_slice(&buffer.read().await[offset..][..buf.len()]);
+        Ok(())
+    }
+
+    /// Writes `buf` to byte `offset` of `block` on, through the cache.
+    async fn write_block(&self, block: u64, offset: usize, buf: &[u8]) -> Result<(), Errno> {
+        if self.readonly {
+            return Err(Errno::EROFS);
+        }
+        let buffer = match buf.len() == self.block_size {
+            true if block < self.blocks_count => {
+                BUFFER_CACHE.getblk(&self.device, block as usize, self.block_size)
+            }
+            _ => self.bread(block).await?,
+        };
+        buffer.write().await[offset..][..buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    /// Reads `buf` from byte `pos` of the device on, across blocks.
+    async fn read_bytes(&self, pos: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        let block_size = self.block_size as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = pos + done as u64;
+            let offset = (pos % block_size) as usize;
+            let chunk = (self.block_size - offset).min(buf.len() - done);
+            self.read_block(pos / block_size, offset, &mut buf[done..done + chunk])
+                .await?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Writes the superblock `sb` with an updated write time and checksum.
+    async fn write_super(&self, sb: &mut [u8]) -> Result<(), Errno> {
+        set_u32(sb, SB_WTIME, TimeSpec::now().tv_sec as u32);
+        if self.csum_seed.is_some() {
+            let csum = crc32c(!0, &sb[..SB_CHECKSUM]);
+            set_u32(sb, SB_CHECKSUM, csum);
+        }
+        let block_size = self.block_size as u64;
+        let block = SUPERBLOCK_OFFSET / block_size;
+        let offset = (SUPERBLOCK_OFFSET % block_size) as usize;
+        self.write_block(block, offset, sb).await
+    }
+
+    /// Block and offset in it of inode `ino` in the inode table.
+    fn inode_pos(&self, ino: u32) -> Result<(u64, usize), Errno> {
+        if ino == 0 || ino > self.inodes_count {
+            return Err(Errno::EIO);
+        }
+        let group = (ino - 1) / self.inodes_per_group;
+        let index = ((ino - 1) % self.inodes_per_group) as u64;
+        let table = self.inode_tables[group as usize];
+        let pos = index * self.inode_size as u64;
+        let block_size = self.block_size as u64;
+        Ok((table + pos / block_size, (pos % block_size) as usize))
+    }
+
+    async fn read_inode(&self, ino: u32) -> Result<DiskInode, Errno> {
+        let (block, offset) = self.inode_pos(ino)?;
+        let mut raw = vec![0; self.inode_size];
+        self.read_block(block, offset, &mut raw).await?;
+        Ok(DiskInode::from(raw))
+    }
+
+    /// Writes inode `ino` to the inode table with an updated checksum.
+    async fn write_inode(&self, ino: u32, inode: &mut DiskInode) -> Result<(), Errno> {
+        if let Some(seed) = self.inode_seed(ino, inode.generation()) {
+            inode.update_checksum(seed);
+        }
+        let (block, offset) = self.inode_pos(ino)?;
+        self.write_block(block, offset, inode.as_bytes()).await
+    }
+
+    /// Sets the `large_file` feature once a file grows past 2 GiB.
+    async fn note_large_file(&self, size: u64) {
+        if size < 1 << 31 || self.has_ro_compat(RO_COMPAT_LARGE_FILE) {
+            return;
+        }
+        let sb = &mut self.alloc.lock().await.sb;
+        let ro_compat = u32_at(sb, SB_FEATURE_RO_COMPAT) | RO_COMPAT_LARGE_FILE;
+        set_u32(sb, SB_FEATURE_RO_COMPAT, ro_compat);
+    }
+}
+
+/// Reads the superblock from the device, bypassing the cache whose block
+/// size is not known yet.
+async fn read_super(device: &Arc<dyn BlockDevice>) -> Result<Vec<u8>, Errno> {
+    let device_block = device.block_size();
+    let first = SUPERBLOCK_OFFSET as usize / device_block;
+    let end = (SUPERBLOCK_OFFSET as usize + SUPERBLOCK_SIZE).div_ceil(device_block);
+    if end > device.num_blocks() {
+        return Err(Errno::EINVAL);
+    }
+    let mut raw = vec![0; (end - first) * device_block];
+    device.read_blocks(first, &mut raw).await?;
+    let offset = SUPERBLOCK_OFFSET as usize - first * device_block;
+    Ok(raw[offset..offset + SUPERBLOCK_SIZE].to_vec())
+}
+
+/// An ext2, ext3 or ext4 filesystem on a block device. Metadata is written
+/// in place rather than through the journal, which is replayed at mount if
+/// the filesystem was not unmounted cleanly.
+pub struct Ext4Fs {
+    info: Arc<Ext4FsInfo>,
+    root: Arc<Ext4Inode>,
+}
+
+impl Ext4Fs {
+    pub async fn new(
+        device: Arc<dyn BlockDevice>,
+        options: Ext4Options,
+    ) -> Result<Arc<Self>, Errno> {
+        let info = Ext4FsInfo::new(device, options).await?;
+        let root = Ext4Inode::load(&info, ROOT_INO).await?;
+        if root.inode_type() != super::InodeType::Dir {
+            return Err(Errno::EINVAL);
+        }
+        info!(
+            "ext4: {} blocks of {} bytes, {} groups{}",
+            info.blocks_count,
+            info.block_size,
+            info.group_count,
+            if info.readonly { ", read-only" } else { "" }
+        );
+        Ok(Arc::new(Self { info, root }))
+    }
+}
+
+impl SuperBlock for Ext4Fs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            let fs = &self.info;
+            if !fs.readonly {
+                let mut sb = fs.alloc.lock().await.sb.clone();
+                fs.write_super(&mut sb).await?;
+            }
+            BUFFER_CACHE.sync_device(&fs.device).await?;
+            Ok(())
+        })
+    }
+
+    /// Restores the state found at mount, which marks the filesystem clean
+    /// unless it was not before.
+    fn unmounted(&self) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            let fs = &self.info;
+            if !fs.readonly {
+                let mut sb = {
+                    let mut alloc = fs.alloc.lock().await;
+                    set_u16(&mut alloc.sb, SB_STATE, fs.mount_state);
+                    alloc.sb.clone()
+                };
+                fs.write_super(&mut sb).await?;
+            }
+            BUFFER_CACHE.sync_device(&fs.device).await?;
+            Ok(())
+        })
+    }
+}
+
+fn mount_ext4(
+    device: Option<Arc<dyn BlockDevice>>,
+    data: &str,
+) -> FsFuture<'_, Arc<dyn SuperBlock>> {
+    Box::pin(async move {
+        let device = device.ok_or(Errno::EINVAL)?;
+        let fs: Arc<dyn SuperBlock> = Ext4Fs::new(device, Ext4Options::parse(data)?).await?;
+        Ok(fs)
+    })
+}
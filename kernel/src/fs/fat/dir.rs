@@ -0,0 +1,472 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::{Errno, runtime::TimeSpec};
+
+use super::{u16_at, u32_at};
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// Attributes marking a long name entry.
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// Bytes of a directory entry.
+pub const ENTRY_SIZE: usize = 32;
+/// First byte of a free entry.
+pub const ENTRY_FREE: u8 = 0xe5;
+/// First byte of the entry ending a directory, after which all are free.
+pub const ENTRY_END: u8 = 0x00;
+/// Most entries of a directory.
+pub const MAX_DIR_ENTRIES: usize = 65536;
+
+/// Set in the order number of the last long name entry, stored first.
+const LFN_LAST: u8 = 0x40;
+/// UTF-16 units of the name in a long name entry, at these offsets.
+const LFN_CHARS: usize = 13;
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Longest name in UTF-16 units.
+const MAX_NAME: usize = 255;
+
+/// `NTRes` flags of short names whose base or extension is lowercase.
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+pub const DOT_NAME: [u8; 11] = *b".          ";
+pub const DOTDOT_NAME: [u8; 11] = *b"..         ";
+/// Characters of short names besides uppercase letters and digits.
+const SHORT_NAME_SPECIAL: &str = "!#$%&'()-@^_`{}~";
+
+/// A short (8.3) directory entry, which holds the attributes of a file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ShortEntry {
+    pub name: [u8; 11],
+    pub attr: u8,
+    /// `NTRes` case flags.
+    pub case: u8,
+    pub ctime_tenth: u8,
+    pub ctime: u16,
+    pub cdate: u16,
+    pub adate: u16,
+    pub cluster: u32,
+    pub mtime: u16,
+    pub mdate: u16,
+    pub size: u32,
+}
+
+impl ShortEntry {
+    pub fn parse(raw: &[u8]) -> Self {
+        Self {
+            name: raw[..11].try_into().unwrap(),
+            attr: raw[11],
+            case: raw[12],
+            ctime_tenth: raw[13],
+            ctime: u16_at(raw, 14),
+            cdate: u16_at(raw, 16),
+            adate: u16_at(raw, 18),
+            cluster: (u16_at(raw, 20) as u32) << 16 | u16_at(raw, 26) as u32,
+            mtime: u16_at(raw, 22),
+            mdate: u16_at(raw, 24),
+            size: u32_at(raw, 28),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; ENTRY_SIZE] {
+        let mut raw = [0; ENTRY_SIZE];
+        raw[..11].copy_from_slice(&self.name);
+        raw[11] = self.attr;
+        raw[12] = self.case;
+        raw[13] = self.ctime_tenth;
+        raw[14..16].copy_from_slice(&self.ctime.to_le_bytes());
+        raw[16..18].copy_from_slice(&self.cdate.to_le_bytes());
+        raw[18..20].copy_from_slice(&self.adate.to_le_bytes());
+        raw[20..22].copy_from_slice(&((self.cluster >> 16) as u16).to_le_bytes());
+        raw[22..24].copy_from_slice(&self.mtime.to_le_bytes());
+        raw[24..26].copy_from_slice(&self.mdate.to_le_bytes());
+        raw[26..28].copy_from_slice(&(self.cluster as u16).to_le_bytes());
+        raw[28..32].copy_from_slice(&self.size.to_le_bytes());
+        raw
+    }
+
+    /// A new entry with all times set to `now`.
+    pub fn new(attr: u8, cluster: u32, now: TimeSpec) -> Self {
+        let (date, time) = to_fat_time(now);
+        Self {
+            attr,
+            ctime_tenth: ((now.tv_sec % 2) * 100 + now.tv_nsec / 10_000_000) as u8,
+            ctime: time,
+            cdate: date,
+            adate: date,
+            cluster,
+            mtime: time,
+            mdate: date,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    /// Whether the entry is the name of the volume rather than a file.
+    pub fn is_volume_label(&self) -> bool {
+        self.attr & ATTR_VOLUME_ID != 0
+    }
+
+    pub fn is_dot(&self) -> bool {
+        self.name == DOT_NAME || self.name == DOTDOT_NAME
+    }
+
+    pub fn set_modified(&mut self, time: TimeSpec) {
+        (self.mdate, self.mtime) = to_fat_time(time);
+        self.adate = self.mdate;
+    }
+
+    /// The name as `BASE.EXT`, in lowercase where the case flags say so.
+    pub fn display_name(&self) -> String {
+        // 0x05 stands for a first byte of 0xe5, which marks free entries
+        let byte = |(i, &b): (usize, &u8)| match (i, b) {
+            (0, 0x05) => 0xe5 as char,
+            _ => b as char,
+        };
+        let lower = |part: String, flag| match self.case & flag {
+            0 => part,
+            _ => part.to_lowercase(),
+        };
+        let base: String = self.name[..8].iter().enumerate().map(byte).collect();
+        let ext: String = self.name[8..].iter().map(|&b| b as char).collect();
+        let base = lower(base.trim_end().to_string(), CASE_LOWER_BASE);
+        let ext = lower(ext.trim_end().to_string(), CASE_LOWER_EXT);
+        match ext.is_empty() {
+            true => base,
+            false => format!("{}.{}", base, ext),
+        }
+    }
+}
+
+/// Days since the Unix epoch of a date of the proleptic Gregorian
+/// calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The date of `days` since the Unix epoch as year, month and day.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = (month_index + 2) % 12 + 1;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// Converts `time` to a FAT date and time, which are local time, here
+/// taken as UTC, from 1980 to 2107 in steps of two seconds.
+pub fn to_fat_time(time: TimeSpec) -> (u16, u16) {
+    let first = days_from_civil(1980, 1, 1) * 86400;
+    let last = days_from_civil(2107, 12, 31) * 86400 + 86399;
+    let secs = time.tv_sec.clamp(first, last);
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let secs = secs % 86400;
+    let date = ((year - 1980) << 9 | month << 5 | day) as u16;
+    let time = ((secs / 3600) << 11 | (secs / 60 % 60) << 5 | (secs % 60 / 2)) as u16;
+    (date, time)
+}
+
+pub fn from_fat_time(date: u16, time: u16) -> TimeSpec {
+    let year = 1980 + (date >> 9) as i64;
+    // unset dates have month and day 0
+    let month = ((date >> 5 & 0xf) as i64).clamp(1, 12);
+    let day = ((date & 0x1f) as i64).max(1);
+    let secs =
+        (time >> 11) as i64 * 3600 + (time >> 5 & 0x3f) as i64 * 60 + (time & 0x1f) as i64 * 2;
+    TimeSpec {
+        tv_sec: days_from_civil(year, month, day) * 86400 + secs,
+        tv_nsec: 0,
+    }
+}
+
+/// Strips the trailing dots FAT ignores, checking that what is left can be
+/// stored as a long name.
+pub fn check_name(name: &str) -> Result<&str, Errno> {
+    let name = name.trim_end_matches('.');
+    if name.encode_utf16().count() > MAX_NAME {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    if name.is_empty() || name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c)) {
+        return Err(Errno::EINVAL);
+    }
+    Ok(name)
+}
+
+/// Names are compared ignoring case, as FAT stores them.
+pub fn names_equal(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_lowercase)
+        .eq(b.chars().flat_map(char::to_lowercase))
+}
+
+/// The short name of `name` if it is a valid one whose base and extension
+/// are each in one case, with the case flags.
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || ext.contains('.') {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let mut case = 0;
+    let (short_base, short_ext) = short.split_at_mut(8);
+    for (part, dst, flag) in [
+        (base, short_base, CASE_LOWER_BASE),
+        (ext, short_ext, CASE_LOWER_EXT),
+    ] {
+        if !part
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || SHORT_NAME_SPECIAL.contains(c))
+        {
+            return None;
+        }
+        let upper = part.chars().any(|c| c.is_ascii_uppercase());
+        let lower = part.chars().any(|c| c.is_ascii_lowercase());
+        match (upper, lower) {
+            (true, true) => return None,
+            (false, true) => case |= flag,
+            _ => {}
+        }
+        dst[..part.len()].copy_from_slice(part.to_ascii_uppercase().as_bytes());
+    }
+    Some((short, case))
+}
+
+/// Chooses the short name of a new entry `name`. A name which is a valid
+/// short name is stored as one, returning its case flags. Others get a
+/// `~N` alias not `taken` in the directory and need long name entries.
+pub fn short_name(name: &str, taken: impl Fn(&[u8; 11]) -> bool) -> ([u8; 11], Option<u8>) {
+    if let Some((short, case)) = exact_short_name(name).filter(|(short, _)| !taken(short)) {
+        return (short, Some(case));
+    }
+    let convert = |c: char| match c {
+        c if c.is_ascii_alphanumeric() => Some(c.to_ascii_uppercase() as u8),
+        c if SHORT_NAME_SPECIAL.contains(c) => Some(c as u8),
+        ' ' | '.' => None,
+        _ => Some(b'_'),
+    };
+    let name = name.trim_start_matches('.');
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (name, ""),
+    };
+    let mut base: Vec<u8> = base.chars().filter_map(convert).take(8).collect();
+    let ext: Vec<u8> = ext.chars().filter_map(convert).take(3).collect();
+    if base.is_empty() {
+        base.push(b'_');
+    }
+    let mut short = [b' '; 11];
+    short[8..8 + ext.len()].copy_from_slice(&ext);
+    for n in 1.. {
+        let tail = format!("~{}", n);
+        let len = base.len().min(8 - tail.len());
+        short[..8].fill(b' ');
+        short[..len].copy_from_slice(&base[..len]);
+        short[len..len + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken(&short) {
+            break;
+        }
+    }
+    (short, None)
+}
+
+/// Checksum of a short name stored in the long name entries of the file.
+pub fn lfn_checksum(short: &[u8; 11]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+/// The long name entries of `name`, in the order they precede the short
+/// entry with checksum `checksum`.
+pub fn long_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LFN_CHARS);
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut raw = [0; ENTRY_SIZE];
+            raw[0] = (i + 1) as u8 | if i + 1 == count { LFN_LAST } else { 0 };
+            raw[11] = ATTR_LONG_NAME;
+            raw[13] = checksum;
+            for (j, offset) in LFN_OFFSETS.iter().enumerate() {
+                // the name ends with a 0 unless it fills the entry, padded
+                // with 0xffff
+                let unit = match (i * LFN_CHARS + j).cmp(&units.len()) {
+                    core::cmp::Ordering::Less => units[i * LFN_CHARS + j],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xffff,
+                };
+                raw[*offset..*offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            raw
+        })
+        .collect()
+}
+
+/// An entry of a directory by the slots it takes.
+#[derive(Debug, Clone)]
+pub struct FatDirEntry {
+    /// The long name, or the short one if there is none.
+    pub name: String,
+    pub short: ShortEntry,
+    /// Slot of the first long name entry, or of the short entry.
+    pub first: usize,
+    /// Slot of the short entry.
+    pub index: usize,
+}
+
+impl FatDirEntry {
+    /// Whether the entry is a file or directory other than `.` and `..`.
+    pub fn is_visible(&self) -> bool {
+        !self.short.is_dot() && !self.short.is_volume_label()
+    }
+}
+
+/// A long name being collected from its entries.
+struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    first: usize,
+    /// Order number of the entry expected next, which count down to 1.
+    next: u8,
+}
+
+/// The entries of a directory, parsed from its clusters.
+pub struct Listing {
+    /// Entries in the order of their slots.
+    pub entries: Vec<FatDirEntry>,
+    /// Slot of the entry ending the directory, or the number of slots.
+    pub end: usize,
+}
+
+impl Listing {
+    pub fn parse(raw: &[u8]) -> Self {
+        let mut entries = Vec::new();
+        let mut long: Option<LongName> = None;
+        let mut end = raw.len() / ENTRY_SIZE;
+        for (slot, raw) in raw.chunks_exact(ENTRY_SIZE).enumerate() {
+            match raw[0] {
+                ENTRY_END => {
+                    end = slot;
+                    break;
+                }
+                ENTRY_FREE => {
+                    long = None;
+                    continue;
+                }
+                _ => {}
+            }
+            if raw[11] & 0x3f == ATTR_LONG_NAME {
+                let order = raw[0] & !LFN_LAST;
+                if raw[0] & LFN_LAST != 0 {
+                    long = (1..=20).contains(&order).then(|| LongName {
+                        units: alloc::vec![0xffff; order as usize * LFN_CHARS],
+                        checksum: raw[13],
+                        first: slot,
+                        next: order,
+                    });
+                }
+                // orphaned entries are left out
+                long = long
+                    .filter(|long| order != 0 && long.next == order && long.checksum == raw[13]);
+                if let Some(long) = &mut long {
+                    let start = (order as usize - 1) * LFN_CHARS;
+                    for (j, offset) in LFN_OFFSETS.iter().enumerate() {
+                        long.units[start + j] = u16_at(raw, *offset);
+                    }
+                    long.next -= 1;
+                }
+                continue;
+            }
+            let short = ShortEntry::parse(raw);
+            let long = long
+                .take()
+                .filter(|long| long.next == 0 && long.checksum == lfn_checksum(&short.name));
+            let (name, first) = match long {
+                Some(long) => {
+                    let len = long
+                        .units
+                        .iter()
+                        .position(|&unit| unit == 0 || unit == 0xffff)
+                        .unwrap_or(long.units.len());
+                    (String::from_utf16_lossy(&long.units[..len]), long.first)
+                }
+                None => (short.display_name(), slot),
+            };
+            entries.push(FatDirEntry {
+                name,
+                short,
+                first,
+                index: slot,
+            });
+        }
+        Self { entries, end }
+    }
+
+    /// Finds a file or directory by its long or short name.
+    pub fn find(&self, name: &str) -> Option<&FatDirEntry> {
+        self.entries.iter().find(|entry| {
+            entry.is_visible()
+                && (names_equal(&entry.name, name)
+                    || names_equal(&entry.short.display_name(), name))
+        })
+    }
+
+    pub fn short_taken(&self, short: &[u8; 11]) -> bool {
+        self.entries.iter().any(|entry| &entry.short.name == short)
+    }
+
+    /// First slot of a run of `count` free ones, which may extend past the
+    /// end of the directory, which then has to grow.
+    pub fn find_free(&self, count: usize) -> usize {
+        let mut start = 0;
+        for entry in &self.entries {
+            if entry.first - start >= count {
+                return start;
+            }
+            start = entry.index + 1;
+        }
+        start
+    }
+
+    pub fn insert(&mut self, entry: FatDirEntry) {
+        self.end = self.end.max(entry.index + 1);
+        let at = self
+            .entries
+            .partition_point(|other| other.index < entry.index);
+        self.entries.insert(at, entry);
+    }
+
+    /// Removes the entry whose short entry is at `slot`.
+    pub fn remove(&mut self, slot: usize) -> Option<FatDirEntry> {
+        let at = self.entries.iter().position(|entry| entry.index == slot)?;
+        Some(self.entries.remove(at))
+    }
+
+    /// Whether there are no entries besides `.` and `..`.
+    pub fn is_empty(&self) -> bool {
+        !self.entries.iter().any(FatDirEntry::is_visible)
+    }
+}
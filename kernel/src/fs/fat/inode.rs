@@ -0,0 +1,762 @@
+use core::{any::Any, ptr};
+
+use alloc::{
+    boxed::Box,
+    string::ToString,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use log::warn;
+
+use crate::{
+    Errno,
+    block::BUFFER_CACHE,
+    fs::{DirEntry, FsFuture, Inode, InodeType, Metadata, RenameFlags, fs_err},
+    runtime::{TimeSpec, spawn},
+    sync::Mutex,
+};
+
+use super::{
+    FatFsInfo,
+    dir::{
+        ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_READ_ONLY, DOT_NAME, DOTDOT_NAME, ENTRY_END, ENTRY_FREE,
+        ENTRY_SIZE, FatDirEntry, Listing, MAX_DIR_ENTRIES, ShortEntry, check_name, from_fat_time,
+        lfn_checksum, long_entries, short_name, to_fat_time,
+    },
+};
+
+/// Largest size of a file.
+const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+/// Inode number of the root directory, which has no entry.
+const ROOT_INO: u64 = 1;
+
+struct InodeState {
+    /// Position of the short entry on the device, `None` for the root
+    /// directory and removed inodes.
+    pos: Option<u64>,
+    entry: ShortEntry,
+    /// Clusters of the data, loaded on first use.
+    chain: Option<Vec<u32>>,
+    /// Entries of a directory, parsed on first use and then kept up to date.
+    listing: Option<Listing>,
+    /// Whether the entry was removed. The clusters are freed with the inode.
+    removed: bool,
+}
+
+impl InodeState {
+    async fn chain(&mut self, fs: &FatFsInfo) -> Result<&mut Vec<u32>, Errno> {
+        if self.chain.is_none() {
+            self.chain = Some(fs.chain(self.entry.cluster).await?);
+        }
+        Ok(self.chain.as_mut().unwrap())
+    }
+
+    /// The clusters and entries of a directory.
+    async fn dir(&mut self, fs: &FatFsInfo) -> Result<(&mut Vec<u32>, &mut Listing), Errno> {
+        if self.chain.is_none() {
+            self.chain = Some(fs.chain(self.entry.cluster).await?);
+        }
+        let chain = self.chain.as_mut().unwrap();
+        if self.listing.is_none() {
+            let mut raw = vec![0; chain.len() * fs.cluster_size];
+            for (&cluster, buf) in chain.iter().zip(raw.chunks_mut(fs.cluster_size)) {
+                fs.read_cluster(cluster, 0, buf).await?;
+            }
+            self.listing = Some(Listing::parse(&raw));
+        }
+        Ok((chain, self.listing.as_mut().unwrap()))
+    }
+
+    /// Writes the short entry back, unless there is none.
+    async fn write_entry(&self, fs: &FatFsInfo) -> Result<(), Errno> {
+        match self.pos {
+            Some(pos) => fs.write_data(pos, &self.entry.to_bytes()).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Updates the modification time and marks the file for backup.
+    async fn modified(&mut self, fs: &FatFsInfo) -> Result<(), Errno> {
+        self.entry.set_modified(TimeSpec::now());
+        if !self.entry.is_dir() {
+            self.entry.attr |= ATTR_ARCHIVE;
+        }
+        self.write_entry(fs).await
+    }
+
+    /// Writes entries for `name` with short entry `entry` into the
+    /// directory, growing it if needed, and returns the position of the
+    /// short entry. Fills in the short name of `entry`.
+    async fn add_entry(
+        &mut self,
+        fs: &FatFsInfo,
+        name: &str,
+        entry: &mut ShortEntry,
+    ) -> Result<u64, Errno> {
+        let (chain, listing) = self.dir(fs).await?;
+        if chain.is_empty() {
+            return Err(Errno::EIO);
+        }
+        let (short, case) = short_name(name, |short| listing.short_taken(short));
+        entry.name = short;
+        entry.case = case.unwrap_or(0);
+        let mut slots = match case {
+            Some(_) => Vec::new(),
+            None => long_entries(name, lfn_checksum(&short)),
+        };
+        slots.push(entry.to_bytes());
+
+        let first = listing.find_free(slots.len());
+        let end = first + slots.len();
+        if end > MAX_DIR_ENTRIES {
+            return Err(Errno::ENOSPC);
+        }
+        let per_cluster = fs.cluster_size / ENTRY_SIZE;
+        while chain.len() * per_cluster < end {
+            let cluster = fs.alloc_cluster(chain.last().copied()).await?;
+            chain.push(cluster);
+        }
+        for (i, slot) in slots.iter().enumerate() {
+            fs.write_data(fs.slot_pos(chain, first + i), slot).await?;
+        }
+        // what follows the entry may not have been zeroed when it was past
+        // the end
+        if end > listing.end && end < chain.len() * per_cluster {
+            fs.write_data(fs.slot_pos(chain, end), &[ENTRY_END]).await?;
+        }
+        listing.insert(FatDirEntry {
+            name: name.to_string(),
+            short: *entry,
+            first,
+            index: end - 1,
+        });
+        Ok(fs.slot_pos(chain, end - 1))
+    }
+
+    /// Frees the slots of the entry whose short entry is at `slot`.
+    async fn remove_entry(&mut self, fs: &FatFsInfo, slot: usize) -> Result<(), Errno> {
+        let (chain, listing) = self.dir(fs).await?;
+        let entry = listing.remove(slot).ok_or(Errno::EIO)?;
+        for slot in entry.first..=entry.index {
+            fs.write_data(fs.slot_pos(chain, slot), &[ENTRY_FREE])
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Finds entry `name`, returning the slot and position of its short
+    /// entry.
+    async fn find(&mut self, fs: &FatFsInfo, name: &str) -> Result<(usize, u64), Errno> {
+        let (chain, listing) = self.dir(fs).await?;
+        let entry = listing.find(name).ok_or(Errno::ENOENT)?;
+        Ok((entry.index, fs.slot_pos(chain, entry.index)))
+    }
+}
+
+/// A file or directory of a FAT filesystem.
+pub struct FatInode {
+    ino: u64,
+    ty: InodeType,
+    fs: Arc<FatFsInfo>,
+    state: Mutex<InodeState>,
+}
+
+impl FatInode {
+    pub(super) fn root(fs: &Arc<FatFsInfo>) -> Arc<Self> {
+        let entry = ShortEntry {
+            attr: ATTR_DIRECTORY,
+            cluster: fs.root_cluster,
+            ..Default::default()
+        };
+        Arc::new(Self::new(fs, None, entry))
+    }
+
+    fn new(fs: &Arc<FatFsInfo>, pos: Option<u64>, entry: ShortEntry) -> Self {
+        Self {
+            // stable as long as the file is not renamed
+            ino: pos.map_or(ROOT_INO, |pos| pos / ENTRY_SIZE as u64),
+            ty: match entry.is_dir() {
+                true => InodeType::Dir,
+                false => InodeType::File,
+            },
+            fs: fs.clone(),
+            state: Mutex::new(InodeState {
+                pos,
+                entry,
+                chain: None,
+                listing: None,
+                removed: false,
+            }),
+        }
+    }
+
+    /// Adds a new inode with its short entry at `pos` to the inodes in
+    /// memory.
+    fn insert(fs: &Arc<FatFsInfo>, pos: u64, entry: ShortEntry) -> Arc<Self> {
+        let inode = Arc::new(Self::new(fs, Some(pos), entry));
+        fs.inodes.lock().insert(pos, Arc::downgrade(&inode));
+        inode
+    }
+
+    /// The inode whose short entry is at `pos`, from memory or read from the
+    /// entry. Callers hold the lock of the directory.
+    async fn load(fs: &Arc<FatFsInfo>, pos: u64) -> Result<Arc<Self>, Errno> {
+        let cached = fs.inodes.lock().get(&pos).and_then(Weak::upgrade);
+        if let Some(inode) = cached {
+            return Ok(inode);
+        }
+        let mut raw = [0; ENTRY_SIZE];
+        fs.read_data(pos, &mut raw).await?;
+        Ok(Self::insert(fs, pos, ShortEntry::parse(&raw)))
+    }
+
+    fn check_writable(&self) -> Result<(), Errno> {
+        match self.fs.readonly {
+            true => Err(Errno::EROFS),
+            false => Ok(()),
+        }
+    }
+
+    fn check_dir(&self) -> Result<(), Errno> {
+        match self.ty {
+            InodeType::Dir => Ok(()),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn check_file(&self) -> Result<(), Errno> {
+        match self.ty {
+            InodeType::Dir => Err(Errno::EISDIR),
+            _ => Ok(()),
+        }
+    }
+
+    /// Cluster of the directory as `..` entries refer to it, which is 0 for
+    /// the root.
+    fn dir_cluster(&self, state: &InodeState) -> u32 {
+        match self.ino {
+            ROOT_INO => 0,
+            _ => state.entry.cluster,
+        }
+    }
+
+    /// Zeroes bytes `from..to` of the file as far as clusters are allocated
+    /// for them, where data of an earlier, longer file may be left.
+    async fn zero_range(&self, chain: &[u32], from: u64, to: u64) -> Result<(), Errno> {
+        let cluster_size = self.fs.cluster_size as u64;
+        let to = to.min(chain.len() as u64 * cluster_size);
+        let zeros = vec![0; self.fs.cluster_size];
+        let mut pos = from;
+        while pos < to {
+            let offset = (pos % cluster_size) as usize;
+            let chunk = (cluster_size - offset as u64).min(to - pos) as usize;
+            let cluster = chain[(pos / cluster_size) as usize];
+            self.fs
+                .write_cluster(cluster, offset, &zeros[..chunk])
+                .await?;
+            pos += chunk as u64;
+        }
+        Ok(())
+    }
+
+    /// Allocates clusters until the file has room for `size` bytes.
+    /// Returns the room there is, which is less if the filesystem is full.
+    async fn grow(&self, state: &mut InodeState, size: u64) -> Result<u64, Errno> {
+        let cluster_size = self.fs.cluster_size as u64;
+        let chain = state.chain(&self.fs).await?;
+        while (chain.len() as u64) * cluster_size < size {
+            match self.fs.alloc_cluster(chain.last().copied()).await {
+                Ok(cluster) => chain.push(cluster),
+                Err(Errno::ENOSPC) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        let room = chain.len() as u64 * cluster_size;
+        state.entry.cluster = chain.first().copied().unwrap_or(0);
+        Ok(room)
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        let state = self.state.get_mut();
+        if let Some(pos) = state.pos {
+            let mut inodes = self.fs.inodes.lock();
+            if inodes
+                .get(&pos)
+                .is_some_and(|inode| inode.strong_count() == 0)
+            {
+                inodes.remove(&pos);
+            }
+        } else if state.removed && state.entry.cluster != 0 {
+            // freeing takes the allocation lock, which cannot be waited for
+            // here
+            let fs = self.fs.clone();
+            let cluster = state.entry.cluster;
+            spawn(async move {
+                if let Err(err) = fs.free_chain(cluster).await {
+                    warn!("vfat: cannot free clusters of a removed file: {:?}", err);
+                }
+            })
+            .detach();
+        }
+    }
+}
+
+/// Marks `inode`, whose entry at `pos` was removed, as gone.
+fn removed(fs: &FatFsInfo, state: &mut InodeState, pos: u64) {
+    state.pos = None;
+    state.removed = true;
+    fs.inodes.lock().remove(&pos);
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> FsFuture<'_, Metadata> {
+        Box::pin(async move {
+            let fs = &self.fs;
+            let mut state = self.state.lock().await;
+            let (size, nlink) = match self.ty {
+                InodeType::Dir => {
+                    let (chain, listing) = state.dir(fs).await?;
+                    let subdirs = listing
+                        .entries
+                        .iter()
+                        .filter(|entry| entry.is_visible() && entry.short.is_dir())
+                        .count();
+                    ((chain.len() * fs.cluster_size) as u64, 2 + subdirs as u32)
+                }
+                _ => (state.entry.size as u64, 1),
+            };
+            let entry = &state.entry;
+            let mut mode = 0o777 & !fs.options.umask;
+            if entry.attr & ATTR_READ_ONLY != 0 && self.ty == InodeType::File {
+                mode &= !0o222;
+            }
+            let mtime = from_fat_time(entry.mdate, entry.mtime);
+            let cluster_size = fs.cluster_size as u64;
+            Ok(Metadata {
+                dev: fs.dev,
+                ino: self.ino,
+                mode: self.ty.mode_bits() | mode,
+                nlink: if state.removed { 0 } else { nlink },
+                uid: fs.options.uid,
+                gid: fs.options.gid,
+                rdev: 0,
+                size,
+                blksize: fs.cluster_size as u32,
+                blocks: size.div_ceil(cluster_size) * cluster_size / 512,
+                atime: from_fat_time(entry.adate, 0),
+                mtime,
+                // FAT keeps no change time
+                ctime: mtime,
+            })
+        })
+    }
+
+    fn inode_type(&self) -> InodeType {
+        self.ty
+    }
+
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            self.check_file()?;
+            let mut state = self.state.lock().await;
+            let size = state.entry.size as u64;
+            if offset >= size {
+                return Ok(0);
+            }
+            let len = buf.len().min((size - offset) as usize);
+            let cluster_size = self.fs.cluster_size;
+            let chain = state.chain(&self.fs).await?;
+            let mut done = 0;
+            while done < len {
+                let pos = offset as usize + done;
+                let offset = pos % cluster_size;
+                let chunk = (cluster_size - offset).min(len - done);
+                let cluster = *chain.get(pos / cluster_size).ok_or(Errno::EIO)?;
+                self.fs
+                    .read_cluster(cluster, offset, &mut buf[done..done + chunk])
+                    .await?;
+                done += chunk;
+            }
+            Ok(len)
+        })
+    }
+
+    fn write_at<'a>(&'a self, offset: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            self.check_file()?;
+            self.check_writable()?;
+            if offset >= MAX_FILE_SIZE {
+                return Err(Errno::EFBIG);
+            }
+            let len = buf.len().min((MAX_FILE_SIZE - offset) as usize);
+            if len == 0 {
+                return Ok(0);
+            }
+            let mut state = self.state.lock().await;
+            let size = state.entry.size as u64;
+            if offset > size {
+                let chain = state.chain(&self.fs).await?;
+                self.zero_range(chain, size, offset).await?;
+            }
+            let room = self.grow(&mut state, offset + len as u64).await?;
+            let len = len.min(room.saturating_sub(offset) as usize);
+            if len == 0 {
+                return Err(Errno::ENOSPC);
+            }
+            let cluster_size = self.fs.cluster_size;
+            let chain = state.chain(&self.fs).await?;
+            let mut done = 0;
+            while done < len {
+                let pos = offset as usize + done;
+                let offset = pos % cluster_size;
+                let chunk = (cluster_size - offset).min(len - done);
+                let cluster = chain[pos / cluster_size];
+                self.fs
+                    .write_cluster(cluster, offset, &buf[done..done + chunk])
+                    .await?;
+                done += chunk;
+            }
+            state.entry.size = state.entry.size.max((offset + len as u64) as u32);
+            state.modified(&self.fs).await?;
+            Ok(len)
+        })
+    }
+
+    fn truncate(&self, size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            self.check_file()?;
+            self.check_writable()?;
+            if size > MAX_FILE_SIZE {
+                return Err(Errno::EFBIG);
+            }
+            let mut state = self.state.lock().await;
+            let old_size = state.entry.size as u64;
+            let cluster_size = self.fs.cluster_size as u64;
+            if size > old_size {
+                let chain = state.chain(&self.fs).await?;
+                self.zero_range(chain, old_size, size).await?;
+                if self.grow(&mut state, size).await? < size {
+                    return Err(Errno::ENOSPC);
+                }
+            } else {
+                let keep = size.div_ceil(cluster_size) as usize;
+                let chain = state.chain(&self.fs).await?;
+                if keep < chain.len() {
+                    match keep {
+                        0 => self.fs.free_chain(chain[0]).await?,
+                        _ => self.fs.truncate_chain(chain[keep - 1]).await?,
+                    }
+                    chain.truncate(keep);
+                    state.entry.cluster = chain.first().copied().unwrap_or(0);
+                }
+            }
+            state.entry.size = size as u32;
+            state.modified(&self.fs).await
+        })
+    }
+
+    /// FAT only knows whether a file is read-only, which is taken from the
+    /// write bits.
+    fn set_mode(&self, mode: u32) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            self.check_writable()?;
+            if self.ty != InodeType::File {
+                return Ok(());
+            }
+            let mut state = self.state.lock().await;
+            match mode & 0o222 {
+                0 => state.entry.attr |= ATTR_READ_ONLY,
+                _ => state.entry.attr &= !ATTR_READ_ONLY,
+            }
+            state.write_entry(&self.fs).await
+        })
+    }
+
+    fn set_times(&self, atime: Option<TimeSpec>, mtime: Option<TimeSpec>) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            self.check_writable()?;
+            let mut state = self.state.lock().await;
+            if let Some(mtime) = mtime {
+                (state.entry.mdate, state.entry.mtime) = to_fat_time(mtime);
+            }
+            if let Some(atime) = atime {
+                state.entry.adate = to_fat_time(atime).0;
+            }
+            state.write_entry(&self.fs).await
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            self.check_dir()?;
+            let mut state = self.state.lock().await;
+            let (_, pos) = state.find(&self.fs, name.trim_end_matches('.')).await?;
+            let inode: Arc<dyn Inode> = FatInode::load(&self.fs, pos).await?;
+            Ok(inode)
+        })
+    }
+
+    /// Only files and directories can be created: FAT has no other types.
+    fn create<'a>(
+        &'a self,
+        name: &'a str,
+        ty: InodeType,
+        mode: u32,
+        _rdev: u64,
+    ) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            self.check_dir()?;
+            let attr = match ty {
+                InodeType::File if mode & 0o222 == 0 => ATTR_ARCHIVE | ATTR_READ_ONLY,
+                InodeType::File => ATTR_ARCHIVE,
+                InodeType::Dir => ATTR_DIRECTORY,
+                _ => return Err(Errno::EPERM),
+            };
+            self.check_writable()?;
+            let name = check_name(name)?;
+            let fs = &self.fs;
+            let mut state = self.state.lock().await;
+            if state.removed {
+                return Err(Errno::ENOENT);
+            }
+            if state.find(fs, name).await.is_ok() {
+                return Err(Errno::EEXIST);
+            }
+            let now = TimeSpec::now();
+            let mut entry = ShortEntry::new(attr, 0, now);
+            if ty == InodeType::Dir {
+                let cluster = fs.alloc_cluster(None).await?;
+                entry.cluster = cluster;
+                let dot = ShortEntry {
+                    name: DOT_NAME,
+                    ..ShortEntry::new(ATTR_DIRECTORY, cluster, now)
+                };
+                let dotdot = ShortEntry {
+                    name: DOTDOT_NAME,
+                    ..ShortEntry::new(ATTR_DIRECTORY, self.dir_cluster(&state), now)
+                };
+                let mut dots = [0; 2 * ENTRY_SIZE];
+                dots[..ENTRY_SIZE].copy_from_slice(&dot.to_bytes());
+                dots[ENTRY_SIZE..].copy_from_slice(&dotdot.to_bytes());
+                if let Err(err) = fs.write_cluster(cluster, 0, &dots).await {
+                    fs.free_chain(cluster).await?;
+                    return Err(err);
+                }
+            }
+            let pos = match state.add_entry(fs, name, &mut entry).await {
+                Ok(pos) => pos,
+                Err(err) => {
+                    if entry.cluster != 0 {
+                        fs.free_chain(entry.cluster).await?;
+                    }
+                    return Err(err);
+                }
+            };
+            state.modified(fs).await?;
+            let inode: Arc<dyn Inode> = FatInode::insert(fs, pos, entry);
+            Ok(inode)
+        })
+    }
+
+    fn symlink<'a>(&'a self, _name: &'a str, _target: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        fs_err(Errno::EPERM)
+    }
+
+    fn link<'a>(&'a self, _name: &'a str, _target: &'a Arc<dyn Inode>) -> FsFuture<'a, ()> {
+        fs_err(Errno::EPERM)
+    }
+
+    /// The clusters of a removed file are freed once it is no longer open.
+    fn unlink<'a>(&'a self, name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            self.check_dir()?;
+            self.check_writable()?;
+            let fs = &self.fs;
+            // checking that a directory is empty locks a second directory
+            let _rename = fs.rename_lock.lock().await;
+            let mut state = self.state.lock().await;
+            let (slot, pos) = state.find(fs, name.trim_end_matches('.')).await?;
+            let inode = FatInode::load(fs, pos).await?;
+            let mut child = inode.state.lock().await;
+            if inode.ty == InodeType::Dir && !child.dir(fs).await?.1.is_empty() {
+                return Err(Errno::ENOTEMPTY);
+            }
+            state.remove_entry(fs, slot).await?;
+            removed(fs, &mut child, pos);
+            drop(child);
+            state.modified(fs).await
+        })
+    }
+
+    fn rename<'a>(
+        &'a self,
+        old: &'a str,
+        new_dir: &'a Arc<dyn Inode>,
+        new: &'a str,
+        flags: RenameFlags,
+    ) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            self.check_dir()?;
+            if flags.intersects(RenameFlags::EXCHANGE | RenameFlags::WHITEOUT) {
+                return Err(Errno::EINVAL);
+            }
+            let new_dir = new_dir
+                .as_any()
+                .downcast_ref::<FatInode>()
+                .filter(|dir| Arc::ptr_eq(&dir.fs, &self.fs))
+                .ok_or(Errno::EXDEV)?;
+            new_dir.check_dir()?;
+            self.check_writable()?;
+            let new = check_name(new)?;
+            let fs = &self.fs;
+            let _rename = fs.rename_lock.lock().await;
+            let mut src = self.state.lock().await;
+            let mut dst = match ptr::eq(self, new_dir) {
+                true => None,
+                false => Some(new_dir.state.lock().await),
+            };
+            rename_entry(
+                fs,
+                (self, &mut src),
+                (new_dir, dst.as_deref_mut()),
+                old.trim_end_matches('.'),
+                new,
+                flags,
+            )
+            .await
+        })
+    }
+
+    fn read_dir(&self, index: usize) -> FsFuture<'_, Option<DirEntry>> {
+        Box::pin(async move {
+            self.check_dir()?;
+            let fs = &self.fs;
+            let mut state = self.state.lock().await;
+            let (chain, listing) = state.dir(fs).await?;
+            let Some(entry) = listing
+                .entries
+                .iter()
+                .filter(|entry| entry.is_visible())
+                .nth(index)
+            else {
+                return Ok(None);
+            };
+            let pos = fs.slot_pos(chain, entry.index);
+            let cached = fs.inodes.lock().get(&pos).and_then(Weak::upgrade);
+            Ok(Some(DirEntry {
+                ino: cached.map_or(pos / ENTRY_SIZE as u64, |inode| inode.ino),
+                name: entry.name.clone(),
+                ty: match entry.short.is_dir() {
+                    true => InodeType::Dir,
+                    false => InodeType::File,
+                },
+            }))
+        })
+    }
+
+    fn sync(&self) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            BUFFER_CACHE.sync_device(&self.fs.device).await?;
+            Ok(())
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Moves entry `old` of directory `src` to `new` of `dst`, which is `src`
+/// itself if its state is `None`.
+async fn rename_entry(
+    fs: &Arc<FatFsInfo>,
+    (src_dir, src): (&FatInode, &mut InodeState),
+    (dst_dir, mut dst): (&FatInode, Option<&mut InodeState>),
+    old: &str,
+    new: &str,
+    flags: RenameFlags,
+) -> Result<(), Errno> {
+    if dst.as_deref().map_or(src.removed, |dst| dst.removed) {
+        return Err(Errno::ENOENT);
+    }
+    let (src_slot, src_pos) = src.find(fs, old).await?;
+    let source = FatInode::load(fs, src_pos).await?;
+    let target = match &mut dst {
+        Some(dst) => dst.find(fs, new).await,
+        None => src.find(fs, new).await,
+    };
+    match target {
+        // only the case of the name changes, or it stays the same
+        Ok((_, pos)) if pos == src_pos => {
+            let listing = src.dir(fs).await?.1;
+            if listing.find(old).is_some_and(|entry| entry.name == new) {
+                return Ok(());
+            }
+        }
+        Ok(_) if flags.contains(RenameFlags::NOREPLACE) => return Err(Errno::EEXIST),
+        Ok((slot, pos)) => {
+            let target = FatInode::load(fs, pos).await?;
+            // the target contains the source
+            if ptr::eq(&*target, src_dir) || ptr::eq(&*target, dst_dir) {
+                return Err(Errno::ENOTEMPTY);
+            }
+            let mut state = target.state.lock().await;
+            match (source.ty, target.ty) {
+                (InodeType::Dir, InodeType::Dir) if !state.dir(fs).await?.1.is_empty() => {
+                    return Err(Errno::ENOTEMPTY);
+                }
+                (InodeType::Dir, InodeType::Dir) => {}
+                (InodeType::Dir, _) => return Err(Errno::ENOTDIR),
+                (_, InodeType::Dir) => return Err(Errno::EISDIR),
+                _ => {}
+            }
+            match &mut dst {
+                Some(dst) => dst.remove_entry(fs, slot).await?,
+                None => src.remove_entry(fs, slot).await?,
+            }
+            removed(fs, &mut state, pos);
+        }
+        Err(Errno::ENOENT) => {}
+        Err(err) => return Err(err),
+    }
+
+    // the new entry is written before the old one is removed, so that the
+    // file is not lost if the directory cannot grow
+    let mut state = source.state.lock().await;
+    let new_pos = match &mut dst {
+        Some(dst) => dst.add_entry(fs, new, &mut state.entry).await?,
+        None => src.add_entry(fs, new, &mut state.entry).await?,
+    };
+    src.remove_entry(fs, src_slot).await?;
+    state.pos = Some(new_pos);
+    {
+        let mut inodes = fs.inodes.lock();
+        inodes.remove(&src_pos);
+        inodes.insert(new_pos, Arc::downgrade(&source));
+    }
+    if source.ty == InodeType::Dir && !ptr::eq(src_dir, dst_dir) {
+        let parent = match &dst {
+            Some(dst) => dst_dir.dir_cluster(dst),
+            None => src_dir.dir_cluster(src),
+        };
+        let chain = state.chain(fs).await?;
+        let dotdot = ShortEntry {
+            name: DOTDOT_NAME,
+            ..ShortEntry::new(ATTR_DIRECTORY, parent, TimeSpec::now())
+        };
+        if let Some(&first) = chain.first() {
+            fs.write_cluster(first, ENTRY_SIZE, &dotdot.to_bytes())
+                .await?;
+        }
+        state.listing = None;
+    }
+    drop(state);
+    src.modified(fs).await?;
+    if let Some(dst) = &mut dst {
+        dst.modified(fs).await?;
+    }
+    Ok(())
+}
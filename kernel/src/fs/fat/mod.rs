@@ -0,0 +1,550 @@
+mod dir;
+mod inode;
+
+pub use inode::*;
+
+use core::ops::Range;
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+
+use crate::{
+    Errno,
+    block::{BUFFER_CACHE, BlockDevice},
+    sync::{Mutex, SpinMutex},
+};
+
+use super::{FileSystemType, FsFuture, Inode, SuperBlock, alloc_anon_dev};
+
+pub const VFAT: FileSystemType = FileSystemType {
+    name: "vfat",
+    needs_device: true,
+    mount: mount_vfat,
+};
+
+/// Bits of a FAT entry holding the next cluster; the top four are reserved.
+const FAT_ENTRY_MASK: u32 = 0x0fff_ffff;
+/// Entries from this value on end a chain.
+const FAT_EOC_MIN: u32 = 0x0fff_fff8;
+/// The end of chain mark written.
+const FAT_EOC: u32 = 0x0fff_ffff;
+/// Number of the first cluster of the data region.
+const FIRST_CLUSTER: u32 = 2;
+
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIG: u32 = 0x6141_7272;
+/// Offset of the free cluster count in the FSInfo sector, followed by the
+/// cluster to search free ones from.
+const FSINFO_FREE_COUNT: usize = 488;
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Options of `mount`. FAT has no owners or permissions, so they apply to
+/// all files.
+#[derive(Debug, Clone, Copy)]
+pub struct FatOptions {
+    pub uid: u32,
+    pub gid: u32,
+    /// Permission bits cleared from the `0o777` of files and directories.
+    pub umask: u32,
+    pub readonly: bool,
+}
+
+impl Default for FatOptions {
+    fn default() -> Self {
+        Self {
+            uid: 0,
+            gid: 0,
+            umask: 0o022,
+            readonly: false,
+        }
+    }
+}
+
+impl FatOptions {
+    /// Parses `uid=`, `gid=`, `umask=`, `ro` and `rw` options separated by
+    /// commas.
+    pub fn parse(data: &str) -> Result<Self, Errno> {
+        let mut options = Self::default();
+        for option in data.split(',').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                None if option == "ro" => options.readonly = true,
+                None if option == "rw" => options.readonly = false,
+                Some(("uid", value)) => options.uid = value.parse().map_err(|_| Errno::EINVAL)?,
+                Some(("gid", value)) => options.gid = value.parse().map_err(|_| Errno::EINVAL)?,
+                Some(("umask", value)) => {
+                    options.umask =
+                        u32::from_str_radix(value, 8).map_err(|_| Errno::EINVAL)? & 0o777
+                }
+                _ => return Err(Errno::EINVAL),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Fields of the BIOS parameter block in the boot sector.
+struct Bpb {
+    bytes_per_sector: usize,
+    sectors_per_cluster: usize,
+    reserved_sectors: usize,
+    num_fats: usize,
+    total_sectors: usize,
+    fat_sectors: usize,
+    ext_flags: u16,
+    root_cluster: u32,
+    fs_info_sector: usize,
+}
+
+impl Bpb {
+    /// Parses the boot sector of a FAT32 filesystem. FAT12 and FAT16 are
+    /// rejected.
+    fn parse(sector: &[u8]) -> Result<Self, Errno> {
+        if sector[510..512] != [0x55, 0xaa] {
+            return Err(Errno::EINVAL);
+        }
+        let total_sectors = match u16_at(sector, 19) {
+            0 => u32_at(sector, 32) as usize,
+            count => count as usize,
+        };
+        let bpb = Self {
+            bytes_per_sector: u16_at(sector, 11) as usize,
+            sectors_per_cluster: sector[13] as usize,
+            reserved_sectors: u16_at(sector, 14) as usize,
+            num_fats: sector[16] as usize,
+            total_sectors,
+            fat_sectors: u32_at(sector, 36) as usize,
+            ext_flags: u16_at(sector, 40),
+            root_cluster: u32_at(sector, 44),
+            fs_info_sector: u16_at(sector, 48) as usize,
+        };
+        let root_entries = u16_at(sector, 17);
+        let fat_sectors_16 = u16_at(sector, 22);
+        if !bpb.bytes_per_sector.is_power_of_two()
+            || !(512..=4096).contains(&bpb.bytes_per_sector)
+            || !bpb.sectors_per_cluster.is_power_of_two()
+            || bpb.bytes_per_sector * bpb.sectors_per_cluster > 64 << 10
+            || bpb.reserved_sectors == 0
+            || bpb.num_fats == 0
+            || root_entries != 0
+            || fat_sectors_16 != 0
+            || bpb.fat_sectors == 0
+        {
+            return Err(Errno::EINVAL);
+        }
+        Ok(bpb)
+    }
+}
+
+/// Cluster allocation state, also kept in the FSInfo sector.
+struct AllocState {
+    free: u32,
+    /// Where the search for a free cluster starts.
+    next: u32,
+}
+
+/// State of a mounted FAT32 filesystem shared by its inodes.
+pub struct FatFsInfo {
+    device: Arc<dyn BlockDevice>,
+    dev: u64,
+    options: FatOptions,
+    readonly: bool,
+    sector_size: usize,
+    cluster_size: usize,
+    /// Byte offset of the first FAT on the device and size of a FAT.
+    fat_start: u64,
+    fat_size: u64,
+    /// FATs kept up to date: all of them, or the active one if mirroring is
+    /// disabled. Reads use the first.
+    fats: Range<usize>,
+    data_start: u64,
+    /// Size of the buffers the data region is accessed through: clusters if
+    /// they are aligned on the device, sectors otherwise.
+    data_block: usize,
+    /// One past the last cluster.
+    end_cluster: u32,
+    root_cluster: u32,
+    /// Byte offset of the FSInfo sector, if the filesystem has a valid one.
+    fs_info: Option<u64>,
+    /// Serializes changes of the FATs.
+    alloc: Mutex<AllocState>,
+    /// Inodes in memory by position of their short entry, so that all
+    /// lookups of a file share one inode.
+    inodes: SpinMutex<BTreeMap<u64, Weak<FatInode>>>,
+    /// Serializes the operations locking more than one directory, renames
+    /// and removals.
+    rename_lock: Mutex<()>,
+}
+
+impl FatFsInfo {
+    async fn new(device: Arc<dyn BlockDevice>, options: FatOptions) -> Result<Arc<Self>, Errno> {
+        if device.block_size() < 512 {
+            return Err(Errno::EINVAL);
+        }
+        let mut boot = vec![0; device.block_size()];
+        device.read_blocks(0, &mut boot).await?;
+        let bpb = Bpb::parse(&boot)?;
+        let sector_size = bpb.bytes_per_sector;
+        let cluster_size = sector_size * bpb.sectors_per_cluster;
+        if sector_size % device.block_size() != 0
+            || bpb.total_sectors * sector_size > device.num_blocks() * device.block_size()
+        {
+            return Err(Errno::EINVAL);
+        }
+        let data_sector = bpb.reserved_sectors + bpb.num_fats * bpb.fat_sectors;
+        let clusters = (bpb.total_sectors.saturating_sub(data_sector) / bpb.sectors_per_cluster)
+            .min(bpb.fat_sectors * sector_size / 4 - FIRST_CLUSTER as usize)
+            .min((FAT_EOC_MIN - 1 - FIRST_CLUSTER) as usize);
+        let end_cluster = FIRST_CLUSTER + clusters as u32;
+        if !(FIRST_CLUSTER..end_cluster).contains(&bpb.root_cluster) {
+            return Err(Errno::EINVAL);
+        }
+        // bit 7 disables mirroring, leaving the FAT in the low bits active
+        let fats = match bpb.ext_flags & 0x80 {
+            0 => 0..bpb.num_fats,
+            _ => {
+                let active = (bpb.ext_flags & 0xf) as usize;
+                if active >= bpb.num_fats {
+                    return Err(Errno::EINVAL);
+                }
+                active..active + 1
+            }
+        };
+        let data_start = (data_sector * sector_size) as u64;
+        let mut fs = Self {
+            dev: alloc_anon_dev(),
+            options,
+            readonly: options.readonly || device.is_readonly(),
+            device,
+            sector_size,
+            cluster_size,
+            fat_start: (bpb.reserved_sectors * sector_size) as u64,
+            fat_size: (bpb.fat_sectors * sector_size) as u64,
+            fats,
+            data_start,
+            data_block: match data_start % cluster_size as u64 {
+                0 => cluster_size,
+                _ => sector_size,
+            },
+            end_cluster,
+            root_cluster: bpb.root_cluster,
+            fs_info: None,
+            alloc: Mutex::new(AllocState {
+                free: FSINFO_UNKNOWN,
+                next: FIRST_CLUSTER,
+            }),
+            inodes: SpinMutex::new(BTreeMap::new()),
+            rename_lock: Mutex::new(()),
+        };
+
+        let (mut free, mut next) = (FSINFO_UNKNOWN, FIRST_CLUSTER);
+        if (1..bpb.reserved_sectors).contains(&bpb.fs_info_sector) {
+            let pos = (bpb.fs_info_sector * sector_size) as u64;
+            let mut sector = vec![0; 512];
+            fs.read_bytes(pos, &mut sector, sector_size).await?;
+            if u32_at(&sector, 0) == FSINFO_LEAD_SIG && u32_at(&sector, 484) == FSINFO_STRUCT_SIG {
+                fs.fs_info = Some(pos);
+                free = u32_at(&sector, FSINFO_FREE_COUNT);
+                next = u32_at(&sector, FSINFO_FREE_COUNT + 4);
+            }
+        }
+        if !fs.is_data_cluster(next) {
+            next = FIRST_CLUSTER;
+        }
+        // unknown or wrong
+        if free > clusters as u32 {
+            free = fs.count_free().await?;
+        }
+        *fs.alloc.get_mut() = AllocState { free, next };
+        Ok(Arc::new(fs))
+    }
+
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..self.end_cluster).contains(&cluster)
+    }
+
+    /// Reads `buf` from byte `pos` of the device through the cache, in
+    /// buffers of `block_size` bytes.
+    async fn read_bytes(&self, pos: u64, buf: &mut [u8], block_size: usize) -> Result<(), Errno> {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = pos + done as u64;
+            let offset = (pos % block_size as u64) as usize;
+            let chunk = (block_size - offset).min(buf.len() - done);
+            let block = (pos / block_size as u64) as usize;
+            let buffer = BUFFER_CACHE.bread(&self.device, block, block_size).await?;
+            buf[done..done + chunk].copy_from_slice(&buffer.read().await[offset..][..chunk]);
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` to byte `pos` of the device through the cache, in
+    /// buffers of `block_size` bytes.
+    async fn write_bytes(&self, pos: u64, buf: &[u8], block_size: usize) -> Result<(), Errno> {
+        if self.readonly {
+            return Err(Errno::EROFS);
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = pos + done as u64;
+            let offset = (pos % block_size as u64) as usize;
+            let chunk = (block_size - offset).min(buf.len() - done);
+            let block = (pos / block_size as u64) as usize;
+            let buffer = match chunk == block_size {
+                true => BUFFER_CACHE.getblk(&self.device, block, block_size),
+                false => BUFFER_CACHE.bread(&self.device, block, block_size).await?,
+            };
+            buffer.write().await[offset..][..chunk].copy_from_slice(&buf[done..done + chunk]);
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Reads from byte `pos` of the data region, e.g. a directory entry.
+    async fn read_data(&self, pos: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        self.read_bytes(pos, buf, self.data_block).await
+    }
+
+    async fn write_data(&self, pos: u64, buf: &[u8]) -> Result<(), Errno> {
+        self.write_bytes(pos, buf, self.data_block).await
+    }
+
+    /// Byte offset of `cluster` on the device.
+    fn cluster_pos(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - FIRST_CLUSTER) as u64 * self.cluster_size as u64
+    }
+
+    /// Byte offset of entry `slot` of the directory stored in `chain`.
+    fn slot_pos(&self, chain: &[u32], slot: usize) -> u64 {
+        let offset = slot * dir::ENTRY_SIZE;
+        self.cluster_pos(chain[offset / self.cluster_size]) + (offset % self.cluster_size) as u64
+    }
+
+    async fn read_cluster(&self, cluster: u32, offset: usize, buf: &mut [u8]) -> Result<(), Errno> {
+        self.read_data(self.cluster_pos(cluster) + offset as u64, buf)
+            .await
+    }
+
+    async fn write_cluster(&self, cluster: u32, offset: usize, buf: &[u8]) -> Result<(), Errno> {
+        self.write_data(self.cluster_pos(cluster) + offset as u64, buf)
+            .await
+    }
+
+    /// Position of the entry of `cluster` in FAT `fat`.
+    fn fat_pos(&self, fat: usize, cluster: u32) -> u64 {
+        self.fat_start + fat as u64 * self.fat_size + cluster as u64 * 4
+    }
+
+    async fn fat_get(&self, cluster: u32) -> Result<u32, Errno> {
+        let mut entry = [0; 4];
+        let pos = self.fat_pos(self.fats.start, cluster);
+        self.read_bytes(pos, &mut entry, self.sector_size).await?;
+        Ok(u32::from_le_bytes(entry) & FAT_ENTRY_MASK)
+    }
+
+    /// Sets the entry of `cluster` in the FATs, keeping its reserved bits.
+    /// Callers hold the allocation lock.
+    async fn fat_set(&self, cluster: u32, value: u32) -> Result<(), Errno> {
+        for fat in self.fats.clone() {
+            let pos = self.fat_pos(fat, cluster);
+            let mut entry = [0; 4];
+            self.read_bytes(pos, &mut entry, self.sector_size).await?;
+            let entry = (u32::from_le_bytes(entry) & !FAT_ENTRY_MASK) | value;
+            self.write_bytes(pos, &entry.to_le_bytes(), self.sector_size)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with the clusters of `range` and their FAT entries a
+    /// sector at a time until it returns a cluster.
+    async fn scan_fat(
+        &self,
+        range: Range<u32>,
+        mut f: impl FnMut(u32, u32) -> Option<u32>,
+    ) -> Result<Option<u32>, Errno> {
+        let per_sector = (self.sector_size / 4) as u32;
+        let mut sector = vec![0; self.sector_size];
+        let mut cluster = range.start;
+        while cluster < range.end {
+            let first = cluster - cluster % per_sector;
+            let pos = self.fat_pos(self.fats.start, first);
+            self.read_bytes(pos, &mut sector, self.sector_size).await?;
+            let end = (first + per_sector).min(range.end);
+            for cluster in cluster..end {
+                let entry = u32_at(&sector, (cluster - first) as usize * 4) & FAT_ENTRY_MASK;
+                if let Some(found) = f(cluster, entry) {
+                    return Ok(Some(found));
+                }
+            }
+            cluster = end;
+        }
+        Ok(None)
+    }
+
+    async fn count_free(&self) -> Result<u32, Errno> {
+        let mut free = 0;
+        self.scan_fat(FIRST_CLUSTER..self.end_cluster, |_, entry| {
+            free += (entry == 0) as u32;
+            None
+        })
+        .await?;
+        Ok(free)
+    }
+
+    /// Finds a free cluster from `start` on, wrapping around at the end.
+    async fn find_free(&self, start: u32) -> Result<Option<u32>, Errno> {
+        let is_free = |cluster, entry| (entry == 0).then_some(cluster);
+        match self.scan_fat(start..self.end_cluster, is_free).await? {
+            Some(cluster) => Ok(Some(cluster)),
+            None => self.scan_fat(FIRST_CLUSTER..start, is_free).await,
+        }
+    }
+
+    /// Writes the allocation state to the FSInfo sector, if there is one.
+    async fn write_fs_info(&self, alloc: &AllocState) -> Result<(), Errno> {
+        let Some(pos) = self.fs_info else {
+            return Ok(());
+        };
+        let mut fields = [0; 8];
+        fields[..4].copy_from_slice(&alloc.free.to_le_bytes());
+        fields[4..].copy_from_slice(&alloc.next.to_le_bytes());
+        self.write_bytes(pos + FSINFO_FREE_COUNT as u64, &fields, self.sector_size)
+            .await
+    }
+
+    /// Allocates a zeroed cluster and appends it to the chain ending with
+    /// `last`, if any.
+    async fn alloc_cluster(&self, last: Option<u32>) -> Result<u32, Errno> {
+        if self.readonly {
+            return Err(Errno::EROFS);
+        }
+        let mut alloc = self.alloc.lock().await;
+        if alloc.free == 0 {
+            return Err(Errno::ENOSPC);
+        }
+        let Some(cluster) = self.find_free(alloc.next).await? else {
+            alloc.free = 0;
+            return Err(Errno::ENOSPC);
+        };
+        self.fat_set(cluster, FAT_EOC).await?;
+        if let Some(last) = last {
+            self.fat_set(last, cluster).await?;
+        }
+        alloc.free -= 1;
+        alloc.next = match cluster + 1 {
+            next if next < self.end_cluster => next,
+            _ => FIRST_CLUSTER,
+        };
+        self.write_fs_info(&alloc).await?;
+        drop(alloc);
+        self.write_cluster(cluster, 0, &vec![0; self.cluster_size])
+            .await?;
+        Ok(cluster)
+    }
+
+    /// Frees the clusters of the chain from `first` on.
+    async fn free_chain(&self, first: u32) -> Result<(), Errno> {
+        let mut alloc = self.alloc.lock().await;
+        let mut cluster = first;
+        let mut freed = 0;
+        while self.is_data_cluster(cluster) && freed < self.end_cluster {
+            let next = self.fat_get(cluster).await?;
+            self.fat_set(cluster, 0).await?;
+            freed += 1;
+            cluster = next;
+        }
+        alloc.free = (alloc.free + freed).min(self.end_cluster - FIRST_CLUSTER);
+        self.write_fs_info(&alloc).await
+    }
+
+    /// Ends a chain at `last`, freeing the clusters after it.
+    async fn truncate_chain(&self, last: u32) -> Result<(), Errno> {
+        let alloc = self.alloc.lock().await;
+        let next = self.fat_get(last).await?;
+        self.fat_set(last, FAT_EOC).await?;
+        drop(alloc);
+        match self.is_data_cluster(next) {
+            true => self.free_chain(next).await,
+            false => Ok(()),
+        }
+    }
+
+    /// The clusters of the chain starting with `first`, which is empty for
+    /// cluster 0, the first cluster of empty files.
+    async fn chain(&self, first: u32) -> Result<Vec<u32>, Errno> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 && cluster < FAT_EOC_MIN {
+            // a free or bad cluster, or a loop
+            if !self.is_data_cluster(cluster) || chain.len() >= self.end_cluster as usize {
+                return Err(Errno::EIO);
+            }
+            chain.push(cluster);
+            cluster = self.fat_get(cluster).await?;
+        }
+        Ok(chain)
+    }
+}
+
+/// A FAT32 filesystem on a block device, with long file names.
+pub struct FatFs {
+    info: Arc<FatFsInfo>,
+    root: Arc<FatInode>,
+}
+
+impl FatFs {
+    pub async fn new(
+        device: Arc<dyn BlockDevice>,
+        options: FatOptions,
+    ) -> Result<Arc<Self>, Errno> {
+        let info = FatFsInfo::new(device, options).await?;
+        let root = FatInode::root(&info);
+        Ok(Arc::new(Self { info, root }))
+    }
+
+    /// Bytes free and the size of the data region.
+    pub async fn usage(&self) -> (u64, u64) {
+        let free = self.info.alloc.lock().await.free as u64;
+        let clusters = (self.info.end_cluster - FIRST_CLUSTER) as u64;
+        let cluster_size = self.info.cluster_size as u64;
+        (free * cluster_size, clusters * cluster_size)
+    }
+}
+
+impl SuperBlock for FatFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            BUFFER_CACHE.sync_device(&self.info.device).await?;
+            Ok(())
+        })
+    }
+}
+
+fn mount_vfat(
+    device: Option<Arc<dyn BlockDevice>>,
+    data: &str,
+) -> FsFuture<'_, Arc<dyn SuperBlock>> {
+    Box::pin(async move {
+        let device = device.ok_or(Errno::EINVAL)?;
+        let fs: Arc<dyn SuperBlock> = FatFs::new(device, FatOptions::parse(data)?).await?;
+        Ok(fs)
+    })
+}
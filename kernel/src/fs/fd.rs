@@ -0,0 +1,128 @@
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::Errno;
+
+use super::File;
+
+/// `FD_CLOEXEC` of `F_GETFD` and `F_SETFD`.
+pub const FD_CLOEXEC: usize = 1;
+
+/// A descriptor: the open file it refers to and its own flags.
+#[derive(Clone)]
+pub struct FdEntry {
+    pub file: Arc<dyn File>,
+    /// Closed by `execve`.
+    pub cloexec: bool,
+}
+
+/// The open files of a process by descriptor. Tasks created with
+/// `CLONE_FILES` share the table, others get a copy referring to the same
+/// open files.
+#[derive(Clone, Default)]
+pub struct FdTable {
+    entries: Vec<Option<FdEntry>>,
+}
+
+impl FdTable {
+    /// Installs `file` at the lowest free descriptor not below `min`.
+    /// Descriptors must stay below `limit`, the `RLIMIT_NOFILE` of the
+    /// caller.
+    pub fn alloc_from(
+        &mut self,
+        min: usize,
+        file: Arc<dyn File>,
+        cloexec: bool,
+        limit: usize,
+    ) -> Result<usize, Errno> {
+        let fd = (min..)
+            .find(|&fd| self.entries.get(fd).is_none_or(Option::is_none))
+            .unwrap();
+        if fd >= limit {
+            return Err(Errno::EMFILE);
+        }
+        self.insert(fd, FdEntry { file, cloexec });
+        Ok(fd)
+    }
+
+    /// Installs `file` at the lowest free descriptor.
+    pub fn alloc(
+        &mut self,
+        file: Arc<dyn File>,
+        cloexec: bool,
+        limit: usize,
+    ) -> Result<usize, Errno> {
+        self.alloc_from(0, file, cloexec, limit)
+    }
+
+    /// Installs `entry` at `fd`, returning what was there, for `dup3`.
+    pub fn replace(
+        &mut self,
+        fd: usize,
+        entry: FdEntry,
+        limit: usize,
+    ) -> Result<Option<FdEntry>, Errno> {
+        if fd >= limit {
+            return Err(Errno::EBADF);
+        }
+        Ok(self.insert(fd, entry))
+    }
+
+    fn insert(&mut self, fd: usize, entry: FdEntry) -> Option<FdEntry> {
+        if fd >= self.entries.len() {
+            self.entries.resize(fd + 1, None);
+        }
+        self.entries[fd].replace(entry)
+    }
+
+    pub fn entry(&self, fd: usize) -> Result<&FdEntry, Errno> {
+        self.entries
+            .get(fd)
+            .and_then(Option::as_ref)
+            .ok_or(Errno::EBADF)
+    }
+
+    pub fn get(&self, fd: usize) -> Result<Arc<dyn File>, Errno> {
+        Ok(self.entry(fd)?.file.clone())
+    }
+
+    pub fn set_cloexec(&mut self, fd: usize, cloexec: bool) -> Result<(), Errno> {
+        let entry = self
+            .entries
+            .get_mut(fd)
+            .and_then(Option::as_mut)
+            .ok_or(Errno::EBADF)?;
+        entry.cloexec = cloexec;
+        Ok(())
+    }
+
+    /// Removes descriptor `fd`, returning its file.
+    pub fn close(&mut self, fd: usize) -> Result<Arc<dyn File>, Errno> {
+        let entry = self.entries.get_mut(fd).and_then(Option::take);
+        self.shrink();
+        entry.map(|entry| entry.file).ok_or(Errno::EBADF)
+    }
+
+    /// Closes the descriptors marked close-on-exec, as `execve` does.
+    pub fn close_on_exec(&mut self) {
+        for slot in &mut self.entries {
+            if slot.as_ref().is_some_and(|entry| entry.cloexec) {
+                *slot = None;
+            }
+        }
+        self.shrink();
+    }
+
+    /// Open descriptors in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &FdEntry)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(fd, entry)| Some((fd, entry.as_ref()?)))
+    }
+
+    fn shrink(&mut self) {
+        while self.entries.last().is_some_and(Option::is_none) {
+            self.entries.pop();
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use alloc::{boxed::Box, sync::Arc};
+
+use crate::{Errno, SysResult, mem::VirtAddr, sync::Mutex};
+
+use super::{FsFuture, Inode, InodeType, Metadata, Path, fs_err, parent_of};
+
+bitflags::bitflags! {
+    /// Flags of `openat`, as in Linux.
+    pub struct OpenFlags: u32 {
+        const WRONLY = 0o1;
+        const RDWR = 0o2;
+        const CREAT = 0o100;
+        const EXCL = 0o200;
+        const NOCTTY = 0o400;
+        const TRUNC = 0o1000;
+        const APPEND = 0o2000;
+        const NONBLOCK = 0o4000;
+        const DIRECTORY = 0o200000;
+        const NOFOLLOW = 0o400000;
+        const CLOEXEC = 0o2000000;
+        const PATH = 0o10000000;
+    }
+}
+
+impl OpenFlags {
+    /// The flags `F_SETFL` can change.
+    pub const SETFL_MASK: Self = Self::APPEND.union(Self::NONBLOCK);
+
+    pub fn readable(self) -> bool {
+        !self.contains(Self::WRONLY) && !self.contains(Self::PATH)
+    }
+
+    pub fn writable(self) -> bool {
+        self.intersects(Self::WRONLY | Self::RDWR) && !self.contains(Self::PATH)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// An open file description: what a file descriptor refers to. It holds
+/// the state of one `open`, e.g. the position, shared by duplicated
+/// descriptors.
+pub trait File: Send + Sync {
+    fn read<'a>(&'a self, _buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        fs_err(Errno::EINVAL)
+    }
+
+    fn write<'a>(&'a self, _buf: &'a [u8]) -> FsFuture<'a, usize> {
+        fs_err(Errno::EINVAL)
+    }
+
+    fn seek(&self, _pos: SeekFrom) -> FsFuture<'_, u64> {
+        fs_err(Errno::ESPIPE)
+    }
+
+    fn stat(&self) -> FsFuture<'_, Metadata>;
+
+    /// Fills `buf` with `struct linux_dirent64` records, returning the bytes
+    /// used. Entries which do not fit are left for the next call.
+    fn getdents<'a>(&'a self, _buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        fs_err(Errno::ENOTDIR)
+    }
+
+    fn ioctl(&self, _request: usize, _arg: VirtAddr) -> SysResult {
+        Err(Errno::ENOTTY)
+    }
+
+    /// Writes cached data back to the device.
+    fn sync(&self) -> FsFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// The status flags the file was opened with.
+    fn flags(&self) -> OpenFlags;
+
+    /// Changes the flags in [`OpenFlags::SETFL_MASK`].
+    fn set_flags(&self, flags: OpenFlags);
+
+    /// Where the file was opened, for `fchdir` and `*at` calls relative to a
+    /// directory descriptor.
+    fn path(&self) -> Option<Path> {
+        None
+    }
+}
+
+/// Stores the status flags of a file.
+pub struct FileFlags(AtomicU32);
+
+impl FileFlags {
+    pub fn new(flags: OpenFlags) -> Self {
+        Self(AtomicU32::new(flags.bits()))
+    }
+
+    pub fn get(&self) -> OpenFlags {
+        OpenFlags::from_bits_truncate(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, flags: OpenFlags) {
+        let old = self.get();
+        let new = (old - OpenFlags::SETFL_MASK) | (flags & OpenFlags::SETFL_MASK);
+        self.0.store(new.bits(), Ordering::Relaxed);
+    }
+}
+
+/// A regular file, directory or symlink opened through the VFS, accessed
+/// through the operations of its inode.
+pub struct InodeFile {
+    path: Path,
+    flags: FileFlags,
+    /// Byte offset, or entry index for directories. Held while an operation
+    /// uses it, so that those sharing the description do not lose updates.
+    pos: Mutex<u64>,
+}
+
+impl InodeFile {
+    pub fn new(path: Path, flags: OpenFlags) -> Self {
+        Self {
+            path,
+            flags: FileFlags::new(flags),
+            pos: Mutex::new(0),
+        }
+    }
+
+    fn inode(&self) -> &Arc<dyn Inode> {
+        self.path.dentry.inode()
+    }
+}
+
+/// Size of `struct linux_dirent64` without the name.
+const DIRENT64_HEADER: usize = 19;
+
+impl File for InodeFile {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if !self.flags().readable() {
+                return Err(Errno::EBADF);
+            }
+            let mut pos = self.pos.lock().await;
+            let n = self.inode().read_at(*pos, buf).await?;
+            *pos += n as u64;
+            Ok(n)
+        })
+    }
+
+    fn write<'a>(&'a self, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if !self.flags().writable() {
+                return Err(Errno::EBADF);
+            }
+            let mut pos = self.pos.lock().await;
+            if self.flags().contains(OpenFlags::APPEND) {
+                *pos = self.inode().metadata().await?.size;
+            }
+            let n = self.inode().write_at(*pos, buf).await?;
+            *pos += n as u64;
+            Ok(n)
+        })
+    }
+
+    fn seek(&self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        Box::pin(async move {
+            let mut current = self.pos.lock().await;
+            let base = match pos {
+                SeekFrom::Start(offset) => {
+                    *current = offset;
+                    return Ok(offset);
+                }
+                SeekFrom::Current(offset) => (*current, offset),
+                SeekFrom::End(offset) => (self.inode().metadata().await?.size, offset),
+            };
+            let new = base.0.checked_add_signed(base.1).ok_or(Errno::EINVAL)?;
+            *current = new;
+            Ok(new)
+        })
+    }
+
+    fn stat(&self) -> FsFuture<'_, Metadata> {
+        self.inode().metadata()
+    }
+
+    fn getdents<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if self.inode().inode_type() != InodeType::Dir {
+                return Err(Errno::ENOTDIR);
+            }
+            let mut written = 0;
+            let mut pos = self.pos.lock().await;
+            let mut index = *pos as usize;
+            loop {
+                // `.` and `..` come first, then the entries of the inode
+                let (ino, name, ty) = match index {
+                    0 => (self.stat().await?.ino, ".".into(), InodeType::Dir),
+                    1 => {
+                        let parent = parent_of(&self.path);
+                        let ino = parent.dentry.inode().metadata().await?.ino;
+                        (ino, "..".into(), InodeType::Dir)
+                    }
+                    _ => match self.inode().read_dir(index - 2).await? {
+                        Some(entry) => (entry.ino, entry.name, entry.ty),
+                        None => break,
+                    },
+                };
+                let reclen = (DIRENT64_HEADER + name.len() + 1).next_multiple_of(8);
+                if written + reclen > buf.len() {
+                    if written == 0 {
+                        return Err(Errno::EINVAL);
+                    }
+                    break;
+                }
+                let record = &mut buf[written..written + reclen];
+                record.fill(0);
+                record[0..8].copy_from_slice(&ino.to_ne_bytes());
+                record[8..16].copy_from_slice(&(index as i64 + 1).to_ne_bytes());
+                record[16..18].copy_from_slice(&(reclen as u16).to_ne_bytes());
+                record[18] = ty.dirent_type();
+                record[DIRENT64_HEADER..DIRENT64_HEADER + name.len()]
+                    .copy_from_slice(name.as_bytes());
+                written += reclen;
+                index += 1;
+            }
+            *pos = index as u64;
+            Ok(written)
+        })
+    }
+
+    fn sync(&self) -> FsFuture<'_, ()> {
+        self.inode().sync()
+    }
+
+    fn flags(&self) -> OpenFlags {
+        self.flags.get()
+    }
+
+    fn set_flags(&self, flags: OpenFlags) {
+        self.flags.set(flags);
+    }
+
+    fn path(&self) -> Option<Path> {
+        Some(self.path.clone())
+    }
+}
@@ -0,0 +1,138 @@
+use alloc::{collections::BTreeMap, string::String, sync::Arc};
+use log::{info, warn};
+
+use crate::{Errno, runtime::TimeSpec};
+
+use super::{Inode, InodeType, Path, makedev, resolve_parent};
+
+const NEWC_MAGIC: &[u8; 6] = b"070701";
+const NEWC_CRC_MAGIC: &[u8; 6] = b"070702";
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+/// Header of an entry of a `newc` cpio archive, with the fields in the
+/// order of the archive.
+struct CpioHeader {
+    ino: u32,
+    mode: u32,
+    nlink: u32,
+    mtime: u32,
+    file_size: usize,
+    rdev_major: u32,
+    rdev_minor: u32,
+    name_size: usize,
+}
+
+fn parse_header(header: &[u8]) -> Option<CpioHeader> {
+    if &header[..6] != NEWC_MAGIC && &header[..6] != NEWC_CRC_MAGIC {
+        return None;
+    }
+    let field = |index: usize| {
+        let hex = core::str::from_utf8(&header[6 + 8 * index..][..8]).ok()?;
+        u32::from_str_radix(hex, 16).ok()
+    };
+    Some(CpioHeader {
+        ino: field(0)?,
+        mode: field(1)?,
+        nlink: field(4)?,
+        mtime: field(5)?,
+        file_size: field(6)? as usize,
+        rdev_major: field(9)?,
+        rdev_minor: field(10)?,
+        name_size: field(11)? as usize,
+    })
+}
+
+/// Unpacks the `newc` cpio archive `archive`, the format of Linux initramfs
+/// images, into directory `root`.
+pub async fn unpack_initrd(root: &Path, archive: &[u8]) -> Result<(), Errno> {
+    // inodes of files with several links by inode number in the archive
+    let mut links: BTreeMap<u32, Arc<dyn Inode>> = BTreeMap::new();
+    let mut offset = 0;
+    let mut count = 0;
+    loop {
+        let header = archive
+            .get(offset..offset + HEADER_LEN)
+            .ok_or(Errno::EINVAL)?;
+        let header = parse_header(header).ok_or(Errno::EINVAL)?;
+        let name_start = offset + HEADER_LEN;
+        let name = archive
+            .get(name_start..name_start + header.name_size.saturating_sub(1))
+            .ok_or(Errno::EINVAL)?;
+        let name = core::str::from_utf8(name).map_err(|_| Errno::EINVAL)?;
+        let data_start = (name_start + header.name_size).next_multiple_of(4);
+        let data = archive
+            .get(data_start..data_start + header.file_size)
+            .ok_or(Errno::EINVAL)?;
+        offset = (data_start + header.file_size).next_multiple_of(4);
+        if name == TRAILER {
+            break;
+        }
+        let name = name.trim_start_matches("./").trim_start_matches('/');
+        if name.is_empty() || name == "." {
+            continue;
+        }
+        if let Err(err) = unpack_entry(root, name, &header, data, &mut links).await {
+            warn!("initrd: cannot unpack {}: {:?}", name, err);
+        }
+        count += 1;
+    }
+    info!("initrd: unpacked {} entries", count);
+    Ok(())
+}
+
+async fn unpack_entry(
+    root: &Path,
+    name: &str,
+    header: &CpioHeader,
+    data: &[u8],
+    links: &mut BTreeMap<u32, Arc<dyn Inode>>,
+) -> Result<(), Errno> {
+    let ty = InodeType::from_mode(header.mode).ok_or(Errno::EINVAL)?;
+    let (dir, name) = resolve_parent(root, name).await?;
+    let inode = match links.get(&header.ino) {
+        // a further link to a file seen before; the data comes with the last
+        Some(inode) if ty != InodeType::Dir => {
+            dir.dentry.inode().link(&name, inode).await?;
+            inode.clone()
+        }
+        _ => match ty {
+            InodeType::Symlink => {
+                let target = String::from_utf8(data.into()).map_err(|_| Errno::EINVAL)?;
+                dir.dentry.inode().symlink(&name, &target).await?
+            }
+            InodeType::Dir => match dir.dentry.lookup(&name).await {
+                // e.g. `dev` unpacked over a directory created earlier
+                Ok(existing) if existing.inode().inode_type() == InodeType::Dir => {
+                    existing.inode().clone()
+                }
+                _ => dir.dentry.inode().create(&name, ty, header.mode, 0).await?,
+            },
+            _ => {
+                let rdev = makedev(header.rdev_major, header.rdev_minor);
+                dir.dentry
+                    .inode()
+                    .create(&name, ty, header.mode, rdev)
+                    .await?
+            }
+        },
+    };
+    dir.dentry.insert(&name, inode.clone());
+    if ty == InodeType::File && header.nlink > 1 {
+        links.insert(header.ino, inode.clone());
+    }
+    if ty == InodeType::File && !data.is_empty() {
+        let written = inode.write_at(0, data).await?;
+        if written < data.len() {
+            return Err(Errno::ENOSPC);
+        }
+    }
+    let mtime = TimeSpec {
+        tv_sec: header.mtime as i64,
+        tv_nsec: 0,
+    };
+    if ty != InodeType::Symlink {
+        inode.set_times(Some(mtime), Some(mtime)).await?;
+    }
+    Ok(())
+}
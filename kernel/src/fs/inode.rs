@@ -0,0 +1,222 @@
+use core::any::Any;
+
+use alloc::{string::String, sync::Arc};
+
+use crate::{Errno, runtime::TimeSpec};
+
+use super::{File, FsFuture, OpenFlags, fs_err};
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFSOCK: u32 = 0o140000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFBLK: u32 = 0o060000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFIFO: u32 = 0o010000;
+
+/// Encodes a device number the way Linux reports it in `stat`.
+pub const fn makedev(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (major as u64, minor as u64);
+    (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12) | ((major & !0xfff) << 32)
+}
+
+/// The major number of device number `dev`, the inverse of [`makedev`].
+pub const fn major(dev: u64) -> u32 {
+    (((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff)) as u32
+}
+
+/// The minor number of device number `dev`, the inverse of [`makedev`].
+pub const fn minor(dev: u64) -> u32 {
+    ((dev & 0xff) | ((dev >> 12) & !0xff)) as u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeType {
+    File,
+    Dir,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+}
+
+impl InodeType {
+    /// The `S_IFMT` bits of the type.
+    pub fn mode_bits(self) -> u32 {
+        match self {
+            Self::File => S_IFREG,
+            Self::Dir => S_IFDIR,
+            Self::Symlink => S_IFLNK,
+            Self::CharDevice => S_IFCHR,
+            Self::BlockDevice => S_IFBLK,
+            Self::Fifo => S_IFIFO,
+            Self::Socket => S_IFSOCK,
+        }
+    }
+
+    pub fn from_mode(mode: u32) -> Option<Self> {
+        match mode & S_IFMT {
+            S_IFREG => Some(Self::File),
+            S_IFDIR => Some(Self::Dir),
+            S_IFLNK => Some(Self::Symlink),
+            S_IFCHR => Some(Self::CharDevice),
+            S_IFBLK => Some(Self::BlockDevice),
+            S_IFIFO => Some(Self::Fifo),
+            S_IFSOCK => Some(Self::Socket),
+            _ => None,
+        }
+    }
+
+    /// The `d_type` of directory entries.
+    pub fn dirent_type(self) -> u8 {
+        (self.mode_bits() >> 12) as u8
+    }
+}
+
+/// Attributes of an inode, as returned by `stat`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Metadata {
+    pub dev: u64,
+    pub ino: u64,
+    /// File type and permission bits.
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Device number of device inodes.
+    pub rdev: u64,
+    pub size: u64,
+    pub blksize: u32,
+    /// Allocated space in 512-byte units.
+    pub blocks: u64,
+    pub atime: TimeSpec,
+    pub mtime: TimeSpec,
+    pub ctime: TimeSpec,
+}
+
+impl Metadata {
+    pub fn inode_type(&self) -> Option<InodeType> {
+        InodeType::from_mode(self.mode)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub ino: u64,
+    pub name: String,
+    pub ty: InodeType,
+}
+
+/// A file, directory or other object of a filesystem. Operations not
+/// supported by a kind of inode fail with the errno Linux would return.
+pub trait Inode: Send + Sync + Any {
+    fn metadata(&self) -> FsFuture<'_, Metadata>;
+
+    fn inode_type(&self) -> InodeType;
+
+    fn read_at<'a>(&'a self, _offset: u64, _buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        fs_err(Errno::EISDIR)
+    }
+
+    fn write_at<'a>(&'a self, _offset: u64, _buf: &'a [u8]) -> FsFuture<'a, usize> {
+        fs_err(Errno::EISDIR)
+    }
+
+    fn truncate(&self, _size: u64) -> FsFuture<'_, ()> {
+        fs_err(Errno::EISDIR)
+    }
+
+    /// Changes the permission bits.
+    fn set_mode(&self, _mode: u32) -> FsFuture<'_, ()> {
+        fs_err(Errno::EPERM)
+    }
+
+    /// Sets access and modification times, `None` leaving one unchanged.
+    fn set_times(&self, _atime: Option<TimeSpec>, _mtime: Option<TimeSpec>) -> FsFuture<'_, ()> {
+        fs_err(Errno::EPERM)
+    }
+
+    /// Finds entry `name` of a directory, which is neither `.` nor `..`.
+    fn lookup<'a>(&'a self, _name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        fs_err(Errno::ENOTDIR)
+    }
+
+    /// Creates entry `name` of type `ty` with permission bits `mode`.
+    /// Device inodes get device number `rdev`.
+    fn create<'a>(
+        &'a self,
+        _name: &'a str,
+        _ty: InodeType,
+        _mode: u32,
+        _rdev: u64,
+    ) -> FsFuture<'a, Arc<dyn Inode>> {
+        fs_err(Errno::ENOTDIR)
+    }
+
+    fn symlink<'a>(&'a self, _name: &'a str, _target: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        fs_err(Errno::ENOTDIR)
+    }
+
+    /// Adds a hard link `name` to `target`, an inode of the same filesystem.
+    fn link<'a>(&'a self, _name: &'a str, _target: &'a Arc<dyn Inode>) -> FsFuture<'a, ()> {
+        fs_err(Errno::ENOTDIR)
+    }
+
+    /// Removes entry `name`; directories must be empty.
+    fn unlink<'a>(&'a self, _name: &'a str) -> FsFuture<'a, ()> {
+        fs_err(Errno::ENOTDIR)
+    }
+
+    /// Moves entry `old` to `new` in `new_dir`, a directory of the same
+    /// filesystem, replacing an entry there unless `flags` say otherwise.
+    fn rename<'a>(
+        &'a self,
+        _old: &'a str,
+        _new_dir: &'a Arc<dyn Inode>,
+        _new: &'a str,
+        _flags: RenameFlags,
+    ) -> FsFuture<'a, ()> {
+        fs_err(Errno::ENOTDIR)
+    }
+
+    fn read_link(&self) -> FsFuture<'_, String> {
+        fs_err(Errno::EINVAL)
+    }
+
+    /// Whether the dentries of entries looked up in this directory may be
+    /// cached. Directories whose entries come and go on their own, e.g. the
+    /// tasks in procfs, are asked again on every lookup.
+    fn cache_lookups(&self) -> bool {
+        true
+    }
+
+    /// Returns the directory entry at `index`, not counting `.` and `..`,
+    /// or `None` past the end.
+    fn read_dir(&self, _index: usize) -> FsFuture<'_, Option<DirEntry>> {
+        fs_err(Errno::ENOTDIR)
+    }
+
+    /// Writes cached data of the inode back to its device.
+    fn sync(&self) -> FsFuture<'_, ()> {
+        alloc::boxed::Box::pin(async { Ok(()) })
+    }
+
+    /// Opens the inode as something other than a plain file, e.g. a device.
+    /// `None` makes the VFS use the generic inode file.
+    fn open(&self, _flags: OpenFlags) -> Option<Result<Arc<dyn File>, Errno>> {
+        None
+    }
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+bitflags::bitflags! {
+    /// Flags of `renameat2`.
+    pub struct RenameFlags: u32 {
+        const NOREPLACE = 1 << 0;
+        const EXCHANGE = 1 << 1;
+        const WHITEOUT = 1 << 2;
+    }
+}
@@ -0,0 +1,192 @@
+mod dentry;
+mod device;
+mod fd;
+mod file;
+mod initrd;
+mod inode;
+mod mount;
+mod path;
+mod pipe;
+mod superblock;
+
+pub mod devfs;
+pub mod ext4;
+pub mod fat;
+pub mod procfs;
+pub mod tmpfs;
+
+pub use dentry::*;
+pub use device::*;
+pub use fd::*;
+pub use file::*;
+pub use initrd::*;
+pub use inode::*;
+pub use mount::*;
+pub use path::*;
+pub use pipe::*;
+pub use superblock::*;
+
+use core::pin::Pin;
+
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec};
+use log::{info, warn};
+
+use crate::{
+    Errno,
+    allocator::release_initrd,
+    block::{PARTITIONS_SCANNED, block_device},
+    dtb::MACHINE_META,
+    runtime::spawn,
+    sync::Event,
+};
+
+use devfs::{DEVTMPFS, DevFs};
+use ext4::{EXT2, EXT3, EXT4};
+use fat::VFAT;
+use procfs::{PROC, ProcFs};
+use tmpfs::{TMPFS, TmpFs, TmpFsOptions};
+
+/// Set once the root filesystem and the kernel filesystems are mounted.
+pub static ROOT_MOUNTED: Event = Event::new();
+
+/// Future returned by filesystem operations. They are boxed so that inodes,
+/// files and superblocks can be used as trait objects.
+pub type FsFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, Errno>> + Send + 'a>>;
+
+/// A future failing with `errno`, the default of unsupported operations.
+pub fn fs_err<'a, T: Send + 'a>(errno: Errno) -> FsFuture<'a, T> {
+    Box::pin(async move { Err(errno) })
+}
+
+/// Registers the filesystem types and mounts the root filesystem: the
+/// device named by `root=` on the command line, with type `rootfstype=` if
+/// given, or else a tmpfs. If the bootloader loaded an initrd, it is
+/// unpacked into a tmpfs root. Then procfs and devfs are mounted on `/proc`
+/// and `/dev`. These run
+/// in kernel tasks, so this needs the executor.
+pub fn init() {
+    register_filesystem(TMPFS);
+    register_filesystem(VFAT);
+    register_filesystem(EXT2);
+    register_filesystem(EXT3);
+    register_filesystem(EXT4);
+    register_filesystem(PROC);
+    register_filesystem(DEVTMPFS);
+
+    let meta = MACHINE_META.get();
+    let Some(device) = meta.and_then(|meta| meta.bootarg("root")) else {
+        mount_tmpfs_root();
+        spawn(async {
+            unpack_initrd_root().await;
+            mount_kernel_filesystems().await;
+        })
+        .detach();
+        return;
+    };
+    let device = String::from(device.strip_prefix("/dev/").unwrap_or(device));
+    let fstype = meta
+        .and_then(|meta| meta.bootarg("rootfstype"))
+        .map(String::from);
+    let data = match meta.and_then(|meta| meta.bootarg("ro")) {
+        Some(_) => "ro",
+        None => "",
+    };
+    spawn(async move {
+        match mount_device_root(&device, fstype.as_deref(), data).await {
+            Ok(()) => release_initrd(),
+            Err(err) => {
+                warn!("root: cannot mount {}: {:?}, using a tmpfs", device, err);
+                mount_tmpfs_root();
+                unpack_initrd_root().await;
+            }
+        }
+        mount_kernel_filesystems().await;
+    })
+    .detach();
+}
+
+/// Mounts block device `name` as the root filesystem, trying the types
+/// stored on devices if `fstype` is not given.
+async fn mount_device_root(name: &str, fstype: Option<&str>, data: &str) -> Result<(), Errno> {
+    // partitions are registered once the disks are scanned
+    PARTITIONS_SCANNED.wait().await;
+    let device = block_device(name).ok_or(Errno::ENODEV)?;
+    let types = match fstype {
+        Some(fstype) => vec![filesystem(fstype).ok_or(Errno::ENODEV)?],
+        None => [EXT4, VFAT].to_vec(),
+    };
+    let mut result = Err(Errno::EINVAL);
+    for fs in types.into_iter().filter(|fs| fs.needs_device) {
+        match (fs.mount)(Some(device.clone()), data).await {
+            Ok(sb) => {
+                info!("root: mounted {} as {}", name, fs.name);
+                mount_root(sb, format!("/dev/{}", name), fs.name);
+                return Ok(());
+            }
+            Err(err) => result = Err(err),
+        }
+    }
+    result
+}
+
+/// Mounts a tmpfs as the root filesystem.
+fn mount_tmpfs_root() {
+    let root = TmpFs::new(TmpFsOptions::default()).expect("root filesystem");
+    mount_root(root, String::from("rootfs"), TMPFS.name);
+}
+
+/// Unpacks the initrd, if the bootloader loaded one, into the root
+/// filesystem.
+async fn unpack_initrd_root() {
+    let Some(initrd) = MACHINE_META.get().and_then(|meta| meta.initrd.clone()) else {
+        return;
+    };
+    let archive = unsafe { core::slice::from_raw_parts(initrd.start as *const u8, initrd.len()) };
+    let root = root_path().expect("root filesystem");
+    if let Err(err) = unpack_initrd(&root, archive).await {
+        warn!("initrd: malformed archive: {:?}", err);
+    }
+    release_initrd();
+}
+
+/// Mounts procfs on `/proc` and devfs on `/dev`, creating the directories
+/// if the root filesystem lacks them.
+async fn mount_kernel_filesystems() {
+    let proc: Arc<dyn SuperBlock> = ProcFs::new();
+    if let Err(err) = mount_on_dir("proc", 0o555, proc, PROC.name).await {
+        warn!("proc: cannot mount on /proc: {:?}", err);
+    }
+    let dev: Arc<dyn SuperBlock> = DevFs::new();
+    if let Err(err) = mount_on_dir("dev", 0o755, dev, DEVTMPFS.name).await {
+        warn!("devtmpfs: cannot mount on /dev: {:?}", err);
+    }
+    ROOT_MOUNTED.set();
+}
+
+/// Mounts `sb` on directory `name` of the root directory, creating it with
+/// permission bits `mode` if needed.
+async fn mount_on_dir(
+    name: &str,
+    mode: u32,
+    sb: Arc<dyn SuperBlock>,
+    fstype: &'static str,
+) -> Result<(), Errno> {
+    let root = root_path()?;
+    let dentry = match root.dentry.lookup(name).await {
+        Ok(dentry) => dentry,
+        Err(Errno::ENOENT) => {
+            let inode = root
+                .dentry
+                .inode()
+                .create(name, InodeType::Dir, mode, 0)
+                .await?;
+            root.dentry.insert(name, inode)
+        }
+        Err(err) => return Err(err),
+    };
+    let target = Path {
+        mount: root.mount,
+        dentry,
+    };
+    mount_at(&target, sb, String::from(fstype), fstype)
+}
@@ -0,0 +1,152 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use crate::{Errno, sync::SpinNoIrq};
+
+use super::{Dentry, InodeType, Path, SuperBlock};
+
+/// A filesystem attached to the directory tree.
+pub struct Mount {
+    id: usize,
+    sb: Arc<dyn SuperBlock>,
+    root: Arc<Dentry>,
+    /// The directory the filesystem is mounted on, `None` for the root
+    /// filesystem.
+    mountpoint: Option<Path>,
+    /// What was mounted, e.g. a device name.
+    source: String,
+    fstype: &'static str,
+}
+
+impl Mount {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn superblock(&self) -> &Arc<dyn SuperBlock> {
+        &self.sb
+    }
+
+    pub fn root(&self) -> &Arc<Dentry> {
+        &self.root
+    }
+
+    pub fn mountpoint(&self) -> Option<&Path> {
+        self.mountpoint.as_ref()
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn fstype(&self) -> &'static str {
+        self.fstype
+    }
+}
+
+static NEXT_MOUNT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// All mounts, in the order they were mounted. Later mounts on the same
+/// directory hide earlier ones.
+static MOUNTS: SpinNoIrq<Vec<Arc<Mount>>> = SpinNoIrq::new(Vec::new());
+
+fn new_mount(
+    sb: Arc<dyn SuperBlock>,
+    mountpoint: Option<Path>,
+    source: String,
+    fstype: &'static str,
+) -> Arc<Mount> {
+    Arc::new(Mount {
+        id: NEXT_MOUNT_ID.fetch_add(1, Ordering::Relaxed),
+        root: Dentry::new_root(sb.root()),
+        sb,
+        mountpoint,
+        source,
+        fstype,
+    })
+}
+
+/// Makes `sb` the root filesystem. It can only be set once.
+pub fn mount_root(sb: Arc<dyn SuperBlock>, source: String, fstype: &'static str) {
+    let mut mounts = MOUNTS.lock();
+    assert!(
+        mounts.iter().all(|mount| mount.mountpoint.is_some()),
+        "root filesystem already mounted"
+    );
+    mounts.insert(0, new_mount(sb, None, source, fstype));
+}
+
+/// Mounts `sb` on directory `target`.
+pub fn mount_at(
+    target: &Path,
+    sb: Arc<dyn SuperBlock>,
+    source: String,
+    fstype: &'static str,
+) -> Result<(), Errno> {
+    if target.dentry.inode().inode_type() != InodeType::Dir {
+        return Err(Errno::ENOTDIR);
+    }
+    let mount = new_mount(sb, Some(target.clone()), source, fstype);
+    MOUNTS.lock().push(mount);
+    Ok(())
+}
+
+/// Detaches the filesystem whose root is `target`. Fails with `EBUSY` if
+/// other filesystems are mounted below it.
+pub fn unmount(target: &Path) -> Result<Arc<Mount>, Errno> {
+    if !target.is_mount_root() {
+        return Err(Errno::EINVAL);
+    }
+    let mut mounts = MOUNTS.lock();
+    let id = target.mount.id;
+    if target.mount.mountpoint.is_none() {
+        return Err(Errno::EBUSY);
+    }
+    if mounts.iter().any(|mount| {
+        mount
+            .mountpoint
+            .as_ref()
+            .is_some_and(|path| path.mount.id == id)
+    }) {
+        return Err(Errno::EBUSY);
+    }
+    let index = mounts
+        .iter()
+        .position(|mount| mount.id == id)
+        .ok_or(Errno::EINVAL)?;
+    Ok(mounts.remove(index))
+}
+
+/// The root directory of the root filesystem.
+pub fn root_path() -> Result<Path, Errno> {
+    let mounts = MOUNTS.lock();
+    let root = mounts
+        .first()
+        .filter(|mount| mount.mountpoint.is_none())
+        .ok_or(Errno::ENOENT)?;
+    Ok(Path {
+        mount: root.clone(),
+        dentry: root.root.clone(),
+    })
+}
+
+/// The mount hiding directory `path`, if any.
+pub fn mounted_on(path: &Path) -> Option<Arc<Mount>> {
+    MOUNTS
+        .lock()
+        .iter()
+        .rev()
+        .find(|mount| {
+            mount
+                .mountpoint
+                .as_ref()
+                .is_some_and(|mountpoint| mountpoint == path)
+        })
+        .cloned()
+}
+
+/// All mounts, the root filesystem first.
+pub fn mounts() -> Vec<Arc<Mount>> {
+    MOUNTS.lock().clone()
+}
@@ -0,0 +1,191 @@
+use alloc::{
+    collections::VecDeque,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use crate::Errno;
+
+use super::{Dentry, InodeType, Mount, mounted_on, root_path};
+
+/// Longest path accepted from user space, including the terminating nul.
+pub const PATH_MAX: usize = 4096;
+/// Longest name of a directory entry.
+pub const NAME_MAX: usize = 255;
+/// Symlinks followed in one resolution before giving up with `ELOOP`.
+const MAX_SYMLINKS: usize = 40;
+
+/// A location in the directory tree: a dentry and the mount it is seen
+/// through, as the same filesystem may be reachable at several places.
+#[derive(Clone)]
+pub struct Path {
+    pub mount: Arc<Mount>,
+    pub dentry: Arc<Dentry>,
+}
+
+impl PartialEq for Path {
+    fn eq(&self, other: &Self) -> bool {
+        self.mount.id() == other.mount.id() && Arc::ptr_eq(&self.dentry, &other.dentry)
+    }
+}
+
+impl Path {
+    /// Whether this is the root directory of its mount.
+    pub fn is_mount_root(&self) -> bool {
+        Arc::ptr_eq(&self.dentry, self.mount.root())
+    }
+
+    pub fn inode_type(&self) -> InodeType {
+        self.dentry.inode().inode_type()
+    }
+}
+
+/// The directory `..` of `path` refers to. It crosses back over mount
+/// points and stays put at the root.
+pub fn parent_of(path: &Path) -> Path {
+    let mut path = path.clone();
+    while path.is_mount_root() {
+        match path.mount.mountpoint() {
+            Some(mountpoint) => path = mountpoint.clone(),
+            None => return path,
+        }
+    }
+    let parent = path.dentry.parent().unwrap_or(path.dentry);
+    Path {
+        mount: path.mount,
+        dentry: parent,
+    }
+}
+
+/// Steps into the filesystems mounted on `path`.
+fn follow_mounts(mut path: Path) -> Path {
+    while let Some(mount) = mounted_on(&path) {
+        path = Path {
+            dentry: mount.root().clone(),
+            mount,
+        };
+    }
+    path
+}
+
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|name| !name.is_empty())
+}
+
+/// Resolves `path` relative to directory `start`, or the root directory if
+/// it is absolute. A symlink as the last component is followed only if
+/// `follow` is set or the path ends with a slash.
+pub async fn resolve(start: &Path, path: &str, follow: bool) -> Result<Path, Errno> {
+    if path.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+    let root = root_path()?;
+    let mut current = match path.starts_with('/') {
+        true => root.clone(),
+        false => start.clone(),
+    };
+    let trailing_slash = path.ends_with('/');
+    let mut pending: VecDeque<String> = components(path).map(ToString::to_string).collect();
+    let mut symlinks = 0;
+    while let Some(name) = pending.pop_front() {
+        if current.inode_type() != InodeType::Dir {
+            return Err(Errno::ENOTDIR);
+        }
+        if name.len() > NAME_MAX {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        match name.as_str() {
+            "." => continue,
+            ".." => {
+                if current != root {
+                    current = parent_of(&current);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        let next = Path {
+            mount: current.mount.clone(),
+            dentry: current.dentry.lookup(&name).await?,
+        };
+        let next = follow_mounts(next);
+        let last = pending.is_empty();
+        if next.inode_type() == InodeType::Symlink && (!last || follow || trailing_slash) {
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(Errno::ELOOP);
+            }
+            let target = next.dentry.inode().read_link().await?;
+            if target.is_empty() {
+                return Err(Errno::ENOENT);
+            }
+            if target.starts_with('/') {
+                current = root.clone();
+            }
+            for name in components(&target).rev() {
+                pending.push_front(name.to_string());
+            }
+            continue;
+        }
+        current = next;
+    }
+    if trailing_slash && current.inode_type() != InodeType::Dir {
+        return Err(Errno::ENOTDIR);
+    }
+    Ok(current)
+}
+
+/// Resolves all but the last component of `path`, for calls creating or
+/// removing the last one. Returns the directory and the last name, which
+/// may be `.` or `..` for the caller to reject.
+pub async fn resolve_parent(start: &Path, path: &str) -> Result<(Path, String), Errno> {
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.is_empty() {
+        // `/` itself, or an empty path
+        return match path.is_empty() {
+            true => Err(Errno::ENOENT),
+            false => Err(Errno::EBUSY),
+        };
+    }
+    let (dir, name) = match trimmed.rfind('/') {
+        Some(index) => (&trimmed[..=index], &trimmed[index + 1..]),
+        None => (".", trimmed),
+    };
+    if name.len() > NAME_MAX {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    let dir = resolve(start, dir, true).await?;
+    if dir.inode_type() != InodeType::Dir {
+        return Err(Errno::ENOTDIR);
+    }
+    Ok((dir, name.to_string()))
+}
+
+/// The absolute path of `path`, as reported by `getcwd`.
+pub fn path_string(path: &Path) -> Result<String, Errno> {
+    let root = root_path()?;
+    let mut names = Vec::new();
+    let mut current = path.clone();
+    while current != root {
+        if current.is_mount_root() {
+            match current.mount.mountpoint() {
+                Some(mountpoint) => current = mountpoint.clone(),
+                // its filesystem was unmounted meanwhile
+                None => break,
+            }
+            continue;
+        }
+        names.push(current.dentry.name());
+        current = parent_of(&current);
+    }
+    let mut string = String::new();
+    for name in names.iter().rev() {
+        string.push('/');
+        string.push_str(name);
+    }
+    if string.is_empty() {
+        string.push('/');
+    }
+    Ok(string)
+}
@@ -0,0 +1,227 @@
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Poll, Waker},
+};
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+
+use crate::{
+    Errno, SysResult,
+    drivers::FIONREAD,
+    hart::{current_space, with_current_task},
+    mem::VirtAddr,
+    runtime::TimeSpec,
+    sync::SpinNoIrq,
+    task::SIGPIPE,
+};
+
+use super::{File, FileFlags, FsFuture, Metadata, OpenFlags, S_IFIFO};
+
+/// Bytes a pipe buffers before writers wait.
+pub const PIPE_CAPACITY: usize = 64 << 10;
+/// Writes up to this size are not interleaved with other writes.
+pub const PIPE_BUF: usize = 4096;
+
+static NEXT_PIPE_INO: AtomicU64 = AtomicU64::new(1);
+
+struct PipeState {
+    buf: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+    read_wakers: Vec<Waker>,
+    write_wakers: Vec<Waker>,
+}
+
+fn wake_all(wakers: &mut Vec<Waker>) {
+    for waker in wakers.drain(..) {
+        waker.wake();
+    }
+}
+
+/// The buffer shared by the two ends of a pipe.
+struct Pipe {
+    ino: u64,
+    ctime: TimeSpec,
+    state: SpinNoIrq<PipeState>,
+}
+
+impl Pipe {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: self.ino,
+            mode: S_IFIFO | 0o600,
+            nlink: 1,
+            blksize: PIPE_BUF as u32,
+            atime: self.ctime,
+            mtime: self.ctime,
+            ctime: self.ctime,
+            ..Default::default()
+        }
+    }
+}
+
+/// The read end of a pipe.
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+    flags: FileFlags,
+}
+
+/// The write end of a pipe.
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+    flags: FileFlags,
+}
+
+/// Creates a pipe whose ends have status flags `flags`, e.g. `O_NONBLOCK`.
+pub fn pipe(flags: OpenFlags) -> (Arc<PipeReader>, Arc<PipeWriter>) {
+    let pipe = Arc::new(Pipe {
+        ino: NEXT_PIPE_INO.fetch_add(1, Ordering::Relaxed),
+        ctime: TimeSpec::now(),
+        state: SpinNoIrq::new(PipeState {
+            buf: VecDeque::new(),
+            readers: 1,
+            writers: 1,
+            read_wakers: Vec::new(),
+            write_wakers: Vec::new(),
+        }),
+    });
+    let reader = PipeReader {
+        pipe: pipe.clone(),
+        flags: FileFlags::new(flags),
+    };
+    let writer = PipeWriter {
+        pipe,
+        flags: FileFlags::new(flags | OpenFlags::WRONLY),
+    };
+    (Arc::new(reader), Arc::new(writer))
+}
+
+impl File for PipeReader {
+    /// Waits for data unless all writers are gone, which reads as the end
+    /// of the file.
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        let task = with_current_task(|task| task.shared().clone());
+        Box::pin(poll_fn(move |cx| {
+            let mut state = self.pipe.state.lock();
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            if !state.buf.is_empty() {
+                let n = buf.len().min(state.buf.len());
+                for (dst, src) in buf.iter_mut().zip(state.buf.drain(..n)) {
+                    *dst = src;
+                }
+                wake_all(&mut state.write_wakers);
+                return Poll::Ready(Ok(n));
+            }
+            if state.writers == 0 {
+                return Poll::Ready(Ok(0));
+            }
+            if self.flags().contains(OpenFlags::NONBLOCK) {
+                return Poll::Ready(Err(Errno::EAGAIN));
+            }
+            if task.signals.interrupts() {
+                return Poll::Ready(Err(Errno::EINTR));
+            }
+            state.read_wakers.push(cx.waker().clone());
+            Poll::Pending
+        }))
+    }
+
+    fn stat(&self) -> FsFuture<'_, Metadata> {
+        let meta = self.pipe.metadata();
+        Box::pin(async move { Ok(meta) })
+    }
+
+    fn ioctl(&self, request: usize, arg: VirtAddr) -> SysResult {
+        match request {
+            FIONREAD => {
+                let len = self.pipe.state.lock().buf.len() as i32;
+                current_space().write_user(arg, &len)?;
+                Ok(0)
+            }
+            _ => Err(Errno::ENOTTY),
+        }
+    }
+
+    fn flags(&self) -> OpenFlags {
+        self.flags.get()
+    }
+
+    fn set_flags(&self, flags: OpenFlags) {
+        self.flags.set(flags);
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let mut state = self.pipe.state.lock();
+        state.readers -= 1;
+        wake_all(&mut state.write_wakers);
+    }
+}
+
+impl File for PipeWriter {
+    /// Writes all of `buf` unless interrupted. Writes of at most
+    /// [`PIPE_BUF`] bytes wait until they fit as a whole. Writing without
+    /// readers raises `SIGPIPE` and fails with `EPIPE`.
+    fn write<'a>(&'a self, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        let task = with_current_task(|task| task.shared().clone());
+        let mut written = 0;
+        Box::pin(poll_fn(move |cx| {
+            let mut state = self.pipe.state.lock();
+            if state.readers == 0 {
+                if written > 0 {
+                    return Poll::Ready(Ok(written));
+                }
+                task.signals.raise(SIGPIPE);
+                return Poll::Ready(Err(Errno::EPIPE));
+            }
+            let free = PIPE_CAPACITY - state.buf.len();
+            let remaining = buf.len() - written;
+            if free > 0 && (buf.len() > PIPE_BUF || free >= remaining) {
+                let n = free.min(remaining);
+                state.buf.extend(&buf[written..written + n]);
+                written += n;
+                wake_all(&mut state.read_wakers);
+            }
+            if written == buf.len() {
+                return Poll::Ready(Ok(written));
+            }
+            let interrupted = match self.flags().contains(OpenFlags::NONBLOCK) {
+                true => Some(Errno::EAGAIN),
+                false => task.signals.interrupts().then_some(Errno::EINTR),
+            };
+            if let Some(errno) = interrupted {
+                return Poll::Ready(match written {
+                    0 => Err(errno),
+                    written => Ok(written),
+                });
+            }
+            state.write_wakers.push(cx.waker().clone());
+            Poll::Pending
+        }))
+    }
+
+    fn stat(&self) -> FsFuture<'_, Metadata> {
+        let meta = self.pipe.metadata();
+        Box::pin(async move { Ok(meta) })
+    }
+
+    fn flags(&self) -> OpenFlags {
+        self.flags.get()
+    }
+
+    fn set_flags(&self, flags: OpenFlags) {
+        self.flags.set(flags);
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let mut state = self.pipe.state.lock();
+        state.writers -= 1;
+        wake_all(&mut state.read_wakers);
+    }
+}
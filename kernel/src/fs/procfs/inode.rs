@@ -0,0 +1,308 @@
+use core::any::Any;
+
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use crate::{
+    Errno,
+    fs::{DirEntry, FsFuture, Inode, InodeType, Metadata, fs_err, path_string},
+    hart::try_with_current_task,
+    runtime::{TimeSpec, ticks_to_duration},
+    task::{TaskShared, all_tasks, find_task},
+};
+
+use super::{system, task};
+
+/// Generates the content of a file about the whole system.
+type SystemFn = fn() -> Vec<u8>;
+/// Generates the content of a file about a task.
+type TaskFn = fn(&TaskShared) -> Vec<u8>;
+
+const SYSTEM_FILES: &[(&str, SystemFn)] = &[
+    ("cpuinfo", system::cpuinfo),
+    ("interrupts", system::interrupts),
+    ("loadavg", system::loadavg),
+    ("meminfo", system::meminfo),
+    ("stat", system::stat),
+    ("uptime", system::uptime),
+];
+
+const TASK_FILES: &[(&str, TaskFn)] = &[
+    ("cmdline", task::cmdline),
+    ("maps", task::maps),
+    ("stat", task::stat),
+    ("status", task::status),
+];
+
+/// What an inode of procfs shows.
+#[derive(Debug, Clone, Copy)]
+enum Node {
+    Root,
+    /// `/proc/self`, a symlink to the directory of the task reading it.
+    SelfLink,
+    /// A file of [`SYSTEM_FILES`].
+    System(usize),
+    /// `/proc/<pid>`.
+    TaskDir,
+    /// `/proc/<pid>/fd`.
+    FdDir,
+    /// `/proc/<pid>/fd/<fd>`, a symlink to what the descriptor refers to.
+    Fd(usize),
+    /// A file of [`TASK_FILES`].
+    TaskFile(usize),
+}
+
+/// An inode of procfs. Nodes below `/proc/<pid>` refer to their task
+/// weakly and fail once it exited, even if its tid was reused since.
+pub struct ProcInode {
+    dev: u64,
+    node: Node,
+    /// The task of the node, 0 for nodes about the whole system.
+    tid: usize,
+    task: Weak<TaskShared>,
+}
+
+impl ProcInode {
+    pub(super) fn root(dev: u64) -> Arc<Self> {
+        Arc::new(Self {
+            dev,
+            node: Node::Root,
+            tid: 0,
+            task: Weak::new(),
+        })
+    }
+
+    fn task_dir(&self, task: &Arc<TaskShared>) -> Arc<Self> {
+        Arc::new(Self {
+            dev: self.dev,
+            node: Node::TaskDir,
+            tid: task.tid(),
+            task: Arc::downgrade(task),
+        })
+    }
+
+    /// Another node of the same task.
+    fn child(&self, node: Node) -> Arc<Self> {
+        Arc::new(Self {
+            dev: self.dev,
+            node,
+            tid: self.tid,
+            task: self.task.clone(),
+        })
+    }
+
+    fn task(&self) -> Result<Arc<TaskShared>, Errno> {
+        self.task.upgrade().ok_or(Errno::ENOENT)
+    }
+
+    /// Inode numbers hold the tid in the high half, so that they are
+    /// stable while the task lives.
+    fn ino(&self) -> u64 {
+        let local = match self.node {
+            Node::Root | Node::TaskDir => 1,
+            Node::SelfLink | Node::FdDir => 2,
+            Node::System(index) | Node::TaskFile(index) => 16 + index as u64,
+            Node::Fd(fd) => 1 << 31 | fd as u64,
+        };
+        (self.tid as u64) << 32 | local
+    }
+
+    /// The content of a file, generated anew on every read.
+    fn content(&self) -> Result<Vec<u8>, Errno> {
+        match self.node {
+            Node::System(index) => Ok((SYSTEM_FILES[index].1)()),
+            Node::TaskFile(index) => {
+                let task = self.task.upgrade().ok_or(Errno::ESRCH)?;
+                Ok((TASK_FILES[index].1)(&task))
+            }
+            Node::Root | Node::TaskDir | Node::FdDir => Err(Errno::EISDIR),
+            Node::SelfLink | Node::Fd(_) => Err(Errno::EINVAL),
+        }
+    }
+
+    fn dir_entry(&self, name: &str, node: Node) -> DirEntry {
+        DirEntry {
+            ino: self.child(node).ino(),
+            name: name.to_string(),
+            ty: node_type(node),
+        }
+    }
+
+    /// Where descriptor `fd` of the task points: the path of the file, or
+    /// a description of objects without one as Linux shows them.
+    async fn fd_target(&self, fd: usize) -> Result<String, Errno> {
+        let files = self.task()?.files().ok_or(Errno::ENOENT)?;
+        let file = files.lock().get(fd).map_err(|_| Errno::ENOENT)?;
+        if let Some(path) = file.path() {
+            return path_string(&path);
+        }
+        let meta = file.stat().await?;
+        Ok(match meta.inode_type() {
+            Some(InodeType::Fifo) => format!("pipe:[{}]", meta.ino),
+            _ => format!("anon_inode:[{}]", meta.ino),
+        })
+    }
+}
+
+fn node_type(node: Node) -> InodeType {
+    match node {
+        Node::Root | Node::TaskDir | Node::FdDir => InodeType::Dir,
+        Node::SelfLink | Node::Fd(_) => InodeType::Symlink,
+        Node::System(_) | Node::TaskFile(_) => InodeType::File,
+    }
+}
+
+impl Inode for ProcInode {
+    fn metadata(&self) -> FsFuture<'_, Metadata> {
+        Box::pin(async move {
+            // nodes of a task date from its creation, the others from boot
+            let start = match self.tid {
+                0 => 0,
+                _ => self.task()?.start_time(),
+            };
+            let start = ticks_to_duration(start);
+            let time = TimeSpec {
+                tv_sec: start.as_secs() as i64,
+                tv_nsec: start.subsec_nanos() as i64,
+            };
+            let (perm, nlink) = match self.node {
+                Node::Root | Node::TaskDir => (0o555, 2),
+                Node::FdDir => (0o500, 2),
+                Node::SelfLink => (0o777, 1),
+                Node::Fd(_) => (0o700, 1),
+                Node::System(_) | Node::TaskFile(_) => (0o444, 1),
+            };
+            Ok(Metadata {
+                dev: self.dev,
+                ino: self.ino(),
+                mode: self.inode_type().mode_bits() | perm,
+                nlink,
+                blksize: 1024,
+                atime: time,
+                mtime: time,
+                ctime: time,
+                ..Metadata::default()
+            })
+        })
+    }
+
+    fn inode_type(&self) -> InodeType {
+        node_type(self.node)
+    }
+
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let content = self.content()?;
+            let start = offset.min(content.len() as u64) as usize;
+            let n = buf.len().min(content.len() - start);
+            buf[..n].copy_from_slice(&content[start..start + n]);
+            Ok(n)
+        })
+    }
+
+    fn write_at<'a>(&'a self, _offset: u64, _buf: &'a [u8]) -> FsFuture<'a, usize> {
+        match self.inode_type() {
+            InodeType::Dir => fs_err(Errno::EISDIR),
+            _ => fs_err(Errno::EACCES),
+        }
+    }
+
+    fn truncate(&self, _size: u64) -> FsFuture<'_, ()> {
+        match self.inode_type() {
+            InodeType::Dir => fs_err(Errno::EISDIR),
+            _ => fs_err(Errno::EACCES),
+        }
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            let inode = match self.node {
+                Node::Root => {
+                    if name == "self" {
+                        self.child(Node::SelfLink)
+                    } else if let Some(index) = SYSTEM_FILES.iter().position(|f| f.0 == name) {
+                        self.child(Node::System(index))
+                    } else {
+                        let tid = name.parse().map_err(|_| Errno::ENOENT)?;
+                        self.task_dir(&find_task(tid).ok_or(Errno::ENOENT)?)
+                    }
+                }
+                Node::TaskDir => {
+                    self.task()?;
+                    match TASK_FILES.iter().position(|f| f.0 == name) {
+                        Some(index) => self.child(Node::TaskFile(index)),
+                        None if name == "fd" => self.child(Node::FdDir),
+                        None => return Err(Errno::ENOENT),
+                    }
+                }
+                Node::FdDir => {
+                    let fd = name.parse().map_err(|_| Errno::ENOENT)?;
+                    let files = self.task()?.files().ok_or(Errno::ENOENT)?;
+                    files.lock().get(fd).map_err(|_| Errno::ENOENT)?;
+                    self.child(Node::Fd(fd))
+                }
+                _ => return Err(Errno::ENOTDIR),
+            };
+            Ok(inode as Arc<dyn Inode>)
+        })
+    }
+
+    fn read_link(&self) -> FsFuture<'_, String> {
+        Box::pin(async move {
+            match self.node {
+                // kernel tasks have no entry of their own
+                Node::SelfLink => match try_with_current_task(|task| task.tid()) {
+                    Some(tid) => Ok(tid.to_string()),
+                    None => Err(Errno::ENOENT),
+                },
+                Node::Fd(fd) => self.fd_target(fd).await,
+                _ => Err(Errno::EINVAL),
+            }
+        })
+    }
+
+    fn cache_lookups(&self) -> bool {
+        !matches!(self.node, Node::Root | Node::FdDir)
+    }
+
+    fn read_dir(&self, index: usize) -> FsFuture<'_, Option<DirEntry>> {
+        Box::pin(async move {
+            let entry = match self.node {
+                // the system files, `self`, then the tasks
+                Node::Root => match index.checked_sub(SYSTEM_FILES.len()) {
+                    None => Some(self.dir_entry(SYSTEM_FILES[index].0, Node::System(index))),
+                    Some(0) => Some(self.dir_entry("self", Node::SelfLink)),
+                    Some(rest) => all_tasks().get(rest - 1).map(|task| DirEntry {
+                        ino: self.task_dir(task).ino(),
+                        name: task.tid().to_string(),
+                        ty: InodeType::Dir,
+                    }),
+                },
+                Node::TaskDir => {
+                    self.task()?;
+                    match index.checked_sub(TASK_FILES.len()) {
+                        None => Some(self.dir_entry(TASK_FILES[index].0, Node::TaskFile(index))),
+                        Some(0) => Some(self.dir_entry("fd", Node::FdDir)),
+                        Some(_) => None,
+                    }
+                }
+                Node::FdDir => {
+                    let files = self.task()?.files().ok_or(Errno::ENOENT)?;
+                    let fd = files.lock().iter().nth(index).map(|(fd, _)| fd);
+                    fd.map(|fd| self.dir_entry(&fd.to_string(), Node::Fd(fd)))
+                }
+                _ => return Err(Errno::ENOTDIR),
+            };
+            Ok(entry)
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,60 @@
+//! The proc filesystem, which shows the tasks and the state of the kernel
+//! as files generated when they are read.
+
+mod inode;
+#[cfg(feature = "selftest")]
+mod selftest;
+mod system;
+mod task;
+
+pub use inode::*;
+#[cfg(feature = "selftest")]
+pub use selftest::*;
+
+use alloc::{boxed::Box, sync::Arc};
+
+use crate::{block::BlockDevice, runtime::ticks_per_sec};
+
+use super::{FileSystemType, FsFuture, Inode, SuperBlock, alloc_anon_dev};
+
+pub const PROC: FileSystemType = FileSystemType {
+    name: "proc",
+    needs_device: false,
+    mount: mount_proc,
+};
+
+/// Clock ticks per second of times in procfs, `USER_HZ` of Linux.
+const USER_HZ: u64 = 100;
+
+/// Converts timer ticks to clock ticks of [`USER_HZ`].
+fn clock_ticks(ticks: u64) -> u64 {
+    ticks * USER_HZ / ticks_per_sec()
+}
+
+pub struct ProcFs {
+    root: Arc<ProcInode>,
+}
+
+impl ProcFs {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            root: ProcInode::root(alloc_anon_dev()),
+        })
+    }
+}
+
+impl SuperBlock for ProcFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+fn mount_proc(
+    _device: Option<Arc<dyn BlockDevice>>,
+    _data: &str,
+) -> FsFuture<'_, Arc<dyn SuperBlock>> {
+    Box::pin(async move {
+        let fs: Arc<dyn SuperBlock> = ProcFs::new();
+        Ok(fs)
+    })
+}
@@ -0,0 +1,48 @@
+//! Self-test of the task files, run on the first user task with the
+//! `selftest` feature.
+
+use alloc::{format, string::ToString, vec::Vec};
+use log::info;
+
+use crate::{
+    Errno,
+    fs::{resolve, root_path},
+    hart::cpu_count,
+    task::find_task,
+};
+
+/// Reads `/proc/<tid>/stat` and checks the scheduling fields against the
+/// settings of task `tid`, with its nice value changed for the occasion.
+pub async fn stat_test(tid: usize) {
+    let task = find_task(tid).expect("task registered");
+    let nice = task.sched_params().nice();
+    task.update_sched_params(|params| {
+        params.set_nice(5);
+        Ok::<_, Errno>(())
+    })
+    .unwrap();
+
+    let path = format!("/proc/{}/stat", tid);
+    let path = resolve(&root_path().unwrap(), &path, true).await.unwrap();
+    let mut buf = [0; 512];
+    let n = path.dentry.inode().read_at(0, &mut buf).await.unwrap();
+    let stat = core::str::from_utf8(&buf[..n]).unwrap();
+    // the name may contain spaces, the fields after it do not
+    let (head, rest) = stat.rsplit_once(") ").unwrap();
+    assert_eq!(head.split_once(" (").unwrap().0, tid.to_string());
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    let field = |n: usize| fields[n - 3].parse::<isize>().unwrap();
+    let sched = task.sched_params();
+    assert_eq!(field(18), 25);
+    assert_eq!(field(19), 5);
+    assert!((field(39) as usize) < cpu_count());
+    assert_eq!(field(40), sched.rt_priority() as isize);
+    assert_eq!(field(41), sched.policy() as isize);
+
+    task.update_sched_params(|params| {
+        params.set_nice(nice);
+        Ok::<_, Errno>(())
+    })
+    .unwrap();
+    info!("procfs self-test passed");
+}
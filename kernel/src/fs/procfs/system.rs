@@ -0,0 +1,187 @@
+//! Files about the whole system. The kernel heap, which also holds the
+//! buffer cache, is reported as unreclaimable slab memory; nothing is
+//! swapped or cached in page frames.
+
+use core::fmt::Write;
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::{
+    allocator::{PHYS_FRAME_ALLOCATOR, heap_stats},
+    block::BUFFER_CACHE,
+    config::PAGE_SIZE_4K,
+    drivers::irq_stats,
+    dtb::MACHINE_META,
+    hart::{booted_cpus, hartid_of, ipi_count},
+    runtime::{EXECUTOR, now, timer_interrupts},
+    task::all_tasks,
+};
+
+use super::{clock_ticks, task::is_running};
+
+pub(super) fn meminfo() -> Vec<u8> {
+    let (total, free) = {
+        let frames = PHYS_FRAME_ALLOCATOR.lock();
+        (frames.total_frames(), frames.available_frames())
+    };
+    let kb = |frames: usize| frames * PAGE_SIZE_4K / 1024;
+    let heap = heap_stats().1 / 1024;
+    let fields = [
+        ("MemTotal", kb(total)),
+        ("MemFree", kb(free)),
+        ("MemAvailable", kb(free)),
+        ("Buffers", BUFFER_CACHE.bytes() / 1024),
+        ("Cached", 0),
+        ("SwapCached", 0),
+        ("SwapTotal", 0),
+        ("SwapFree", 0),
+        ("Shmem", 0),
+        ("Slab", heap),
+        ("SReclaimable", 0),
+        ("SUnreclaim", heap),
+    ];
+    let mut s = String::new();
+    for (key, value) in fields {
+        let _ = writeln!(s, "{:<15}{:>9} kB", format!("{}:", key), value);
+    }
+    s.into_bytes()
+}
+
+pub(super) fn cpuinfo() -> Vec<u8> {
+    let harts = MACHINE_META
+        .get()
+        .map(|meta| meta.harts.as_slice())
+        .unwrap_or_default();
+    let mut s = String::new();
+    for cpu in booted_cpus().iter() {
+        let hartid = hartid_of(cpu);
+        let _ = writeln!(s, "processor\t: {}", cpu);
+        let _ = writeln!(s, "hart\t\t: {}", hartid);
+        if let Some(hart) = harts.iter().find(|hart| hart.hartid == hartid) {
+            let _ = writeln!(s, "isa\t\t: {}", hart.isa);
+            let mmu = hart.mmu.strip_prefix("riscv,").unwrap_or(&hart.mmu);
+            let _ = writeln!(s, "mmu\t\t: {}", mmu);
+        }
+        s.push('\n');
+    }
+    s.into_bytes()
+}
+
+pub(super) fn interrupts() -> Vec<u8> {
+    let cpus: Vec<usize> = booted_cpus().iter().collect();
+    let mut s = String::from("     ");
+    for cpu in &cpus {
+        let _ = write!(s, " {:>10}", format!("CPU{}", cpu));
+    }
+    s.push('\n');
+    let mut row = |label: &str, counts: &mut dyn Iterator<Item = usize>, name: &str| {
+        let _ = write!(s, "{:>4}:", label);
+        for count in counts {
+            let _ = write!(s, " {:>10}", count);
+        }
+        let _ = writeln!(s, "  {}", name);
+    };
+    for irq in irq_stats() {
+        let name = format!("PLIC  {}", irq.name);
+        row(
+            &format!("{}", irq.irq),
+            &mut cpus.iter().map(|&cpu| irq.counts[cpu]),
+            &name,
+        );
+    }
+    let timer = &mut cpus.iter().map(|&cpu| timer_interrupts(cpu));
+    row("LOC", timer, "Timer interrupts");
+    let ipi = &mut cpus.iter().map(|&cpu| ipi_count(cpu));
+    row("IPI", ipi, "Inter-processor interrupts");
+    s.into_bytes()
+}
+
+/// Time the executor of `cpu` spent running tasks, in timer ticks.
+fn busy_time(cpu: usize) -> u64 {
+    let stats = EXECUTOR.get().expect("executor initialized").stats(cpu);
+    stats.classes.iter().map(|class| class.runtime).sum()
+}
+
+/// Time since boot and the time harts spent idle, in seconds.
+pub(super) fn uptime() -> Vec<u8> {
+    let now = now();
+    let idle: u64 = booted_cpus()
+        .iter()
+        .map(|cpu| now.saturating_sub(busy_time(cpu)))
+        .sum();
+    let (up, idle) = (clock_ticks(now), clock_ticks(idle));
+    format!(
+        "{}.{:02} {}.{:02}\n",
+        up / 100,
+        up % 100,
+        idle / 100,
+        idle % 100
+    )
+    .into_bytes()
+}
+
+/// Times of the harts and counters of the scheduler. The busy time of a
+/// hart is not split between user and kernel mode, it is all reported as
+/// user time.
+pub(super) fn stat() -> Vec<u8> {
+    let now = now();
+    let times: Vec<(usize, u64, u64)> = booted_cpus()
+        .iter()
+        .map(|cpu| {
+            let busy = busy_time(cpu);
+            (
+                cpu,
+                clock_ticks(busy),
+                clock_ticks(now.saturating_sub(busy)),
+            )
+        })
+        .collect();
+    let mut s = String::new();
+    let line = |s: &mut String, name: &str, user: u64, idle: u64| {
+        let _ = writeln!(s, "{} {} 0 0 {} 0 0 0 0 0 0", name, user, idle);
+    };
+    let user = times.iter().map(|time| time.1).sum();
+    let idle = times.iter().map(|time| time.2).sum();
+    line(&mut s, "cpu ", user, idle);
+    for &(cpu, user, idle) in &times {
+        line(&mut s, &format!("cpu{}", cpu), user, idle);
+    }
+
+    let cpus = || booted_cpus().iter();
+    let irqs: usize = irq_stats()
+        .iter()
+        .map(|irq| irq.counts.iter().sum::<usize>())
+        .sum();
+    let interrupts = irqs
+        + cpus()
+            .map(|cpu| timer_interrupts(cpu) + ipi_count(cpu))
+            .sum::<usize>();
+    let executor = EXECUTOR.get().expect("executor initialized");
+    let switches: usize = cpus()
+        .map(|cpu| {
+            executor
+                .stats(cpu)
+                .classes
+                .iter()
+                .map(|class| class.picked)
+                .sum::<usize>()
+        })
+        .sum();
+    let running = all_tasks().iter().filter(|task| is_running(task)).count();
+    let _ = writeln!(s, "intr {}", interrupts);
+    let _ = writeln!(s, "ctxt {}", switches);
+    // the realtime clock starts at boot
+    let _ = writeln!(s, "btime 0");
+    let _ = writeln!(s, "procs_running {}", running);
+    let _ = writeln!(s, "procs_blocked 0");
+    s.into_bytes()
+}
+
+/// Load averages are not tracked and read as zero; the task counts are
+/// current.
+pub(super) fn loadavg() -> Vec<u8> {
+    let tasks = all_tasks();
+    let running = tasks.iter().filter(|task| is_running(task)).count();
+    let last = tasks.last().map_or(0, |task| task.tid());
+    format!("0.00 0.00 0.00 {}/{} {}\n", running, tasks.len(), last).into_bytes()
+}
@@ -0,0 +1,163 @@
+//! Files about a task. Tasks have no parent, threads or sessions yet, so
+//! they report parent 0, one thread, and their process group as session.
+
+use core::fmt::Write;
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::{
+    config::PAGE_SIZE_4K,
+    mem::{AddrSpace, AreaType, PTEFlags},
+    runtime::SchedClass,
+    task::{RLIMIT_RSS, TaskShared},
+};
+
+use super::clock_ticks;
+
+/// Whether the task is running or waiting to, rather than parked.
+pub(super) fn is_running(task: &TaskShared) -> bool {
+    !task.waker().is_parked()
+}
+
+fn state(task: &TaskShared) -> (char, &'static str) {
+    match is_running(task) {
+        true => ('R', "running"),
+        false => ('S', "sleeping"),
+    }
+}
+
+/// Bytes of the areas of `space` and of their resident pages, for areas
+/// of `ty` or all of them.
+fn memory(space: Option<&AddrSpace>, ty: Option<AreaType>) -> (usize, usize) {
+    space
+        .into_iter()
+        .flat_map(AddrSpace::areas)
+        .filter(|area| ty.is_none_or(|ty| area.area_type() == ty))
+        .fold((0, 0), |(size, rss), area| {
+            let range = area.range();
+            (
+                size + (range.end.as_usize() - range.start.as_usize()),
+                rss + area.resident_pages() * PAGE_SIZE_4K,
+            )
+        })
+}
+
+pub(super) fn cmdline(task: &TaskShared) -> Vec<u8> {
+    task.cmdline()
+}
+
+pub(super) fn stat(task: &TaskShared) -> Vec<u8> {
+    let space = task.space();
+    let (vsize, rss) = memory(space.as_deref(), None);
+    let rsslim = task
+        .limits
+        .lock()
+        .get(RLIMIT_RSS)
+        .map_or(0, |limit| limit.rlim_cur);
+    let comm = task.comm();
+    let sched = task.sched_params();
+    // as Linux reports it: -2 to -100 for real-time tasks, 20 + nice for
+    // the others
+    let priority = match sched.class() {
+        SchedClass::RealTime => -1 - sched.rt_priority() as isize,
+        _ => 20 + sched.nice() as isize,
+    };
+    let mut s = String::new();
+    let _ = write!(
+        s,
+        "{} ({}) {} 0 {pgid} {pgid} 0 -1 0 0 0 0 0 {} 0 0 0 {} {} 1 0 {} {} {} {} ",
+        task.tid(),
+        String::from_utf8_lossy(&comm),
+        state(task).0,
+        clock_ticks(task.runtime()),
+        priority,
+        sched.nice(),
+        clock_ticks(task.start_time()),
+        vsize,
+        rss / PAGE_SIZE_4K,
+        rsslim,
+        pgid = task.pgid(),
+    );
+    // code, stack and registers, then signals, wait channel and swap
+    let _ = write!(s, "0 0 0 0 0 {} 0 0 0 0 0 0 ", task.signals.bits());
+    // exit signal, processor, real-time priority, policy, then the rest
+    let _ = writeln!(
+        s,
+        "17 {} {} {} 0 0 0 0 0 0 0 0 0 0 0",
+        task.processor(),
+        sched.rt_priority(),
+        sched.policy() as usize
+    );
+    s.into_bytes()
+}
+
+pub(super) fn status(task: &TaskShared) -> Vec<u8> {
+    let space = task.space();
+    let space = space.as_deref();
+    let (vsize, rss) = memory(space, None);
+    let stack = memory(space, Some(AreaType::Stack)).0;
+    let heap = memory(space, Some(AreaType::Heap)).0;
+    let (state, state_name) = state(task);
+    let fds = task.files().map_or(0, |files| files.lock().iter().count());
+    let tid = task.tid();
+    let fields = [
+        ("Name", String::from_utf8_lossy(&task.comm()).into_owned()),
+        ("State", format!("{} ({})", state, state_name)),
+        ("Tgid", format!("{}", tid)),
+        ("Pid", format!("{}", tid)),
+        ("PPid", String::from("0")),
+        ("Uid", String::from("0\t0\t0\t0")),
+        ("Gid", String::from("0\t0\t0\t0")),
+        ("FDSize", format!("{}", fds)),
+        ("VmSize", format!("{:>8} kB", vsize / 1024)),
+        ("VmRSS", format!("{:>8} kB", rss / 1024)),
+        ("VmData", format!("{:>8} kB", heap / 1024)),
+        ("VmStk", format!("{:>8} kB", stack / 1024)),
+        ("Threads", String::from("1")),
+        ("SigPnd", format!("{:016x}", task.signals.bits())),
+    ];
+    let mut s = String::new();
+    for (key, value) in fields {
+        let _ = writeln!(s, "{}:\t{}", key, value);
+    }
+    s.into_bytes()
+}
+
+/// Column of the names in `maps`, as Linux aligns them.
+const MAPS_NAME_COLUMN: usize = 73;
+
+pub(super) fn maps(task: &TaskShared) -> Vec<u8> {
+    let Some(space) = task.space() else {
+        return Vec::new();
+    };
+    let mut s = String::new();
+    for area in space.areas() {
+        let range = area.range();
+        let perm = area.perm();
+        let flag = |flag, c| if perm.contains(flag) { c } else { '-' };
+        let (shared, name) = match area.area_type() {
+            AreaType::Stack => ('p', "[stack]"),
+            AreaType::Heap => ('p', "[heap]"),
+            AreaType::Shm => ('s', ""),
+            AreaType::Elf | AreaType::Mmap => ('p', ""),
+        };
+        let line = format!(
+            "{:08x}-{:08x} {}{}{}{} 00000000 00:00 0",
+            range.start.as_usize(),
+            range.end.as_usize(),
+            flag(PTEFlags::R, 'r'),
+            flag(PTEFlags::W, 'w'),
+            flag(PTEFlags::X, 'x'),
+            shared,
+        );
+        match name {
+            "" => {
+                let _ = writeln!(s, "{}", line);
+            }
+            name => {
+                let _ = writeln!(s, "{:<width$}{}", line, name, width = MAPS_NAME_COLUMN);
+            }
+        }
+    }
+    s.into_bytes()
+}
@@ -0,0 +1,55 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
+
+use crate::{block::BlockDevice, sync::SpinNoIrq};
+
+use super::{FsFuture, Inode, makedev};
+
+/// A mounted instance of a filesystem.
+pub trait SuperBlock: Send + Sync {
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Writes all cached data of the filesystem back to its device.
+    fn sync(&self) -> FsFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Called once `umount` detached the filesystem, to write back what
+    /// only an unmount writes.
+    fn unmounted(&self) -> FsFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Creates a superblock from a block device, for filesystems stored on one,
+/// and the option string of `mount`.
+pub type MountFn =
+    for<'a> fn(Option<Arc<dyn BlockDevice>>, &'a str) -> FsFuture<'a, Arc<dyn SuperBlock>>;
+
+/// A kind of filesystem `mount` can create.
+#[derive(Clone, Copy)]
+pub struct FileSystemType {
+    pub name: &'static str,
+    /// Whether the filesystem is stored on a block device.
+    pub needs_device: bool,
+    pub mount: MountFn,
+}
+
+static FILESYSTEMS: SpinNoIrq<BTreeMap<&'static str, FileSystemType>> =
+    SpinNoIrq::new(BTreeMap::new());
+
+pub fn register_filesystem(fs: FileSystemType) {
+    FILESYSTEMS.lock().insert(fs.name, fs);
+}
+
+pub fn filesystem(name: &str) -> Option<FileSystemType> {
+    FILESYSTEMS.lock().get(name).copied()
+}
+
+static NEXT_ANON_MINOR: AtomicU32 = AtomicU32::new(1);
+
+/// A device number for a filesystem without a device, e.g. tmpfs.
+pub fn alloc_anon_dev() -> u64 {
+    makedev(0, NEXT_ANON_MINOR.fetch_add(1, Ordering::Relaxed))
+}
@@ -0,0 +1,592 @@
+use core::{any::Any, ptr};
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, btree_map::Entry},
+    string::{String, ToString},
+    sync::{Arc, Weak},
+};
+
+use crate::{
+    Errno,
+    allocator::PHYS_FRAME_ALLOCATOR,
+    config::PAGE_SIZE_4K,
+    fs::{DirEntry, FsFuture, Inode, InodeType, Metadata, RenameFlags, fs_err},
+    mem::PhysAddr,
+    runtime::TimeSpec,
+    sync::{RwLock, SpinMutex},
+};
+
+use super::{RENAME_LOCK, TmpFsInfo};
+
+/// Largest size of a file.
+const MAX_FILE_SIZE: u64 = 1 << 40;
+
+/// A page frame holding file data.
+struct Page(PhysAddr);
+
+impl Page {
+    fn alloc() -> Result<Self, Errno> {
+        let paddr = PHYS_FRAME_ALLOCATOR
+            .lock()
+            .alloc_frames(1, PAGE_SIZE_4K)
+            .ok_or(Errno::ENOMEM)?;
+        let page = Self(paddr);
+        page.as_mut().fill(0);
+        Ok(page)
+    }
+
+    fn as_ref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.0.as_usize() as *const u8, PAGE_SIZE_4K) }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn as_mut(&self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.0.as_usize() as *mut u8, PAGE_SIZE_4K) }
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        PHYS_FRAME_ALLOCATOR.lock().dealloc_frames(self.0, 1);
+    }
+}
+
+/// Contents of a regular file. Pages never written are holes reading as
+/// zeros.
+#[derive(Default)]
+struct FileData {
+    pages: BTreeMap<usize, Page>,
+    size: u64,
+}
+
+enum InodeData {
+    File(RwLock<FileData>),
+    Dir(SpinMutex<BTreeMap<String, Arc<TmpInode>>>),
+    Symlink(String),
+    /// Devices, fifos and sockets, which have no data of their own.
+    Special,
+}
+
+struct Attr {
+    /// Permission bits.
+    mode: u32,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u64,
+    atime: TimeSpec,
+    mtime: TimeSpec,
+    ctime: TimeSpec,
+}
+
+pub struct TmpInode {
+    ino: u64,
+    ty: InodeType,
+    fs: Arc<TmpFsInfo>,
+    /// The inode itself, for adding it to directories through a reference.
+    this: Weak<TmpInode>,
+    attr: SpinMutex<Attr>,
+    data: InodeData,
+}
+
+impl TmpInode {
+    /// Creates an inode of type `ty` other than a symlink, with permission
+    /// bits `mode` and, for devices, device number `rdev`.
+    pub fn new(
+        fs: &Arc<TmpFsInfo>,
+        ty: InodeType,
+        mode: u32,
+        rdev: u64,
+    ) -> Result<Arc<Self>, Errno> {
+        let data = match ty {
+            InodeType::File => InodeData::File(RwLock::new(FileData::default())),
+            InodeType::Dir => InodeData::Dir(SpinMutex::new(BTreeMap::new())),
+            InodeType::Symlink => return Err(Errno::EINVAL),
+            _ => InodeData::Special,
+        };
+        Self::with_data(fs, ty, mode, rdev, data)
+    }
+
+    fn with_data(
+        fs: &Arc<TmpFsInfo>,
+        ty: InodeType,
+        mode: u32,
+        rdev: u64,
+        data: InodeData,
+    ) -> Result<Arc<Self>, Errno> {
+        let ino = fs.alloc_ino()?;
+        let now = TimeSpec::now();
+        Ok(Arc::new_cyclic(|this| Self {
+            ino,
+            ty,
+            fs: fs.clone(),
+            this: this.clone(),
+            attr: SpinMutex::new(Attr {
+                mode: mode & 0o7777,
+                nlink: if ty == InodeType::Dir { 2 } else { 1 },
+                uid: 0,
+                gid: 0,
+                rdev,
+                atime: now,
+                mtime: now,
+                ctime: now,
+            }),
+            data,
+        }))
+    }
+
+    fn file(&self) -> Result<&RwLock<FileData>, Errno> {
+        match &self.data {
+            InodeData::File(file) => Ok(file),
+            InodeData::Dir(_) => Err(Errno::EISDIR),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    fn entries(&self) -> Result<&SpinMutex<BTreeMap<String, Arc<TmpInode>>>, Errno> {
+        match &self.data {
+            InodeData::Dir(entries) => Ok(entries),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    /// Updates the access time if `access`, and the modification and change
+    /// times if `modify`.
+    fn touch(&self, access: bool, modify: bool) {
+        let now = TimeSpec::now();
+        let mut attr = self.attr.lock();
+        if access {
+            attr.atime = now;
+        }
+        if modify {
+            attr.mtime = now;
+            attr.ctime = now;
+        }
+    }
+
+    /// Updates the change time after a change of attributes or names.
+    fn changed(&self) {
+        self.attr.lock().ctime = TimeSpec::now();
+    }
+
+    /// Adjusts the link count after an entry was added or removed.
+    fn add_links(&self, delta: i32) {
+        let mut attr = self.attr.lock();
+        attr.nlink = attr.nlink.saturating_add_signed(delta);
+        attr.ctime = TimeSpec::now();
+    }
+
+    /// Marks a removed inode: directories lose their `.` link as well.
+    fn unlinked(&self) {
+        match self.ty {
+            InodeType::Dir => {
+                let mut attr = self.attr.lock();
+                attr.nlink = 0;
+                attr.ctime = TimeSpec::now();
+            }
+            _ => self.add_links(-1),
+        }
+    }
+
+    /// Adds entry `name` for `inode`, a new inode or, for hard links, one of
+    /// the same filesystem. Removed directories take no new entries; they
+    /// are marked so while their entries are locked.
+    fn add_entry(&self, name: &str, inode: Arc<TmpInode>) -> Result<(), Errno> {
+        let mut entries = self.entries()?.lock();
+        if self.attr.lock().nlink == 0 {
+            return Err(Errno::ENOENT);
+        }
+        if entries.contains_key(name) {
+            return Err(Errno::EEXIST);
+        }
+        if inode.ty == InodeType::Dir {
+            self.add_links(1);
+        }
+        entries.insert(name.to_string(), inode);
+        drop(entries);
+        self.touch(false, true);
+        Ok(())
+    }
+
+    /// Shrinks or grows the file to `size`, freeing pages past the end.
+    fn resize(&self, data: &mut FileData, size: u64) {
+        if size < data.size {
+            let first_gone = (size as usize).div_ceil(PAGE_SIZE_4K);
+            let freed = data.pages.split_off(&first_gone);
+            self.fs.uncharge_pages(freed.len());
+            // the tail of the last page must read as zeros when the file grows
+            // again
+            let offset = size as usize % PAGE_SIZE_4K;
+            if let Some(page) = data.pages.get(&(size as usize / PAGE_SIZE_4K)) {
+                page.as_mut()[offset..].fill(0);
+            }
+        }
+        data.size = size;
+    }
+}
+
+impl Drop for TmpInode {
+    fn drop(&mut self) {
+        if let InodeData::File(file) = &mut self.data {
+            self.fs.uncharge_pages(file.get_mut().pages.len());
+        }
+        self.fs.free_ino();
+    }
+}
+
+/// Moves entry `old` of `src` to `new` of `dst`, which is `src` itself if
+/// `None`. `src_dir` and `dst_dir` are the directories of the maps.
+fn move_entry(
+    src: &mut BTreeMap<String, Arc<TmpInode>>,
+    mut dst: Option<&mut BTreeMap<String, Arc<TmpInode>>>,
+    (src_dir, old): (&TmpInode, &str),
+    (dst_dir, new): (&TmpInode, &str),
+    flags: RenameFlags,
+) -> Result<(), Errno> {
+    let source = src.get(old).cloned().ok_or(Errno::ENOENT)?;
+    let target = match &dst {
+        Some(dst) => dst.get(new).cloned(),
+        None => src.get(new).cloned(),
+    };
+    let exchange = flags.contains(RenameFlags::EXCHANGE);
+    // a replaced directory stays locked until it is marked removed, so that
+    // nothing is created in it meanwhile
+    let mut _target_entries = None;
+    match &target {
+        // renaming a name to itself or to another link of the same inode
+        Some(target) if Arc::ptr_eq(target, &source) => return Ok(()),
+        Some(_) if flags.contains(RenameFlags::NOREPLACE) => return Err(Errno::EEXIST),
+        // the target contains the source, and its entries are locked
+        Some(target) if ptr::eq(&**target, src_dir) || ptr::eq(&**target, dst_dir) => {
+            return match exchange {
+                true => Err(Errno::EINVAL),
+                false => Err(Errno::ENOTEMPTY),
+            };
+        }
+        Some(target) if !exchange => match (source.ty, target.ty) {
+            (InodeType::Dir, InodeType::Dir) => {
+                let entries = target.entries()?.lock();
+                if !entries.is_empty() {
+                    return Err(Errno::ENOTEMPTY);
+                }
+                _target_entries = Some(entries);
+            }
+            (InodeType::Dir, _) => return Err(Errno::ENOTDIR),
+            (_, InodeType::Dir) => return Err(Errno::EISDIR),
+            _ => {}
+        },
+        Some(_) => {}
+        None if exchange => return Err(Errno::ENOENT),
+        None => {}
+    }
+
+    let moves_dir = |inode: &TmpInode| inode.ty == InodeType::Dir && !ptr::eq(src_dir, dst_dir);
+    if exchange {
+        let target = target.clone().unwrap();
+        match &mut dst {
+            Some(dst) => dst.insert(new.to_string(), source.clone()),
+            None => src.insert(new.to_string(), source.clone()),
+        };
+        src.insert(old.to_string(), target.clone());
+        if moves_dir(&source) {
+            src_dir.add_links(-1);
+            dst_dir.add_links(1);
+        }
+        if moves_dir(&target) {
+            dst_dir.add_links(-1);
+            src_dir.add_links(1);
+        }
+        source.changed();
+        target.changed();
+    } else {
+        src.remove(old);
+        let replaced = match &mut dst {
+            Some(dst) => dst.insert(new.to_string(), source.clone()),
+            None => src.insert(new.to_string(), source.clone()),
+        };
+        if let Some(replaced) = replaced {
+            if replaced.ty == InodeType::Dir {
+                dst_dir.add_links(-1);
+            }
+            replaced.unlinked();
+        }
+        if moves_dir(&source) {
+            src_dir.add_links(-1);
+            dst_dir.add_links(1);
+        }
+        source.changed();
+    }
+    src_dir.touch(false, true);
+    dst_dir.touch(false, true);
+    Ok(())
+}
+
+impl Inode for TmpInode {
+    fn metadata(&self) -> FsFuture<'_, Metadata> {
+        Box::pin(async move {
+            let (size, pages) = match &self.data {
+                InodeData::File(file) => {
+                    let file = file.read().await;
+                    (file.size, file.pages.len() as u64)
+                }
+                // what Linux reports for tmpfs directories
+                InodeData::Dir(entries) => ((entries.lock().len() as u64 + 2) * 20, 0),
+                InodeData::Symlink(target) => (target.len() as u64, 0),
+                InodeData::Special => (0, 0),
+            };
+            let attr = self.attr.lock();
+            Ok(Metadata {
+                dev: self.fs.dev,
+                ino: self.ino,
+                mode: self.ty.mode_bits() | attr.mode,
+                nlink: attr.nlink,
+                uid: attr.uid,
+                gid: attr.gid,
+                rdev: attr.rdev,
+                size,
+                blksize: PAGE_SIZE_4K as u32,
+                blocks: pages * (PAGE_SIZE_4K / 512) as u64,
+                atime: attr.atime,
+                mtime: attr.mtime,
+                ctime: attr.ctime,
+            })
+        })
+    }
+
+    fn inode_type(&self) -> InodeType {
+        self.ty
+    }
+
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let file = self.file()?.read().await;
+            if offset >= file.size {
+                return Ok(0);
+            }
+            let len = buf.len().min((file.size - offset) as usize);
+            let mut done = 0;
+            while done < len {
+                let pos = offset as usize + done;
+                let page_offset = pos % PAGE_SIZE_4K;
+                let chunk = (PAGE_SIZE_4K - page_offset).min(len - done);
+                let dst = &mut buf[done..done + chunk];
+                match file.pages.get(&(pos / PAGE_SIZE_4K)) {
+                    Some(page) => dst.copy_from_slice(&page.as_ref()[page_offset..][..chunk]),
+                    None => dst.fill(0),
+                }
+                done += chunk;
+            }
+            drop(file);
+            self.touch(true, false);
+            Ok(len)
+        })
+    }
+
+    fn write_at<'a>(&'a self, offset: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let mut file = self.file()?.write().await;
+            let end = offset.checked_add(buf.len() as u64).ok_or(Errno::EFBIG)?;
+            if end > MAX_FILE_SIZE {
+                return Err(Errno::EFBIG);
+            }
+            let mut done = 0;
+            while done < buf.len() {
+                let pos = offset as usize + done;
+                let page_offset = pos % PAGE_SIZE_4K;
+                let chunk = (PAGE_SIZE_4K - page_offset).min(buf.len() - done);
+                let index = pos / PAGE_SIZE_4K;
+                let page = match file.pages.entry(index) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let page = self
+                            .fs
+                            .charge_pages(1)
+                            .and_then(|_| Page::alloc().inspect_err(|_| self.fs.uncharge_pages(1)));
+                        match page {
+                            Ok(page) => entry.insert(page),
+                            Err(_) if done > 0 => break,
+                            Err(err) => return Err(err),
+                        }
+                    }
+                };
+                page.as_mut()[page_offset..][..chunk].copy_from_slice(&buf[done..done + chunk]);
+                done += chunk;
+            }
+            file.size = file.size.max(offset + done as u64);
+            drop(file);
+            self.touch(false, true);
+            Ok(done)
+        })
+    }
+
+    fn truncate(&self, size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            if size > MAX_FILE_SIZE {
+                return Err(Errno::EFBIG);
+            }
+            let mut file = self.file()?.write().await;
+            self.resize(&mut file, size);
+            drop(file);
+            self.touch(false, true);
+            Ok(())
+        })
+    }
+
+    fn set_mode(&self, mode: u32) -> FsFuture<'_, ()> {
+        let mut attr = self.attr.lock();
+        attr.mode = mode & 0o7777;
+        attr.ctime = TimeSpec::now();
+        Box::pin(async { Ok(()) })
+    }
+
+    fn set_times(&self, atime: Option<TimeSpec>, mtime: Option<TimeSpec>) -> FsFuture<'_, ()> {
+        let mut attr = self.attr.lock();
+        if let Some(atime) = atime {
+            attr.atime = atime;
+        }
+        if let Some(mtime) = mtime {
+            attr.mtime = mtime;
+        }
+        attr.ctime = TimeSpec::now();
+        Box::pin(async { Ok(()) })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        let inode = self.entries().and_then(|entries| {
+            let inode: Arc<dyn Inode> = entries.lock().get(name).ok_or(Errno::ENOENT)?.clone();
+            Ok(inode)
+        });
+        Box::pin(async move { inode })
+    }
+
+    fn create<'a>(
+        &'a self,
+        name: &'a str,
+        ty: InodeType,
+        mode: u32,
+        rdev: u64,
+    ) -> FsFuture<'a, Arc<dyn Inode>> {
+        let result = self.entries().and_then(|_| {
+            let inode = TmpInode::new(&self.fs, ty, mode, rdev)?;
+            self.add_entry(name, inode.clone())?;
+            let inode: Arc<dyn Inode> = inode;
+            Ok(inode)
+        });
+        Box::pin(async move { result })
+    }
+
+    fn symlink<'a>(&'a self, name: &'a str, target: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        let result = self.entries().and_then(|_| {
+            let data = InodeData::Symlink(target.to_string());
+            let inode = TmpInode::with_data(&self.fs, InodeType::Symlink, 0o777, 0, data)?;
+            self.add_entry(name, inode.clone())?;
+            let inode: Arc<dyn Inode> = inode;
+            Ok(inode)
+        });
+        Box::pin(async move { result })
+    }
+
+    fn link<'a>(&'a self, name: &'a str, target: &'a Arc<dyn Inode>) -> FsFuture<'a, ()> {
+        let result = (|| {
+            self.entries()?;
+            let target = target
+                .as_any()
+                .downcast_ref::<TmpInode>()
+                .filter(|target| Arc::ptr_eq(&target.fs, &self.fs))
+                .ok_or(Errno::EXDEV)?;
+            if target.ty == InodeType::Dir {
+                return Err(Errno::EPERM);
+            }
+            let inode = target.this.upgrade().ok_or(Errno::ENOENT)?;
+            self.add_entry(name, inode)?;
+            target.add_links(1);
+            Ok(())
+        })();
+        Box::pin(async move { result })
+    }
+
+    fn unlink<'a>(&'a self, name: &'a str) -> FsFuture<'a, ()> {
+        let result = self.entries().and_then(|entries| {
+            // checking that a directory is empty locks a second directory
+            let _rename = RENAME_LOCK.lock();
+            let mut entries = entries.lock();
+            let inode = entries.get(name).cloned().ok_or(Errno::ENOENT)?;
+            let children = inode.entries().ok().map(|children| children.lock());
+            if children
+                .as_ref()
+                .is_some_and(|children| !children.is_empty())
+            {
+                return Err(Errno::ENOTEMPTY);
+            }
+            entries.remove(name);
+            inode.unlinked();
+            drop(children);
+            drop(entries);
+            if inode.ty == InodeType::Dir {
+                self.add_links(-1);
+            }
+            self.touch(false, true);
+            Ok(())
+        });
+        Box::pin(async move { result })
+    }
+
+    fn rename<'a>(
+        &'a self,
+        old: &'a str,
+        new_dir: &'a Arc<dyn Inode>,
+        new: &'a str,
+        flags: RenameFlags,
+    ) -> FsFuture<'a, ()> {
+        let result = (|| {
+            if flags.contains(RenameFlags::WHITEOUT) {
+                return Err(Errno::EINVAL);
+            }
+            let new_dir = new_dir
+                .as_any()
+                .downcast_ref::<TmpInode>()
+                .filter(|dir| Arc::ptr_eq(&dir.fs, &self.fs))
+                .ok_or(Errno::EXDEV)?;
+            let _rename = RENAME_LOCK.lock();
+            let mut src = self.entries()?.lock();
+            match ptr::eq(self, new_dir) {
+                true => move_entry(&mut src, None, (self, old), (self, new), flags),
+                false => {
+                    let mut dst = new_dir.entries()?.lock();
+                    move_entry(&mut src, Some(&mut dst), (self, old), (new_dir, new), flags)
+                }
+            }
+        })();
+        Box::pin(async move { result })
+    }
+
+    fn read_link(&self) -> FsFuture<'_, String> {
+        match &self.data {
+            InodeData::Symlink(target) => {
+                let target = target.clone();
+                Box::pin(async move { Ok(target) })
+            }
+            _ => fs_err(Errno::EINVAL),
+        }
+    }
+
+    fn read_dir(&self, index: usize) -> FsFuture<'_, Option<DirEntry>> {
+        let entry = self.entries().map(|entries| {
+            entries
+                .lock()
+                .iter()
+                .nth(index)
+                .map(|(name, inode)| DirEntry {
+                    ino: inode.ino,
+                    name: name.clone(),
+                    ty: inode.ty,
+                })
+        });
+        Box::pin(async move { entry })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,173 @@
+mod inode;
+
+pub use inode::*;
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use alloc::{boxed::Box, sync::Arc};
+
+use crate::{
+    Errno, allocator::PHYS_FRAME_ALLOCATOR, block::BlockDevice, config::PAGE_SIZE_4K,
+    sync::SpinMutex,
+};
+
+use super::{FileSystemType, FsFuture, Inode, InodeType, SuperBlock, alloc_anon_dev};
+
+pub const TMPFS: FileSystemType = FileSystemType {
+    name: "tmpfs",
+    needs_device: false,
+    mount: mount_tmpfs,
+};
+
+/// Limits of a tmpfs instance, from the options of `mount`.
+#[derive(Debug, Clone, Copy)]
+pub struct TmpFsOptions {
+    /// Most pages of file data.
+    pub max_pages: usize,
+    pub max_inodes: usize,
+    /// Permission bits of the root directory.
+    pub mode: u32,
+}
+
+impl Default for TmpFsOptions {
+    /// Half of the memory and one inode per page of it, as Linux does.
+    fn default() -> Self {
+        let pages = PHYS_FRAME_ALLOCATOR.lock().total_frames() / 2;
+        Self {
+            max_pages: pages,
+            max_inodes: pages,
+            mode: 0o1777,
+        }
+    }
+}
+
+/// Parses a size with an optional `k`, `m` or `g` suffix, or a percentage of
+/// the memory.
+fn parse_size(value: &str) -> Option<usize> {
+    if let Some(percent) = value.strip_suffix('%') {
+        let total = PHYS_FRAME_ALLOCATOR.lock().total_frames() * PAGE_SIZE_4K;
+        return Some(total / 100 * percent.parse::<usize>().ok()?);
+    }
+    let (digits, shift) = match value.as_bytes().last()? {
+        b'k' | b'K' => (&value[..value.len() - 1], 10),
+        b'm' | b'M' => (&value[..value.len() - 1], 20),
+        b'g' | b'G' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+impl TmpFsOptions {
+    /// Parses `size=`, `nr_blocks=`, `nr_inodes=` and `mode=` options
+    /// separated by commas.
+    pub fn parse(data: &str) -> Result<Self, Errno> {
+        let mut options = Self::default();
+        for option in data.split(',').filter(|option| !option.is_empty()) {
+            let (key, value) = option.split_once('=').ok_or(Errno::EINVAL)?;
+            match key {
+                "size" => {
+                    let size = parse_size(value).ok_or(Errno::EINVAL)?;
+                    options.max_pages = size.div_ceil(PAGE_SIZE_4K);
+                }
+                "nr_blocks" => options.max_pages = parse_size(value).ok_or(Errno::EINVAL)?,
+                "nr_inodes" => options.max_inodes = parse_size(value).ok_or(Errno::EINVAL)?,
+                "mode" => {
+                    options.mode =
+                        u32::from_str_radix(value, 8).map_err(|_| Errno::EINVAL)? & 0o7777
+                }
+                _ => return Err(Errno::EINVAL),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// State of a tmpfs instance shared by its inodes.
+pub struct TmpFsInfo {
+    dev: u64,
+    options: TmpFsOptions,
+    pages: AtomicUsize,
+    inodes: AtomicUsize,
+    next_ino: AtomicU64,
+}
+
+impl TmpFsInfo {
+    /// Accounts for `count` more pages, failing with `ENOSPC` past the
+    /// size limit.
+    fn charge_pages(&self, count: usize) -> Result<(), Errno> {
+        self.pages
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pages| {
+                (pages + count <= self.options.max_pages).then_some(pages + count)
+            })
+            .map(|_| ())
+            .map_err(|_| Errno::ENOSPC)
+    }
+
+    fn uncharge_pages(&self, count: usize) {
+        self.pages.fetch_sub(count, Ordering::AcqRel);
+    }
+
+    /// Takes an inode number, failing with `ENOSPC` past the inode limit.
+    fn alloc_ino(&self) -> Result<u64, Errno> {
+        self.inodes
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |inodes| {
+                (inodes < self.options.max_inodes).then_some(inodes + 1)
+            })
+            .map_err(|_| Errno::ENOSPC)?;
+        Ok(self.next_ino.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn free_ino(&self) {
+        self.inodes.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A filesystem in memory: file data lives in page frames, and everything
+/// is gone when it is unmounted.
+pub struct TmpFs {
+    info: Arc<TmpFsInfo>,
+    root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+    pub fn new(options: TmpFsOptions) -> Result<Arc<Self>, Errno> {
+        let info = Arc::new(TmpFsInfo {
+            dev: alloc_anon_dev(),
+            options,
+            pages: AtomicUsize::new(0),
+            inodes: AtomicUsize::new(0),
+            next_ino: AtomicU64::new(1),
+        });
+        let root = TmpInode::new(&info, InodeType::Dir, options.mode, 0)?;
+        Ok(Arc::new(Self { info, root }))
+    }
+
+    /// Bytes of file data stored and the limit.
+    pub fn usage(&self) -> (usize, usize) {
+        (
+            self.info.pages.load(Ordering::Acquire) * PAGE_SIZE_4K,
+            self.info.options.max_pages * PAGE_SIZE_4K,
+        )
+    }
+}
+
+impl SuperBlock for TmpFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+fn mount_tmpfs(
+    _device: Option<Arc<dyn BlockDevice>>,
+    data: &str,
+) -> FsFuture<'_, Arc<dyn SuperBlock>> {
+    Box::pin(async move {
+        let fs: Arc<dyn SuperBlock> = TmpFs::new(TmpFsOptions::parse(data)?)?;
+        Ok(fs)
+    })
+}
+
+/// Serializes the operations locking the entries of more than one
+/// directory, renames and removals of directories, so that they cannot
+/// deadlock each other.
+static RENAME_LOCK: SpinMutex<()> = SpinMutex::new(());
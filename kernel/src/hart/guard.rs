@@ -0,0 +1,91 @@
+use core::{cell::Cell, marker::PhantomData};
+
+use riscv::register::sstatus;
+
+use crate::percpu;
+
+percpu! {
+    /// Number of [`NoPreemptGuard`]s alive on the hart.
+    static PREEMPT_COUNT: Cell<usize> = Cell::new(0);
+}
+
+/// Disables supervisor interrupts on this hart until dropped, restoring the
+/// previous `sstatus.SIE`. Guards nest.
+pub struct IrqGuard {
+    was_enabled: bool,
+    // tied to the hart whose interrupts were disabled
+    _not_send: PhantomData<*const ()>,
+}
+
+impl IrqGuard {
+    pub fn new() -> Self {
+        let was_enabled = sstatus::read().sie();
+        if was_enabled {
+            unsafe { sstatus::clear_sie() };
+        }
+        Self {
+            was_enabled,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Default for IrqGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        if self.was_enabled {
+            unsafe { sstatus::set_sie() };
+        }
+    }
+}
+
+/// Keeps the current task on this hart until dropped. Kernel code only
+/// leaves a hart at awaits, so the guard marks sections which rely on
+/// hart-local state, and the executor panics if a task awaits with a guard
+/// alive. Guards nest.
+pub struct NoPreemptGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl NoPreemptGuard {
+    pub fn new() -> Self {
+        PREEMPT_COUNT.with(|count| count.set(count.get() + 1));
+        Self {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Default for NoPreemptGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for NoPreemptGuard {
+    fn drop(&mut self) {
+        PREEMPT_COUNT.with(|count| count.set(count.get() - 1));
+    }
+}
+
+/// Whether no [`NoPreemptGuard`] is alive on this hart.
+pub fn preemptible() -> bool {
+    PREEMPT_COUNT.with(|count| count.get() == 0)
+}
+
+/// Panics if task `tid` left a [`NoPreemptGuard`] alive when it was
+/// suspended. Called by the executor after each poll.
+pub fn check_preemptible(tid: usize) {
+    let count = PREEMPT_COUNT.with(Cell::get);
+    assert!(
+        count == 0,
+        "task {} kept preemption disabled ({}) across an await",
+        tid,
+        count
+    );
+}
@@ -0,0 +1,87 @@
+use core::time::Duration;
+
+use log::info;
+
+use crate::{
+    KError, KResult,
+    runtime::{EXECUTOR, Executor, sleep, timeout, wake_timers},
+};
+
+use super::{
+    CpuMask, booted_cpus, cpu_count, handle_ipi, hart_id, hartid_of, kick, mark_stopped,
+    restart_hart,
+};
+
+/// Interval at which [`offline`] and [`online`] check the hart state.
+const HOTPLUG_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How long [`offline`] and [`online`] wait for the hart to change state.
+const HOTPLUG_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Takes logical CPU `cpu` offline: the executor stops handing it work, its
+/// tasks move to other harts and it is stopped with `hart_stop`. Completes
+/// once the hart is down. May be called on `cpu` itself, the caller then
+/// continues on another hart.
+///
+/// The last online hart cannot be taken offline.
+pub async fn offline(cpu: usize) -> KResult<()> {
+    if cpu >= cpu_count() {
+        return Err(KError::InvalidArgument);
+    }
+    let executor = EXECUTOR.get().expect("executor initialized");
+    let mut online = executor.online();
+    if !online.contains(cpu) {
+        return Ok(());
+    }
+    online.remove(cpu);
+    if online.is_empty() {
+        return Err(KError::Busy);
+    }
+    executor.set_online(cpu, false);
+    kick(CpuMask::single(cpu));
+    wait_for(|| !booted_cpus().contains(cpu)).await
+}
+
+/// Restarts logical CPU `cpu` after [`offline`] and completes once it takes
+/// tasks again.
+pub async fn online(cpu: usize) -> KResult<()> {
+    if cpu >= cpu_count() {
+        return Err(KError::InvalidArgument);
+    }
+    let executor = EXECUTOR.get().expect("executor initialized");
+    if executor.online().contains(cpu) {
+        return Ok(());
+    }
+    if booted_cpus().contains(cpu) {
+        // still on its way down
+        return Err(KError::Busy);
+    }
+    restart_hart(cpu)?;
+    wait_for(|| executor.online().contains(cpu)).await
+}
+
+pub(super) async fn wait_for(done: impl Fn() -> bool) -> KResult<()> {
+    let wait = async {
+        while !done() {
+            sleep(HOTPLUG_POLL_INTERVAL).await;
+        }
+    };
+    timeout(HOTPLUG_TIMEOUT, wait)
+        .await
+        .map_err(|_| KError::TimedOut)
+}
+
+/// Called by the scheduler loop of a hart whose online bit was cleared:
+/// hands its queued tasks and timers to other harts and stops it.
+pub(super) fn stop_local_hart(executor: &Executor) -> ! {
+    let cpu = hart_id();
+    executor.set_idle(cpu, false);
+    executor.drain(cpu);
+    wake_timers(cpu);
+    handle_ipi();
+    mark_stopped(cpu);
+    info!("cpu {} (hart {}) going offline", cpu, hartid_of(cpu));
+    let ret = sbi_rt::hart_stop();
+    // only returns on failure
+    panic!("hart_stop of cpu {} failed: {:?}", cpu, ret);
+}
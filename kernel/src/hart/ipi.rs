@@ -0,0 +1,107 @@
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc};
+use log::warn;
+use riscv::register::{sie, sip};
+
+use crate::{
+    mem::{VirtAddr, flush_tlb_local},
+    percpu,
+    sync::SpinNoIrq,
+};
+
+use super::{CpuMask, booted_cpus, hart_id, hart_masks};
+
+/// A cross-call request delivered to another hart by an IPI.
+pub enum IpiRequest {
+    /// Flushes the local TLB for `asid` in `range`, then clears the bit of
+    /// this hart in `ack`.
+    FlushTlb {
+        asid: usize,
+        range: Range<VirtAddr>,
+        ack: Arc<AtomicUsize>,
+    },
+    /// Runs a closure on the target hart.
+    Call(Box<dyn FnOnce() + Send>),
+}
+
+percpu! {
+    /// Requests waiting to be handled by the hart.
+    static MAILBOX: SpinNoIrq<VecDeque<IpiRequest>> = SpinNoIrq::new(VecDeque::new());
+    /// IPIs taken by the hart.
+    static IPI_COUNT: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Enables the supervisor software interrupt, which carries IPIs.
+pub fn init_ipi() {
+    unsafe { sie::set_ssoft() };
+}
+
+/// Interrupts the harts in `mask`. An IPI without a mailbox request only
+/// makes an idle hart leave `wfi` and look for work.
+pub fn kick(mask: CpuMask) {
+    if mask.is_empty() {
+        return;
+    }
+    for harts in hart_masks(mask) {
+        if let Some(err) = sbi_rt::send_ipi(harts).err() {
+            warn!("send_ipi to {:?} failed: {:?}", mask, err);
+        }
+    }
+}
+
+/// Queues `request` for `hart_id` and interrupts it.
+pub fn send_ipi(hart_id: usize, request: IpiRequest) {
+    MAILBOX.on(hart_id).lock().push_back(request);
+    kick(CpuMask::single(hart_id));
+}
+
+/// Sends a TLB flush of `asid` in `range` to every hart in `mask` and waits
+/// until all of them performed it. Requests sent to this hart meanwhile are
+/// served while waiting, so that two harts shooting at each other do not
+/// deadlock. A hart which stops meanwhile is not waited for, it flushes its
+/// whole TLB when it starts again.
+pub fn shootdown_tlb(mask: CpuMask, asid: usize, range: Range<VirtAddr>) {
+    let ack = Arc::new(AtomicUsize::new(mask.bits()));
+    for id in mask.iter() {
+        send_ipi(id, IpiRequest::FlushTlb {
+            asid,
+            range: range.clone(),
+            ack: ack.clone(),
+        });
+    }
+    while ack.load(Ordering::Acquire) & booted_cpus().bits() != 0 {
+        handle_requests();
+        core::hint::spin_loop();
+    }
+}
+
+/// Handles a supervisor software interrupt: acknowledges it and drains the
+/// mailbox of this hart.
+pub fn handle_ipi() {
+    unsafe { sip::clear_ssoft() };
+    IPI_COUNT.local().fetch_add(1, Ordering::Relaxed);
+    handle_requests();
+}
+
+/// IPIs taken by CPU `cpu`.
+pub fn ipi_count(cpu: usize) -> usize {
+    IPI_COUNT.on(cpu).load(Ordering::Relaxed)
+}
+
+fn handle_requests() {
+    let mailbox = MAILBOX.local();
+    // pop one at a time, a call may send IPIs itself
+    while let Some(request) = mailbox.lock().pop_front() {
+        match request {
+            IpiRequest::FlushTlb { asid, range, ack } => {
+                flush_tlb_local(asid, range);
+                ack.fetch_and(!(1 << hart_id()), Ordering::Release);
+            }
+            IpiRequest::Call(f) => f(),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use core::fmt;
+
+use crate::config::MAX_HARTS;
+
+/// A set of harts, one bit per hart id.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct CpuMask(usize);
+
+impl CpuMask {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn full() -> Self {
+        Self((1 << MAX_HARTS) - 1)
+    }
+
+    pub const fn single(hart_id: usize) -> Self {
+        Self(1 << hart_id)
+    }
+
+    /// Builds a mask from raw bits, dropping harts beyond `MAX_HARTS`.
+    pub const fn from_bits(bits: usize) -> Self {
+        Self(bits & Self::full().0)
+    }
+
+    pub const fn bits(self) -> usize {
+        self.0
+    }
+
+    pub const fn contains(self, hart_id: usize) -> bool {
+        hart_id < MAX_HARTS && self.0 & (1 << hart_id) != 0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub fn insert(&mut self, hart_id: usize) {
+        self.0 |= 1 << hart_id;
+    }
+
+    pub fn remove(&mut self, hart_id: usize) {
+        self.0 &= !(1 << hart_id);
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = usize> {
+        (0..MAX_HARTS).filter(move |&id| self.contains(id))
+    }
+}
+
+impl fmt::Debug for CpuMask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_fmt(format_args!("CpuMask({:#x})", self.0))
+    }
+}
@@ -1,69 +1,250 @@
-use core::arch::asm;
+mod guard;
+mod hotplug;
+mod ipi;
+mod mask;
+mod percpu;
+#[cfg(feature = "selftest")]
+mod selftest;
+mod smp;
+
+pub use guard::*;
+pub use hotplug::*;
+pub use ipi::*;
+pub use mask::*;
+pub use percpu::*;
+#[cfg(feature = "selftest")]
+pub use selftest::*;
+pub use smp::*;
+
+use core::cell::{Cell, RefCell, SyncUnsafeCell};
 
 use alloc::{boxed::Box, sync::Arc};
 use riscv::register::sstatus::{self, FS};
 
+use core::task::{Context, Poll, Waker};
+
+use log::{debug, warn};
+use riscv::register::{satp, sip};
+
 use crate::{
     config::{KERNEL_STACK_SIZE, MAX_HARTS},
-    runtime::EXECUTOR,
-    task::{IdleTask, Task},
-    trap::{TrapContext, user_trap_return},
+    drivers::{handle_external_irq, init_plic_hart},
+    mem::AddrSpace,
+    percpu,
+    runtime::{EXECUTOR, Runnable, handle_timer, init_timer},
+    sync::check_no_spin_held,
+    syscall::syscall_ret,
+    task::{DefaultAction, Task, default_action, find_task},
+    trap::{TrapContext, user_trap_handler, user_trap_return},
 };
 
-const HART_EACH: Hart = Hart::empty();
-static mut HARTS: [Hart; MAX_HARTS] = [HART_EACH; MAX_HARTS];
+/// Stack on which a hart handles traps from user mode and runs the scheduler
+/// loop.
+#[repr(align(4096))]
+struct KernelStack([u8; KERNEL_STACK_SIZE]);
 
-pub struct Hart {
-    hart_id: usize,
-    kernel_stack: [u8; KERNEL_STACK_SIZE],
-    task: Option<Box<Task>>,
-    idle: IdleTask,
+static KERNEL_STACKS: [SyncUnsafeCell<KernelStack>; MAX_HARTS] =
+    [const { SyncUnsafeCell::new(KernelStack([0; KERNEL_STACK_SIZE])) }; MAX_HARTS];
+
+fn kernel_stack_top(cpu: usize) -> usize {
+    KERNEL_STACKS[cpu].get() as usize + KERNEL_STACK_SIZE
 }
 
-impl Hart {
-    pub const fn empty() -> Self {
-        Self {
-            hart_id: 0,
-            kernel_stack: [0; KERNEL_STACK_SIZE],
-            task: None,
-            idle: IdleTask::new(),
-        }
+percpu! {
+    /// Logical CPU number of the hart owning the area.
+    static CPU_ID: Cell<usize> = Cell::new(0);
+    /// The user task this hart is running.
+    static CURRENT_TASK: RefCell<Option<Box<Task>>> = RefCell::new(None);
+}
+
+/// Sets up the per-CPU area of logical CPU `cpu` for the calling hart.
+pub fn init(cpu: usize) {
+    unsafe { set_local_area(cpu) };
+    CPU_ID.with(|id| id.set(cpu));
+    // sstatus::set_fs(FS::Initial);
+}
+
+/// Id of the hart we are running on.
+pub fn hart_id() -> usize {
+    CPU_ID.with(Cell::get)
+}
+
+/// Like [`hart_id`], but returns `None` before [`init`] ran on this hart.
+pub fn try_hart_id() -> Option<usize> {
+    if local_area() == 0 {
+        None
+    } else {
+        Some(hart_id())
     }
 }
 
-pub fn init(hart_id: usize) {
-    unsafe {
-        set_local_hart(hart_id);
-        // sstatus::set_fs(FS::Initial);
+pub fn run_first() -> ! {
+    EXECUTOR
+        .get()
+        .expect("executor initialized")
+        .set_online(hart_id(), true);
+    init_timer();
+    init_ipi();
+    init_plic_hart();
+    run_tasks()
+}
+
+/// Scheduler loop of a hart. Kernel tasks are polled in place, user tasks are
+/// resumed by returning to user mode, from where the next trap enters the
+/// kernel on a fresh stack.
+pub fn run_tasks() -> ! {
+    let executor = EXECUTOR.get().expect("executor initialized");
+    let id = hart_id();
+    loop {
+        if !executor.online().contains(id) {
+            stop_local_hart(executor);
+        }
+        // interrupts are off in the kernel, so poll for them
+        let pending = sip::read();
+        if pending.stimer() {
+            handle_timer();
+        }
+        if pending.ssoft() {
+            handle_ipi();
+        }
+        if pending.sext() {
+            handle_external_irq();
+        }
+        let runnable = executor.fetch().or_else(|| {
+            // Whoever queues a runnable after the idle mark is visible kicks
+            // us, and the pending IPI makes `wfi` return right away.
+            executor.set_idle(id, true);
+            let runnable = executor.fetch();
+            if runnable.is_none() {
+                riscv::asm::wfi();
+            }
+            executor.set_idle(id, false);
+            runnable
+        });
+        match runnable {
+            Some(Runnable::User(task)) => run_task(task),
+            Some(Runnable::Kernel(task)) => executor.run_kernel_task(task),
+            None => {}
+        }
     }
 }
 
-unsafe fn get_hart(hart_id: usize) -> &'static mut Hart {
-    &mut HARTS[hart_id]
+pub fn run_task(task: Box<Task>) -> ! {
+    // `/proc/self` of the task leads to its entry in the registry
+    debug_assert!(
+        find_task(task.tid()).is_some(),
+        "task {} is not registered",
+        task.tid()
+    );
+    CURRENT_TASK.with(|slot| *slot.borrow_mut() = Some(task));
+    resume_current()
+}
+
+/// Takes the current task off this hart.
+fn take_current() -> Option<Box<Task>> {
+    CURRENT_TASK.with(|slot| slot.borrow_mut().take())
+}
+
+/// Runs `f` with the user task this hart is running. The task stays on the
+/// hart while `f` runs, which must not use the current task itself.
+pub fn with_current_task<R>(f: impl FnOnce(&mut Task) -> R) -> R {
+    try_with_current_task(f).expect("no current task")
 }
 
-pub unsafe fn set_local_hart(hart_id: usize) {
-    let hart = get_hart(hart_id);
-    hart.hart_id = hart_id;
-    let hart_addr = hart as *const _ as usize;
-    asm!("mv tp, {}", in(reg) hart_addr);
+/// The address space of the current task.
+pub fn current_space() -> Arc<AddrSpace> {
+    with_current_task(|task| task.space().clone())
 }
 
-pub fn local_hart() -> &'static mut Hart {
-    unsafe {
-        let tp: usize;
-        asm!("mv {}, tp", out(reg) tp);
-        &mut *(tp as *mut Hart)
+/// Like [`with_current_task`], but returns `None` outside of user tasks,
+/// e.g. in kernel tasks.
+pub fn try_with_current_task<R>(f: impl FnOnce(&mut Task) -> R) -> Option<R> {
+    CURRENT_TASK.with(|slot| slot.borrow_mut().as_deref_mut().map(f))
+}
+
+/// Continues the current task: finishes its pending syscall if any and
+/// returns to user mode. If the syscall is not ready, the task is parked and
+/// the hart goes back to the scheduler loop. A task whose affinity no longer
+/// includes this hart, or whose hart is going offline, is handed back to the
+/// executor to migrate.
+///
+/// The hart-specific fields of the trap context are refreshed on every
+/// return, so a task can be resumed on any hart it is moved to.
+pub fn resume_current() -> ! {
+    let executor = EXECUTOR.get().expect("executor initialized");
+    let cpu = hart_id();
+    let kernel_sp = kernel_stack_top(cpu);
+    // the syscall is polled out of the task, which it reaches through
+    // `with_current_task`
+    let (tid, waker, syscall) =
+        with_current_task(|task| (task.tid(), task.waker().clone(), task.syscall.take()));
+    if let Some(mut syscall) = syscall {
+        waker.clear_notified();
+        let poll = syscall
+            .as_mut()
+            .poll(&mut Context::from_waker(&Waker::from(waker)));
+        check_no_spin_held(tid);
+        check_preemptible(tid);
+        match poll {
+            Poll::Ready(ret) => {
+                with_current_task(|task| task.trap_context_mut().user_x[10] = syscall_ret(ret))
+            }
+            Poll::Pending => {
+                let mut task = take_current().expect("no current task");
+                task.syscall = Some(syscall);
+                executor.park(Runnable::User(task));
+                run_tasks()
+            }
+        }
+    }
+    while let Some(sig) = with_current_task(|task| task.shared().signals.take()) {
+        match default_action(sig) {
+            DefaultAction::Terminate => {
+                warn!("task {} terminated by signal {}", tid, sig);
+                exit_current()
+            }
+            action => debug!("task {} ignores signal {} ({:?})", tid, sig, action),
+        }
     }
+    let allowed = with_current_task(|task| {
+        task.apply_sched_params();
+        task.sched.can_run_on(cpu)
+    });
+    if !allowed || !executor.online().contains(cpu) {
+        let task = take_current().expect("no current task");
+        executor.schedule(Runnable::User(task));
+        run_tasks()
+    }
+    let cx = with_current_task(|task| {
+        let cx = task.trap_context_mut();
+        cx.kernel_sp = kernel_sp;
+        cx.kernel_satp = satp::read().bits();
+        cx.user_trap_handler = user_trap_handler as usize;
+        executor.begin_run(&mut task.sched);
+        task.shared().set_processor(cpu);
+        task.trap_context.get()
+    });
+    // the task stays on this hart, and its trap context in place, until the
+    // next trap
+    user_trap_return(cx)
 }
 
-pub fn run_first() {
-    let task = unsafe { EXECUTOR.get_unchecked().fetch().expect("no task to run") };
-    let hart = local_hart();
-    hart.task = Some(task);
-    user_trap_return(hart.task.as_ref().unwrap())
+/// Puts the current task back to the run queue and goes back to the
+/// scheduler loop, e.g. when its time slice is over.
+pub fn yield_current() -> ! {
+    if let Some(task) = take_current() {
+        EXECUTOR
+            .get()
+            .expect("executor initialized")
+            .schedule(Runnable::User(task));
+    }
+    run_tasks()
 }
 
-pub fn run_task(task: Box<Task>) {
-    todo!()
+/// Drops the current task and goes back to the scheduler loop.
+pub fn exit_current() -> ! {
+    if let Some(task) = take_current() {
+        task.waker().clear_notified();
+    }
+    run_tasks()
 }
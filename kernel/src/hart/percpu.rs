@@ -0,0 +1,134 @@
+use core::{arch::asm, cell::UnsafeCell};
+
+use crate::config::MAX_HARTS;
+
+use super::IrqGuard;
+
+unsafe extern "C" {
+    fn spercpu();
+    fn epercpu();
+    fn epercpu_areas();
+}
+
+/// Declares per-CPU variables. Every hart gets its own instance, initialized
+/// with the given value:
+///
+/// ```ignore
+/// percpu! {
+///     static COUNTER: AtomicUsize = AtomicUsize::new(0);
+/// }
+/// COUNTER.local().fetch_add(1, Ordering::Relaxed);
+/// ```
+///
+/// The variables live in the `.percpu` section, which serves as the area of
+/// CPU 0 and is copied for the other CPUs at boot.
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            #[unsafe(link_section = ".percpu")]
+            $vis static $name: $crate::hart::PerCpu<$ty> = $crate::hart::PerCpu::new($init);
+        )*
+    };
+}
+
+/// Distance between the per-CPU areas.
+fn area_size() -> usize {
+    epercpu as usize - spercpu as usize
+}
+
+fn area_of(cpu: usize) -> usize {
+    spercpu as usize + cpu * area_size()
+}
+
+/// Copies the initial values of the per-CPU variables into the areas of all
+/// CPUs but 0. Must run on the boot hart before any of them is modified.
+pub fn init_percpu_areas() {
+    assert_eq!(
+        area_of(MAX_HARTS),
+        epercpu_areas as usize,
+        "the linker script reserves per-CPU areas for another MAX_HARTS"
+    );
+    for cpu in 1..MAX_HARTS {
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                spercpu as *const u8,
+                area_of(cpu) as *mut u8,
+                area_size(),
+            );
+        }
+    }
+}
+
+/// Points `tp` at the per-CPU area of `cpu`.
+///
+/// # Safety
+///
+/// `cpu` must be the logical CPU number of the calling hart, no other hart
+/// may use its area.
+pub(super) unsafe fn set_local_area(cpu: usize) {
+    unsafe { asm!("mv tp, {}", in(reg) area_of(cpu)) };
+}
+
+/// Base of the local per-CPU area, or 0 before [`set_local_area`].
+pub(super) fn local_area() -> usize {
+    let tp: usize;
+    unsafe { asm!("mv {}, tp", out(reg) tp) };
+    tp
+}
+
+/// A per-CPU variable, declared with [`percpu!`].
+///
+/// The instance of another CPU is reachable with [`on`](Self::on) if the
+/// type is `Sync`. Other types can only be used by their own hart, through
+/// [`with`](Self::with).
+pub struct PerCpu<T> {
+    value: UnsafeCell<T>,
+}
+
+// Instances of non-`Sync` types are only handed out to their own hart, which
+// is like moving them there.
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn offset(&self) -> usize {
+        self.value.get() as usize - spercpu as usize
+    }
+
+    fn local_ptr(&self) -> *mut T {
+        let area = local_area();
+        debug_assert!(area != 0, "per-CPU area not set up");
+        (area + self.offset()) as *mut T
+    }
+
+    /// Runs `f` with the instance of this hart, with interrupts disabled so
+    /// that no handler touches it meanwhile.
+    ///
+    /// Kernel code is never preempted and `f` cannot await, so the task
+    /// cannot move to another hart while `f` runs.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let _irq = IrqGuard::new();
+        f(unsafe { &*self.local_ptr() })
+    }
+}
+
+impl<T: Sync> PerCpu<T> {
+    /// The instance of `cpu`.
+    pub fn on(&self, cpu: usize) -> &T {
+        assert!(cpu < MAX_HARTS);
+        unsafe { &*((area_of(cpu) + self.offset()) as *const T) }
+    }
+
+    /// The instance of this hart. It stays valid when the caller migrates,
+    /// but then refers to the previous hart's instance.
+    pub fn local(&self) -> &T {
+        unsafe { &*self.local_ptr() }
+    }
+}
@@ -0,0 +1,27 @@
+use log::{info, warn};
+
+use crate::runtime::{EXECUTOR, spawn};
+
+use super::{cpu_count, offline, online, wait_for};
+
+/// Takes the last CPU offline and brings it back once it runs tasks, which
+/// exercises the migration of tasks and timers. Needs several harts.
+pub fn hotplug_test() {
+    let cpu = cpu_count() - 1;
+    if cpu == 0 {
+        return;
+    }
+    spawn(async move {
+        let executor = EXECUTOR.get().expect("executor initialized");
+        let result = async {
+            wait_for(|| executor.online().contains(cpu)).await?;
+            offline(cpu).await?;
+            online(cpu).await
+        };
+        match result.await {
+            Ok(()) => info!("hotplug test: cpu {} went offline and back", cpu),
+            Err(err) => warn!("hotplug test on cpu {} failed: {:?}", cpu, err),
+        }
+    })
+    .detach();
+}
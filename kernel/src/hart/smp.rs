@@ -0,0 +1,172 @@
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use arrayvec::ArrayVec;
+use log::{info, warn};
+use sbi_rt::{HartMask, Hsm};
+use sbi_spec::binary::Error as SbiError;
+use spin::Once;
+
+use crate::{
+    KError, KResult,
+    config::MAX_HARTS,
+    dtb::MACHINE_META,
+    runtime::{duration_to_ticks, now},
+};
+
+use super::CpuMask;
+
+/// Physical hart id of each logical CPU. The boot hart is CPU 0, the other
+/// harts of the dtb follow in their order there.
+static HART_IDS: Once<ArrayVec<usize, MAX_HARTS>> = Once::new();
+
+/// Entry point passed to `hart_start`, see [`start_secondary_harts`].
+static SECONDARY_ENTRY: AtomicUsize = AtomicUsize::new(0);
+
+/// CPUs which reached `rust_main`.
+static BOOTED: AtomicUsize = AtomicUsize::new(0);
+
+/// Numbers the harts found in the dtb, with `boot_hartid` as CPU 0. Harts
+/// beyond `MAX_HARTS` are left out.
+pub fn init_cpu_map(boot_hartid: usize) {
+    let meta = MACHINE_META.get().expect("dtb parsed");
+    let mut ids = ArrayVec::new();
+    ids.push(boot_hartid);
+    for hart in meta.harts.iter().filter(|h| h.hartid != boot_hartid) {
+        if ids.try_push(hart.hartid).is_err() {
+            warn!("hart {} ignored, MAX_HARTS is {}", hart.hartid, MAX_HARTS);
+        }
+    }
+    HART_IDS.call_once(|| ids);
+}
+
+fn hart_ids() -> &'static ArrayVec<usize, MAX_HARTS> {
+    HART_IDS.get().expect("cpu map initialized")
+}
+
+/// Number of logical CPUs known from the dtb.
+pub fn cpu_count() -> usize {
+    hart_ids().len()
+}
+
+/// Physical hart id of logical CPU `cpu`.
+pub fn hartid_of(cpu: usize) -> usize {
+    hart_ids()[cpu]
+}
+
+/// Logical CPU number of physical hart `hartid`.
+pub fn cpu_of(hartid: usize) -> Option<usize> {
+    hart_ids().iter().position(|&id| id == hartid)
+}
+
+/// Records that `cpu` is up, called early in its `rust_main`.
+pub fn mark_booted(cpu: usize) {
+    BOOTED.fetch_or(1 << cpu, Ordering::AcqRel);
+}
+
+pub fn mark_stopped(cpu: usize) {
+    BOOTED.fetch_and(!(1 << cpu), Ordering::AcqRel);
+}
+
+/// CPUs which reached `rust_main` and were not stopped since.
+pub fn booted_cpus() -> CpuMask {
+    CpuMask::from_bits(BOOTED.load(Ordering::Acquire))
+}
+
+/// Converts logical CPUs to SBI hart masks, as few as the spread of their
+/// physical ids allows.
+pub fn hart_masks(cpus: CpuMask) -> ArrayVec<HartMask, MAX_HARTS> {
+    let mut hartids: ArrayVec<usize, MAX_HARTS> = cpus.iter().map(hartid_of).collect();
+    hartids.sort_unstable();
+    let mut masks = ArrayVec::new();
+    let mut group: Option<(usize, usize)> = None;
+    for hartid in hartids {
+        match group.as_mut() {
+            Some((base, bits)) if hartid - *base < usize::BITS as usize => {
+                *bits |= 1 << (hartid - *base);
+            }
+            _ => {
+                if let Some((base, bits)) = group.replace((hartid, 1)) {
+                    masks.push(HartMask::from_mask_base(bits, base));
+                }
+            }
+        }
+    }
+    if let Some((base, bits)) = group {
+        masks.push(HartMask::from_mask_base(bits, base));
+    }
+    masks
+}
+
+/// `hart_get_status` value of a hart which is not running.
+const HART_STATE_STOPPED: usize = 1;
+
+/// How long the boot hart waits for the others to reach `rust_main`.
+const HART_START_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Starts every other CPU through the SBI HSM extension at `entry`, which
+/// receives the physical hart id in `a0` and the logical CPU number in `a1`.
+/// Waits until they reached `rust_main`, stopped, or timed out, and reports
+/// the harts that are online.
+pub fn start_secondary_harts(entry: usize) {
+    SECONDARY_ENTRY.store(entry, Ordering::Release);
+    if sbi_rt::probe_extension(Hsm).is_unavailable() {
+        warn!("SBI has no HSM extension, running on the boot hart only");
+        return;
+    }
+    let mut starting = CpuMask::empty();
+    for cpu in 1..cpu_count() {
+        let hartid = hartid_of(cpu);
+        match sbi_rt::hart_start(hartid, entry, cpu).err() {
+            None => starting.insert(cpu),
+            Some(err) => warn!("failed to start hart {}: {:?}", hartid, err),
+        }
+    }
+    let deadline = now() + duration_to_ticks(HART_START_TIMEOUT);
+    while !starting.is_empty() {
+        let booted = booted_cpus();
+        for cpu in starting.iter() {
+            let status = sbi_rt::hart_get_status(hartid_of(cpu));
+            if booted.contains(cpu) {
+                starting.remove(cpu);
+            } else if status.ok() == Some(HART_STATE_STOPPED) {
+                warn!("hart {} stopped before reaching rust_main", hartid_of(cpu));
+                starting.remove(cpu);
+            } else if now() > deadline {
+                warn!(
+                    "hart {} did not come up in time, HSM status {:?}",
+                    hartid_of(cpu),
+                    status
+                );
+                starting.remove(cpu);
+            }
+        }
+        core::hint::spin_loop();
+    }
+    let online: ArrayVec<_, MAX_HARTS> = booted_cpus()
+        .iter()
+        .map(|cpu| (cpu, hartid_of(cpu)))
+        .collect();
+    info!("online (cpu, hartid): {:?}", online);
+}
+
+/// Starts `cpu` again after it was stopped.
+pub(super) fn restart_hart(cpu: usize) -> KResult<()> {
+    let entry = SECONDARY_ENTRY.load(Ordering::Acquire);
+    if entry == 0 {
+        return Err(KError::Busy);
+    }
+    let hartid = hartid_of(cpu);
+    let Some(err) = sbi_rt::hart_start(hartid, entry, cpu).err() else {
+        return Ok(());
+    };
+    warn!("failed to restart hart {}: {:?}", hartid, err);
+    Err(match err {
+        SbiError::InvalidParam | SbiError::InvalidAddress => KError::InvalidArgument,
+        SbiError::AlreadyAvailable => KError::Busy,
+        SbiError::NotSupported => KError::Unsupported,
+        _ => KError::Io,
+    })
+}
@@ -1,6 +1,8 @@
 use core::panic::PanicInfo;
 
 use log::error;
+
+use crate::logging::console_flush;
 use sbi_rt::system_reset;
 
 #[panic_handler]
@@ -15,6 +17,7 @@ fn panic(info: &PanicInfo) -> ! {
     } else {
         error!("Panicked: {}", info.message());
     }
+    console_flush();
     system_reset(sbi_rt::Shutdown, sbi_rt::SystemFailure);
     unreachable!()
 }
@@ -1,5 +1,13 @@
 use core::fmt::{self, Write};
 
+use sbi_rt::{Console, Physical};
+use spin::Once;
+
+use crate::drivers::UART;
+
+/// Whether the SBI implements the debug console extension.
+static HAS_DBCN: Once<bool> = Once::new();
+
 struct Stdout;
 
 impl Write for Stdout {
@@ -9,14 +17,50 @@ impl Write for Stdout {
     }
 }
 
-// this could be used to debug logging functionality
-#[allow(deprecated)]
 pub fn console_putstr(s: &str) {
-    for c in s.chars() {
+    console_write(s.as_bytes());
+}
+
+/// Writes to the UART once it is initialised, and through the SBI before.
+pub fn console_write(bytes: &[u8]) {
+    match UART.get() {
+        Some(uart) => uart.write(bytes),
+        None => sbi_write(bytes),
+    }
+}
+
+/// Writes through the SBI debug console, a whole string per call, or one
+/// byte per call with the legacy extension if DBCN is unavailable. The kernel
+/// is mapped linearly, so `bytes` can be passed by its virtual address.
+pub fn sbi_write(mut bytes: &[u8]) {
+    let dbcn = *HAS_DBCN.call_once(|| sbi_rt::probe_extension(Console).is_available());
+    if dbcn {
+        while !bytes.is_empty() {
+            let written =
+                sbi_rt::console_write(Physical::new(bytes.len(), bytes.as_ptr() as usize, 0));
+            match written.ok() {
+                Some(n) if n > 0 => bytes = &bytes[n.min(bytes.len())..],
+                _ => break,
+            }
+        }
+    }
+    sbi_legacy_putstr(bytes);
+}
+
+#[allow(deprecated)]
+fn sbi_legacy_putstr(bytes: &[u8]) {
+    for &c in bytes {
         sbi_rt::legacy::console_putchar(c as usize);
     }
 }
 
+/// Waits until buffered console output reached the hardware.
+pub fn console_flush() {
+    if let Some(uart) = UART.get() {
+        uart.flush();
+    }
+}
+
 pub fn print(args: fmt::Arguments) {
     Stdout.write_fmt(args).unwrap();
 }
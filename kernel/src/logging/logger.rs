@@ -1,7 +1,6 @@
 use log::{self, Level, LevelFilter, Log, Metadata, Record};
-use spin::Mutex;
 
-use crate::println;
+use crate::{println, sync::SpinNoIrq};
 
 struct SimpleLogger;
 
@@ -20,7 +19,7 @@ impl Log for SimpleLogger {
             Level::Debug => 32, // Green
             Level::Trace => 90, // BrightBlack
         };
-        static CONSOLE_MUTEX: Mutex<()> = Mutex::new(());
+        static CONSOLE_MUTEX: SpinNoIrq<()> = SpinNoIrq::new(());
         let guard = CONSOLE_MUTEX.lock();
         println!(
             "\u{1B}[{}m[{}] {}\u{1B}[0m",
@@ -7,83 +7,121 @@
 extern crate alloc;
 
 mod allocator;
+mod block;
 mod config;
+mod drivers;
 mod dtb;
 mod error;
+mod fs;
 mod hart;
 mod lang_items;
 mod logging;
 mod mem;
 mod runtime;
+mod sync;
+mod syscall;
 mod task;
 mod trap;
 
 pub use error::*;
 
-use core::sync::atomic::{AtomicBool, Ordering};
-
 use config::{BOOT_STACK_SIZE, MAX_HARTS};
-use dtb::MACHINE_META;
 use log::info;
 
 #[unsafe(link_section = ".bss.stack")]
 static BOOT_STACK: [u8; BOOT_STACK_SIZE * MAX_HARTS] = [0u8; BOOT_STACK_SIZE * MAX_HARTS];
 
+const _: () = assert!(BOOT_STACK_SIZE.is_power_of_two());
+
+// Entry of the boot hart, which uses the first boot stack as CPU 0.
 #[unsafe(link_section = ".text.entry")]
 #[unsafe(no_mangle)]
 #[naked]
 pub unsafe extern "C" fn _start(hart_id: usize, dtb_addr: usize) -> ! {
     // PC = 0x8020_0000
-    // a0 = har_tid
+    // a0 = hartid
     // a1 = dtb
     unsafe {
         core::arch::naked_asm!(
             "
-                addi    t0, a0, 1
-                slli    t0, t0, 16              // t0 = (hart_id + 1) * 64KB
                 la      sp, {boot_stack}
-                add     sp, sp, t0              // set boot stack
+                li      t0, {boot_stack_size}
+                add     sp, sp, t0              // set boot stack of cpu 0
+                mv      tp, zero                // no local hart yet
                 call rust_main
             ",
             boot_stack = sym BOOT_STACK,
+            boot_stack_size = const BOOT_STACK_SIZE,
         )
     }
 }
 
-static FIRST_HART: AtomicBool = AtomicBool::new(true);
+// Entry of the other harts, started by `hart::start_secondary_harts` with
+// their logical CPU number as the opaque argument.
+#[unsafe(no_mangle)]
+#[naked]
+pub unsafe extern "C" fn _start_secondary(hart_id: usize, cpu: usize) -> ! {
+    // a0 = hartid
+    // a1 = cpu < MAX_HARTS
+    unsafe {
+        core::arch::naked_asm!(
+            "
+                addi    t0, a1, 1
+                slli    t0, t0, {boot_stack_shift}  // t0 = (cpu + 1) * BOOT_STACK_SIZE
+                la      sp, {boot_stack}
+                add     sp, sp, t0              // set boot stack
+                mv      tp, zero                // no local hart yet
+                call rust_main_secondary
+            ",
+            boot_stack = sym BOOT_STACK,
+            boot_stack_shift = const BOOT_STACK_SIZE.trailing_zeros(),
+        )
+    }
+}
 
 #[unsafe(no_mangle)]
 fn rust_main(hart_id: usize, dtb: usize) {
-    if FIRST_HART
-        .compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst)
-        .is_ok()
-    {
-        clear_bss();
-        logging::init();
+    clear_bss();
+    hart::init_percpu_areas();
+    hart::init(0);
+    logging::init();
 
-        dtb::parse(dtb);
-        hart::init(hart_id);
+    dtb::parse(dtb);
+    hart::init_cpu_map(hart_id);
+    hart::mark_booted(0);
 
-        allocator::init();
+    allocator::init();
 
-        mem::init();
-        trap::init();
+    mem::init();
+    trap::init();
+    drivers::init();
 
-        runtime::init();
+    runtime::init();
+    drivers::init_tty();
+    block::init();
+    fs::init();
+    task::spawn_init();
+    #[cfg(feature = "selftest")]
+    sync::selftest();
 
-        info!("Main hart {} started!", hart_id);
+    info!("Main hart {} started!", hart_id);
 
-        start_other_harts(hart_id);
+    hart::start_secondary_harts(_start_secondary as usize);
+    #[cfg(feature = "selftest")]
+    hart::hotplug_test();
 
-        hart::run_first();
-    } else {
-        hart::init(hart_id);
-        mem::swich_kernel_space();
-        trap::init();
-        info!("Other hart {} started!", hart_id);
+    hart::run_first();
+}
 
-        hart::run_first();
-    }
+#[unsafe(no_mangle)]
+fn rust_main_secondary(hart_id: usize, cpu: usize) {
+    hart::init(cpu);
+    hart::mark_booted(cpu);
+    mem::swich_kernel_space();
+    trap::init();
+    info!("Other hart {} (cpu {}) started!", hart_id, cpu);
+
+    hart::run_first();
 }
 
 /// clear BSS segment
@@ -94,14 +132,3 @@ pub fn clear_bss() {
     }
     (sbss as usize..ebss as usize).for_each(|a| unsafe { (a as *mut u8).write_volatile(0) });
 }
-
-pub fn start_other_harts(main_hart_id: usize) {
-    let harts = MACHINE_META.get().expect("dtb parsed").harts.len();
-    for i in 0..harts {
-        if i == main_hart_id {
-            continue;
-        }
-        let status = sbi_rt::hart_start(i, _start as usize, 0);
-        info!("Start to wake up hart {}... status {:?}", i, status);
-    }
-}
@@ -2,11 +2,14 @@ mod addr;
 mod page_table;
 mod pte;
 mod space;
+mod tlb;
+mod user;
 
 pub use addr::*;
 pub use page_table::*;
 pub use pte::*;
 pub use space::*;
+pub use tlb::*;
 
 pub fn init() {
     init_kernel_space();
@@ -75,6 +75,23 @@ impl PageTable {
         self.map_region(start.into(), start.into(), num_pages, flags);
     }
 
+    /// Removes the mapping of page `vaddr`, returning the frame it mapped.
+    pub fn unmap(&mut self, vaddr: VirtAddr) -> Option<PhysAddr> {
+        let pte = self.find_entry_mut(vaddr)?;
+        let paddr = pte.ppn();
+        *pte = PageTableEntry::empty();
+        Some(paddr)
+    }
+
+    /// Changes the flags of the mapping of page `vaddr` to `flags`, keeping
+    /// its accessed and dirty bits.
+    pub fn protect(&mut self, vaddr: VirtAddr, flags: PTEFlags) -> KResult<()> {
+        let pte = self.find_entry_mut(vaddr).ok_or(KError::MemNotMapped)?;
+        let kept = pte.flags() & (PTEFlags::A | PTEFlags::D);
+        *pte = PageTableEntry::new(pte.ppn(), flags | kept);
+        Ok(())
+    }
+
     pub fn query_page(&mut self, vpn: VirtAddr) -> (PhysAddr, PTEFlags) {
         assert_eq!(vpn.as_usize() & (PAGE_SIZE_4K - 1), 0);
         let pte = self.get_entry_mut(vpn, false);
@@ -82,13 +99,19 @@ impl PageTable {
     }
 
     pub fn translate(&mut self, vaddr: VirtAddr) -> KResult<PhysAddr> {
-        let pte = self.get_entry_mut(vaddr, false);
-        if pte.is_valid() {
-            let offset = vaddr.as_usize() & (PAGE_SIZE_4K - 1);
-            let paddr = pte.ppn().as_usize() + offset;
-            Ok(paddr.into())
-        } else {
-            Err(KError::MemNotMapped)
+        self.translate_with(vaddr, PTEFlags::V)
+    }
+
+    /// Translates `vaddr` if it is mapped with all of `flags`, without
+    /// panicking on missing intermediate tables.
+    pub fn translate_with(&self, vaddr: VirtAddr, flags: PTEFlags) -> KResult<PhysAddr> {
+        match self.find_entry(vaddr) {
+            Some(pte) if pte.flags().contains(flags | PTEFlags::V) => {
+                let offset = vaddr.as_usize() & (PAGE_SIZE_4K - 1);
+                let paddr = pte.ppn().as_usize() + offset;
+                Ok(paddr.into())
+            }
+            _ => Err(KError::MemNotMapped),
         }
     }
 
@@ -118,6 +141,38 @@ impl PageTable {
         }
     }
 
+    fn find_entry(&self, vaddr: VirtAddr) -> Option<&PageTableEntry> {
+        let mut table = self.table_of_mut(self.root_paddr);
+        for level in 0..3 {
+            let index = (vaddr.as_usize() >> (12 + 9 * (2 - level))) & (SV39_TABLE_PTE_COUNT - 1);
+            let pte = &table[index];
+            if !pte.is_valid() {
+                return None;
+            }
+            if level == 2 {
+                return Some(pte);
+            }
+            table = self.table_of_mut(pte.ppn());
+        }
+        None
+    }
+
+    fn find_entry_mut(&mut self, vaddr: VirtAddr) -> Option<&mut PageTableEntry> {
+        let mut table = self.table_of_mut(self.root_paddr);
+        for level in 0..3 {
+            let index = (vaddr.as_usize() >> (12 + 9 * (2 - level))) & (SV39_TABLE_PTE_COUNT - 1);
+            let pte = &mut table[index];
+            if !pte.is_valid() {
+                return None;
+            }
+            if level == 2 {
+                return Some(pte);
+            }
+            table = self.table_of_mut(pte.ppn());
+        }
+        None
+    }
+
     fn get_entry_mut(&mut self, vaddr: VirtAddr, create_if_absent: bool) -> &mut PageTableEntry {
         let table1 = self.table_of_mut(self.root_paddr);
         let table1_pte_index = (vaddr.as_usize() >> (12 + 18)) & (SV39_TABLE_PTE_COUNT - 1);
@@ -1,18 +1,20 @@
 use core::ops::{Add, Range};
 
-use alloc::collections::btree_map::BTreeMap;
+use alloc::{collections::btree_map::BTreeMap, vec, vec::Vec};
 use log::info;
-use spin::Mutex;
 
 use crate::{
+    KError, KResult,
+    allocator::PHYS_FRAME_ALLOCATOR,
     config::{PAGE_SIZE_4K, TRAMPOLINE},
     dtb::MACHINE_META,
     mem::PTEFlags,
+    sync::SpinMutex,
 };
 
-use super::{PageTable, PhysAddr, VirtAddr};
+use super::{PageTable, PhysAddr, VirtAddr, flush_tlb};
 
-pub static KERNEL_SPACE: Mutex<AddrSpace> = Mutex::new(AddrSpace::empty());
+pub static KERNEL_SPACE: SpinMutex<AddrSpace> = SpinMutex::new(AddrSpace::empty());
 
 unsafe extern "C" {
     fn stext();
@@ -30,97 +32,52 @@ unsafe extern "C" {
     fn ekernel();
 }
 
-pub fn init_kernel_space() {
-    let mut space = AddrSpace::new();
-
+/// Regions of the kernel mapped linearly in every address space: user
+/// spaces need them from the trap entry on, before switching to the kernel
+/// space.
+fn kernel_regions() -> Vec<(&'static str, Range<usize>, PTEFlags)> {
     let meta = MACHINE_META.get().expect("dtb parsed");
     let phys_mem_end = meta.phys_mem_start + meta.phys_mem_size;
-
-    log::info!(
-        "[kernel] .text [{:#x}, {:#x}) [{:#x}, {:#x})",
-        stext as usize,
-        strampoline as usize,
-        etrampoline as usize,
-        etext as usize
-    );
-    space.page_table.map_range_linear(
-        (stext as usize).into()..(strampoline as usize).into(),
-        PTEFlags::R | PTEFlags::X | PTEFlags::V,
-    );
-    space.page_table.map_range_linear(
-        (etrampoline as usize).into()..(etext as usize).into(),
-        PTEFlags::R | PTEFlags::X | PTEFlags::V,
-    );
-
-    log::info!(
-        "[kernel] .text.trampoline [{:#x}, {:#x})",
-        strampoline as usize,
-        etrampoline as usize,
-    );
-    space.page_table.map_range_linear(
-        (strampoline as usize).into()..(etrampoline as usize).into(),
-        PTEFlags::R | PTEFlags::X | PTEFlags::V,
-    );
-
-    log::info!(
-        "[kernel] .rodata [{:#x}, {:#x})",
-        srodata as usize,
-        erodata as usize
-    );
-    space.page_table.map_range_linear(
-        (srodata as usize).into()..(erodata as usize).into(),
-        PTEFlags::R | PTEFlags::V,
-    );
-
-    log::info!(
-        "[kernel] .data [{:#x}, {:#x})",
-        sdata as usize,
-        edata as usize
-    );
-    space.page_table.map_range_linear(
-        (sdata as usize).into()..(edata as usize).into(),
-        PTEFlags::R | PTEFlags::W | PTEFlags::V,
-    );
-
-    log::info!(
-        "[kernel] .stack [{:#x}, {:#x})",
-        sstack as usize,
-        estack as usize
-    );
-    space.page_table.map_range_linear(
-        (sstack as usize).into()..(estack as usize).into(),
-        PTEFlags::R | PTEFlags::W | PTEFlags::V,
-    );
-
-    log::info!("[kernel] .bss [{:#x}, {:#x})", sbss as usize, ebss as usize);
-    space.page_table.map_range_linear(
-        (sbss as usize).into()..(ebss as usize).into(),
-        PTEFlags::R | PTEFlags::W | PTEFlags::V,
-    );
-
-    log::info!(
-        "[kernel] physical mem [{:#x}, {:#x})",
-        ekernel as usize,
-        phys_mem_end,
-    );
-    space.page_table.map_range_linear(
-        (ekernel as usize).into()..phys_mem_end.into(),
-        PTEFlags::R | PTEFlags::W | PTEFlags::V,
-    );
-
+    let rx = PTEFlags::R | PTEFlags::X | PTEFlags::V;
+    let r = PTEFlags::R | PTEFlags::V;
+    let rw = PTEFlags::R | PTEFlags::W | PTEFlags::V;
+    let mut regions = vec![
+        (".text", stext as usize..strampoline as usize, rx),
+        (".text", etrampoline as usize..etext as usize, rx),
+        (
+            ".text.trampoline",
+            strampoline as usize..etrampoline as usize,
+            rx,
+        ),
+        (".rodata", srodata as usize..erodata as usize, r),
+        (".data", sdata as usize..edata as usize, rw),
+        (".stack", sstack as usize..estack as usize, rw),
+        (".bss", sbss as usize..ebss as usize, rw),
+        ("physical mem", ekernel as usize..phys_mem_end, rw),
+    ];
+    if let Some(plic) = &meta.plic {
+        let range = plic.base_address..plic.base_address + plic.size;
+        regions.push(("plic mmio", range, rw));
+    }
+    if let Some(serial) = &meta.serial {
+        let range = serial.base_address..serial.base_address + serial.size;
+        regions.push(("uart mmio", range, rw));
+    }
     for virtio_dev in meta.virtio.iter() {
-        let end = virtio_dev.base_address + virtio_dev.size;
-        log::info!(
-            "[kernel] virtio mmio [{:#x}, {:#x})",
-            virtio_dev.base_address,
-            end,
-        );
-        space.page_table.map_range_linear(
-            virtio_dev.base_address.into()..end.into(),
-            PTEFlags::R | PTEFlags::W | PTEFlags::V,
-        );
+        let range = virtio_dev.base_address..virtio_dev.base_address + virtio_dev.size;
+        regions.push(("virtio mmio", range, rw));
     }
+    regions
+}
 
+pub fn init_kernel_space() {
+    let mut space = AddrSpace::new();
+    for (name, range, flags) in kernel_regions() {
+        log::info!("[kernel] {} [{:#x}, {:#x})", name, range.start, range.end);
+        space
+            .page_table
+            .map_range_linear(range.start.into()..range.end.into(), flags);
+    }
     space.map_trampoline();
 
     *KERNEL_SPACE.lock() = space;
@@ -128,16 +85,37 @@ pub fn init_kernel_space() {
 }
 
 pub struct AddrSpace {
-    page_table: PageTable,
+    pub(super) page_table: PageTable,
     areas: BTreeMap<VirtAddr, MemoryArea>,
 }
 
 pub struct MemoryArea {
     va_range: Range<VirtAddr>,
     area_type: AreaType,
+    /// Access rights of the pages, `R`, `W` and `X` of [`PTEFlags`].
+    perm: PTEFlags,
     pages: BTreeMap<VirtAddr, PhysAddr>,
 }
 
+impl MemoryArea {
+    pub fn range(&self) -> Range<VirtAddr> {
+        self.va_range.clone()
+    }
+
+    pub fn area_type(&self) -> AreaType {
+        self.area_type
+    }
+
+    pub fn perm(&self) -> PTEFlags {
+        self.perm
+    }
+
+    /// Pages of the area backed by memory.
+    pub fn resident_pages(&self) -> usize {
+        self.pages.len()
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AreaType {
     // For user.
@@ -169,6 +147,167 @@ impl AddrSpace {
         }
     }
 
+    /// Creates the address space of a user program. Besides the trampoline
+    /// it maps the kernel without user access, as traps from user mode
+    /// save the registers before switching to the kernel space.
+    pub fn new_user() -> Self {
+        let mut space = Self::new();
+        for (_, range, flags) in kernel_regions() {
+            space
+                .page_table
+                .map_range_linear(range.start.into()..range.end.into(), flags);
+        }
+        space.map_trampoline();
+        space
+    }
+
+    /// Copies the user memory of this space into a new user space, as
+    /// `fork` does.
+    pub fn fork(&self) -> KResult<Self> {
+        let mut space = Self::new_user();
+        for area in self.areas.values() {
+            space.map_area(area.range(), area.area_type, area.perm)?;
+            for (&vaddr, &paddr) in area.pages.iter() {
+                let page = unsafe {
+                    core::slice::from_raw_parts(paddr.as_usize() as *const u8, PAGE_SIZE_4K)
+                };
+                space.fill(vaddr, page)?;
+            }
+        }
+        Ok(space)
+    }
+
+    /// Value of `satp` selecting this space.
+    pub fn token(&self) -> usize {
+        let mode = riscv::register::satp::Mode::Sv39 as usize;
+        (mode << 60) | (self.page_table.root_paddr().as_usize() >> 12)
+    }
+
+    /// Maps `range` as a new area of `area_type` with access rights `perm`,
+    /// backed by zeroed frames.
+    pub fn map_area(
+        &mut self,
+        range: Range<VirtAddr>,
+        area_type: AreaType,
+        perm: PTEFlags,
+    ) -> KResult<()> {
+        let start = range.start.align_down(PAGE_SIZE_4K);
+        let end = range.end.align_up(PAGE_SIZE_4K);
+        let overlaps = self
+            .areas
+            .range(..end)
+            .next_back()
+            .is_some_and(|(_, area)| area.va_range.end > start);
+        if start >= end || overlaps {
+            return Err(KError::InvalidArgument);
+        }
+        self.areas.insert(start, MemoryArea {
+            va_range: start..end,
+            area_type,
+            perm,
+            pages: BTreeMap::new(),
+        });
+        let flags = perm | PTEFlags::U | PTEFlags::V;
+        for vaddr in (start.as_usize()..end.as_usize()).step_by(PAGE_SIZE_4K) {
+            let frame = PHYS_FRAME_ALLOCATOR.lock().alloc_frames(1, PAGE_SIZE_4K);
+            let Some(paddr) = frame else {
+                self.unmap(start..end);
+                return Err(KError::NoMemory);
+            };
+            unsafe { core::ptr::write_bytes(paddr.as_usize() as *mut u8, 0, PAGE_SIZE_4K) };
+            self.page_table.map(vaddr.into(), paddr, flags);
+            let area = self.areas.get_mut(&start).unwrap();
+            area.pages.insert(vaddr.into(), paddr);
+        }
+        Ok(())
+    }
+
+    /// The areas of user memory, by address.
+    pub fn areas(&self) -> impl Iterator<Item = &MemoryArea> {
+        self.areas.values()
+    }
+
+    /// Identifies the address space while it is alive, e.g. in keys of
+    /// process-private futexes.
+    pub fn id(&self) -> usize {
+        self.page_table.root_paddr().as_usize()
+    }
+
+    /// Address space identifier of this space in `satp`. Every space uses
+    /// ASID 0 for now.
+    pub fn asid(&self) -> usize {
+        0
+    }
+
+    /// Flushes stale translations of `range` on every hart, to be called
+    /// after unmapping or write-protecting pages of this space.
+    pub fn flush_tlb(&self, range: Range<VirtAddr>) {
+        flush_tlb(self.asid(), range);
+    }
+
+    /// Unmaps the pages of `range`, shrinking or splitting the areas it
+    /// overlaps. The frames of private areas are freed only once no hart can
+    /// reach them through a stale translation.
+    pub fn unmap(&mut self, range: Range<VirtAddr>) {
+        let start = range.start.align_down(PAGE_SIZE_4K);
+        let end = range.end.align_up(PAGE_SIZE_4K);
+        self.split_area(start);
+        self.split_area(end);
+        let mut freed = Vec::new();
+        while let Some((&key, _)) = self.areas.range(start..end).next() {
+            let area = self.areas.remove(&key).unwrap();
+            for (vaddr, paddr) in area.pages {
+                self.page_table.unmap(vaddr);
+                // frames of shared memory belong to their segment
+                if area.area_type != AreaType::Shm {
+                    freed.push(paddr);
+                }
+            }
+        }
+        self.flush_tlb(start..end);
+        let mut allocator = PHYS_FRAME_ALLOCATOR.lock();
+        for paddr in freed {
+            allocator.dealloc_frames(paddr, 1);
+        }
+    }
+
+    /// Changes the access rights of the pages of `range` to `perm`, which
+    /// is made of `R`, `W` and `X`, splitting the areas it overlaps.
+    pub fn protect(&mut self, range: Range<VirtAddr>, perm: PTEFlags) {
+        let start = range.start.align_down(PAGE_SIZE_4K);
+        let end = range.end.align_up(PAGE_SIZE_4K);
+        self.split_area(start);
+        self.split_area(end);
+        for area in self.areas.range_mut(start..end).map(|(_, area)| area) {
+            area.perm = perm;
+            for &vaddr in area.pages.keys() {
+                let flags = perm | PTEFlags::U | PTEFlags::V;
+                self.page_table
+                    .protect(vaddr, flags)
+                    .expect("resident page mapped");
+            }
+        }
+        self.flush_tlb(start..end);
+    }
+
+    /// Splits the area containing `at` in two areas meeting at `at`.
+    fn split_area(&mut self, at: VirtAddr) {
+        let Some((_, area)) = self.areas.range_mut(..at).next_back() else {
+            return;
+        };
+        if area.va_range.end <= at {
+            return;
+        }
+        let upper = MemoryArea {
+            va_range: at..area.va_range.end,
+            area_type: area.area_type,
+            perm: area.perm,
+            pages: area.pages.split_off(&at),
+        };
+        area.va_range.end = at;
+        self.areas.insert(at, upper);
+    }
+
     pub fn switch(&self) {
         let page_table_root = self.page_table.root_paddr().as_usize();
         unsafe {
@@ -197,6 +336,21 @@ impl AddrSpace {
     }
 }
 
+impl Drop for AddrSpace {
+    fn drop(&mut self) {
+        let mut allocator = PHYS_FRAME_ALLOCATOR.lock();
+        let private = self
+            .areas
+            .values()
+            .filter(|area| area.area_type != AreaType::Shm);
+        for area in private {
+            for &paddr in area.pages.values() {
+                allocator.dealloc_frames(paddr, 1);
+            }
+        }
+    }
+}
+
 pub fn kernel_space_test() {
     let mut space = KERNEL_SPACE.lock();
     let mid_text: VirtAddr = ((stext as usize + etext as usize) / 2).into();
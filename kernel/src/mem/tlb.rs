@@ -0,0 +1,60 @@
+use core::{arch::asm, ops::Range};
+
+use log::warn;
+use sbi_rt::Fence;
+use spin::Once;
+
+use crate::{config::PAGE_SIZE_4K, hart, runtime::EXECUTOR};
+
+use super::VirtAddr;
+
+/// Ranges longer than this many pages are flushed as a whole.
+const FLUSH_ALL_THRESHOLD: usize = 64;
+
+/// Whether the SBI implements the remote fence extension.
+static HAS_RFENCE: Once<bool> = Once::new();
+
+/// Flushes TLB entries of `asid` for `range` on this hart only.
+pub fn flush_tlb_local(asid: usize, range: Range<VirtAddr>) {
+    let start = range.start.align_down(PAGE_SIZE_4K).as_usize();
+    let end = range.end.align_up(PAGE_SIZE_4K).as_usize();
+    unsafe {
+        if (end - start) / PAGE_SIZE_4K > FLUSH_ALL_THRESHOLD {
+            asm!("sfence.vma zero, {}", in(reg) asid);
+        } else {
+            for addr in (start..end).step_by(PAGE_SIZE_4K) {
+                asm!("sfence.vma {}, {}", in(reg) addr, in(reg) asid);
+            }
+        }
+    }
+}
+
+/// Flushes TLB entries of `asid` for `range` on every online hart, which is
+/// required after a mapping is removed or its permissions are reduced.
+///
+/// Remote harts are fenced by the SBI if it supports remote fences, else by
+/// an IPI to each of them.
+pub fn flush_tlb(asid: usize, range: Range<VirtAddr>) {
+    flush_tlb_local(asid, range.clone());
+    let Some(executor) = EXECUTOR.get() else {
+        return;
+    };
+    let mut others = executor.online();
+    others.remove(hart::hart_id());
+    if others.is_empty() {
+        return;
+    }
+    let rfence = *HAS_RFENCE.call_once(|| sbi_rt::probe_extension(Fence).is_available());
+    if rfence {
+        let start = range.start.align_down(PAGE_SIZE_4K).as_usize();
+        let size = range.end.align_up(PAGE_SIZE_4K).as_usize() - start;
+        let failed = hart::hart_masks(others)
+            .into_iter()
+            .find_map(|harts| sbi_rt::remote_sfence_vma_asid(harts, start, size, asid).err());
+        match failed {
+            None => return,
+            Some(err) => warn!("remote_sfence_vma_asid failed: {:?}, using IPIs", err),
+        }
+    }
+    hart::shootdown_tlb(others, asid, range);
+}
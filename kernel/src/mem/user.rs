@@ -0,0 +1,108 @@
+use core::mem::{MaybeUninit, size_of};
+
+use alloc::vec::Vec;
+
+use crate::{KResult, config::PAGE_SIZE_4K};
+
+use super::{AddrSpace, PTEFlags, PhysAddr, VirtAddr};
+
+/// Accessors for user memory. Physical memory is identity-mapped in kernel
+/// space, so user pages are accessed through their physical addresses page by
+/// page.
+impl AddrSpace {
+    pub fn copy_from_user(&self, src: VirtAddr, dst: &mut [u8]) -> KResult<()> {
+        self.for_each_user_chunk(
+            src,
+            dst.len(),
+            PTEFlags::U | PTEFlags::R,
+            |paddr, off, len| {
+                let page = unsafe { core::slice::from_raw_parts(paddr as *const u8, len) };
+                dst[off..off + len].copy_from_slice(page);
+            },
+        )
+    }
+
+    pub fn copy_to_user(&self, dst: VirtAddr, src: &[u8]) -> KResult<()> {
+        self.for_each_user_chunk(
+            dst,
+            src.len(),
+            PTEFlags::U | PTEFlags::W,
+            |paddr, off, len| {
+                let page = unsafe { core::slice::from_raw_parts_mut(paddr as *mut u8, len) };
+                page.copy_from_slice(&src[off..off + len]);
+            },
+        )
+    }
+
+    /// Copies `data` to `vaddr` regardless of the access rights of the
+    /// pages, e.g. to load a program.
+    pub fn fill(&self, vaddr: VirtAddr, data: &[u8]) -> KResult<()> {
+        self.for_each_user_chunk(vaddr, data.len(), PTEFlags::U, |paddr, off, len| {
+            let page = unsafe { core::slice::from_raw_parts_mut(paddr as *mut u8, len) };
+            page.copy_from_slice(&data[off..off + len]);
+        })
+    }
+
+    pub fn read_user<T: Copy>(&self, src: VirtAddr) -> KResult<T> {
+        let mut val = MaybeUninit::<T>::uninit();
+        let bytes =
+            unsafe { core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        self.copy_from_user(src, bytes)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    pub fn write_user<T: Copy>(&self, dst: VirtAddr, val: &T) -> KResult<()> {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) };
+        self.copy_to_user(dst, bytes)
+    }
+
+    /// Reads the nul-terminated string at `src`, without the nul. Returns
+    /// `None` if there is no nul in the first `max` bytes.
+    pub fn read_user_cstr(&self, src: VirtAddr, max: usize) -> KResult<Option<Vec<u8>>> {
+        let mut string = Vec::new();
+        while string.len() < max {
+            let va = src + string.len();
+            let chunk = (PAGE_SIZE_4K - va.as_usize() % PAGE_SIZE_4K).min(max - string.len());
+            let paddr = self
+                .page_table
+                .translate_with(va, PTEFlags::U | PTEFlags::R)?;
+            let page = unsafe { core::slice::from_raw_parts(paddr.as_usize() as *const u8, chunk) };
+            match page.iter().position(|&b| b == 0) {
+                Some(len) => {
+                    string.extend_from_slice(&page[..len]);
+                    return Ok(Some(string));
+                }
+                None => string.extend_from_slice(page),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Physical address of user address `vaddr`, which must be mapped with
+    /// `flags` in addition to `U`.
+    pub fn translate_user(&self, vaddr: VirtAddr, flags: PTEFlags) -> KResult<PhysAddr> {
+        self.page_table.translate_with(vaddr, flags | PTEFlags::U)
+    }
+
+    /// Calls `f(paddr, offset, len)` for every page-bounded chunk of
+    /// `[vaddr, vaddr + len)`, failing at the first page not mapped with
+    /// `flags`. Chunks before the faulting page have been processed by then.
+    fn for_each_user_chunk(
+        &self,
+        vaddr: VirtAddr,
+        len: usize,
+        flags: PTEFlags,
+        mut f: impl FnMut(usize, usize, usize),
+    ) -> KResult<()> {
+        let mut off = 0;
+        while off < len {
+            let va = vaddr + off;
+            let chunk = (PAGE_SIZE_4K - va.as_usize() % PAGE_SIZE_4K).min(len - off);
+            let paddr = self.page_table.translate_with(va, flags)?;
+            f(paddr.as_usize(), off, chunk);
+            off += chunk;
+        }
+        Ok(())
+    }
+}
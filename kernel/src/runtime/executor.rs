@@ -1,32 +1,461 @@
-use crate::task::{Task, TidHandle};
-use alloc::collections::BTreeMap;
-use alloc::{boxed::Box, collections::vec_deque::VecDeque, sync::Arc};
-use spin::{Mutex, Once};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use core::task::{Context, Poll, Waker};
+
+use crate::config::MAX_HARTS;
+use crate::hart::{self, CpuMask};
+use crate::sync::{SpinNoIrq, check_no_spin_held};
+use crate::task::Task;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use riscv::register::time;
+use spin::Once;
+
+use super::{KernelTask, RunQueue, SCHED_CLASSES, SchedEntity, TaskWaker};
 
 pub static EXECUTOR: Once<Executor> = Once::new();
 
 pub fn init_executor() {
-    EXECUTOR.call_once(|| Executor::new());
+    EXECUTOR.call_once(Executor::new);
 }
 
+/// Upper bound of tasks moved by a single steal, so that a thief does not
+/// drain a busy victim in one go.
+const MAX_STEAL_BATCH: usize = 32;
+
+/// A hart with local work still looks at the injector every this many
+/// fetches, so that the runnables queued there are not starved.
+const INJECTOR_INTERVAL: usize = 31;
+
+/// Something the executor can run: either a user task which is resumed by
+/// returning to user mode, or a kernel future which is polled in place.
+pub enum Runnable {
+    User(Box<Task>),
+    Kernel(Box<KernelTask>),
+}
+
+impl Runnable {
+    /// The tid under which wakers refer to this runnable.
+    pub fn id(&self) -> usize {
+        match self {
+            Runnable::User(task) => task.tid(),
+            Runnable::Kernel(task) => task.tid(),
+        }
+    }
+
+    /// The waker of the runnable, which holds it while it is parked.
+    pub fn waker(&self) -> &Arc<TaskWaker> {
+        match self {
+            Runnable::User(task) => task.waker(),
+            Runnable::Kernel(task) => task.waker(),
+        }
+    }
+
+    pub fn sched(&self) -> &SchedEntity {
+        match self {
+            Runnable::User(task) => &task.sched,
+            Runnable::Kernel(task) => &task.sched,
+        }
+    }
+
+    pub fn sched_mut(&mut self) -> &mut SchedEntity {
+        match self {
+            Runnable::User(task) => &mut task.sched,
+            Runnable::Kernel(task) => &mut task.sched,
+        }
+    }
+}
+
+/// Work-stealing executor.
+///
+/// Every hart owns a local [`RunQueue`] which it pushes to and pops from,
+/// picking real-time, then fair, then idle runnables. Tasks
+/// added before a hart is up (or from outside any hart) go to the global
+/// injector. A hart looks at the injector when its local queue runs dry, and
+/// every [`INJECTOR_INTERVAL`] fetches otherwise. With both empty, it steals
+/// half of the queue of a randomly chosen victim.
+///
+/// Only runnables allowed on every hart go to the injector. Those with a
+/// narrower affinity are queued on one of their harts directly, and thieves
+/// skip runnables they may not run.
+///
+/// Runnables waiting for an event are parked in their own [`TaskWaker`]
+/// until it is woken and moves them back.
+///
+/// Harts without work wait in `wfi` and are marked in `idle`. Queuing a
+/// runnable kicks an idle hart with an IPI so that it picks it up or steals
+/// it.
 pub struct Executor {
-    ready: Arc<Mutex<VecDeque<Box<Task>>>>,
-    pending: Arc<Mutex<BTreeMap<TidHandle, Box<Task>>>>,
+    injector: SpinNoIrq<RunQueue>,
+    locals: [LocalQueue; MAX_HARTS],
+    online: AtomicUsize,
+    idle: AtomicUsize,
+}
+
+struct LocalQueue {
+    queue: SpinNoIrq<RunQueue>,
+    rng: AtomicUsize,
+    /// Fetches so far, only touched by the owning hart.
+    fetches: AtomicUsize,
+    stats: HartCounters,
+}
+
+struct HartCounters {
+    local_pushes: AtomicUsize,
+    local_pops: AtomicUsize,
+    injector_pops: AtomicUsize,
+    steal_attempts: AtomicUsize,
+    steals: AtomicUsize,
+    stolen_tasks: AtomicUsize,
+    migrations: AtomicUsize,
+    classes: [ClassCounters; SCHED_CLASSES],
+}
+
+struct ClassCounters {
+    picked: AtomicUsize,
+    runtime: AtomicU64,
+}
+
+/// Snapshot of the scheduling counters of one hart.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HartStats {
+    /// Tasks pushed to this hart's local queue.
+    pub local_pushes: usize,
+    /// Tasks fetched from this hart's local queue.
+    pub local_pops: usize,
+    /// Tasks fetched from the global injector.
+    pub injector_pops: usize,
+    /// Victims probed while stealing.
+    pub steal_attempts: usize,
+    /// Successful steals.
+    pub steals: usize,
+    /// Tasks moved from victims into this hart's local queue.
+    pub stolen_tasks: usize,
+    /// Runs started here whose previous run was on another hart.
+    pub migrations: usize,
+    /// Per scheduling class, indexed by [`SchedClass`](super::SchedClass).
+    pub classes: [ClassStats; SCHED_CLASSES],
+}
+
+/// Per-class part of [`HartStats`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClassStats {
+    /// Runnables of this class picked to run.
+    pub picked: usize,
+    /// Time spent running them, in timer ticks.
+    pub runtime: u64,
+}
+
+impl LocalQueue {
+    fn new(seed: usize) -> Self {
+        Self {
+            queue: SpinNoIrq::new(RunQueue::default()),
+            rng: AtomicUsize::new(seed),
+            fetches: AtomicUsize::new(0),
+            stats: HartCounters {
+                local_pushes: AtomicUsize::new(0),
+                local_pops: AtomicUsize::new(0),
+                injector_pops: AtomicUsize::new(0),
+                steal_attempts: AtomicUsize::new(0),
+                steals: AtomicUsize::new(0),
+                stolen_tasks: AtomicUsize::new(0),
+                migrations: AtomicUsize::new(0),
+                classes: core::array::from_fn(|_| ClassCounters {
+                    picked: AtomicUsize::new(0),
+                    runtime: AtomicU64::new(0),
+                }),
+            },
+        }
+    }
+
+    /// xorshift64, only touched by the owning hart.
+    fn next_random(&self) -> usize {
+        let mut x = self.rng.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.store(x, Ordering::Relaxed);
+        x
+    }
 }
 
 impl Executor {
     pub fn new() -> Self {
         Self {
-            ready: Arc::new(Mutex::new(VecDeque::new())),
-            pending: Arc::new(Mutex::new(BTreeMap::new())),
+            injector: SpinNoIrq::new(RunQueue::default()),
+            locals: core::array::from_fn(|i| LocalQueue::new(0x9e37_79b9_7f4a_7c15 ^ (i + 1))),
+            online: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+        }
+    }
+
+    /// Harts currently taking runnables from the executor.
+    pub fn online(&self) -> CpuMask {
+        CpuMask::from_bits(self.online.load(Ordering::Acquire))
+    }
+
+    pub fn set_online(&self, hart_id: usize, online: bool) {
+        if online {
+            self.online.fetch_or(1 << hart_id, Ordering::AcqRel);
+        } else {
+            self.online.fetch_and(!(1 << hart_id), Ordering::AcqRel);
         }
     }
 
+    /// Marks a hart as waiting for work, see [`hart::run_tasks`].
+    pub fn set_idle(&self, hart_id: usize, idle: bool) {
+        if idle {
+            self.idle.fetch_or(1 << hart_id, Ordering::SeqCst);
+        } else {
+            self.idle.fetch_and(!(1 << hart_id), Ordering::SeqCst);
+        }
+    }
+
+    fn idle_harts(&self) -> CpuMask {
+        CpuMask::from_bits(self.idle.load(Ordering::SeqCst))
+    }
+
     pub fn add(&self, task: Box<Task>) {
-        self.ready.lock().push_back(task);
+        self.schedule(Runnable::User(task));
     }
 
-    pub fn fetch(&self) -> Option<Box<Task>> {
-        self.ready.lock().pop_front()
+    /// Makes `runnable` ready, preferring the local queue of the current hart
+    /// if the runnable may run there.
+    pub fn schedule(&self, mut runnable: Runnable) {
+        if let Runnable::User(task) = &mut runnable {
+            task.apply_sched_params();
+        }
+        let current = hart::try_hart_id();
+        let allowed = runnable.sched().affinity();
+        let (target, queued) = loop {
+            let online = self.online();
+            let target = match current {
+                Some(id) if allowed.contains(id) && online.contains(id) => Some(id),
+                _ if allowed == CpuMask::full() => None,
+                _ => Some(self.select_hart(runnable.sched())),
+            };
+            let Some(id) = target else {
+                self.injector.lock().push(runnable);
+                break (None, usize::MAX);
+            };
+            let local = &self.locals[id];
+            let mut queue = local.queue.lock();
+            // A hart going offline clears its online bit before draining its
+            // queue, so checking again under the lock catches a hart which
+            // went offline since it was selected.
+            let online = self.online();
+            if !online.contains(id) && !allowed.intersection(online).is_empty() {
+                continue;
+            }
+            queue.push(runnable);
+            local.stats.local_pushes.fetch_add(1, Ordering::Relaxed);
+            break (Some(id), queue.len());
+        };
+        // A runnable queued on the current hart is run by it unless another
+        // one is already waiting, then an idle hart may steal it.
+        match target {
+            Some(id) if Some(id) == current && queued < 2 => {}
+            Some(id) if Some(id) != current && self.idle_harts().contains(id) => {
+                hart::kick(CpuMask::single(id));
+            }
+            _ => self.kick_idle(allowed, current),
+        }
+    }
+
+    /// Moves the runnables queued on `hart_id`, which went offline, to
+    /// online harts. Those allowed only on offline harts stay to wait for
+    /// one of them to come back.
+    pub fn drain(&self, hart_id: usize) {
+        let mut queue = core::mem::take(&mut *self.locals[hart_id].queue.lock());
+        let online = self.online();
+        let mut stranded = RunQueue::default();
+        while let Some(runnable) = queue.pop() {
+            if runnable.sched().affinity().intersection(online).is_empty() {
+                stranded.push(runnable);
+            } else {
+                self.schedule(runnable);
+            }
+        }
+        let mut queue = self.locals[hart_id].queue.lock();
+        while let Some(runnable) = stranded.pop() {
+            queue.push(runnable);
+        }
+    }
+
+    /// Kicks one idle hart in `allowed` other than `current`.
+    fn kick_idle(&self, allowed: CpuMask, current: Option<usize>) {
+        let mut idle = self.idle_harts().intersection(allowed);
+        if let Some(id) = current {
+            idle.remove(id);
+        }
+        if let Some(id) = idle.iter().next() {
+            hart::kick(CpuMask::single(id));
+        }
+    }
+
+    /// Chooses a hart for a runnable which may not run on the current one:
+    /// the hart it last ran on if still allowed and online, else the allowed
+    /// online hart with the shortest queue. Offline harts are used only if
+    /// no allowed hart is online, the runnable then waits for them to start.
+    fn select_hart(&self, sched: &SchedEntity) -> usize {
+        let allowed = sched.affinity();
+        let candidates = allowed.intersection(self.online());
+        if let Some(last) = sched.last_hart().filter(|&id| candidates.contains(id)) {
+            return last;
+        }
+        candidates
+            .iter()
+            .min_by_key(|&id| self.locals[id].queue.lock().len())
+            .or_else(|| allowed.iter().next())
+            .expect("empty affinity")
+    }
+
+    /// Picks the next runnable of this hart. When the injector is looked
+    /// at, its head and the local one are compared by class, priority and
+    /// virtual runtime. Stealing is the last resort.
+    pub fn fetch(&self) -> Option<Runnable> {
+        let id = hart::hart_id();
+        let local = &self.locals[id];
+        let fetches = local.fetches.fetch_add(1, Ordering::Relaxed) + 1;
+        let runnable = {
+            let mut queue = local.queue.lock();
+            if !queue.is_empty() && fetches % INJECTOR_INTERVAL != 0 {
+                local.stats.local_pops.fetch_add(1, Ordering::Relaxed);
+                queue.pop()
+            } else {
+                Self::pop_either(&mut queue, &mut self.injector.lock(), &local.stats)
+            }
+        };
+        let runnable = runnable.or_else(|| self.steal(id))?;
+        let class = runnable.sched().class() as usize;
+        local.stats.classes[class]
+            .picked
+            .fetch_add(1, Ordering::Relaxed);
+        Some(runnable)
+    }
+
+    /// Pops the better ranked head of the local queue and the injector.
+    fn pop_either(
+        queue: &mut RunQueue,
+        injector: &mut RunQueue,
+        stats: &HartCounters,
+    ) -> Option<Runnable> {
+        match (queue.head_rank(), injector.head_rank()) {
+            (Some(l), Some(g)) if g < l => {
+                stats.injector_pops.fetch_add(1, Ordering::Relaxed);
+                injector.pop()
+            }
+            (Some(_), _) => {
+                stats.local_pops.fetch_add(1, Ordering::Relaxed);
+                queue.pop()
+            }
+            (None, Some(_)) => {
+                stats.injector_pops.fetch_add(1, Ordering::Relaxed);
+                injector.pop()
+            }
+            (None, None) => None,
+        }
+    }
+
+    /// Starts the run clock of `sched` on the current hart.
+    pub fn begin_run(&self, sched: &mut SchedEntity) {
+        let id = hart::hart_id();
+        if sched.start(id, time::read() as u64) {
+            self.locals[id]
+                .stats
+                .migrations
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Stops the run clock of `sched`, charging the elapsed time to it and to
+    /// the current hart's class statistics. Returns the elapsed time.
+    pub fn end_run(&self, sched: &mut SchedEntity) -> u64 {
+        let delta = sched.stop(time::read() as u64);
+        let local = &self.locals[hart::hart_id()];
+        local.stats.classes[sched.class() as usize]
+            .runtime
+            .fetch_add(delta, Ordering::Relaxed);
+        delta
+    }
+
+    /// Parks `runnable` until its waker is woken. If a wake-up arrived
+    /// since it was fetched, it is made ready again right away.
+    pub fn park(&self, runnable: Runnable) {
+        let waker = runnable.waker().clone();
+        if let Some(runnable) = waker.park(runnable) {
+            self.schedule(runnable);
+        }
+    }
+
+    /// Polls a kernel task once, parking it if it is not finished.
+    pub fn run_kernel_task(&self, mut task: Box<KernelTask>) {
+        let tid = task.tid();
+        task.waker().clear_notified();
+        if task.is_aborted() {
+            // dropping the future completes its join handle with an error
+            return;
+        }
+        let waker = Waker::from(task.waker().clone());
+        let mut cx = Context::from_waker(&waker);
+        self.begin_run(&mut task.sched);
+        let poll = task.poll(&mut cx);
+        self.end_run(&mut task.sched);
+        check_no_spin_held(tid);
+        hart::check_preemptible(tid);
+        match poll {
+            Poll::Ready(()) => task.waker().clear_notified(),
+            Poll::Pending => self.park(Runnable::Kernel(task)),
+        }
+    }
+
+    pub fn stats(&self, hart_id: usize) -> HartStats {
+        let c = &self.locals[hart_id].stats;
+        HartStats {
+            local_pushes: c.local_pushes.load(Ordering::Relaxed),
+            local_pops: c.local_pops.load(Ordering::Relaxed),
+            injector_pops: c.injector_pops.load(Ordering::Relaxed),
+            steal_attempts: c.steal_attempts.load(Ordering::Relaxed),
+            steals: c.steals.load(Ordering::Relaxed),
+            stolen_tasks: c.stolen_tasks.load(Ordering::Relaxed),
+            migrations: c.migrations.load(Ordering::Relaxed),
+            classes: core::array::from_fn(|i| ClassStats {
+                picked: c.classes[i].picked.load(Ordering::Relaxed),
+                runtime: c.classes[i].runtime.load(Ordering::Relaxed),
+            }),
+        }
+    }
+
+    /// Steals half of a random victim's queue, probing every other hart once
+    /// starting from a random one. Returns the best of the stolen runnables
+    /// and keeps the rest in the local queue.
+    fn steal(&self, id: usize) -> Option<Runnable> {
+        let local = &self.locals[id];
+        let start = local.next_random() % MAX_HARTS;
+        for i in 0..MAX_HARTS {
+            let victim = (start + i) % MAX_HARTS;
+            if victim == id {
+                continue;
+            }
+            local.stats.steal_attempts.fetch_add(1, Ordering::Relaxed);
+            let batch = self.locals[victim]
+                .queue
+                .lock()
+                .steal_half(id, MAX_STEAL_BATCH);
+            if batch.is_empty() {
+                continue;
+            }
+            local.stats.steals.fetch_add(1, Ordering::Relaxed);
+            local
+                .stats
+                .stolen_tasks
+                .fetch_add(batch.len(), Ordering::Relaxed);
+            let mut queue = local.queue.lock();
+            for runnable in batch {
+                queue.push(runnable);
+            }
+            return queue.pop();
+        }
+        None
     }
 }
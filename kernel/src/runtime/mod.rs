@@ -1,7 +1,15 @@
 mod executor;
+mod future;
+mod sched;
+mod spawn;
+mod timer;
 mod waker;
 
 pub use executor::*;
+pub use future::*;
+pub use sched::*;
+pub use spawn::*;
+pub use timer::*;
 pub use waker::*;
 
 pub fn init() {
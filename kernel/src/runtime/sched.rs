@@ -0,0 +1,376 @@
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
+use core::cmp::Reverse;
+
+use crate::hart::CpuMask;
+
+use super::Runnable;
+
+/// Scheduling policies, numbered as in Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum SchedPolicy {
+    Normal = 0,
+    Fifo = 1,
+    Batch = 3,
+    Idle = 5,
+}
+
+impl SchedPolicy {
+    pub fn from_raw(policy: usize) -> Option<Self> {
+        match policy {
+            0 => Some(Self::Normal),
+            1 => Some(Self::Fifo),
+            3 => Some(Self::Batch),
+            5 => Some(Self::Idle),
+            _ => None,
+        }
+    }
+
+    pub fn class(self) -> SchedClass {
+        match self {
+            Self::Fifo => SchedClass::RealTime,
+            Self::Normal | Self::Batch => SchedClass::Fair,
+            Self::Idle => SchedClass::Idle,
+        }
+    }
+}
+
+/// Scheduling classes in the order they are picked from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SchedClass {
+    RealTime = 0,
+    Fair = 1,
+    Idle = 2,
+}
+
+pub const SCHED_CLASSES: usize = 3;
+
+pub const MIN_RT_PRIORITY: u8 = 1;
+pub const MAX_RT_PRIORITY: u8 = 99;
+pub const MIN_NICE: i8 = -20;
+pub const MAX_NICE: i8 = 19;
+
+/// A woken fair task may lag behind `min_vruntime` by at most this many
+/// timer ticks, so that long sleepers do not monopolize the hart.
+const SCHED_LATENCY: u64 = 60_000;
+
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// Load weight of nice levels -20..=19, taken from Linux.
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+/// The scheduling settings of a runnable, which syscalls read and change.
+#[derive(Debug, Clone, Copy)]
+pub struct SchedParams {
+    policy: SchedPolicy,
+    rt_priority: u8,
+    nice: i8,
+    affinity: CpuMask,
+}
+
+impl Default for SchedParams {
+    fn default() -> Self {
+        Self::new(SchedPolicy::Normal)
+    }
+}
+
+impl SchedParams {
+    pub const fn new(policy: SchedPolicy) -> Self {
+        Self {
+            policy,
+            rt_priority: if matches!(policy, SchedPolicy::Fifo) {
+                MIN_RT_PRIORITY
+            } else {
+                0
+            },
+            nice: 0,
+            affinity: CpuMask::full(),
+        }
+    }
+
+    pub fn policy(&self) -> SchedPolicy {
+        self.policy
+    }
+
+    pub fn class(&self) -> SchedClass {
+        self.policy.class()
+    }
+
+    pub fn rt_priority(&self) -> u8 {
+        self.rt_priority
+    }
+
+    pub fn nice(&self) -> i8 {
+        self.nice
+    }
+
+    /// Harts the runnable may run on.
+    pub fn affinity(&self) -> CpuMask {
+        self.affinity
+    }
+
+    /// Restricts the runnable to `affinity`, which must not be empty. A
+    /// running task moves away at its next scheduling point if the current
+    /// hart is no longer allowed.
+    pub fn set_affinity(&mut self, affinity: CpuMask) {
+        assert!(!affinity.is_empty());
+        self.affinity = affinity;
+    }
+
+    /// Changes policy and real-time priority. The priority must be in
+    /// `MIN_RT_PRIORITY..=MAX_RT_PRIORITY` for real-time policies and 0
+    /// otherwise.
+    pub fn set_policy(&mut self, policy: SchedPolicy, rt_priority: u8) -> bool {
+        let valid = match policy.class() {
+            SchedClass::RealTime => (MIN_RT_PRIORITY..=MAX_RT_PRIORITY).contains(&rt_priority),
+            _ => rt_priority == 0,
+        };
+        if valid {
+            self.policy = policy;
+            self.rt_priority = rt_priority;
+        }
+        valid
+    }
+
+    pub fn set_nice(&mut self, nice: i8) {
+        self.nice = nice.clamp(MIN_NICE, MAX_NICE);
+    }
+}
+
+/// Per-runnable scheduling state.
+#[derive(Debug, Clone)]
+pub struct SchedEntity {
+    params: SchedParams,
+    vruntime: u64,
+    exec_start: Option<u64>,
+    last_hart: Option<usize>,
+}
+
+impl Default for SchedEntity {
+    fn default() -> Self {
+        Self::new(SchedPolicy::Normal)
+    }
+}
+
+impl SchedEntity {
+    pub const fn new(policy: SchedPolicy) -> Self {
+        Self {
+            params: SchedParams::new(policy),
+            vruntime: 0,
+            exec_start: None,
+            last_hart: None,
+        }
+    }
+
+    pub fn params(&self) -> SchedParams {
+        self.params
+    }
+
+    /// Replaces the settings, e.g. with those a syscall changed while the
+    /// runnable was queued. A queued runnable keeps its place until it is
+    /// queued again.
+    pub fn set_params(&mut self, params: SchedParams) {
+        self.params = params;
+    }
+
+    pub fn policy(&self) -> SchedPolicy {
+        self.params.policy
+    }
+
+    pub fn class(&self) -> SchedClass {
+        self.params.class()
+    }
+
+    pub fn rt_priority(&self) -> u8 {
+        self.params.rt_priority
+    }
+
+    pub fn vruntime(&self) -> u64 {
+        self.vruntime
+    }
+
+    /// Harts this entity may run on.
+    pub fn affinity(&self) -> CpuMask {
+        self.params.affinity
+    }
+
+    pub fn can_run_on(&self, hart_id: usize) -> bool {
+        self.params.affinity.contains(hart_id)
+    }
+
+    /// The hart this entity ran on most recently.
+    pub fn last_hart(&self) -> Option<usize> {
+        self.last_hart
+    }
+
+    pub fn weight(&self) -> u64 {
+        NICE_TO_WEIGHT[(self.params.nice - MIN_NICE) as usize]
+    }
+
+    /// Starts a run on `hart_id`, returning whether the entity migrated
+    /// there from another hart.
+    pub(super) fn start(&mut self, hart_id: usize, now: u64) -> bool {
+        self.exec_start = Some(now);
+        self.last_hart
+            .replace(hart_id)
+            .is_some_and(|last| last != hart_id)
+    }
+
+    /// Ends the current run and returns how long it was, charging weighted
+    /// virtual runtime to fair entities.
+    pub(super) fn stop(&mut self, now: u64) -> u64 {
+        let Some(start) = self.exec_start.take() else {
+            return 0;
+        };
+        let delta = now.saturating_sub(start);
+        if self.class() == SchedClass::Fair {
+            self.vruntime += delta * NICE_0_WEIGHT / self.weight();
+        }
+        delta
+    }
+}
+
+/// Run queue of one hart (or of the global injector), holding one
+/// sub-queue per scheduling class.
+#[derive(Default)]
+pub struct RunQueue {
+    /// Real-time FIFO queues, highest priority first.
+    rt: BTreeMap<Reverse<u8>, VecDeque<Runnable>>,
+    /// Fair runnables ordered by `(vruntime, arrival)`.
+    fair: BTreeMap<(u64, u64), Runnable>,
+    idle: VecDeque<Runnable>,
+    min_vruntime: u64,
+    seq: u64,
+    len: usize,
+}
+
+impl RunQueue {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Rank of the runnable [`pop`](Self::pop) would return, lower runs
+    /// first: by class, then by priority for real-time runnables and by
+    /// virtual runtime for fair ones. Used to choose between two queues.
+    pub fn head_rank(&self) -> Option<(SchedClass, Reverse<u8>, u64)> {
+        if let Some(prio) = self.rt.keys().next() {
+            Some((SchedClass::RealTime, *prio, 0))
+        } else if let Some(&(vruntime, _)) = self.fair.keys().next() {
+            Some((SchedClass::Fair, Reverse(0), vruntime))
+        } else if !self.idle.is_empty() {
+            Some((SchedClass::Idle, Reverse(0), 0))
+        } else {
+            None
+        }
+    }
+
+    pub fn push(&mut self, mut runnable: Runnable) {
+        let sched = runnable.sched_mut();
+        match sched.class() {
+            SchedClass::RealTime => {
+                let prio = sched.rt_priority();
+                self.rt
+                    .entry(Reverse(prio))
+                    .or_default()
+                    .push_back(runnable);
+            }
+            SchedClass::Fair => {
+                let floor = self.min_vruntime.saturating_sub(SCHED_LATENCY);
+                sched.vruntime = sched.vruntime.max(floor);
+                let key = (sched.vruntime, self.seq);
+                self.seq += 1;
+                self.fair.insert(key, runnable);
+            }
+            SchedClass::Idle => self.idle.push_back(runnable),
+        }
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<Runnable> {
+        let runnable = self
+            .pop_rt()
+            .or_else(|| self.pop_fair())
+            .or_else(|| self.idle.pop_front());
+        if runnable.is_some() {
+            self.len -= 1;
+        }
+        runnable
+    }
+
+    /// Removes up to half of the queue (at most `max`) for `thief`, only
+    /// taking runnables allowed to run there. Fair runnables which are
+    /// furthest from running go first, then idle ones and finally the lowest
+    /// real-time priorities.
+    pub fn steal_half(&mut self, thief: usize, max: usize) -> Vec<Runnable> {
+        let n = self.len.div_ceil(2).min(max);
+        let mut stolen = Vec::with_capacity(n);
+        let allowed = |r: &Runnable| r.sched().can_run_on(thief);
+
+        let keys: Vec<_> = self
+            .fair
+            .iter()
+            .rev()
+            .filter(|(_, r)| allowed(r))
+            .map(|(key, _)| *key)
+            .take(n)
+            .collect();
+        for key in keys {
+            stolen.extend(self.fair.remove(&key));
+        }
+        Self::steal_from_back(&mut self.idle, &mut stolen, n, allowed);
+        let prios: Vec<_> = self.rt.keys().rev().copied().collect();
+        for prio in prios {
+            if stolen.len() >= n {
+                break;
+            }
+            let queue = self.rt.get_mut(&prio).unwrap();
+            Self::steal_from_back(queue, &mut stolen, n, allowed);
+            if queue.is_empty() {
+                self.rt.remove(&prio);
+            }
+        }
+        self.len -= stolen.len();
+        stolen
+    }
+
+    fn steal_from_back(
+        queue: &mut VecDeque<Runnable>,
+        stolen: &mut Vec<Runnable>,
+        n: usize,
+        allowed: impl Fn(&Runnable) -> bool,
+    ) {
+        let mut i = queue.len();
+        while i > 0 && stolen.len() < n {
+            i -= 1;
+            if allowed(&queue[i]) {
+                stolen.extend(queue.remove(i));
+            }
+        }
+    }
+
+    fn pop_rt(&mut self) -> Option<Runnable> {
+        let mut entry = self.rt.first_entry()?;
+        let runnable = entry.get_mut().pop_front();
+        if entry.get().is_empty() {
+            entry.remove();
+        }
+        runnable
+    }
+
+    fn pop_fair(&mut self) -> Option<Runnable> {
+        let ((vruntime, _), runnable) = self.fair.pop_first()?;
+        self.min_vruntime = self.min_vruntime.max(vruntime);
+        Some(runnable)
+    }
+}
@@ -0,0 +1,165 @@
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use crate::{
+    KError, KResult,
+    sync::SpinMutex,
+    task::{TidHandle, alloc_tid},
+};
+use alloc::{boxed::Box, sync::Arc, task::Wake};
+
+use super::{EXECUTOR, Runnable, SchedEntity, TaskWaker};
+
+/// A `'static` kernel future scheduled on the executor, e.g. a driver daemon
+/// or a flusher. It gets a tid like a user task so that it is woken through
+/// the same [`TaskWaker`].
+pub struct KernelTask {
+    tid: TidHandle,
+    pub sched: SchedEntity,
+    waker: Arc<TaskWaker>,
+    aborted: Arc<AtomicBool>,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl KernelTask {
+    pub fn tid(&self) -> usize {
+        self.tid.0
+    }
+
+    pub fn waker(&self) -> &Arc<TaskWaker> {
+        &self.waker
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+
+    pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+struct JoinState<T> {
+    output: Option<KResult<T>>,
+    waiter: Option<Waker>,
+}
+
+/// Completes the join state with [`KError::TaskAborted`] if the task future
+/// is dropped before it produced an output.
+struct Completion<T>(Arc<SpinMutex<JoinState<T>>>);
+
+impl<T> Completion<T> {
+    fn complete(&self, output: KResult<T>) {
+        let mut state = self.0.lock();
+        if state.output.is_none() {
+            state.output = Some(output);
+            if let Some(waiter) = state.waiter.take() {
+                waiter.wake();
+            }
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        self.complete(Err(KError::TaskAborted));
+    }
+}
+
+/// An owned permission to await or cancel a spawned kernel task.
+///
+/// Dropping the handle detaches the task: it keeps running and its output is
+/// discarded.
+pub struct JoinHandle<T> {
+    tid: usize,
+    waker: Arc<TaskWaker>,
+    aborted: Arc<AtomicBool>,
+    state: Arc<SpinMutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn tid(&self) -> usize {
+        self.tid
+    }
+
+    /// Requests cancellation. The future is dropped the next time the
+    /// executor picks the task up, and awaiting the handle then yields
+    /// [`KError::TaskAborted`]. Has no effect if the task already finished.
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Release);
+        self.waker.wake_by_ref();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().output.is_some()
+    }
+
+    /// Lets the task run to completion on its own.
+    pub fn detach(self) {}
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = KResult<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                state.waiter = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Spawns a kernel future on the executor, sharing the run queues with user
+/// tasks.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn_with(SchedEntity::default(), future)
+}
+
+/// Like [`spawn`], but with explicit scheduling parameters, e.g. a real-time
+/// class for interrupt bottom halves.
+pub fn spawn_with<F>(sched: SchedEntity, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let tid = alloc_tid();
+    let id = tid.0;
+    let waker = Arc::new(TaskWaker::new(id));
+    let aborted = Arc::new(AtomicBool::new(false));
+    let state = Arc::new(SpinMutex::new(JoinState {
+        output: None,
+        waiter: None,
+    }));
+    let completion = Completion(state.clone());
+    let task = Box::new(KernelTask {
+        tid,
+        sched,
+        waker: waker.clone(),
+        aborted: aborted.clone(),
+        future: Box::pin(async move {
+            let output = future.await;
+            completion.complete(Ok(output));
+        }),
+    });
+    EXECUTOR
+        .get()
+        .expect("executor initialized")
+        .schedule(Runnable::Kernel(task));
+    JoinHandle {
+        tid: id,
+        waker,
+        aborted,
+        state,
+    }
+}
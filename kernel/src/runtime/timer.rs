@@ -0,0 +1,216 @@
+use core::{
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::collections::BTreeMap;
+use riscv::register::{sie, time};
+
+use crate::{dtb::MACHINE_META, hart, percpu, sync::SpinNoIrq};
+
+/// Interval of the scheduler tick which preempts user tasks.
+pub const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Timers registered on one hart, ordered by `(deadline, id)`.
+struct TimerQueue {
+    timers: BTreeMap<(u64, u64), Waker>,
+    next_id: u64,
+}
+
+percpu! {
+    static TIMERS: SpinNoIrq<TimerQueue> = SpinNoIrq::new(TimerQueue {
+        timers: BTreeMap::new(),
+        next_id: 0,
+    });
+    /// Timer interrupts taken by the hart.
+    static TIMER_INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+}
+
+pub fn init_timer() {
+    unsafe { sie::set_stimer() };
+    program_next();
+}
+
+/// Current time in timer ticks since boot.
+pub fn now() -> u64 {
+    time::read() as u64
+}
+
+pub fn ticks_per_sec() -> u64 {
+    MACHINE_META.get().expect("dtb parsed").timebase_frequency as u64
+}
+
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let freq = ticks_per_sec();
+    duration.as_secs() * freq + duration.subsec_nanos() as u64 * freq / 1_000_000_000
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let freq = ticks_per_sec();
+    Duration::new(ticks / freq, ((ticks % freq) * 1_000_000_000 / freq) as u32)
+}
+
+/// Wakes the timers of this hart which are due and arms the timer interrupt
+/// for the next deadline, or for the next scheduler tick if sooner.
+pub fn handle_timer() {
+    TIMER_INTERRUPTS.local().fetch_add(1, Ordering::Relaxed);
+    let now = now();
+    let mut expired = alloc::vec::Vec::new();
+    {
+        let mut queue = TIMERS.local().lock();
+        while let Some(entry) = queue.timers.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            expired.push(entry.remove());
+        }
+    }
+    for waker in expired {
+        waker.wake();
+    }
+    program_next();
+}
+
+/// Timer interrupts taken by CPU `cpu`.
+pub fn timer_interrupts(cpu: usize) -> usize {
+    TIMER_INTERRUPTS.on(cpu).load(Ordering::Relaxed)
+}
+
+/// Wakes every timer of `hart_id` ahead of time, e.g. before the hart goes
+/// offline. The sleeps register again on the hart they are polled on next.
+pub fn wake_timers(hart_id: usize) {
+    let timers = core::mem::take(&mut TIMERS.on(hart_id).lock().timers);
+    for waker in timers.into_values() {
+        waker.wake();
+    }
+}
+
+fn program_next() {
+    let tick = now() + duration_to_ticks(TICK_INTERVAL);
+    let next = TIMERS
+        .local()
+        .lock()
+        .timers
+        .keys()
+        .next()
+        .map_or(tick, |&(deadline, _)| deadline.min(tick));
+    sbi_rt::set_timer(next);
+}
+
+/// `struct timespec` of user space.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimeSpec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl TimeSpec {
+    /// Time since boot, which also serves as the realtime clock.
+    pub fn now() -> Self {
+        let time = ticks_to_duration(now());
+        Self {
+            tv_sec: time.as_secs() as i64,
+            tv_nsec: time.subsec_nanos() as i64,
+        }
+    }
+
+    /// Returns `None` for negative or unnormalized values.
+    pub fn to_duration(self) -> Option<Duration> {
+        if self.tv_sec < 0 || !(0..1_000_000_000).contains(&self.tv_nsec) {
+            return None;
+        }
+        Some(Duration::new(self.tv_sec as u64, self.tv_nsec as u32))
+    }
+}
+
+/// Completes once the deadline, in ticks since boot, has passed.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep {
+        deadline,
+        entry: None,
+    }
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(now() + duration_to_ticks(duration))
+}
+
+/// Future of [`sleep_until`]. It is registered on the queue of the hart it
+/// was last polled on, and moves along if its task migrates.
+pub struct Sleep {
+    deadline: u64,
+    entry: Option<(usize, (u64, u64))>,
+}
+
+impl Sleep {
+    fn cancel(&mut self) {
+        if let Some((hart_id, key)) = self.entry.take() {
+            TIMERS.on(hart_id).lock().timers.remove(&key);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if now() >= self.deadline {
+            self.cancel();
+            return Poll::Ready(());
+        }
+        let hart_id = hart::hart_id();
+        let mut queue = TIMERS.on(hart_id).lock();
+        match self.entry {
+            Some((id, key)) if id == hart_id => {
+                queue.timers.insert(key, cx.waker().clone());
+            }
+            _ => {
+                drop(queue);
+                self.cancel();
+                queue = TIMERS.on(hart_id).lock();
+                let key = (self.deadline, queue.next_id);
+                queue.next_id += 1;
+                let first = queue.timers.keys().next().is_none_or(|&k| key < k);
+                queue.timers.insert(key, cx.waker().clone());
+                drop(queue);
+                self.entry = Some((hart_id, key));
+                if first {
+                    program_next();
+                }
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Error of [`timeout`] when the deadline passed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Runs `future` until it completes or the deadline, in ticks since boot,
+/// passes.
+pub async fn timeout_at<F: Future>(deadline: u64, future: F) -> Result<F::Output, Elapsed> {
+    let mut future = core::pin::pin!(future);
+    let mut sleep = core::pin::pin!(sleep_until(deadline));
+    core::future::poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        sleep.as_mut().poll(cx).map(|()| Err(Elapsed))
+    })
+    .await
+}
+
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    timeout_at(now() + duration_to_ticks(duration), future).await
+}
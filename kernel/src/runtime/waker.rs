@@ -1,20 +1,74 @@
-use crate::task::{Task, TidHandle};
-use alloc::boxed::Box;
-use alloc::collections::{BTreeMap, VecDeque};
 use alloc::sync::Arc;
 use alloc::task::Wake;
-use spin::Mutex;
 
+use crate::sync::SpinNoIrq;
+
+use super::{EXECUTOR, Runnable};
+
+/// Wakes the runnable with tid `target`, and holds it while it is parked.
+///
+/// Every runnable has its own waker, so parking and waking only lock the
+/// state of that runnable. A wake that arrives while the runnable is not
+/// parked (e.g. it is being polled) is remembered, so that the following
+/// park requeues it immediately.
 pub struct TaskWaker {
-    ready: Arc<Mutex<VecDeque<Box<Task>>>>,
-    pending: Arc<Mutex<BTreeMap<TidHandle, Box<Task>>>>,
-    target: TidHandle,
+    target: usize,
+    state: SpinNoIrq<ParkState>,
+}
+
+#[derive(Default)]
+struct ParkState {
+    parked: Option<Runnable>,
+    notified: bool,
+}
+
+impl TaskWaker {
+    pub fn new(tid: usize) -> Self {
+        Self {
+            target: tid,
+            state: SpinNoIrq::new(ParkState::default()),
+        }
+    }
+
+    /// Parks `runnable` until it is woken. Gives it back if a wake-up
+    /// arrived since it was fetched.
+    pub(super) fn park(&self, runnable: Runnable) -> Option<Runnable> {
+        debug_assert_eq!(runnable.id(), self.target);
+        let mut state = self.state.lock();
+        if core::mem::take(&mut state.notified) {
+            Some(runnable)
+        } else {
+            state.parked = Some(runnable);
+            None
+        }
+    }
+
+    /// Whether the runnable is parked waiting for a wake-up.
+    pub fn is_parked(&self) -> bool {
+        self.state.lock().parked.is_some()
+    }
+
+    /// Drops a recorded wake-up, called before the runnable is run and when
+    /// it exits.
+    pub fn clear_notified(&self) {
+        self.state.lock().notified = false;
+    }
 }
 
 impl Wake for TaskWaker {
     fn wake(self: Arc<Self>) {
-        if let Some(task) = self.pending.lock().remove(&self.target) {
-            self.ready.lock().push_back(task);
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let parked = {
+            let mut state = self.state.lock();
+            let parked = state.parked.take();
+            state.notified = parked.is_none();
+            parked
+        };
+        if let (Some(runnable), Some(executor)) = (parked, EXECUTOR.get()) {
+            executor.schedule(runnable);
         }
     }
 }
@@ -0,0 +1,22 @@
+mod mutex;
+mod noirq;
+mod notify;
+mod rwlock;
+#[cfg(feature = "selftest")]
+mod selftest;
+mod semaphore;
+mod spin;
+mod wait;
+
+pub mod mpsc;
+pub mod oneshot;
+
+pub use mutex::*;
+pub use noirq::*;
+pub use notify::*;
+pub use rwlock::*;
+#[cfg(feature = "selftest")]
+pub use selftest::*;
+pub use semaphore::*;
+pub use spin::*;
+pub(crate) use wait::*;
@@ -0,0 +1,148 @@
+//! A bounded multi-producer, single-consumer channel.
+
+use core::{
+    future::poll_fn,
+    task::{Poll, Waker},
+};
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use super::{Semaphore, SpinMutex};
+
+/// Error returned when sending to a channel whose receiver is gone. Carries
+/// the value back.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+struct Chan<T> {
+    /// One permit per free slot. Closed when the receiver is dropped.
+    slots: Semaphore,
+    state: SpinMutex<ChanState<T>>,
+}
+
+struct ChanState<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    rx_waker: Option<Waker>,
+}
+
+/// Creates a channel holding at most `capacity` queued values. Senders wait
+/// for a free slot when it is full.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0);
+    let chan = Arc::new(Chan {
+        slots: Semaphore::new(capacity),
+        state: SpinMutex::new(ChanState {
+            queue: VecDeque::with_capacity(capacity),
+            senders: 1,
+            rx_waker: None,
+        }),
+    });
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        if self.chan.slots.acquire_raw(1).await.is_err() {
+            return Err(SendError(value));
+        }
+        self.push(value);
+        Ok(())
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if self.chan.slots.is_closed() {
+            Err(TrySendError::Closed(value))
+        } else if self.chan.slots.try_acquire_raw(1) {
+            self.push(value);
+            Ok(())
+        } else {
+            Err(TrySendError::Full(value))
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.chan.slots.is_closed()
+    }
+
+    fn push(&self, value: T) {
+        let mut state = self.chan.state.lock();
+        state.queue.push_back(value);
+        if let Some(waker) = state.rx_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.state.lock().senders += 1;
+        Self {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.chan.state.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            if let Some(waker) = state.rx_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receives the next value, or `None` once all senders are gone and the
+    /// queue is drained.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| {
+            let mut state = self.chan.state.lock();
+            if let Some(value) = state.queue.pop_front() {
+                drop(state);
+                self.chan.slots.add_permits(1);
+                Poll::Ready(Some(value))
+            } else if state.senders == 0 {
+                Poll::Ready(None)
+            } else {
+                state.rx_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        let value = self.chan.state.lock().queue.pop_front()?;
+        self.chan.slots.add_permits(1);
+        Some(value)
+    }
+
+    /// Stops accepting values. Queued values can still be received.
+    pub fn close(&mut self) {
+        self.chan.slots.close();
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.chan.slots.close();
+    }
+}
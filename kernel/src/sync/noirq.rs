@@ -0,0 +1,79 @@
+use core::ops::{Deref, DerefMut};
+
+use crate::hart::{IrqGuard, NoPreemptGuard};
+
+/// A spin lock which disables interrupts while held, for state shared with
+/// interrupt handlers. Taking a plain spin lock there could deadlock the hart
+/// if the interrupted code already held it.
+pub struct SpinNoIrq<T: ?Sized> {
+    inner: spin::Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for SpinNoIrq<T> {}
+unsafe impl<T: ?Sized + Send> Send for SpinNoIrq<T> {}
+
+impl<T> SpinNoIrq<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            inner: spin::Mutex::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> SpinNoIrq<T> {
+    pub fn lock(&self) -> SpinNoIrqGuard<'_, T> {
+        let irq = IrqGuard::new();
+        let preempt = NoPreemptGuard::new();
+        SpinNoIrqGuard {
+            guard: self.inner.lock(),
+            _preempt: preempt,
+            _irq: irq,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinNoIrqGuard<'_, T>> {
+        let irq = IrqGuard::new();
+        let preempt = NoPreemptGuard::new();
+        Some(SpinNoIrqGuard {
+            guard: self.inner.try_lock()?,
+            _preempt: preempt,
+            _irq: irq,
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: Default> Default for SpinNoIrq<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// Fields drop in order: the lock is released before interrupts are
+/// restored.
+pub struct SpinNoIrqGuard<'a, T: ?Sized> {
+    guard: spin::MutexGuard<'a, T>,
+    _preempt: NoPreemptGuard,
+    _irq: IrqGuard,
+}
+
+impl<T: ?Sized> Deref for SpinNoIrqGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinNoIrqGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
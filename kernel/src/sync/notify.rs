@@ -0,0 +1,210 @@
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use super::{
+    SpinMutex,
+    wait::{WAITING, WOKEN, WOKEN_ALL, WaitNode},
+};
+
+/// Notifies a single task, or all waiting tasks, of an event.
+///
+/// [`notify_one`](Self::notify_one) without a waiter stores a permit which
+/// the next [`notified`](Self::notified) consumes immediately, so a
+/// notification sent just before the receiver starts waiting is not lost.
+pub struct Notify {
+    state: SpinMutex<NotifyState>,
+}
+
+struct NotifyState {
+    permit: bool,
+    waiters: VecDeque<Arc<WaitNode>>,
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Self {
+            state: SpinMutex::new(NotifyState {
+                permit: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn notify_one(&self) {
+        let mut state = self.state.lock();
+        match state.waiters.pop_front() {
+            Some(node) => node.wake(WOKEN),
+            None => state.permit = true,
+        }
+    }
+
+    /// Wakes every task waiting right now, without storing a permit.
+    pub fn notify_waiters(&self) {
+        let mut state = self.state.lock();
+        for node in state.waiters.drain(..) {
+            node.wake(WOKEN_ALL);
+        }
+    }
+
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            node: None,
+            done: false,
+        }
+    }
+}
+
+/// Future of [`Notify::notified`]. If it is dropped after being picked by
+/// [`Notify::notify_one`], the notification is passed on.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    node: Option<Arc<WaitNode>>,
+    done: bool,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(node) = self.node.as_ref() {
+            if node.register(cx.waker()) == WAITING {
+                return Poll::Pending;
+            }
+            self.done = true;
+            return Poll::Ready(());
+        }
+        let mut state = self.notify.state.lock();
+        if state.permit {
+            state.permit = false;
+            self.done = true;
+            return Poll::Ready(());
+        }
+        let node = Arc::new(WaitNode::new(cx.waker()));
+        state.waiters.push_back(node.clone());
+        drop(state);
+        self.node = Some(node);
+        Poll::Pending
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let Some(node) = self.node.take() else {
+            return;
+        };
+        if self.done {
+            return;
+        }
+        let mut state = self.notify.state.lock();
+        match node.state() {
+            WOKEN => {
+                drop(state);
+                self.notify.notify_one();
+            }
+            WAITING => state.waiters.retain(|n| !Arc::ptr_eq(n, &node)),
+            _ => {}
+        }
+    }
+}
+
+/// A level-triggered event: once [`set`](Self::set), every
+/// [`wait`](Self::wait) completes immediately until it is
+/// [`reset`](Self::reset).
+pub struct Event {
+    state: SpinMutex<EventState>,
+}
+
+struct EventState {
+    set: bool,
+    waiters: VecDeque<Arc<WaitNode>>,
+}
+
+impl Default for Event {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Event {
+    pub const fn new() -> Self {
+        Self {
+            state: SpinMutex::new(EventState {
+                set: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.state.lock().set
+    }
+
+    pub fn set(&self) {
+        let mut state = self.state.lock();
+        state.set = true;
+        for node in state.waiters.drain(..) {
+            node.wake(WOKEN_ALL);
+        }
+    }
+
+    pub fn reset(&self) {
+        self.state.lock().set = false;
+    }
+
+    pub fn wait(&self) -> EventWait<'_> {
+        EventWait {
+            event: self,
+            node: None,
+        }
+    }
+}
+
+pub struct EventWait<'a> {
+    event: &'a Event,
+    node: Option<Arc<WaitNode>>,
+}
+
+impl Future for EventWait<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(node) = self.node.as_ref() {
+            if node.register(cx.waker()) != WAITING {
+                return Poll::Ready(());
+            }
+        }
+        let mut state = self.event.state.lock();
+        if state.set {
+            return Poll::Ready(());
+        }
+        if self.node.is_none() {
+            let node = Arc::new(WaitNode::new(cx.waker()));
+            state.waiters.push_back(node.clone());
+            drop(state);
+            self.node = Some(node);
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for EventWait<'_> {
+    fn drop(&mut self) {
+        if let Some(node) = self.node.take() {
+            if node.state() == WAITING {
+                let mut state = self.event.state.lock();
+                state.waiters.retain(|n| !Arc::ptr_eq(n, &node));
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! A channel carrying a single value from one task to another.
+
+use core::{
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use alloc::sync::Arc;
+
+use super::SpinMutex;
+
+/// Error returned by the [`Receiver`] when the [`Sender`] was dropped
+/// without sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+struct Inner<T> {
+    value: Option<T>,
+    rx_waker: Option<Waker>,
+    tx_dropped: bool,
+    rx_dropped: bool,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(SpinMutex::new(Inner {
+        value: None,
+        rx_waker: None,
+        tx_dropped: false,
+        rx_dropped: false,
+    }));
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner },
+    )
+}
+
+pub struct Sender<T> {
+    inner: Arc<SpinMutex<Inner<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, giving it back if the receiver is gone.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let mut inner = self.inner.lock();
+        if inner.rx_dropped {
+            return Err(value);
+        }
+        inner.value = Some(value);
+        if let Some(waker) = inner.rx_waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.lock().rx_dropped
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock();
+        inner.tx_dropped = true;
+        if let Some(waker) = inner.rx_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Awaiting the receiver yields the sent value.
+pub struct Receiver<T> {
+    inner: Arc<SpinMutex<Inner<T>>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.inner.lock().value.take()
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.inner.lock();
+        if let Some(value) = inner.value.take() {
+            Poll::Ready(Ok(value))
+        } else if inner.tx_dropped {
+            Poll::Ready(Err(RecvError))
+        } else {
+            inner.rx_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.lock().rx_dropped = true;
+    }
+}
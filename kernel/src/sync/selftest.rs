@@ -0,0 +1,72 @@
+//! Self-tests of the channels, run as kernel tasks at boot with the
+//! `selftest` feature.
+
+use log::info;
+
+use crate::runtime::{spawn, yield_now};
+
+use super::{mpsc, oneshot};
+
+async fn oneshot_test() {
+    let (tx, rx) = oneshot::channel();
+    let receiver = spawn(rx);
+    yield_now().await;
+    assert_eq!(tx.send(1), Ok(()));
+    assert_eq!(receiver.await.unwrap(), Ok(1));
+
+    let (tx, rx) = oneshot::channel::<usize>();
+    let receiver = spawn(rx);
+    yield_now().await;
+    drop(tx);
+    assert_eq!(receiver.await.unwrap(), Err(oneshot::RecvError));
+
+    let (tx, rx) = oneshot::channel();
+    assert!(!tx.is_closed());
+    drop(rx);
+    assert!(tx.is_closed());
+    assert_eq!(tx.send(2), Err(2));
+}
+
+async fn mpsc_test() {
+    let (tx, mut rx) = mpsc::channel(2);
+    assert_eq!(tx.try_send(0), Ok(()));
+    assert_eq!(tx.try_send(1), Ok(()));
+    assert_eq!(tx.try_send(2), Err(mpsc::TrySendError::Full(2)));
+
+    // a full channel parks the sender until a slot is free
+    let sender = tx.clone();
+    let blocked = spawn(async move { sender.send(2).await });
+    yield_now().await;
+    assert!(!blocked.is_finished());
+    assert_eq!(rx.recv().await, Some(0));
+    assert_eq!(blocked.await.unwrap(), Ok(()));
+    assert_eq!(rx.recv().await, Some(1));
+    assert_eq!(rx.recv().await, Some(2));
+
+    // the receiver parks until a value comes or the last sender is gone
+    let receiver = spawn(async move {
+        let value = rx.recv().await;
+        (value, rx.recv().await)
+    });
+    yield_now().await;
+    tx.send(3).await.unwrap();
+    drop(tx);
+    assert_eq!(receiver.await.unwrap(), (Some(3), None));
+
+    let (tx, mut rx) = mpsc::channel(1);
+    tx.try_send(4).unwrap();
+    rx.close();
+    assert!(tx.is_closed());
+    assert_eq!(tx.send(5).await, Err(mpsc::SendError(5)));
+    assert_eq!(rx.try_recv(), Some(4));
+}
+
+/// Spawns the channel tests. A failing test panics.
+pub fn selftest() {
+    spawn(async {
+        oneshot_test().await;
+        mpsc_test().await;
+        info!("sync self-test passed");
+    })
+    .detach();
+}
@@ -0,0 +1,206 @@
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use super::{
+    SpinMutex,
+    wait::{CLOSED, WOKEN, WaitNode},
+};
+
+/// Error returned when acquiring from a closed [`Semaphore`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcquireError;
+
+/// An async counting semaphore.
+///
+/// Waiters are served strictly in FIFO order: a waiter asking for many
+/// permits blocks those queued behind it, so writers of a
+/// [`RwLock`](super::RwLock) are not starved by readers.
+pub struct Semaphore {
+    state: SpinMutex<SemState>,
+}
+
+struct SemState {
+    permits: usize,
+    closed: bool,
+    waiters: VecDeque<(Arc<WaitNode>, usize)>,
+}
+
+impl SemState {
+    /// Hands permits to waiters at the head of the queue while possible.
+    fn grant(&mut self) {
+        while let Some((_, needed)) = self.waiters.front() {
+            if *needed > self.permits {
+                break;
+            }
+            self.permits -= needed;
+            let (node, _) = self.waiters.pop_front().unwrap();
+            node.wake(WOKEN);
+        }
+    }
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            state: SpinMutex::new(SemState {
+                permits,
+                closed: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    pub fn add_permits(&self, n: usize) {
+        let mut state = self.state.lock();
+        state.permits += n;
+        state.grant();
+    }
+
+    /// Closes the semaphore: waiters and later acquisitions fail.
+    pub fn close(&self) {
+        let mut state = self.state.lock();
+        state.closed = true;
+        for (node, _) in state.waiters.drain(..) {
+            node.wake(CLOSED);
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'_>, AcquireError> {
+        self.acquire_many(1).await
+    }
+
+    pub async fn acquire_many(&self, n: usize) -> Result<SemaphorePermit<'_>, AcquireError> {
+        self.acquire_raw(n).await?;
+        Ok(SemaphorePermit {
+            sem: self,
+            permits: n,
+        })
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_raw(1).then_some(SemaphorePermit {
+            sem: self,
+            permits: 1,
+        })
+    }
+
+    /// Acquires `n` permits which must later be returned by
+    /// [`add_permits`](Self::add_permits).
+    pub(super) fn acquire_raw(&self, n: usize) -> Acquire<'_> {
+        Acquire {
+            sem: self,
+            needed: n,
+            node: None,
+            done: false,
+        }
+    }
+
+    pub(super) fn try_acquire_raw(&self, n: usize) -> bool {
+        let mut state = self.state.lock();
+        if !state.closed && state.waiters.is_empty() && state.permits >= n {
+            state.permits -= n;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Future of [`Semaphore::acquire_raw`]. Dropping it while queued gives up
+/// the place in the queue, and returns the permits if they were granted but
+/// not observed yet.
+pub(super) struct Acquire<'a> {
+    sem: &'a Semaphore,
+    needed: usize,
+    node: Option<Arc<WaitNode>>,
+    done: bool,
+}
+
+impl Future for Acquire<'_> {
+    type Output = Result<(), AcquireError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(node) = self.node.as_ref() {
+            let ready = match node.register(cx.waker()) {
+                WOKEN => Ok(()),
+                CLOSED => Err(AcquireError),
+                _ => return Poll::Pending,
+            };
+            self.done = true;
+            return Poll::Ready(ready);
+        }
+        let mut state = self.sem.state.lock();
+        if state.closed {
+            self.done = true;
+            return Poll::Ready(Err(AcquireError));
+        }
+        if state.waiters.is_empty() && state.permits >= self.needed {
+            state.permits -= self.needed;
+            drop(state);
+            self.done = true;
+            return Poll::Ready(Ok(()));
+        }
+        let node = Arc::new(WaitNode::new(cx.waker()));
+        state.waiters.push_back((node.clone(), self.needed));
+        drop(state);
+        self.node = Some(node);
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let Some(node) = self.node.take() else {
+            return;
+        };
+        if self.done {
+            return;
+        }
+        let mut state = self.sem.state.lock();
+        match node.state() {
+            WOKEN => {
+                state.permits += self.needed;
+                state.grant();
+            }
+            CLOSED => {}
+            _ => {
+                state.waiters.retain(|(n, _)| !Arc::ptr_eq(n, &node));
+                // the head may have changed
+                state.grant();
+            }
+        }
+    }
+}
+
+/// Permits acquired from a [`Semaphore`], returned on drop.
+pub struct SemaphorePermit<'a> {
+    sem: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permits acquired for good.
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.sem.add_permits(self.permits);
+        }
+    }
+}
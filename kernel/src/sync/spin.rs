@@ -0,0 +1,97 @@
+use core::ops::{Deref, DerefMut};
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicIsize, Ordering};
+
+#[cfg(debug_assertions)]
+use crate::{hart, percpu};
+
+#[cfg(debug_assertions)]
+percpu! {
+    /// Number of [`SpinMutex`] guards alive on the hart.
+    static HELD: AtomicIsize = AtomicIsize::new(0);
+}
+
+/// A spin lock for state shared with async code.
+///
+/// In debug builds every guard is counted per hart, and the executor checks
+/// after each poll that none is left: a guard living in a suspended future
+/// would spin any other task touching the lock on this hart forever.
+pub struct SpinMutex<T: ?Sized> {
+    inner: spin::Mutex<T>,
+}
+
+impl<T> SpinMutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            inner: spin::Mutex::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinMutex<T> {
+    pub fn lock(&self) -> SpinMutexGuard<'_, T> {
+        let guard = self.inner.lock();
+        held_add(1);
+        SpinMutexGuard { guard }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        held_add(1);
+        Some(SpinMutexGuard { guard })
+    }
+}
+
+pub struct SpinMutexGuard<'a, T: ?Sized> {
+    guard: spin::MutexGuard<'a, T>,
+}
+
+impl<T: ?Sized> Deref for SpinMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for SpinMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        held_add(-1);
+    }
+}
+
+#[cfg(debug_assertions)]
+fn held_add(delta: isize) {
+    if hart::try_hart_id().is_some() {
+        HELD.local().fetch_add(delta, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(debug_assertions))]
+fn held_add(_delta: isize) {}
+
+/// Panics if a [`SpinMutex`] guard is alive on this hart. Called by the
+/// executor after polling task `tid`, when no lock may be held. A negative
+/// count means a guard taken on another hart was dropped here, i.e. the
+/// task migrated while holding it.
+#[cfg(debug_assertions)]
+pub fn check_no_spin_held(tid: usize) {
+    let id = hart::hart_id();
+    let held = HELD.local().swap(0, Ordering::Relaxed);
+    assert!(
+        held == 0,
+        "task {} held {} spin lock(s) across an await on hart {}",
+        tid,
+        held,
+        id
+    );
+}
+
+#[cfg(not(debug_assertions))]
+pub fn check_no_spin_held(_tid: usize) {}
@@ -0,0 +1,53 @@
+use core::{
+    sync::atomic::{AtomicU8, Ordering},
+    task::Waker,
+};
+
+use super::SpinMutex;
+
+pub(crate) const WAITING: u8 = 0;
+pub(crate) const WOKEN: u8 = 1;
+pub(crate) const CLOSED: u8 = 2;
+/// Woken together with all other waiters, nothing to pass on.
+pub(crate) const WOKEN_ALL: u8 = 3;
+
+/// A parked waiter of one of the primitives in this module.
+///
+/// The waker is registered before the state is checked and the state is set
+/// before the waker is taken, so a wake-up racing with a poll is never lost.
+pub(crate) struct WaitNode {
+    state: AtomicU8,
+    waker: SpinMutex<Option<Waker>>,
+}
+
+impl WaitNode {
+    pub fn new(waker: &Waker) -> Self {
+        Self {
+            state: AtomicU8::new(WAITING),
+            waker: SpinMutex::new(Some(waker.clone())),
+        }
+    }
+
+    pub fn state(&self) -> u8 {
+        self.state.load(Ordering::Acquire)
+    }
+
+    /// Updates the waker and returns the current state.
+    pub fn register(&self, waker: &Waker) -> u8 {
+        {
+            let mut slot = self.waker.lock();
+            if !slot.as_ref().is_some_and(|w| w.will_wake(waker)) {
+                *slot = Some(waker.clone());
+            }
+        }
+        self.state()
+    }
+
+    pub fn wake(&self, state: u8) {
+        self.state.store(state, Ordering::Release);
+        let waker = self.waker.lock().take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
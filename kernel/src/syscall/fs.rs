@@ -0,0 +1,603 @@
+use alloc::{string::String, sync::Arc, vec};
+
+use crate::{
+    Errno, SysResult,
+    block::block_device,
+    config::PAGE_SIZE_4K,
+    fs::{
+        FD_CLOEXEC, FdEntry, File, Inode, InodeFile, InodeType, Metadata, OpenFlags, PATH_MAX,
+        Path, RenameFlags, SeekFrom, filesystem, mount_at, mounted_on, open_device, path_string,
+        pipe, resolve, resolve_parent, root_path, unmount,
+    },
+    hart::{current_space, with_current_task},
+    mem::VirtAddr,
+    runtime::TimeSpec,
+};
+
+/// `dirfd` of the `*at` calls meaning the working directory.
+pub const AT_FDCWD: isize = -100;
+pub const AT_SYMLINK_NOFOLLOW: usize = 0x100;
+pub const AT_REMOVEDIR: usize = 0x200;
+pub const AT_EMPTY_PATH: usize = 0x1000;
+
+pub const F_DUPFD: usize = 0;
+pub const F_GETFD: usize = 1;
+pub const F_SETFD: usize = 2;
+pub const F_GETFL: usize = 3;
+pub const F_SETFL: usize = 4;
+pub const F_DUPFD_CLOEXEC: usize = 1030;
+
+/// `tv_nsec` of `utimensat` setting the current time.
+pub const UTIME_NOW: i64 = (1 << 30) - 1;
+/// `tv_nsec` of `utimensat` leaving a time unchanged.
+pub const UTIME_OMIT: i64 = (1 << 30) - 2;
+
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+/// Largest chunk copied through the kernel per read or write.
+const IO_CHUNK: usize = 64 << 10;
+
+/// `struct stat` of user space.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Kstat {
+    pub st_dev: u64,
+    pub st_ino: u64,
+    pub st_mode: u32,
+    pub st_nlink: u32,
+    pub st_uid: u32,
+    pub st_gid: u32,
+    pub st_rdev: u64,
+    __pad1: u64,
+    pub st_size: i64,
+    pub st_blksize: i32,
+    __pad2: i32,
+    pub st_blocks: i64,
+    pub st_atime: TimeSpec,
+    pub st_mtime: TimeSpec,
+    pub st_ctime: TimeSpec,
+    __unused: [u32; 2],
+}
+
+impl From<Metadata> for Kstat {
+    fn from(meta: Metadata) -> Self {
+        Self {
+            st_dev: meta.dev,
+            st_ino: meta.ino,
+            st_mode: meta.mode,
+            st_nlink: meta.nlink,
+            st_uid: meta.uid,
+            st_gid: meta.gid,
+            st_rdev: meta.rdev,
+            st_size: meta.size as i64,
+            st_blksize: meta.blksize as i32,
+            st_blocks: meta.blocks as i64,
+            st_atime: meta.atime,
+            st_mtime: meta.mtime,
+            st_ctime: meta.ctime,
+            ..Default::default()
+        }
+    }
+}
+
+fn file(fd: usize) -> Result<Arc<dyn File>, Errno> {
+    with_current_task(|task| task.files.lock().get(fd))
+}
+
+/// Installs `file` at the lowest free descriptor of the current task.
+fn install(file: Arc<dyn File>, cloexec: bool) -> SysResult {
+    with_current_task(|task| {
+        let limit = task.fd_limit();
+        task.files.lock().alloc(file, cloexec, limit)
+    })
+}
+
+/// Reads a path argument.
+pub(super) fn read_path(path: VirtAddr) -> Result<String, Errno> {
+    let bytes = current_space()
+        .read_user_cstr(path, PATH_MAX)?
+        .ok_or(Errno::ENAMETOOLONG)?;
+    String::from_utf8(bytes).map_err(|_| Errno::EINVAL)
+}
+
+fn cwd() -> Result<Path, Errno> {
+    match with_current_task(|task| task.cwd.clone()) {
+        Some(cwd) => Ok(cwd),
+        None => root_path(),
+    }
+}
+
+/// The directory relative paths given to an `*at` call start from.
+pub(super) fn start_dir(dirfd: usize, path: &str) -> Result<Path, Errno> {
+    if path.starts_with('/') {
+        return root_path();
+    }
+    if dirfd as isize == AT_FDCWD {
+        return cwd();
+    }
+    let dir = file(dirfd)?.path().ok_or(Errno::ENOTDIR)?;
+    if dir.inode_type() != InodeType::Dir {
+        return Err(Errno::ENOTDIR);
+    }
+    Ok(dir)
+}
+
+pub async fn sys_openat(dirfd: usize, path: VirtAddr, flags: usize, mode: usize) -> SysResult {
+    let flags = OpenFlags::from_bits_truncate(flags as u32);
+    let path = read_path(path)?;
+    let start = start_dir(dirfd, &path)?;
+    let follow = !flags.contains(OpenFlags::NOFOLLOW);
+    let target = match flags.contains(OpenFlags::CREAT) {
+        true => {
+            let (dir, name) = resolve_parent(&start, &path).await?;
+            if name == "." || name == ".." || path.ends_with('/') {
+                return Err(Errno::EISDIR);
+            }
+            match dir.dentry.lookup(&name).await {
+                Ok(_) if flags.contains(OpenFlags::EXCL) => return Err(Errno::EEXIST),
+                Ok(_) => resolve(&start, &path, follow).await?,
+                Err(Errno::ENOENT) => {
+                    let inode = dir
+                        .dentry
+                        .inode()
+                        .create(&name, InodeType::File, mode as u32 & 0o7777, 0)
+                        .await?;
+                    Path {
+                        dentry: dir.dentry.insert(&name, inode),
+                        mount: dir.mount,
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        false => resolve(&start, &path, follow).await?,
+    };
+
+    let ty = target.inode_type();
+    if ty == InodeType::Symlink && !flags.contains(OpenFlags::PATH) {
+        return Err(Errno::ELOOP);
+    }
+    if flags.contains(OpenFlags::DIRECTORY) && ty != InodeType::Dir {
+        return Err(Errno::ENOTDIR);
+    }
+    if ty == InodeType::Dir && flags.writable() {
+        return Err(Errno::EISDIR);
+    }
+    if flags.contains(OpenFlags::TRUNC) && flags.writable() && ty == InodeType::File {
+        target.dentry.inode().truncate(0).await?;
+    }
+    // flags which only matter while opening are not kept
+    let status =
+        flags - (OpenFlags::CREAT | OpenFlags::EXCL | OpenFlags::TRUNC | OpenFlags::CLOEXEC);
+    let file: Arc<dyn File> = match target.dentry.inode().open(status) {
+        Some(file) => file?,
+        None if matches!(ty, InodeType::CharDevice | InodeType::BlockDevice) => {
+            open_device(target, status).await?
+        }
+        None => Arc::new(InodeFile::new(target, status)),
+    };
+    install(file, flags.contains(OpenFlags::CLOEXEC))
+}
+
+pub fn sys_close(fd: usize) -> SysResult {
+    // the file may be released here, outside the lock of the table
+    let _file = with_current_task(|task| task.files.lock().close(fd))?;
+    Ok(0)
+}
+
+pub fn sys_dup(fd: usize) -> SysResult {
+    install(file(fd)?, false)
+}
+
+pub fn sys_dup3(old_fd: usize, new_fd: usize, flags: usize) -> SysResult {
+    let flags = OpenFlags::from_bits(flags as u32).ok_or(Errno::EINVAL)?;
+    if old_fd == new_fd || !(flags - OpenFlags::CLOEXEC).is_empty() {
+        return Err(Errno::EINVAL);
+    }
+    let replaced = with_current_task(|task| {
+        let limit = task.fd_limit();
+        let mut files = task.files.lock();
+        let entry = FdEntry {
+            file: files.get(old_fd)?,
+            cloexec: flags.contains(OpenFlags::CLOEXEC),
+        };
+        files.replace(new_fd, entry, limit)
+    })?;
+    drop(replaced);
+    Ok(new_fd)
+}
+
+pub fn sys_fcntl(fd: usize, cmd: usize, arg: usize) -> SysResult {
+    let (files, limit) = with_current_task(|task| (task.files.clone(), task.fd_limit()));
+    match cmd {
+        F_DUPFD | F_DUPFD_CLOEXEC => {
+            let mut files = files.lock();
+            let file = files.get(fd)?;
+            if arg >= limit {
+                return Err(Errno::EINVAL);
+            }
+            files.alloc_from(arg, file, cmd == F_DUPFD_CLOEXEC, limit)
+        }
+        F_GETFD => {
+            let cloexec = files.lock().entry(fd)?.cloexec;
+            Ok(if cloexec { FD_CLOEXEC } else { 0 })
+        }
+        F_SETFD => {
+            files.lock().set_cloexec(fd, arg & FD_CLOEXEC != 0)?;
+            Ok(0)
+        }
+        F_GETFL => Ok(file(fd)?.flags().bits() as usize),
+        F_SETFL => {
+            file(fd)?.set_flags(OpenFlags::from_bits_truncate(arg as u32));
+            Ok(0)
+        }
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+pub fn sys_pipe2(fds: VirtAddr, flags: usize) -> SysResult {
+    let flags = OpenFlags::from_bits(flags as u32).ok_or(Errno::EINVAL)?;
+    if !(flags - (OpenFlags::CLOEXEC | OpenFlags::NONBLOCK)).is_empty() {
+        return Err(Errno::EINVAL);
+    }
+    let cloexec = flags.contains(OpenFlags::CLOEXEC);
+    let (reader, writer) = pipe(flags - OpenFlags::CLOEXEC);
+    let read_fd = install(reader, cloexec)?;
+    let write_fd = match install(writer, cloexec) {
+        Ok(fd) => fd,
+        Err(err) => {
+            let _ = sys_close(read_fd);
+            return Err(err);
+        }
+    };
+    let pair = [read_fd as i32, write_fd as i32];
+    if let Err(err) = current_space().write_user(fds, &pair) {
+        let _ = sys_close(read_fd);
+        let _ = sys_close(write_fd);
+        return Err(err.into());
+    }
+    Ok(0)
+}
+
+pub async fn sys_read(fd: usize, buf: VirtAddr, len: usize) -> SysResult {
+    let file = file(fd)?;
+    let mut kbuf = vec![0; len.min(IO_CHUNK)];
+    let n = file.read(&mut kbuf).await?;
+    current_space().copy_to_user(buf, &kbuf[..n])?;
+    Ok(n)
+}
+
+pub async fn sys_write(fd: usize, buf: VirtAddr, len: usize) -> SysResult {
+    let file = file(fd)?;
+    let mut kbuf = vec![0; len.min(IO_CHUNK)];
+    let mut written = 0;
+    while written < len {
+        let chunk = &mut kbuf[..(len - written).min(IO_CHUNK)];
+        current_space().copy_from_user(buf + written, chunk)?;
+        // an error after a partial write is reported by the next call
+        let n = match file.write(chunk).await {
+            Ok(n) => n,
+            Err(_) if written > 0 => break,
+            Err(err) => return Err(err),
+        };
+        written += n;
+        if n < chunk.len() {
+            break;
+        }
+    }
+    Ok(written)
+}
+
+pub async fn sys_lseek(fd: usize, offset: isize, whence: usize) -> SysResult {
+    let pos = match whence {
+        SEEK_SET => SeekFrom::Start(u64::try_from(offset).map_err(|_| Errno::EINVAL)?),
+        SEEK_CUR => SeekFrom::Current(offset as i64),
+        SEEK_END => SeekFrom::End(offset as i64),
+        _ => return Err(Errno::EINVAL),
+    };
+    let pos = file(fd)?.seek(pos).await?;
+    Ok(pos as usize)
+}
+
+pub async fn sys_getdents64(fd: usize, buf: VirtAddr, len: usize) -> SysResult {
+    let file = file(fd)?;
+    let mut kbuf = vec![0; len.min(IO_CHUNK)];
+    let n = file.getdents(&mut kbuf).await?;
+    current_space().copy_to_user(buf, &kbuf[..n])?;
+    Ok(n)
+}
+
+pub fn sys_ioctl(fd: usize, request: usize, arg: VirtAddr) -> SysResult {
+    file(fd)?.ioctl(request, arg)
+}
+
+fn write_stat(statbuf: VirtAddr, meta: Metadata) -> SysResult {
+    current_space().write_user(statbuf, &Kstat::from(meta))?;
+    Ok(0)
+}
+
+pub async fn sys_fstat(fd: usize, statbuf: VirtAddr) -> SysResult {
+    let meta = file(fd)?.stat().await?;
+    write_stat(statbuf, meta)
+}
+
+pub async fn sys_newfstatat(
+    dirfd: usize,
+    path: VirtAddr,
+    statbuf: VirtAddr,
+    flags: usize,
+) -> SysResult {
+    let path = read_path(path)?;
+    // descriptors without a path, e.g. pipes, are stated through the file
+    let meta = match path.is_empty() && flags & AT_EMPTY_PATH != 0 && dirfd as isize != AT_FDCWD {
+        true => file(dirfd)?.stat().await?,
+        false => at_inode(dirfd, &path, flags).await?.metadata().await?,
+    };
+    write_stat(statbuf, meta)
+}
+
+pub async fn sys_mkdirat(dirfd: usize, path: VirtAddr, mode: usize) -> SysResult {
+    let path = read_path(path)?;
+    let start = start_dir(dirfd, &path)?;
+    let (dir, name) = resolve_parent(&start, &path).await?;
+    if name == "." || name == ".." {
+        return Err(Errno::EEXIST);
+    }
+    match dir.dentry.lookup(&name).await {
+        Ok(_) => return Err(Errno::EEXIST),
+        Err(Errno::ENOENT) => {}
+        Err(err) => return Err(err),
+    }
+    let inode = dir
+        .dentry
+        .inode()
+        .create(&name, InodeType::Dir, mode as u32 & 0o7777, 0)
+        .await?;
+    dir.dentry.insert(&name, inode);
+    Ok(0)
+}
+
+pub async fn sys_unlinkat(dirfd: usize, path: VirtAddr, flags: usize) -> SysResult {
+    let path = read_path(path)?;
+    let start = start_dir(dirfd, &path)?;
+    let (dir, name) = resolve_parent(&start, &path).await?;
+    let remove_dir = flags & AT_REMOVEDIR != 0;
+    match name.as_str() {
+        "." if remove_dir => return Err(Errno::EINVAL),
+        ".." if remove_dir => return Err(Errno::ENOTEMPTY),
+        "." | ".." => return Err(Errno::EISDIR),
+        _ => {}
+    }
+    let child = Path {
+        mount: dir.mount.clone(),
+        dentry: dir.dentry.lookup(&name).await?,
+    };
+    match child.inode_type() {
+        InodeType::Dir if !remove_dir => return Err(Errno::EISDIR),
+        InodeType::Dir => {}
+        _ if remove_dir || path.ends_with('/') => return Err(Errno::ENOTDIR),
+        _ => {}
+    }
+    if mounted_on(&child).is_some() {
+        return Err(Errno::EBUSY);
+    }
+    dir.dentry.inode().unlink(&name).await?;
+    dir.dentry.invalidate(&name);
+    Ok(0)
+}
+
+pub async fn sys_renameat2(
+    old_dirfd: usize,
+    old_path: VirtAddr,
+    new_dirfd: usize,
+    new_path: VirtAddr,
+    flags: usize,
+) -> SysResult {
+    let flags = RenameFlags::from_bits(flags as u32).ok_or(Errno::EINVAL)?;
+    if flags.contains(RenameFlags::NOREPLACE | RenameFlags::EXCHANGE) {
+        return Err(Errno::EINVAL);
+    }
+    let old_path = read_path(old_path)?;
+    let new_path = read_path(new_path)?;
+    let old_start = start_dir(old_dirfd, &old_path)?;
+    let new_start = start_dir(new_dirfd, &new_path)?;
+    let (old_dir, old_name) = resolve_parent(&old_start, &old_path).await?;
+    let (new_dir, new_name) = resolve_parent(&new_start, &new_path).await?;
+    for name in [&old_name, &new_name] {
+        if name == "." || name == ".." {
+            return Err(Errno::EBUSY);
+        }
+    }
+    if old_dir.mount.id() != new_dir.mount.id() {
+        return Err(Errno::EXDEV);
+    }
+    let old = Path {
+        mount: old_dir.mount.clone(),
+        dentry: old_dir.dentry.lookup(&old_name).await?,
+    };
+    if mounted_on(&old).is_some() {
+        return Err(Errno::EBUSY);
+    }
+    let new = new_dir.dentry.lookup(&new_name).await.ok();
+    if let Some(dentry) = &new {
+        let new = Path {
+            mount: new_dir.mount.clone(),
+            dentry: dentry.clone(),
+        };
+        if mounted_on(&new).is_some() {
+            return Err(Errno::EBUSY);
+        }
+    }
+    // a directory cannot be moved below itself
+    let mut ancestor = Some(new_dir.dentry.clone());
+    while let Some(dentry) = ancestor {
+        if Arc::ptr_eq(&dentry, &old.dentry) {
+            return Err(Errno::EINVAL);
+        }
+        ancestor = dentry.parent();
+    }
+    // nor replace or swap with a directory containing it
+    if let Some(new) = &new {
+        let mut ancestor = old.dentry.parent();
+        while let Some(dentry) = ancestor {
+            if Arc::ptr_eq(&dentry, new) {
+                return match flags.contains(RenameFlags::EXCHANGE) {
+                    true => Err(Errno::EINVAL),
+                    false => Err(Errno::ENOTEMPTY),
+                };
+            }
+            ancestor = dentry.parent();
+        }
+    }
+    old_dir
+        .dentry
+        .inode()
+        .rename(&old_name, new_dir.dentry.inode(), &new_name, flags)
+        .await?;
+    old_dir.dentry.rename(
+        &old_name,
+        &new_dir.dentry,
+        &new_name,
+        flags.contains(RenameFlags::EXCHANGE),
+    );
+    Ok(0)
+}
+
+/// The inode a descriptor refers to, for calls acting on it.
+fn fd_inode(fd: usize) -> Result<Arc<dyn Inode>, Errno> {
+    let path = file(fd)?.path().ok_or(Errno::EBADF)?;
+    Ok(path.dentry.inode().clone())
+}
+
+/// The inode `path` names for an `*at` call, or the one of `dirfd` if the
+/// path is empty and `AT_EMPTY_PATH` is given.
+async fn at_inode(dirfd: usize, path: &str, flags: usize) -> Result<Arc<dyn Inode>, Errno> {
+    if path.is_empty() && flags & AT_EMPTY_PATH != 0 {
+        return match dirfd as isize == AT_FDCWD {
+            true => Ok(cwd()?.dentry.inode().clone()),
+            false => fd_inode(dirfd),
+        };
+    }
+    let start = start_dir(dirfd, path)?;
+    let target = resolve(&start, path, flags & AT_SYMLINK_NOFOLLOW == 0).await?;
+    Ok(target.dentry.inode().clone())
+}
+
+pub async fn sys_fchmod(fd: usize, mode: usize) -> SysResult {
+    fd_inode(fd)?.set_mode(mode as u32).await?;
+    Ok(0)
+}
+
+pub async fn sys_fchmodat(dirfd: usize, path: VirtAddr, mode: usize, flags: usize) -> SysResult {
+    let path = read_path(path)?;
+    let inode = at_inode(dirfd, &path, flags).await?;
+    if inode.inode_type() == InodeType::Symlink {
+        return Err(Errno::EOPNOTSUPP);
+    }
+    inode.set_mode(mode as u32).await?;
+    Ok(0)
+}
+
+/// Sets the access and modification times of `path`, or of `dirfd` if the
+/// path is null. Null `times` sets both to the current time.
+pub async fn sys_utimensat(
+    dirfd: usize,
+    path: VirtAddr,
+    times: VirtAddr,
+    flags: usize,
+) -> SysResult {
+    let inode = match path.as_usize() {
+        0 => fd_inode(dirfd)?,
+        _ => at_inode(dirfd, &read_path(path)?, flags).await?,
+    };
+    let now = TimeSpec::now();
+    let times = match times.as_usize() {
+        0 => [now, now],
+        _ => current_space().read_user::<[TimeSpec; 2]>(times)?,
+    };
+    let [atime, mtime] = times.map(|time| match time.tv_nsec {
+        UTIME_NOW => Ok(Some(now)),
+        UTIME_OMIT => Ok(None),
+        0..1_000_000_000 => Ok(Some(time)),
+        _ => Err(Errno::EINVAL),
+    });
+    let (atime, mtime) = (atime?, mtime?);
+    if atime.is_some() || mtime.is_some() {
+        inode.set_times(atime, mtime).await?;
+    }
+    Ok(0)
+}
+
+pub async fn sys_chdir(path: VirtAddr) -> SysResult {
+    let path = read_path(path)?;
+    let target = resolve(&cwd()?, &path, true).await?;
+    if target.inode_type() != InodeType::Dir {
+        return Err(Errno::ENOTDIR);
+    }
+    with_current_task(|task| task.cwd = Some(target));
+    Ok(0)
+}
+
+/// Returns the length of the path including the nul, as Linux does.
+pub fn sys_getcwd(buf: VirtAddr, size: usize) -> SysResult {
+    let mut path = path_string(&cwd()?)?;
+    path.push('\0');
+    if path.len() > size {
+        return Err(Errno::ERANGE);
+    }
+    current_space().copy_to_user(buf, path.as_bytes())?;
+    Ok(path.len())
+}
+
+/// Mounts a filesystem of type `fstype` on `target`. `source` names the
+/// block device for filesystems stored on one; mount flags are ignored.
+pub async fn sys_mount(
+    source: VirtAddr,
+    target: VirtAddr,
+    fstype: VirtAddr,
+    _flags: usize,
+    data: VirtAddr,
+) -> SysResult {
+    let fstype = read_path(fstype)?;
+    let fs = filesystem(&fstype).ok_or(Errno::ENODEV)?;
+    let source = match source.as_usize() {
+        0 => String::new(),
+        _ => read_path(source)?,
+    };
+    let device = match fs.needs_device {
+        true => {
+            let name = source.strip_prefix("/dev/").unwrap_or(&source);
+            Some(block_device(name).ok_or(Errno::ENOENT)?)
+        }
+        false => None,
+    };
+    let data = match data.as_usize() {
+        0 => String::new(),
+        _ => {
+            let bytes = current_space()
+                .read_user_cstr(data, PAGE_SIZE_4K)?
+                .ok_or(Errno::EINVAL)?;
+            String::from_utf8(bytes).map_err(|_| Errno::EINVAL)?
+        }
+    };
+    let target = read_path(target)?;
+    let target = resolve(&cwd()?, &target, true).await?;
+    let sb = (fs.mount)(device, &data).await?;
+    mount_at(&target, sb, source, fs.name)?;
+    Ok(0)
+}
+
+pub async fn sys_umount2(target: VirtAddr, _flags: usize) -> SysResult {
+    let target = read_path(target)?;
+    let target = resolve(&cwd()?, &target, true).await?;
+    if !target.is_mount_root() {
+        return Err(Errno::EINVAL);
+    }
+    // the filesystem stays mounted if its data cannot be written back
+    target.mount.superblock().sync().await?;
+    let mount = unmount(&target)?;
+    mount.superblock().unmounted().await?;
+    Ok(0)
+}
@@ -0,0 +1,433 @@
+use core::{
+    future::poll_fn,
+    pin::Pin,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Poll, Waker},
+};
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use crate::{
+    Errno, SysResult,
+    hart::{current_space, with_current_task},
+    mem::{AddrSpace, PTEFlags, VirtAddr},
+    runtime::{TimeSpec, duration_to_ticks, now, sleep_until},
+    sync::{SpinMutex, WAITING, WOKEN, WaitNode},
+    task::find_task,
+};
+
+const FUTEX_WAIT: usize = 0;
+const FUTEX_WAKE: usize = 1;
+const FUTEX_REQUEUE: usize = 3;
+const FUTEX_CMP_REQUEUE: usize = 4;
+const FUTEX_LOCK_PI: usize = 6;
+const FUTEX_UNLOCK_PI: usize = 7;
+const FUTEX_TRYLOCK_PI: usize = 8;
+const FUTEX_WAIT_BITSET: usize = 9;
+const FUTEX_WAKE_BITSET: usize = 10;
+const FUTEX_LOCK_PI2: usize = 13;
+
+const FUTEX_PRIVATE_FLAG: usize = 128;
+const FUTEX_CLOCK_REALTIME: usize = 256;
+
+const FUTEX_BITSET_MATCH_ANY: u32 = u32::MAX;
+
+/// Bits of the futex word used by the PI protocol.
+const FUTEX_WAITERS: u32 = 0x8000_0000;
+const FUTEX_OWNER_DIED: u32 = 0x4000_0000;
+const FUTEX_TID_MASK: u32 = 0x3fff_ffff;
+
+/// Identifies a futex. Private futexes are only visible within one address
+/// space and keyed by virtual address, shared ones by physical address so
+/// that every mapping of the page finds the same waiters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum FutexKey {
+    Private { space: usize, addr: usize },
+    Shared { paddr: usize },
+}
+
+/// A task parked on a futex. `key` changes when it is requeued.
+struct FutexWaiter {
+    node: WaitNode,
+    key: SpinMutex<FutexKey>,
+    bitset: u32,
+    tid: usize,
+    /// The word of the PI futex waited for, which names its owner.
+    pi_word: Option<PiWord>,
+}
+
+/// Where the word of a PI futex lives in the address space of a waiter. It
+/// is resolved again whenever it is used, as the waiter may have exited and
+/// its frames been freed since it started waiting.
+struct PiWord {
+    space: Weak<AddrSpace>,
+    addr: VirtAddr,
+}
+
+impl PiWord {
+    fn new(addr: VirtAddr) -> Self {
+        Self {
+            space: Arc::downgrade(&current_space()),
+            addr,
+        }
+    }
+
+    /// Runs `f` with the word, unless its address space is gone or it is no
+    /// longer mapped writable.
+    fn with<R>(&self, f: impl FnOnce(&AtomicU32) -> R) -> Option<R> {
+        let space = self.space.upgrade()?;
+        let paddr = space.translate_user(self.addr, PTEFlags::W).ok()?;
+        // frames are freed with their space, which `space` keeps alive, and
+        // physical memory is identity-mapped in kernel space
+        Some(f(unsafe { &*(paddr.as_usize() as *const AtomicU32) }))
+    }
+}
+
+/// Parked waiters of every futex, in FIFO order.
+static FUTEX_TABLE: SpinMutex<BTreeMap<FutexKey, VecDeque<Arc<FutexWaiter>>>> =
+    SpinMutex::new(BTreeMap::new());
+
+/// Resolves `uaddr` of the current task to its key and the futex word.
+fn futex_word(uaddr: VirtAddr, private: bool) -> Result<(FutexKey, &'static AtomicU32), Errno> {
+    if uaddr.as_usize() % 4 != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let space = current_space();
+    let paddr = space.translate_user(uaddr, PTEFlags::R)?.as_usize();
+    let key = if private {
+        FutexKey::Private {
+            space: space.id(),
+            addr: uaddr.as_usize(),
+        }
+    } else {
+        FutexKey::Shared { paddr }
+    };
+    // physical memory is identity-mapped in kernel space
+    Ok((key, unsafe { &*(paddr as *const AtomicU32) }))
+}
+
+/// Converts the user timeout into an absolute deadline in ticks.
+fn deadline(timeout: VirtAddr, absolute: bool) -> Result<Option<u64>, Errno> {
+    if timeout.as_usize() == 0 {
+        return Ok(None);
+    }
+    let ts: TimeSpec = current_space().read_user(timeout)?;
+    let ticks = duration_to_ticks(ts.to_duration().ok_or(Errno::EINVAL)?);
+    // the realtime clock starts at boot as well
+    Ok(Some(if absolute { ticks } else { now() + ticks }))
+}
+
+fn enqueue(table: &mut BTreeMap<FutexKey, VecDeque<Arc<FutexWaiter>>>, waiter: Arc<FutexWaiter>) {
+    let key = *waiter.key.lock();
+    table.entry(key).or_default().push_back(waiter);
+}
+
+fn dequeue(
+    table: &mut BTreeMap<FutexKey, VecDeque<Arc<FutexWaiter>>>,
+    key: FutexKey,
+) -> Option<Arc<FutexWaiter>> {
+    let queue = table.get_mut(&key)?;
+    let waiter = queue.pop_front();
+    if queue.is_empty() {
+        table.remove(&key);
+    }
+    waiter
+}
+
+/// Removes `waiter` from the table unless it was woken already. Returns
+/// whether it was still waiting.
+fn cancel(waiter: &Arc<FutexWaiter>) -> bool {
+    let mut table = FUTEX_TABLE.lock();
+    if waiter.node.state() != WAITING {
+        return false;
+    }
+    let key = *waiter.key.lock();
+    if let Some(queue) = table.get_mut(&key) {
+        queue.retain(|w| !Arc::ptr_eq(w, waiter));
+        if queue.is_empty() {
+            table.remove(&key);
+        }
+    }
+    true
+}
+
+/// Parks until `waiter` is woken, the deadline passes or a signal arrives.
+/// The waiter must already be in the table.
+async fn wait(waiter: Arc<FutexWaiter>, deadline: Option<u64>) -> SysResult {
+    let mut sleep = deadline.map(sleep_until);
+    poll_fn(|cx| {
+        if waiter.node.register(cx.waker()) != WAITING {
+            return Poll::Ready(Ok(0));
+        }
+        let timed_out = sleep
+            .as_mut()
+            .is_some_and(|sleep| Pin::new(sleep).poll(cx).is_ready());
+        let interrupted = with_current_task(|task| task.has_pending_signal());
+        if (timed_out || interrupted) && cancel(&waiter) {
+            let errno = if interrupted {
+                Errno::EINTR
+            } else {
+                Errno::ETIMEDOUT
+            };
+            return Poll::Ready(Err(errno));
+        }
+        if waiter.node.state() != WAITING {
+            return Poll::Ready(Ok(0));
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+fn new_waiter(key: FutexKey, bitset: u32, pi_word: Option<PiWord>) -> Arc<FutexWaiter> {
+    let (waker, tid) = with_current_task(|task| (Waker::from(task.waker().clone()), task.tid()));
+    Arc::new(FutexWaiter {
+        node: WaitNode::new(&waker),
+        key: SpinMutex::new(key),
+        bitset,
+        tid,
+        pi_word,
+    })
+}
+
+async fn futex_wait(
+    uaddr: VirtAddr,
+    private: bool,
+    val: u32,
+    deadline: Option<u64>,
+    bitset: u32,
+) -> SysResult {
+    if bitset == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let (key, word) = futex_word(uaddr, private)?;
+    let waiter = new_waiter(key, bitset, None);
+    {
+        // checking the value under the table lock orders us against wakers
+        let mut table = FUTEX_TABLE.lock();
+        if word.load(Ordering::SeqCst) != val {
+            return Err(Errno::EAGAIN);
+        }
+        enqueue(&mut table, waiter.clone());
+    }
+    wait(waiter, deadline).await
+}
+
+fn futex_wake(uaddr: VirtAddr, private: bool, n: usize, bitset: u32) -> SysResult {
+    if bitset == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let (key, _) = futex_word(uaddr, private)?;
+    let mut table = FUTEX_TABLE.lock();
+    let Some(queue) = table.get_mut(&key) else {
+        return Ok(0);
+    };
+    let mut woken = 0;
+    queue.retain(|waiter| {
+        if woken < n && waiter.bitset & bitset != 0 {
+            waiter.node.wake(WOKEN);
+            woken += 1;
+            false
+        } else {
+            true
+        }
+    });
+    if queue.is_empty() {
+        table.remove(&key);
+    }
+    Ok(woken)
+}
+
+/// Wakes `n_wake` waiters of `uaddr` and moves up to `n_requeue` of the
+/// rest to `uaddr2`. With `expected`, fails unless `*uaddr` still holds it.
+fn futex_requeue(
+    uaddr: VirtAddr,
+    private: bool,
+    n_wake: usize,
+    n_requeue: usize,
+    uaddr2: VirtAddr,
+    expected: Option<u32>,
+) -> SysResult {
+    let (key, word) = futex_word(uaddr, private)?;
+    let (key2, _) = futex_word(uaddr2, private)?;
+    let mut table = FUTEX_TABLE.lock();
+    if expected.is_some_and(|val| word.load(Ordering::SeqCst) != val) {
+        return Err(Errno::EAGAIN);
+    }
+    let mut woken = 0;
+    while woken < n_wake {
+        let Some(waiter) = dequeue(&mut table, key) else {
+            break;
+        };
+        waiter.node.wake(WOKEN);
+        woken += 1;
+    }
+    let mut requeued = 0;
+    if key != key2 {
+        while requeued < n_requeue {
+            let Some(waiter) = dequeue(&mut table, key) else {
+                break;
+            };
+            *waiter.key.lock() = key2;
+            enqueue(&mut table, waiter);
+            requeued += 1;
+        }
+    }
+    Ok(if expected.is_some() {
+        woken + requeued
+    } else {
+        woken
+    })
+}
+
+/// Takes the PI futex at `uaddr` for the current task, whose tid is stored
+/// in the word. When it is contended, `FUTEX_WAITERS` is set and the task
+/// waits until [`futex_unlock_pi`] or the exit of the owner hands the lock
+/// over. Fails with `ESRCH` if the owner does not exist.
+///
+/// Priority inheritance proper is not implemented yet: the owner keeps its
+/// own scheduling parameters while higher-priority tasks wait.
+async fn futex_lock_pi(
+    uaddr: VirtAddr,
+    private: bool,
+    deadline: Option<u64>,
+    try_only: bool,
+) -> SysResult {
+    let (key, word) = futex_word(uaddr, private)?;
+    let tid = with_current_task(|task| task.tid()) as u32;
+    let waiter = loop {
+        let val = word.load(Ordering::SeqCst);
+        let owner = val & FUTEX_TID_MASK;
+        if owner == 0 {
+            let new = tid | (val & FUTEX_WAITERS);
+            if word
+                .compare_exchange(val, new, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                return Ok(0);
+            }
+            continue;
+        }
+        if owner == tid {
+            return Err(Errno::EDEADLK);
+        }
+        if find_task(owner as usize).is_none() {
+            return Err(Errno::ESRCH);
+        }
+        if try_only {
+            return Err(Errno::EAGAIN);
+        }
+        let mut table = FUTEX_TABLE.lock();
+        if word
+            .compare_exchange(val, val | FUTEX_WAITERS, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            continue;
+        }
+        let waiter = new_waiter(key, FUTEX_BITSET_MATCH_ANY, Some(PiWord::new(uaddr)));
+        enqueue(&mut table, waiter.clone());
+        break waiter;
+    };
+    wait(waiter, deadline).await
+}
+
+/// Releases the PI futex at `uaddr`, handing it to the first waiter.
+fn futex_unlock_pi(uaddr: VirtAddr, private: bool) -> SysResult {
+    let (key, word) = futex_word(uaddr, private)?;
+    let tid = with_current_task(|task| task.tid()) as u32;
+    let mut table = FUTEX_TABLE.lock();
+    let val = word.load(Ordering::SeqCst);
+    if val & FUTEX_TID_MASK != tid {
+        return Err(Errno::EPERM);
+    }
+    hand_over(&mut table, key, word, 0);
+    Ok(0)
+}
+
+/// Passes the PI futex `key` with `word` to its first waiter, or frees it
+/// if there is none. `died` is `FUTEX_OWNER_DIED` if the owner exited
+/// without releasing it, else 0.
+fn hand_over(
+    table: &mut BTreeMap<FutexKey, VecDeque<Arc<FutexWaiter>>>,
+    key: FutexKey,
+    word: &AtomicU32,
+    died: u32,
+) {
+    match dequeue(table, key) {
+        Some(waiter) => {
+            let more = table.contains_key(&key);
+            let new = waiter.tid as u32 | if more { FUTEX_WAITERS } else { 0 };
+            word.store(new | died, Ordering::SeqCst);
+            waiter.node.wake(WOKEN);
+        }
+        None => word.store(died, Ordering::SeqCst),
+    }
+}
+
+/// Hands the PI futexes task `tid` still holds to their waiters with
+/// `FUTEX_OWNER_DIED` set, as the task is exiting and would never release
+/// them.
+pub fn exit_pi_futexes(tid: usize) {
+    let mut table = FUTEX_TABLE.lock();
+    let owned = |word: &AtomicU32| word.load(Ordering::SeqCst) & FUTEX_TID_MASK == tid as u32;
+    // the word of a shared futex is reached through any waiter still alive
+    let held: Vec<(FutexKey, Arc<FutexWaiter>)> = table
+        .iter()
+        .filter_map(|(key, queue)| {
+            let waiter = queue.iter().find(|waiter| {
+                let word = waiter.pi_word.as_ref();
+                word.and_then(|word| word.with(owned))
+                    .is_some_and(|owned| owned)
+            })?;
+            Some((*key, waiter.clone()))
+        })
+        .collect();
+    for (key, waiter) in held {
+        let word = waiter.pi_word.as_ref().unwrap();
+        word.with(|word| hand_over(&mut table, key, word, FUTEX_OWNER_DIED));
+    }
+}
+
+pub async fn sys_futex(
+    uaddr: VirtAddr,
+    op: usize,
+    val: usize,
+    timeout: VirtAddr,
+    uaddr2: VirtAddr,
+    val3: usize,
+) -> SysResult {
+    let private = op & FUTEX_PRIVATE_FLAG != 0;
+    let realtime = op & FUTEX_CLOCK_REALTIME != 0;
+    let cmd = op & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME);
+    // only these take a timeout measured against the realtime clock
+    if realtime && !matches!(cmd, FUTEX_WAIT_BITSET | FUTEX_LOCK_PI2) {
+        return Err(Errno::ENOSYS);
+    }
+    // `timeout` doubles as the second count for requeue operations
+    let val2 = timeout.as_usize();
+    match cmd {
+        FUTEX_WAIT => {
+            let deadline = deadline(timeout, false)?;
+            futex_wait(uaddr, private, val as u32, deadline, FUTEX_BITSET_MATCH_ANY).await
+        }
+        FUTEX_WAIT_BITSET => {
+            let deadline = deadline(timeout, true)?;
+            futex_wait(uaddr, private, val as u32, deadline, val3 as u32).await
+        }
+        FUTEX_WAKE => futex_wake(uaddr, private, val, FUTEX_BITSET_MATCH_ANY),
+        FUTEX_WAKE_BITSET => futex_wake(uaddr, private, val, val3 as u32),
+        FUTEX_REQUEUE => futex_requeue(uaddr, private, val, val2, uaddr2, None),
+        FUTEX_CMP_REQUEUE => futex_requeue(uaddr, private, val, val2, uaddr2, Some(val3 as u32)),
+        FUTEX_LOCK_PI | FUTEX_LOCK_PI2 => {
+            let deadline = deadline(timeout, true)?;
+            futex_lock_pi(uaddr, private, deadline, false).await
+        }
+        FUTEX_TRYLOCK_PI => futex_lock_pi(uaddr, private, None, true).await,
+        FUTEX_UNLOCK_PI => futex_unlock_pi(uaddr, private),
+        _ => Err(Errno::ENOSYS),
+    }
+}
@@ -0,0 +1,154 @@
+mod fs;
+mod futex;
+mod process;
+mod sched;
+mod signal;
+
+pub use fs::*;
+pub use futex::*;
+pub use process::*;
+pub use sched::*;
+pub use signal::*;
+
+use log::warn;
+
+use crate::{Errno, SysResult};
+
+pub const SYSCALL_GETCWD: usize = 17;
+pub const SYSCALL_DUP: usize = 23;
+pub const SYSCALL_DUP3: usize = 24;
+pub const SYSCALL_FCNTL: usize = 25;
+pub const SYSCALL_IOCTL: usize = 29;
+pub const SYSCALL_MKDIRAT: usize = 34;
+pub const SYSCALL_UNLINKAT: usize = 35;
+pub const SYSCALL_UMOUNT2: usize = 39;
+pub const SYSCALL_MOUNT: usize = 40;
+pub const SYSCALL_CHDIR: usize = 49;
+pub const SYSCALL_FCHMOD: usize = 52;
+pub const SYSCALL_FCHMODAT: usize = 53;
+pub const SYSCALL_OPENAT: usize = 56;
+pub const SYSCALL_CLOSE: usize = 57;
+pub const SYSCALL_PIPE2: usize = 59;
+pub const SYSCALL_GETDENTS64: usize = 61;
+pub const SYSCALL_LSEEK: usize = 62;
+pub const SYSCALL_READ: usize = 63;
+pub const SYSCALL_WRITE: usize = 64;
+pub const SYSCALL_NEWFSTATAT: usize = 79;
+pub const SYSCALL_FSTAT: usize = 80;
+pub const SYSCALL_UTIMENSAT: usize = 88;
+pub const SYSCALL_FUTEX: usize = 98;
+pub const SYSCALL_SCHED_SETPARAM: usize = 118;
+pub const SYSCALL_SCHED_SETSCHEDULER: usize = 119;
+pub const SYSCALL_SCHED_GETSCHEDULER: usize = 120;
+pub const SYSCALL_SCHED_GETPARAM: usize = 121;
+pub const SYSCALL_SCHED_SETAFFINITY: usize = 122;
+pub const SYSCALL_SCHED_GETAFFINITY: usize = 123;
+pub const SYSCALL_SCHED_YIELD: usize = 124;
+pub const SYSCALL_SCHED_GET_PRIORITY_MAX: usize = 125;
+pub const SYSCALL_SCHED_GET_PRIORITY_MIN: usize = 126;
+pub const SYSCALL_KILL: usize = 129;
+pub const SYSCALL_TKILL: usize = 130;
+pub const SYSCALL_TGKILL: usize = 131;
+pub const SYSCALL_SETPRIORITY: usize = 140;
+pub const SYSCALL_GETPRIORITY: usize = 141;
+pub const SYSCALL_SETPGID: usize = 154;
+pub const SYSCALL_GETPGID: usize = 155;
+pub const SYSCALL_GETRLIMIT: usize = 163;
+pub const SYSCALL_SETRLIMIT: usize = 164;
+pub const SYSCALL_PRLIMIT64: usize = 261;
+pub const SYSCALL_CLONE: usize = 220;
+pub const SYSCALL_EXECVE: usize = 221;
+pub const SYSCALL_RENAMEAT2: usize = 276;
+
+/// Handles system call `id` of the current task. The returned future is kept
+/// in the task and polled again whenever it is woken.
+pub async fn syscall(id: usize, args: [usize; 6]) -> SysResult {
+    match id {
+        SYSCALL_GETCWD => sys_getcwd(args[0].into(), args[1]),
+        SYSCALL_DUP => sys_dup(args[0]),
+        SYSCALL_DUP3 => sys_dup3(args[0], args[1], args[2]),
+        SYSCALL_FCNTL => sys_fcntl(args[0], args[1], args[2]),
+        SYSCALL_IOCTL => sys_ioctl(args[0], args[1], args[2].into()),
+        SYSCALL_MKDIRAT => sys_mkdirat(args[0], args[1].into(), args[2]).await,
+        SYSCALL_UNLINKAT => sys_unlinkat(args[0], args[1].into(), args[2]).await,
+        SYSCALL_UMOUNT2 => sys_umount2(args[0].into(), args[1]).await,
+        SYSCALL_MOUNT => {
+            sys_mount(
+                args[0].into(),
+                args[1].into(),
+                args[2].into(),
+                args[3],
+                args[4].into(),
+            )
+            .await
+        }
+        SYSCALL_CHDIR => sys_chdir(args[0].into()).await,
+        SYSCALL_FCHMOD => sys_fchmod(args[0], args[1]).await,
+        SYSCALL_FCHMODAT => sys_fchmodat(args[0], args[1].into(), args[2], args[3]).await,
+        SYSCALL_OPENAT => sys_openat(args[0], args[1].into(), args[2], args[3]).await,
+        SYSCALL_CLOSE => sys_close(args[0]),
+        SYSCALL_PIPE2 => sys_pipe2(args[0].into(), args[1]),
+        SYSCALL_GETDENTS64 => sys_getdents64(args[0], args[1].into(), args[2]).await,
+        SYSCALL_LSEEK => sys_lseek(args[0], args[1] as isize, args[2]).await,
+        SYSCALL_READ => sys_read(args[0], args[1].into(), args[2]).await,
+        SYSCALL_WRITE => sys_write(args[0], args[1].into(), args[2]).await,
+        SYSCALL_NEWFSTATAT => {
+            sys_newfstatat(args[0], args[1].into(), args[2].into(), args[3]).await
+        }
+        SYSCALL_FSTAT => sys_fstat(args[0], args[1].into()).await,
+        SYSCALL_UTIMENSAT => sys_utimensat(args[0], args[1].into(), args[2].into(), args[3]).await,
+        SYSCALL_FUTEX => {
+            sys_futex(
+                args[0].into(),
+                args[1],
+                args[2],
+                args[3].into(),
+                args[4].into(),
+                args[5],
+            )
+            .await
+        }
+        SYSCALL_SCHED_SETPARAM => sys_sched_setparam(args[0], args[1].into()),
+        SYSCALL_SCHED_SETSCHEDULER => sys_sched_setscheduler(args[0], args[1], args[2].into()),
+        SYSCALL_SCHED_GETSCHEDULER => sys_sched_getscheduler(args[0]),
+        SYSCALL_SCHED_GETPARAM => sys_sched_getparam(args[0], args[1].into()),
+        SYSCALL_SCHED_SETAFFINITY => sys_sched_setaffinity(args[0], args[1], args[2].into()),
+        SYSCALL_SCHED_GETAFFINITY => sys_sched_getaffinity(args[0], args[1], args[2].into()),
+        SYSCALL_SCHED_YIELD => sys_sched_yield().await,
+        SYSCALL_SCHED_GET_PRIORITY_MAX => sys_sched_get_priority_max(args[0]),
+        SYSCALL_SCHED_GET_PRIORITY_MIN => sys_sched_get_priority_min(args[0]),
+        SYSCALL_KILL => sys_kill(args[0], args[1]),
+        SYSCALL_TKILL => sys_tkill(args[0], args[1]),
+        SYSCALL_TGKILL => sys_tgkill(args[0], args[1], args[2]),
+        SYSCALL_SETPRIORITY => sys_setpriority(args[0], args[1], args[2] as isize),
+        SYSCALL_GETPRIORITY => sys_getpriority(args[0], args[1]),
+        SYSCALL_SETPGID => sys_setpgid(args[0], args[1]),
+        SYSCALL_GETPGID => sys_getpgid(args[0]),
+        SYSCALL_GETRLIMIT => sys_getrlimit(args[0], args[1].into()),
+        SYSCALL_SETRLIMIT => sys_setrlimit(args[0], args[1].into()),
+        SYSCALL_PRLIMIT64 => sys_prlimit64(args[0], args[1], args[2].into(), args[3].into()),
+        SYSCALL_CLONE => sys_clone(
+            args[0],
+            args[1].into(),
+            args[2].into(),
+            args[3],
+            args[4].into(),
+        ),
+        SYSCALL_EXECVE => sys_execve(args[0].into(), args[1].into(), args[2].into()).await,
+        SYSCALL_RENAMEAT2 => {
+            sys_renameat2(args[0], args[1].into(), args[2], args[3].into(), args[4]).await
+        }
+        _ => {
+            warn!("unsupported syscall {}", id);
+            Err(Errno::ENOSYS)
+        }
+    }
+}
+
+/// Encodes a syscall result into the value returned in `a0`.
+pub fn syscall_ret(result: SysResult) -> usize {
+    match result {
+        Ok(ret) => ret,
+        Err(errno) => -(errno as isize) as usize,
+    }
+}
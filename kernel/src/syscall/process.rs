@@ -0,0 +1,147 @@
+use alloc::{boxed::Box, vec::Vec};
+
+use crate::{
+    Errno, SysResult,
+    fs::resolve,
+    hart::{current_space, with_current_task},
+    mem::VirtAddr,
+    runtime::EXECUTOR,
+    task::{ARG_MAX, CLONE_FILES, CLONE_VM, RLimit, find_task, load_program},
+};
+
+use super::{AT_FDCWD, read_path, start_dir};
+
+/// Low byte of the `clone` flags: the signal sent to the parent on exit.
+const CSIGNAL: usize = 0xff;
+const CLONE_PARENT_SETTID: usize = 0x0010_0000;
+const CLONE_CHILD_SETTID: usize = 0x0100_0000;
+
+/// Creates a task running on like the caller, sharing what `flags` ask for.
+/// The new task gets 0 and the caller its tid. A new `stack` is only needed
+/// for tasks sharing the address space.
+pub fn sys_clone(
+    flags: usize,
+    stack: VirtAddr,
+    parent_tid: VirtAddr,
+    _tls: usize,
+    child_tid: VirtAddr,
+) -> SysResult {
+    let supported = CSIGNAL | CLONE_VM | CLONE_FILES | CLONE_PARENT_SETTID | CLONE_CHILD_SETTID;
+    if flags & !supported != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let task = with_current_task(|task| task.clone_task(flags))?;
+    let tid = task.tid();
+    let cx = task.trap_context_mut();
+    cx.user_x[10] = 0;
+    if stack.as_usize() != 0 {
+        cx.user_x[2] = stack.as_usize();
+    }
+    if flags & CLONE_PARENT_SETTID != 0 {
+        current_space().write_user(parent_tid, &(tid as u32))?;
+    }
+    if flags & CLONE_CHILD_SETTID != 0 {
+        task.space().write_user(child_tid, &(tid as u32))?;
+    }
+    EXECUTOR
+        .get()
+        .expect("executor initialized")
+        .add(Box::new(task));
+    Ok(tid)
+}
+
+/// Reads the nul-terminated array of strings at `array`, e.g. `argv`.
+/// Their total length is limited by [`ARG_MAX`].
+fn read_strings(array: VirtAddr) -> Result<Vec<Vec<u8>>, Errno> {
+    let space = current_space();
+    let mut strings = Vec::new();
+    let mut total = 0;
+    if array.as_usize() == 0 {
+        return Ok(strings);
+    }
+    loop {
+        let ptr: usize = space.read_user(array + strings.len() * size_of::<usize>())?;
+        if ptr == 0 {
+            return Ok(strings);
+        }
+        let string = space
+            .read_user_cstr(ptr.into(), ARG_MAX - total)?
+            .ok_or(Errno::E2BIG)?;
+        total += string.len() + 1;
+        strings.push(string);
+    }
+}
+
+/// Runs the program at `path` in place of the caller's. Descriptors marked
+/// close-on-exec are closed.
+pub async fn sys_execve(path: VirtAddr, argv: VirtAddr, envp: VirtAddr) -> SysResult {
+    let path = read_path(path)?;
+    let args = read_strings(argv)?;
+    let envs = read_strings(envp)?;
+    let start = start_dir(AT_FDCWD as usize, &path)?;
+    let target = resolve(&start, &path, true).await?;
+    let (space, trap_context) = load_program(&target, &args, &envs).await?;
+    with_current_task(|task| task.exec(space, trap_context, &args));
+    Ok(0)
+}
+
+/// Moves task `pid` (the caller if 0) into process group `pgid`, which is
+/// `pid` itself if 0.
+pub fn sys_setpgid(pid: usize, pgid: usize) -> SysResult {
+    let task = match pid {
+        0 => with_current_task(|task| task.shared().clone()),
+        pid => find_task(pid).ok_or(Errno::ESRCH)?,
+    };
+    let pgid = match pgid {
+        0 => task.tid(),
+        pgid => pgid,
+    };
+    task.set_pgid(pgid);
+    Ok(0)
+}
+
+pub fn sys_getpgid(pid: usize) -> SysResult {
+    match pid {
+        0 => Ok(with_current_task(|task| task.shared().pgid())),
+        pid => Ok(find_task(pid).ok_or(Errno::ESRCH)?.pgid()),
+    }
+}
+
+/// Gets and optionally sets resource limit `resource` of task `pid` (the
+/// caller if 0). Null pointers skip either part.
+pub fn sys_prlimit64(
+    pid: usize,
+    resource: usize,
+    new_limit: VirtAddr,
+    old_limit: VirtAddr,
+) -> SysResult {
+    let task = match pid {
+        0 => with_current_task(|task| task.shared().clone()),
+        pid => find_task(pid).ok_or(Errno::ESRCH)?,
+    };
+    let space = current_space();
+    let new_limit = match new_limit.as_usize() {
+        0 => None,
+        _ => Some(space.read_user::<RLimit>(new_limit)?),
+    };
+    let old = {
+        let mut limits = task.limits.lock();
+        let old = limits.get(resource).ok_or(Errno::EINVAL)?;
+        if let Some(limit) = new_limit {
+            limits.set(resource, limit)?;
+        }
+        old
+    };
+    if old_limit.as_usize() != 0 {
+        space.write_user(old_limit, &old)?;
+    }
+    Ok(0)
+}
+
+pub fn sys_getrlimit(resource: usize, limit: VirtAddr) -> SysResult {
+    sys_prlimit64(0, resource, VirtAddr::from(0), limit)
+}
+
+pub fn sys_setrlimit(resource: usize, limit: VirtAddr) -> SysResult {
+    sys_prlimit64(0, resource, limit, VirtAddr::from(0))
+}
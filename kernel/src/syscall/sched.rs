@@ -0,0 +1,137 @@
+use core::mem::size_of;
+
+use alloc::sync::Arc;
+
+use crate::{
+    Errno, SysResult,
+    hart::{CpuMask, current_space, with_current_task},
+    mem::VirtAddr,
+    runtime::{
+        EXECUTOR, MAX_NICE, MAX_RT_PRIORITY, MIN_NICE, MIN_RT_PRIORITY, SchedClass, SchedPolicy,
+        yield_now,
+    },
+    task::{TaskShared, find_task},
+};
+
+/// `sched_setscheduler` flag, accepted and ignored.
+const SCHED_RESET_ON_FORK: usize = 0x4000_0000;
+
+const PRIO_PROCESS: usize = 0;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SchedParam {
+    sched_priority: i32,
+}
+
+/// The task `pid` names: the caller for 0, else the task with that tid.
+fn target(pid: usize) -> Result<Arc<TaskShared>, Errno> {
+    match pid {
+        0 => Ok(with_current_task(|task| task.shared().clone())),
+        _ => find_task(pid).ok_or(Errno::ESRCH),
+    }
+}
+
+fn read_priority(param: VirtAddr) -> Result<u8, Errno> {
+    let param: SchedParam = current_space().read_user(param)?;
+    u8::try_from(param.sched_priority).map_err(|_| Errno::EINVAL)
+}
+
+/// Sets policy and priority of the target, keeping its policy if `policy`
+/// is `None`.
+fn set_policy(pid: usize, policy: Option<SchedPolicy>, param: VirtAddr) -> SysResult {
+    let priority = read_priority(param)?;
+    target(pid)?.update_sched_params(|params| {
+        match params.set_policy(policy.unwrap_or(params.policy()), priority) {
+            true => Ok(0),
+            false => Err(Errno::EINVAL),
+        }
+    })
+}
+
+pub fn sys_sched_setscheduler(pid: usize, policy: usize, param: VirtAddr) -> SysResult {
+    let policy = SchedPolicy::from_raw(policy & !SCHED_RESET_ON_FORK).ok_or(Errno::EINVAL)?;
+    set_policy(pid, Some(policy), param)
+}
+
+pub fn sys_sched_getscheduler(pid: usize) -> SysResult {
+    Ok(target(pid)?.sched_params().policy() as usize)
+}
+
+pub fn sys_sched_setparam(pid: usize, param: VirtAddr) -> SysResult {
+    set_policy(pid, None, param)
+}
+
+pub fn sys_sched_getparam(pid: usize, param: VirtAddr) -> SysResult {
+    let value = SchedParam {
+        sched_priority: target(pid)?.sched_params().rt_priority() as i32,
+    };
+    current_space().write_user(param, &value)?;
+    Ok(0)
+}
+
+pub async fn sys_sched_yield() -> SysResult {
+    yield_now().await;
+    Ok(0)
+}
+
+pub fn sys_sched_get_priority_max(policy: usize) -> SysResult {
+    match SchedPolicy::from_raw(policy).ok_or(Errno::EINVAL)?.class() {
+        SchedClass::RealTime => Ok(MAX_RT_PRIORITY as usize),
+        _ => Ok(0),
+    }
+}
+
+pub fn sys_sched_get_priority_min(policy: usize) -> SysResult {
+    match SchedPolicy::from_raw(policy).ok_or(Errno::EINVAL)?.class() {
+        SchedClass::RealTime => Ok(MIN_RT_PRIORITY as usize),
+        _ => Ok(0),
+    }
+}
+
+/// `setpriority(PRIO_PROCESS, pid, nice)`, which is what libc's `nice()`
+/// is built on.
+pub fn sys_setpriority(which: usize, who: usize, nice: isize) -> SysResult {
+    if which != PRIO_PROCESS {
+        return Err(Errno::EINVAL);
+    }
+    target(who)?.update_sched_params(|params| {
+        params.set_nice(nice.clamp(MIN_NICE as isize, MAX_NICE as isize) as i8);
+        Ok(0)
+    })
+}
+
+/// Returns `20 - nice` like the Linux syscall, so that the result is never
+/// negative.
+pub fn sys_getpriority(which: usize, who: usize) -> SysResult {
+    if which != PRIO_PROCESS {
+        return Err(Errno::EINVAL);
+    }
+    Ok((20 - target(who)?.sched_params().nice() as isize) as usize)
+}
+
+pub fn sys_sched_setaffinity(pid: usize, len: usize, mask: VirtAddr) -> SysResult {
+    let task = target(pid)?;
+    let mut bits = [0u8; size_of::<usize>()];
+    let len = len.min(bits.len());
+    current_space().copy_from_user(mask, &mut bits[..len])?;
+    let mask = CpuMask::from_bits(usize::from_le_bytes(bits));
+    let online = EXECUTOR.get().expect("executor initialized").online();
+    if mask.intersection(online).is_empty() {
+        return Err(Errno::EINVAL);
+    }
+    task.update_sched_params(|params| {
+        params.set_affinity(mask);
+        Ok(0)
+    })
+}
+
+/// Returns the number of bytes written, like the Linux syscall.
+pub fn sys_sched_getaffinity(pid: usize, len: usize, mask: VirtAddr) -> SysResult {
+    if len < size_of::<usize>() {
+        return Err(Errno::EINVAL);
+    }
+    let affinity = target(pid)?.sched_params().affinity();
+    current_space().write_user(mask, &affinity.bits())?;
+    Ok(size_of::<usize>())
+}
@@ -0,0 +1,27 @@
+use crate::{
+    SysResult,
+    hart::with_current_task,
+    task::{send_group_signal, send_signal},
+};
+
+/// There are no processes yet, so a positive `pid` names a single task. 0
+/// targets the process group of the caller and `-pgid` another group.
+pub fn sys_kill(pid: usize, sig: usize) -> SysResult {
+    match pid as isize {
+        0 => send_group_signal(with_current_task(|task| task.shared().pgid()), sig)?,
+        pid if pid < 0 => send_group_signal(pid.unsigned_abs(), sig)?,
+        _ => send_signal(pid, sig)?,
+    }
+    Ok(0)
+}
+
+pub fn sys_tkill(tid: usize, sig: usize) -> SysResult {
+    send_signal(tid, sig)?;
+    Ok(0)
+}
+
+/// `tgid` is ignored until tasks are grouped into processes.
+pub fn sys_tgkill(_tgid: usize, tid: usize, sig: usize) -> SysResult {
+    send_signal(tid, sig)?;
+    Ok(0)
+}
@@ -0,0 +1,59 @@
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+use log::{info, warn};
+
+use crate::{
+    Errno,
+    dtb::MACHINE_META,
+    fs::{ROOT_MOUNTED, resolve, root_path},
+    runtime::{EXECUTOR, spawn},
+};
+
+use super::{Task, load_program};
+
+/// Programs tried as the first user task if `init=` names none, as in
+/// Linux.
+const INIT_PROGRAMS: [&str; 4] = ["/sbin/init", "/etc/init", "/bin/init", "/bin/sh"];
+
+/// Starts the first user task once the root filesystem is mounted: the
+/// program `init=` on the command line names, or else the first of
+/// [`INIT_PROGRAMS`] found.
+pub fn spawn_init() {
+    let chosen = MACHINE_META
+        .get()
+        .and_then(|meta| meta.bootarg("init"))
+        .map(String::from);
+    spawn(async move {
+        ROOT_MOUNTED.wait().await;
+        let programs: Vec<&str> = match &chosen {
+            Some(program) => vec![program],
+            None => INIT_PROGRAMS.to_vec(),
+        };
+        for program in programs {
+            match start_init(program).await {
+                Ok(tid) => {
+                    info!("init: started {} as task {}", program, tid);
+                    #[cfg(feature = "selftest")]
+                    crate::fs::procfs::stat_test(tid).await;
+                    return;
+                }
+                Err(Errno::ENOENT) => {}
+                Err(err) => warn!("init: cannot run {}: {:?}", program, err),
+            }
+        }
+        warn!("init: no program to run");
+    })
+    .detach();
+}
+
+async fn start_init(program: &str) -> Result<usize, Errno> {
+    let path = resolve(&root_path()?, program, true).await?;
+    let args = [Vec::from(program.as_bytes())];
+    let (space, trap_context) = load_program(&path, &args, &[]).await?;
+    let task = Task::new_init(space, trap_context, &args);
+    let tid = task.tid();
+    EXECUTOR
+        .get()
+        .expect("executor initialized")
+        .add(Box::new(task));
+    Ok(tid)
+}
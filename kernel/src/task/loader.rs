@@ -0,0 +1,198 @@
+use alloc::{vec, vec::Vec};
+
+use crate::{
+    Errno,
+    config::{PAGE_SIZE_4K, USER_PIE_BASE, USER_STACK_SIZE, USER_STACK_TOP},
+    fs::{InodeType, Path},
+    mem::{AddrSpace, AreaType, PTEFlags, VirtAddr},
+    runtime::now,
+    trap::TrapContext,
+};
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_RISCV: u16 = 243;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+/// Program headers read at most, as the headers are read in one go.
+const MAX_PHNUM: usize = 64;
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_BASE: usize = 7;
+const AT_ENTRY: usize = 9;
+const AT_RANDOM: usize = 25;
+
+/// Longest total of arguments and environment strings, as `ARG_MAX`.
+pub const ARG_MAX: usize = 128 * 1024;
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> usize {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap()) as usize
+}
+
+/// Reads exactly `buf.len()` bytes of `path` at `offset`.
+async fn read_exact(path: &Path, offset: usize, buf: &mut [u8]) -> Result<(), Errno> {
+    let inode = path.dentry.inode();
+    let mut done = 0;
+    while done < buf.len() {
+        let n = inode
+            .read_at((offset + done) as u64, &mut buf[done..])
+            .await?;
+        if n == 0 {
+            return Err(Errno::ENOEXEC);
+        }
+        done += n;
+    }
+    Ok(())
+}
+
+/// Loads the statically linked ELF program at `path` into a new address
+/// space, with a stack holding `args`, `envs` and the auxiliary vector as
+/// the Linux ABI lays them out. Returns the space and the context entering
+/// the program.
+pub async fn load_program(
+    path: &Path,
+    args: &[Vec<u8>],
+    envs: &[Vec<u8>],
+) -> Result<(AddrSpace, TrapContext), Errno> {
+    let meta = path.dentry.inode().metadata().await?;
+    if meta.inode_type() != Some(InodeType::File) || meta.mode & 0o111 == 0 {
+        return Err(Errno::EACCES);
+    }
+    let mut ehdr = [0; EHDR_SIZE];
+    read_exact(path, 0, &mut ehdr).await?;
+    let elf_type = u16_at(&ehdr, 16);
+    if &ehdr[..4] != ELF_MAGIC
+        || ehdr[4] != ELFCLASS64
+        || ehdr[5] != ELFDATA2LSB
+        || !matches!(elf_type, ET_EXEC | ET_DYN)
+        || u16_at(&ehdr, 18) != EM_RISCV
+        || u16_at(&ehdr, 54) as usize != PHDR_SIZE
+    {
+        return Err(Errno::ENOEXEC);
+    }
+    let base = match elf_type {
+        ET_DYN => USER_PIE_BASE,
+        _ => 0,
+    };
+    let entry = base + u64_at(&ehdr, 24);
+    let phoff = u64_at(&ehdr, 32);
+    let phnum = u16_at(&ehdr, 56) as usize;
+    if phnum > MAX_PHNUM {
+        return Err(Errno::ENOEXEC);
+    }
+    let mut phdrs = vec![0; phnum * PHDR_SIZE];
+    read_exact(path, phoff, &mut phdrs).await?;
+
+    let mut space = AddrSpace::new_user();
+    let mut phdr_addr = 0;
+    for phdr in phdrs.chunks_exact(PHDR_SIZE) {
+        match u32_at(phdr, 0) {
+            PT_LOAD => {}
+            // no dynamic linker yet
+            PT_INTERP => return Err(Errno::ENOEXEC),
+            _ => continue,
+        }
+        let flags = u32_at(phdr, 4);
+        let offset = u64_at(phdr, 8);
+        let vaddr = base + u64_at(phdr, 16);
+        let file_size = u64_at(phdr, 32);
+        let mem_size = u64_at(phdr, 40);
+        let end = vaddr.checked_add(mem_size).ok_or(Errno::ENOEXEC)?;
+        if file_size > mem_size || end > USER_STACK_TOP - USER_STACK_SIZE {
+            return Err(Errno::ENOEXEC);
+        }
+        let mut perm = PTEFlags::empty();
+        for (flag, bit) in [
+            (PF_R, PTEFlags::R),
+            (PF_W, PTEFlags::W),
+            (PF_X, PTEFlags::X),
+        ] {
+            if flags & flag != 0 {
+                perm |= bit;
+            }
+        }
+        let start = VirtAddr::from(vaddr);
+        space.map_area(start..end.into(), AreaType::Elf, perm)?;
+        let mut data = vec![0; file_size];
+        read_exact(path, offset, &mut data).await?;
+        space.fill(start, &data)?;
+        if (offset..offset + file_size).contains(&phoff) {
+            phdr_addr = vaddr + (phoff - offset);
+        }
+    }
+
+    let stack_bottom = VirtAddr::from(USER_STACK_TOP - USER_STACK_SIZE);
+    space.map_area(
+        stack_bottom..USER_STACK_TOP.into(),
+        AreaType::Stack,
+        PTEFlags::R | PTEFlags::W,
+    )?;
+    let mut top = USER_STACK_TOP;
+    let mut push_bytes = |bytes: &[u8]| -> Result<usize, Errno> {
+        top -= bytes.len();
+        space.fill(top.into(), bytes)?;
+        Ok(top)
+    };
+    let mut strings = |list: &[Vec<u8>]| -> Result<Vec<usize>, Errno> {
+        list.iter()
+            .map(|string| {
+                push_bytes(&[0])?;
+                push_bytes(string)
+            })
+            .collect()
+    };
+    let arg_ptrs = strings(args)?;
+    let env_ptrs = strings(envs)?;
+    // not random, but different for every program
+    let seed = now().wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let random = push_bytes(&[seed.to_le_bytes(), seed.rotate_left(32).to_le_bytes()].concat())?;
+
+    let auxv = [
+        (AT_PHDR, phdr_addr),
+        (AT_PHENT, PHDR_SIZE),
+        (AT_PHNUM, phnum),
+        (AT_PAGESZ, PAGE_SIZE_4K),
+        (AT_BASE, 0),
+        (AT_ENTRY, entry),
+        (AT_RANDOM, random),
+        (AT_NULL, 0),
+    ];
+    let mut words = vec![args.len()];
+    words.extend(&arg_ptrs);
+    words.push(0);
+    words.extend(&env_ptrs);
+    words.push(0);
+    words.extend(auxv.iter().flat_map(|&(key, value)| [key, value]));
+    let sp = (top - words.len() * 8) & !0xf;
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    if sp < stack_bottom.as_usize() {
+        return Err(Errno::E2BIG);
+    }
+    space.fill(sp.into(), &bytes)?;
+
+    let cx = TrapContext::new_user(entry, sp, space.token());
+    Ok((space, cx))
+}
@@ -1,9 +1,15 @@
-mod idle;
-mod manager;
+mod init;
+mod loader;
+mod registry;
+mod rlimit;
+mod signal;
 mod task;
 mod tid;
 
-pub use idle::*;
-pub use manager::*;
+pub use init::*;
+pub use loader::*;
+pub use registry::*;
+pub use rlimit::*;
+pub use signal::*;
 pub use task::*;
 pub use tid::*;
@@ -0,0 +1,218 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use crate::{
+    fs::FdTable,
+    mem::AddrSpace,
+    runtime::{SchedParams, TaskWaker, now},
+    sync::SpinMutex,
+};
+
+use super::{PendingSignals, ResourceLimits};
+
+/// Length of the names of tasks, as in Linux.
+pub const TASK_COMM_LEN: usize = 16;
+
+/// The part of a task other tasks may access while it is queued, parked or
+/// running on another hart.
+pub struct TaskShared {
+    tid: usize,
+    /// Wakes the task, and holds it while it is parked.
+    waker: Arc<TaskWaker>,
+    /// Process group, for job control. A new task leads its own group.
+    pgid: AtomicUsize,
+    pub signals: PendingSignals,
+    pub limits: SpinMutex<ResourceLimits>,
+    /// Scheduling settings, which the task takes over when it is next
+    /// queued or returns to user mode once `sched_changed` is set.
+    sched: SpinMutex<SchedParams>,
+    sched_changed: AtomicBool,
+    /// The hart the task last ran on.
+    processor: AtomicUsize,
+    /// The address space of the task, for inspecting it from outside,
+    /// replaced by `Task::exec`.
+    space: SpinMutex<Weak<AddrSpace>>,
+    /// The fd table of the task, replaced along with it by `Task::exec`.
+    files: SpinMutex<Weak<SpinMutex<FdTable>>>,
+    /// Arguments of the program the task runs, each followed by a nul.
+    cmdline: SpinMutex<Vec<u8>>,
+    /// When the task was created, in timer ticks since boot.
+    start_time: u64,
+    /// Time the task ran in user mode, in timer ticks.
+    runtime: AtomicU64,
+}
+
+impl TaskShared {
+    pub fn new(tid: usize, space: Weak<AddrSpace>, files: Weak<SpinMutex<FdTable>>) -> Self {
+        Self {
+            tid,
+            waker: Arc::new(TaskWaker::new(tid)),
+            pgid: AtomicUsize::new(tid),
+            signals: PendingSignals::default(),
+            limits: SpinMutex::new(ResourceLimits::default()),
+            sched: SpinMutex::new(SchedParams::default()),
+            sched_changed: AtomicBool::new(false),
+            processor: AtomicUsize::new(0),
+            space: SpinMutex::new(space),
+            files: SpinMutex::new(files),
+            cmdline: SpinMutex::new(Vec::new()),
+            start_time: now(),
+            runtime: AtomicU64::new(0),
+        }
+    }
+
+    pub fn tid(&self) -> usize {
+        self.tid
+    }
+
+    pub fn waker(&self) -> &Arc<TaskWaker> {
+        &self.waker
+    }
+
+    pub fn pgid(&self) -> usize {
+        self.pgid.load(Ordering::Acquire)
+    }
+
+    pub fn set_pgid(&self, pgid: usize) {
+        self.pgid.store(pgid, Ordering::Release);
+    }
+
+    /// The address space of the task, `None` once it exited.
+    pub fn space(&self) -> Option<Arc<AddrSpace>> {
+        self.space.lock().upgrade()
+    }
+
+    pub(super) fn set_space(&self, space: &Arc<AddrSpace>) {
+        *self.space.lock() = Arc::downgrade(space);
+    }
+
+    /// The fd table of the task, `None` once it exited.
+    pub fn files(&self) -> Option<Arc<SpinMutex<FdTable>>> {
+        self.files.lock().upgrade()
+    }
+
+    pub(super) fn set_files(&self, files: &Arc<SpinMutex<FdTable>>) {
+        *self.files.lock() = Arc::downgrade(files);
+    }
+
+    pub fn sched_params(&self) -> SchedParams {
+        *self.sched.lock()
+    }
+
+    /// Changes the scheduling settings with `f`, which works on a copy that
+    /// is kept if it succeeds. The task applies them when it is next queued
+    /// or returns to user mode, see
+    /// [`Task::apply_sched_params`](super::Task::apply_sched_params).
+    pub fn update_sched_params<R, E>(
+        &self,
+        f: impl FnOnce(&mut SchedParams) -> Result<R, E>,
+    ) -> Result<R, E> {
+        let mut sched = self.sched.lock();
+        let mut params = *sched;
+        let ret = f(&mut params)?;
+        *sched = params;
+        self.sched_changed.store(true, Ordering::Release);
+        Ok(ret)
+    }
+
+    /// The settings changed since the last call, if any.
+    pub(super) fn take_sched_change(&self) -> Option<SchedParams> {
+        self.sched_changed
+            .swap(false, Ordering::Acquire)
+            .then(|| self.sched_params())
+    }
+
+    /// The hart the task last ran on.
+    pub fn processor(&self) -> usize {
+        self.processor.load(Ordering::Relaxed)
+    }
+
+    pub fn set_processor(&self, cpu: usize) {
+        self.processor.store(cpu, Ordering::Relaxed);
+    }
+
+    /// Takes over what a task created by `clone` inherits from `parent`:
+    /// its process group, resource limits, scheduling settings and
+    /// arguments.
+    pub(super) fn inherit(&self, parent: &TaskShared) {
+        self.set_pgid(parent.pgid());
+        *self.limits.lock() = *parent.limits.lock();
+        *self.sched.lock() = parent.sched_params();
+        *self.cmdline.lock() = parent.cmdline();
+    }
+
+    /// The arguments of the program, each followed by a nul.
+    pub fn cmdline(&self) -> Vec<u8> {
+        self.cmdline.lock().clone()
+    }
+
+    /// Records the arguments of the program the task runs.
+    pub fn set_cmdline<'a>(&self, args: impl IntoIterator<Item = &'a [u8]>) {
+        let mut cmdline = self.cmdline.lock();
+        cmdline.clear();
+        for arg in args {
+            cmdline.extend_from_slice(arg);
+            cmdline.push(0);
+        }
+    }
+
+    /// The name of the task: the last component of its first argument,
+    /// truncated to fit [`TASK_COMM_LEN`] with a nul.
+    pub fn comm(&self) -> Vec<u8> {
+        let cmdline = self.cmdline.lock();
+        let program = cmdline.split(|&byte| byte == 0).next().unwrap_or_default();
+        let name = program
+            .rsplit(|&byte| byte == b'/')
+            .next()
+            .unwrap_or_default();
+        name[..name.len().min(TASK_COMM_LEN - 1)].to_vec()
+    }
+
+    pub fn start_time(&self) -> u64 {
+        self.start_time
+    }
+
+    pub fn runtime(&self) -> u64 {
+        self.runtime.load(Ordering::Relaxed)
+    }
+
+    /// Adds `ticks` to the time the task ran.
+    pub fn charge_runtime(&self, ticks: u64) {
+        self.runtime.fetch_add(ticks, Ordering::Relaxed);
+    }
+}
+
+/// All live user tasks by tid.
+static TASKS: SpinMutex<BTreeMap<usize, Weak<TaskShared>>> = SpinMutex::new(BTreeMap::new());
+
+pub fn register_task(shared: &Arc<TaskShared>) {
+    TASKS.lock().insert(shared.tid, Arc::downgrade(shared));
+}
+
+pub fn unregister_task(tid: usize) {
+    TASKS.lock().remove(&tid);
+}
+
+pub fn find_task(tid: usize) -> Option<Arc<TaskShared>> {
+    TASKS.lock().get(&tid)?.upgrade()
+}
+
+/// All live tasks, by tid.
+pub fn all_tasks() -> Vec<Arc<TaskShared>> {
+    TASKS.lock().values().filter_map(Weak::upgrade).collect()
+}
+
+/// Live tasks in process group `pgid`.
+pub fn tasks_in_group(pgid: usize) -> Vec<Arc<TaskShared>> {
+    TASKS
+        .lock()
+        .values()
+        .filter_map(Weak::upgrade)
+        .filter(|task| task.pgid() == pgid)
+        .collect()
+}
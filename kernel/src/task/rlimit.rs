@@ -0,0 +1,79 @@
+use crate::Errno;
+
+pub const RLIMIT_CPU: usize = 0;
+pub const RLIMIT_FSIZE: usize = 1;
+pub const RLIMIT_DATA: usize = 2;
+pub const RLIMIT_STACK: usize = 3;
+pub const RLIMIT_CORE: usize = 4;
+pub const RLIMIT_RSS: usize = 5;
+pub const RLIMIT_NPROC: usize = 6;
+pub const RLIMIT_NOFILE: usize = 7;
+pub const RLIMIT_MEMLOCK: usize = 8;
+pub const RLIMIT_AS: usize = 9;
+pub const RLIM_NLIMITS: usize = 16;
+
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+/// Highest `RLIMIT_NOFILE` that can be set.
+pub const NR_OPEN: u64 = 1 << 20;
+
+/// `struct rlimit` of user space.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RLimit {
+    pub rlim_cur: u64,
+    pub rlim_max: u64,
+}
+
+impl RLimit {
+    pub const INFINITY: Self = Self {
+        rlim_cur: RLIM_INFINITY,
+        rlim_max: RLIM_INFINITY,
+    };
+}
+
+/// The resource limits of a task, inherited by the tasks it creates.
+#[derive(Debug, Clone, Copy)]
+pub struct ResourceLimits([RLimit; RLIM_NLIMITS]);
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        let mut limits = [RLimit::INFINITY; RLIM_NLIMITS];
+        limits[RLIMIT_STACK].rlim_cur = 8 << 20;
+        limits[RLIMIT_CORE].rlim_cur = 0;
+        limits[RLIMIT_NOFILE] = RLimit {
+            rlim_cur: 1024,
+            rlim_max: 4096,
+        };
+        limits[RLIMIT_MEMLOCK] = RLimit {
+            rlim_cur: 8 << 20,
+            rlim_max: 8 << 20,
+        };
+        Self(limits)
+    }
+}
+
+impl ResourceLimits {
+    pub fn get(&self, resource: usize) -> Option<RLimit> {
+        self.0.get(resource).copied()
+    }
+
+    /// Changes limit `resource`. Raising the hard limit needs privileges,
+    /// which every task has for now.
+    pub fn set(&mut self, resource: usize, limit: RLimit) -> Result<(), Errno> {
+        let slot = self.0.get_mut(resource).ok_or(Errno::EINVAL)?;
+        if limit.rlim_cur > limit.rlim_max {
+            return Err(Errno::EINVAL);
+        }
+        if resource == RLIMIT_NOFILE && limit.rlim_max > NR_OPEN {
+            return Err(Errno::EPERM);
+        }
+        *slot = limit;
+        Ok(())
+    }
+
+    /// Descriptors must be below this.
+    pub fn nofile(&self) -> usize {
+        self.0[RLIMIT_NOFILE].rlim_cur as usize
+    }
+}
@@ -0,0 +1,117 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::task::Wake;
+
+use crate::Errno;
+
+use super::{TaskShared, find_task, tasks_in_group};
+
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGPIPE: usize = 13;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+/// Signals are numbered `1..NSIG`.
+pub const NSIG: usize = 65;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+pub fn default_action(sig: usize) -> DefaultAction {
+    match sig {
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        SIGCONT => DefaultAction::Continue,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+/// Signals sent to a task and not delivered yet, one bit per signal.
+#[derive(Default)]
+pub struct PendingSignals(AtomicU64);
+
+impl PendingSignals {
+    pub fn raise(&self, sig: usize) {
+        self.0.fetch_or(1 << (sig - 1), Ordering::AcqRel);
+    }
+
+    /// Whether a pending signal interrupts blocking syscalls. There are no
+    /// masks or handlers yet, so these are the signals whose default action
+    /// terminates the task: ignored ones would be dropped on delivery, and
+    /// stopping is not implemented.
+    pub fn interrupts(&self) -> bool {
+        let bits = self.0.load(Ordering::Acquire);
+        (1..NSIG).any(|sig| {
+            bits & (1 << (sig - 1)) != 0 && default_action(sig) == DefaultAction::Terminate
+        })
+    }
+
+    /// The pending signals, bit `sig - 1` for signal `sig`.
+    pub fn bits(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Takes the lowest pending signal.
+    pub fn take(&self) -> Option<usize> {
+        let mut bits = self.0.load(Ordering::Acquire);
+        loop {
+            if bits == 0 {
+                return None;
+            }
+            let sig = bits.trailing_zeros() as usize + 1;
+            match self.0.compare_exchange(
+                bits,
+                bits & !(1 << (sig - 1)),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(sig),
+                Err(current) => bits = current,
+            }
+        }
+    }
+}
+
+/// Sends `sig` to task `tid` and wakes it, so that an interruptible wait
+/// returns `EINTR`. Signal 0 only checks that the task exists.
+pub fn send_signal(tid: usize, sig: usize) -> Result<(), Errno> {
+    if sig >= NSIG {
+        return Err(Errno::EINVAL);
+    }
+    let task = find_task(tid).ok_or(Errno::ESRCH)?;
+    raise(&task, sig);
+    Ok(())
+}
+
+/// Sends `sig` to every task of process group `pgid`.
+pub fn send_group_signal(pgid: usize, sig: usize) -> Result<(), Errno> {
+    if sig >= NSIG {
+        return Err(Errno::EINVAL);
+    }
+    let tasks = tasks_in_group(pgid);
+    if tasks.is_empty() {
+        return Err(Errno::ESRCH);
+    }
+    for task in tasks {
+        raise(&task, sig);
+    }
+    Ok(())
+}
+
+fn raise(task: &TaskShared, sig: usize) {
+    if sig != 0 {
+        task.signals.raise(sig);
+        task.waker().wake_by_ref();
+    }
+}
@@ -1,17 +1,174 @@
-use core::cell::SyncUnsafeCell;
+use core::{cell::SyncUnsafeCell, pin::Pin};
 
-use crate::{mem::AddrSpace, trap::TrapContext};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
 
-use super::TidHandle;
+use crate::{
+    Errno, SysResult,
+    drivers::TtyFile,
+    fs::{FdTable, File, OpenFlags, Path},
+    mem::AddrSpace,
+    runtime::{SchedEntity, TaskWaker},
+    sync::SpinMutex,
+    syscall::exit_pi_futexes,
+    trap::TrapContext,
+};
+
+use super::{TaskShared, TidHandle, alloc_tid, register_task, unregister_task};
+
+/// `clone` flag sharing the address space instead of copying it.
+pub const CLONE_VM: usize = 0x100;
+/// `clone` flag sharing the fd table instead of copying it.
+pub const CLONE_FILES: usize = 0x400;
+
+/// A system call in progress, kept in the task while it is parked.
+pub type SyscallFuture = Pin<Box<dyn Future<Output = SysResult> + Send>>;
 
 pub struct Task {
     tid: TidHandle,
-    space: AddrSpace,
+    space: Arc<AddrSpace>,
     pub trap_context: SyncUnsafeCell<TrapContext>,
+    pub sched: SchedEntity,
+    pub syscall: Option<SyscallFuture>,
+    shared: Arc<TaskShared>,
+    /// Open files, shared with the tasks the table was shared with.
+    pub files: Arc<SpinMutex<FdTable>>,
+    /// Working directory, `None` for the root directory.
+    pub cwd: Option<Path>,
 }
 
 impl Task {
+    pub fn new(space: AddrSpace, trap_context: TrapContext) -> Self {
+        let files = Arc::new(SpinMutex::new(FdTable::default()));
+        Self::with_parts(Arc::new(space), trap_context, files)
+    }
+
+    fn with_parts(
+        space: Arc<AddrSpace>,
+        trap_context: TrapContext,
+        files: Arc<SpinMutex<FdTable>>,
+    ) -> Self {
+        let tid = alloc_tid();
+        let shared = Arc::new(TaskShared::new(
+            tid.0,
+            Arc::downgrade(&space),
+            Arc::downgrade(&files),
+        ));
+        register_task(&shared);
+        Self {
+            tid,
+            space,
+            trap_context: SyncUnsafeCell::new(trap_context),
+            sched: SchedEntity::default(),
+            syscall: None,
+            shared,
+            files,
+            cwd: None,
+        }
+    }
+
+    /// Creates the first user task, running the program loaded into
+    /// `space` with `args`, with descriptors 0 to 2 open on the console.
+    pub fn new_init(space: AddrSpace, trap_context: TrapContext, args: &[Vec<u8>]) -> Self {
+        let task = Self::new(space, trap_context);
+        task.shared.set_cmdline(args.iter().map(Vec::as_slice));
+        let console: Arc<dyn File> = Arc::new(TtyFile::new(OpenFlags::RDWR));
+        {
+            let mut files = task.files.lock();
+            for _ in 0..3 {
+                files.alloc(console.clone(), false, usize::MAX).unwrap();
+            }
+        }
+        task
+    }
+
+    pub fn tid(&self) -> usize {
+        self.tid.0
+    }
+
+    /// Creates the task `clone` with `flags` makes of this one. It resumes
+    /// where this task does, with a copy of its registers.
+    pub fn clone_task(&self, flags: usize) -> Result<Self, Errno> {
+        let space = match flags & CLONE_VM {
+            0 => Arc::new(self.space.fork()?),
+            _ => self.space.clone(),
+        };
+        let mut trap_context = *self.trap_context_mut();
+        trap_context.user_satp = space.token();
+        let mut task = Self::with_parts(space, trap_context, self.clone_files(flags));
+        task.sched = self.sched.clone();
+        task.cwd = self.cwd.clone();
+        task.shared.inherit(&self.shared);
+        task.sched.set_params(task.shared.sched_params());
+        Ok(task)
+    }
+
+    /// Replaces the program of the task with the one loaded into `space`,
+    /// as `execve` does.
+    pub fn exec(&mut self, space: AddrSpace, trap_context: TrapContext, args: &[Vec<u8>]) {
+        self.space = Arc::new(space);
+        self.shared.set_space(&self.space);
+        *self.trap_context.get_mut() = trap_context;
+        self.shared.set_cmdline(args.iter().map(Vec::as_slice));
+        self.exec_files();
+    }
+
+    /// The fd table for a task created by `clone` with `flags`.
+    pub fn clone_files(&self, flags: usize) -> Arc<SpinMutex<FdTable>> {
+        match flags & CLONE_FILES {
+            0 => Arc::new(SpinMutex::new(self.files.lock().clone())),
+            _ => self.files.clone(),
+        }
+    }
+
+    /// Prepares the fd table for `execve`: it stops being shared, and the
+    /// descriptors marked close-on-exec are closed.
+    fn exec_files(&mut self) {
+        if Arc::strong_count(&self.files) > 1 {
+            let files = self.files.lock().clone();
+            self.files = Arc::new(SpinMutex::new(files));
+            self.shared.set_files(&self.files);
+        }
+        self.files.lock().close_on_exec();
+    }
+
+    /// Takes over the scheduling settings changed through
+    /// [`TaskShared::update_sched_params`], e.g. by another task.
+    pub fn apply_sched_params(&mut self) {
+        if let Some(params) = self.shared.take_sched_change() {
+            self.sched.set_params(params);
+        }
+    }
+
+    /// Descriptors of the task must be below this, its `RLIMIT_NOFILE`.
+    pub fn fd_limit(&self) -> usize {
+        self.shared.limits.lock().nofile()
+    }
+
+    pub fn space(&self) -> &Arc<AddrSpace> {
+        &self.space
+    }
+
+    pub fn waker(&self) -> &Arc<TaskWaker> {
+        self.shared.waker()
+    }
+
+    pub fn shared(&self) -> &Arc<TaskShared> {
+        &self.shared
+    }
+
+    /// Whether a signal waiting for delivery interrupts blocking syscalls.
+    pub fn has_pending_signal(&self) -> bool {
+        self.shared.signals.interrupts()
+    }
+
     pub fn trap_context_mut(&self) -> &mut TrapContext {
         unsafe { &mut *self.trap_context.get() }
     }
 }
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        exit_pi_futexes(self.tid());
+        unregister_task(self.tid());
+    }
+}
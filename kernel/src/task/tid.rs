@@ -1,8 +1,6 @@
-use spin::Mutex;
+use crate::{allocator::RecycleAllocator, sync::SpinMutex};
 
-use crate::allocator::RecycleAllocator;
-
-pub static TID_ALLOCATOR: Mutex<RecycleAllocator> = Mutex::new(RecycleAllocator::new(1));
+pub static TID_ALLOCATOR: SpinMutex<RecycleAllocator> = SpinMutex::new(RecycleAllocator::new(1));
 
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq)]
 pub struct TidHandle(pub usize);
@@ -14,7 +14,22 @@ pub struct TrapContext {
     pub user_satp: usize,
 }
 
+/// `sstatus.SPIE`, enabling interrupts once `sret` entered user mode.
+const SSTATUS_SPIE: usize = 1 << 5;
+
 impl TrapContext {
+    /// Context entering a user program at `entry` with stack pointer `sp`,
+    /// in the address space `satp` selects. `sstatus.SPP` is clear, so that
+    /// `sret` goes to user mode.
+    pub fn new_user(entry: usize, sp: usize, satp: usize) -> Self {
+        let mut cx = Self::empty();
+        cx.user_x[2] = sp;
+        cx.sstatus = SSTATUS_SPIE;
+        cx.sepc = entry;
+        cx.user_satp = satp;
+        cx
+    }
+
     pub const fn empty() -> Self {
         Self {
             user_x: [0; 32],
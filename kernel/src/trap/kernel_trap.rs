@@ -1,6 +1,8 @@
 use log::info;
 use riscv::register::{scause, sepc, sstatus, stval, stvec};
 
+use crate::drivers::handle_external_irq;
+
 pub fn set_kernel_trap() {
     unsafe {
         stvec::write(__trap_from_kernel as usize, stvec::TrapMode::Direct);
@@ -73,6 +75,7 @@ pub fn kernel_trap_handler() {
                 sepc::write(sepc::read() + 4);
             }
         }
+        scause::Trap::Interrupt(scause::Interrupt::SupervisorExternal) => handle_external_irq(),
         _ => {
             panic_on_unknown_trap();
         }
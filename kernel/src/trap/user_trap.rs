@@ -1,6 +1,17 @@
-use riscv::register::stvec;
+use alloc::boxed::Box;
+use log::error;
+use riscv::register::{
+    scause::{self, Exception, Interrupt, Trap},
+    stval, stvec,
+};
 
-use crate::{config::TRAMPOLINE, task::Task};
+use crate::{
+    config::TRAMPOLINE,
+    drivers::handle_external_irq,
+    hart::{exit_current, handle_ipi, resume_current, with_current_task, yield_current},
+    runtime::{EXECUTOR, handle_timer},
+    syscall::syscall,
+};
 
 use super::{TrapContext, set_kernel_trap};
 
@@ -146,14 +157,53 @@ pub unsafe extern "C" fn __return_to_user(cx: *mut TrapContext, user_satp: usize
 }
 
 #[unsafe(no_mangle)]
-pub fn user_trap_handler() {
+pub fn user_trap_handler() -> ! {
     set_kernel_trap();
+    let executor = EXECUTOR.get().expect("executor initialized");
+    with_current_task(|task| {
+        let ran = executor.end_run(&mut task.sched);
+        task.shared().charge_runtime(ran);
+    });
+    match scause::read().cause() {
+        Trap::Exception(Exception::UserEnvCall) => with_current_task(|task| {
+            let cx = task.trap_context_mut();
+            cx.sepc += 4;
+            let id = cx.user_x[17];
+            let args = [
+                cx.user_x[10],
+                cx.user_x[11],
+                cx.user_x[12],
+                cx.user_x[13],
+                cx.user_x[14],
+                cx.user_x[15],
+            ];
+            task.syscall = Some(Box::pin(syscall(id, args)));
+        }),
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            handle_timer();
+            yield_current();
+        }
+        Trap::Interrupt(Interrupt::SupervisorSoft) => handle_ipi(),
+        Trap::Interrupt(Interrupt::SupervisorExternal) => handle_external_irq(),
+        cause => {
+            with_current_task(|task| {
+                error!(
+                    "task {} killed by {:?}, bad addr = {:#x}, bad instruction = {:#x}",
+                    task.tid(),
+                    cause,
+                    stval::read(),
+                    task.trap_context_mut().sepc,
+                )
+            });
+            exit_current();
+        }
+    }
+    resume_current()
 }
 
 #[unsafe(no_mangle)]
-pub fn user_trap_return(task: &Task) -> ! {
+pub fn user_trap_return(cx: *mut TrapContext) -> ! {
     set_user_trap();
-    let trap_ctx = task.trap_context_mut();
-    unsafe { __return_to_user(trap_ctx, trap_ctx.user_satp) };
+    unsafe { __return_to_user(cx, (*cx).user_satp) };
     unreachable!();
 }